@@ -0,0 +1,342 @@
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+
+use crate::date;
+use crate::request::{Method, Request, Version};
+use crate::status::StatusCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// The Apache/nginx combined log format, followed by the latency in
+    /// seconds.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "unknown log format {:?}, expected combined or json",
+                s
+            )),
+        }
+    }
+}
+
+/// One served request, as it is recorded in the access log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub remote_addr: Option<IpAddr>,
+    /// When the request was received.
+    pub time: SystemTime,
+    /// `None` when the request could not be parsed.
+    pub request: Option<RequestLine>,
+    pub status: StatusCode,
+    /// Body bytes sent, not counting headers or chunk framing.
+    pub bytes_sent: u64,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub latency: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestLine {
+    pub method: Method,
+    pub target: String,
+    pub version: Version,
+}
+
+impl Entry {
+    /// Starts an entry for `request`; the response details are filled in
+    /// once it has been sent.
+    pub fn new(remote_addr: Option<IpAddr>, time: SystemTime, request: &Request) -> Entry {
+        Entry {
+            remote_addr,
+            time,
+            request: Some(RequestLine {
+                method: request.method,
+                target: request.target.clone(),
+                version: request.version,
+            }),
+            status: StatusCode::Ok,
+            bytes_sent: 0,
+            referer: request.header("Referer").map(str::to_string),
+            user_agent: request.header("User-Agent").map(str::to_string),
+            latency: Duration::ZERO,
+        }
+    }
+
+    /// An entry for a request that was rejected before it could be parsed.
+    pub fn unparsed(remote_addr: Option<IpAddr>, time: SystemTime) -> Entry {
+        Entry {
+            remote_addr,
+            time,
+            request: None,
+            status: StatusCode::BadRequest,
+            bytes_sent: 0,
+            referer: None,
+            user_agent: None,
+            latency: Duration::ZERO,
+        }
+    }
+
+    /// Formats the entry as one line, including the trailing newline.
+    pub fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Combined => self.combined(),
+            LogFormat::Json => self.json(),
+        }
+    }
+
+    // host ident user [time] "request" status bytes "referer" "agent" latency
+    fn combined(&self) -> String {
+        let request = match &self.request {
+            Some(line) => format!("{} {} {}", line.method, line.target, line.version),
+            None => "-".to_string(),
+        };
+        let bytes = match self.bytes_sent {
+            0 => "-".to_string(),
+            n => n.to_string(),
+        };
+        format!(
+            "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\" {:.3}\n",
+            self.remote_addr
+                .map_or_else(|| "-".to_string(), |ip| ip.to_string()),
+            date::format_common_log_date(self.time),
+            escape_quoted(&request),
+            self.status.code(),
+            bytes,
+            escape_quoted(self.referer.as_deref().unwrap_or("-")),
+            escape_quoted(self.user_agent.as_deref().unwrap_or("-")),
+            self.latency.as_secs_f64()
+        )
+    }
+
+    fn json(&self) -> String {
+        let mut out = String::from("{");
+        let mut field = |name: &str, value: Option<String>| {
+            if out.len() > 1 {
+                out.push(',');
+            }
+            out.push_str(&json_string(name));
+            out.push(':');
+            out.push_str(value.as_deref().unwrap_or("null"));
+        };
+        let line = self.request.as_ref();
+        let path = line.map(|l| l.target.split('?').next().unwrap_or(""));
+        let query = line.and_then(|l| l.target.split_once('?')).map(|(_, q)| q);
+
+        field("time", Some(json_string(&date::format_rfc3339(self.time))));
+        field(
+            "remote_addr",
+            self.remote_addr.map(|ip| json_string(&ip.to_string())),
+        );
+        field("method", line.map(|l| json_string(l.method.as_str())));
+        field("path", path.map(json_string));
+        field("query", query.map(json_string));
+        field("version", line.map(|l| json_string(l.version.as_str())));
+        field("status", Some(self.status.code().to_string()));
+        field("bytes_sent", Some(self.bytes_sent.to_string()));
+        field("referer", self.referer.as_deref().map(json_string));
+        field("user_agent", self.user_agent.as_deref().map(json_string));
+        field(
+            "latency_ms",
+            Some(format!("{:.3}", self.latency.as_secs_f64() * 1000.0)),
+        );
+        out.push_str("}\n");
+        out
+    }
+}
+
+// Keeps client-controlled text from breaking out of its quotes or
+// forging extra log lines.
+fn escape_quoted(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\x{:02x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub(crate) fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Writes access log entries to stdout or a file.
+///
+/// Clones share the destination. Each entry is written with a single
+/// call, so lines from concurrent connections never interleave.
+#[derive(Clone)]
+pub struct AccessLog {
+    format: LogFormat,
+    target: Arc<Mutex<Target>>,
+}
+
+enum Target {
+    Stdout,
+    File { path: PathBuf, file: File },
+}
+
+impl AccessLog {
+    pub fn stdout(format: LogFormat) -> AccessLog {
+        AccessLog {
+            format,
+            target: Arc::new(Mutex::new(Target::Stdout)),
+        }
+    }
+
+    /// Appends to `path`, creating it if needed.
+    pub fn file(path: impl Into<PathBuf>, format: LogFormat) -> io::Result<AccessLog> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        Ok(AccessLog {
+            format,
+            target: Arc::new(Mutex::new(Target::File { path, file })),
+        })
+    }
+
+    pub fn format(&self) -> LogFormat {
+        self.format
+    }
+
+    /// Reopens the log file so that a rotated file is let go. Does nothing
+    /// when logging to stdout.
+    pub fn reopen(&self) -> io::Result<()> {
+        let mut target = self.target.lock().unwrap();
+        if let Target::File { path, file } = &mut *target {
+            *file = open_append(path)?;
+        }
+        Ok(())
+    }
+
+    pub fn log(&self, entry: &Entry) {
+        let line = entry.format(self.format);
+        let mut target = self.target.lock().unwrap();
+        let written = match &mut *target {
+            Target::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+            Target::File { file, .. } => file.write_all(line.as_bytes()),
+        };
+        if let Err(e) = written {
+            eprintln!("failed to write access log: {}", e);
+        }
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::RequestReader;
+    use std::env;
+    use std::fs;
+    use std::time::UNIX_EPOCH;
+
+    fn entry() -> Entry {
+        let raw = "GET /docs/a%20b.txt?x=1 HTTP/1.1\r\nUser-Agent: curl/8.0 \"quoted\"\r\n\
+                   Referer: http://example.com/\r\n\r\n";
+        let request = RequestReader::new(raw.as_bytes())
+            .read_request()
+            .unwrap()
+            .unwrap();
+        let time = UNIX_EPOCH + Duration::from_millis(784_111_777_042);
+        let mut entry = Entry::new(Some([192, 0, 2, 7].into()), time, &request);
+        entry.status = StatusCode::NotFound;
+        entry.bytes_sent = 1234;
+        entry.latency = Duration::from_micros(2500);
+        entry
+    }
+
+    #[test]
+    fn formats_combined_lines() {
+        assert_eq!(
+            entry().format(LogFormat::Combined),
+            "192.0.2.7 - - [06/Nov/1994:08:49:37 +0000] \"GET /docs/a%20b.txt?x=1 HTTP/1.1\" \
+             404 1234 \"http://example.com/\" \"curl/8.0 \\\"quoted\\\"\" 0.003\n"
+        );
+        let bad = Entry::unparsed(None, UNIX_EPOCH);
+        assert_eq!(
+            bad.format(LogFormat::Combined),
+            "- - - [01/Jan/1970:00:00:00 +0000] \"-\" 400 - \"-\" \"-\" 0.000\n"
+        );
+    }
+
+    #[test]
+    fn formats_json_lines() {
+        assert_eq!(
+            entry().format(LogFormat::Json),
+            "{\"time\":\"1994-11-06T08:49:37.042Z\",\"remote_addr\":\"192.0.2.7\",\
+             \"method\":\"GET\",\"path\":\"/docs/a%20b.txt\",\"query\":\"x=1\",\
+             \"version\":\"HTTP/1.1\",\"status\":404,\"bytes_sent\":1234,\
+             \"referer\":\"http://example.com/\",\"user_agent\":\"curl/8.0 \\\"quoted\\\"\",\
+             \"latency_ms\":2.500}\n"
+        );
+        let bad = Entry::unparsed(None, UNIX_EPOCH).format(LogFormat::Json);
+        assert!(bad.contains("\"method\":null,"), "{}", bad);
+    }
+
+    #[test]
+    fn escapes_control_characters() {
+        assert_eq!(escape_quoted("a\nb\"\\"), "a\\x0ab\\\"\\\\");
+        assert_eq!(json_string("a\nb\u{1}"), "\"a\\nb\\u0001\"");
+    }
+
+    #[test]
+    fn reopen_follows_a_rotated_file() {
+        let dir = env::temp_dir().join(format!("web-service-access-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let rotated = dir.join("access.log.1");
+
+        let log = AccessLog::file(&path, LogFormat::Combined).unwrap();
+        log.log(&entry());
+        fs::rename(&path, &rotated).unwrap();
+        log.log(&entry());
+        log.reopen().unwrap();
+        log.log(&entry());
+
+        assert_eq!(fs::read_to_string(&rotated).unwrap().lines().count(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
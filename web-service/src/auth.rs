@@ -0,0 +1,502 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+
+pub const DEFAULT_REALM: &str = "web-service";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Public,
+    /// Needs a user from the htpasswd file or a bearer token.
+    Protected,
+}
+
+/// Whether the paths under a prefix need credentials.
+///
+/// The prefix matches whole path segments, so `/admin` covers `/admin` and
+/// `/admin/users` but not `/administrator`. Paths are matched once
+/// normalized, as the router sees them, so `//admin` and `/%61dmin` are
+/// covered too.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AuthRule {
+    pub prefix: String,
+    pub access: Access,
+}
+
+impl AuthRule {
+    pub fn new(prefix: impl Into<String>, access: Access) -> AuthRule {
+        AuthRule {
+            prefix: prefix.into(),
+            access,
+        }
+    }
+
+    pub fn public(prefix: impl Into<String>) -> AuthRule {
+        AuthRule::new(prefix, Access::Public)
+    }
+
+    pub fn protected(prefix: impl Into<String>) -> AuthRule {
+        AuthRule::new(prefix, Access::Protected)
+    }
+
+    /// `path` should be normalized; see [`Request::normalized_path`].
+    pub fn matches(&self, path: &str) -> bool {
+        let prefix = self.prefix.trim_end_matches('/');
+        match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+/// Users and password hashes read from an htpasswd file.
+///
+/// Each line is `user:hash`, where the hash is bcrypt (`htpasswd -B`) or
+/// `{SHA}` (`htpasswd -s`); other formats are refused when the file is
+/// read. Blank lines and lines starting with `#` are skipped.
+///
+/// Clones share the users, so a reload is seen by all of them.
+#[derive(Clone)]
+pub struct Htpasswd {
+    path: PathBuf,
+    users: Arc<RwLock<Users>>,
+}
+
+struct Users {
+    hashes: HashMap<String, String>,
+    // Checked in place of an unknown user's hash, so that a name that is
+    // not in the file takes as long to refuse as a wrong password.
+    dummy: String,
+}
+
+impl Htpasswd {
+    pub fn load(path: impl Into<PathBuf>) -> Result<Htpasswd, HtpasswdError> {
+        let path = path.into();
+        let users = read_users(&path)?;
+        Ok(Htpasswd {
+            path,
+            users: Arc::new(RwLock::new(users)),
+        })
+    }
+
+    /// Reads the file again, keeping the users from before if it cannot
+    /// be read.
+    pub fn reload(&self) -> Result<(), HtpasswdError> {
+        let users = read_users(&self.path)?;
+        *self.users.write().unwrap() = users;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        let users = self.users.read().unwrap();
+        let (hash, known) = match users.hashes.get(user) {
+            Some(hash) => (hash, true),
+            None => (&users.dummy, false),
+        };
+        let matches = match hash.strip_prefix("{SHA}") {
+            Some(digest) => {
+                let actual = BASE64.encode(Sha1::digest(password.as_bytes()));
+                constant_time_eq(actual.as_bytes(), digest.as_bytes())
+            }
+            None => bcrypt::verify(password, hash).unwrap_or(false),
+        };
+        matches && known
+    }
+}
+
+fn read_users(path: &Path) -> Result<Users, HtpasswdError> {
+    let text = fs::read_to_string(path)?;
+    let mut hashes = HashMap::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: &str| HtpasswdError::Invalid {
+            line: index + 1,
+            reason: reason.to_string(),
+        };
+        let (user, hash) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("expected user:hash"))?;
+        if user.is_empty() {
+            return Err(invalid("empty user name"));
+        }
+        let bcrypt = ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix));
+        if !bcrypt && !hash.starts_with("{SHA}") {
+            return Err(invalid(&format!(
+                "unsupported hash for {}; use htpasswd -B",
+                user
+            )));
+        }
+        hashes.insert(user.to_string(), hash.to_string());
+    }
+    let dummy = dummy_hash(&hashes);
+    Ok(Users { hashes, dummy })
+}
+
+// A bcrypt hash at the highest cost in the file, or a `{SHA}` one if it
+// has no bcrypt hashes.
+fn dummy_hash(hashes: &HashMap<String, String>) -> String {
+    let cost = hashes
+        .values()
+        .filter(|hash| !hash.starts_with("{SHA}"))
+        .filter_map(|hash| hash.get(4..6)?.parse::<u32>().ok())
+        .filter(|cost| (4..=31).contains(cost))
+        .max();
+    match cost {
+        Some(cost) => bcrypt::hash("", cost).expect("cost is in range"),
+        None => format!("{{SHA}}{}", BASE64.encode(Sha1::digest(b""))),
+    }
+}
+
+/// Turns away requests for protected paths that lack valid credentials.
+///
+/// Rules are tried in order and the first whose prefix matches decides;
+/// paths no rule matches are public. Credentials are either HTTP Basic,
+/// checked against an htpasswd file, or one of a set of bearer tokens.
+pub struct Auth {
+    rules: Vec<AuthRule>,
+    htpasswd: Option<Htpasswd>,
+    tokens: Vec<String>,
+    realm: String,
+}
+
+impl Auth {
+    pub fn new(rules: Vec<AuthRule>) -> Auth {
+        Auth {
+            rules,
+            htpasswd: None,
+            tokens: Vec::new(),
+            realm: DEFAULT_REALM.to_string(),
+        }
+    }
+
+    pub fn htpasswd(mut self, htpasswd: Htpasswd) -> Self {
+        self.htpasswd = Some(htpasswd);
+        self
+    }
+
+    pub fn bearer_tokens(mut self, tokens: Vec<String>) -> Self {
+        self.tokens = tokens;
+        self
+    }
+
+    /// Named in challenges so that browsers can tell sites apart.
+    pub fn realm(mut self, realm: impl Into<String>) -> Self {
+        self.realm = realm.into();
+        self
+    }
+
+    /// Whether a normalized `path` needs credentials.
+    pub fn is_protected(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(path))
+            .is_some_and(|rule| rule.access == Access::Protected)
+    }
+
+    /// Lets `request` through, or returns the `401 Unauthorized` challenge
+    /// to answer it with.
+    pub fn authorize(&self, request: &Request) -> Result<(), Response> {
+        let Some(path) = request.normalized_path() else {
+            return Err(Response::error(StatusCode::BadRequest));
+        };
+        if !self.is_protected(&path) {
+            return Ok(());
+        }
+        let authorization = request.header("Authorization").unwrap_or("").trim();
+        let (scheme, credentials) = authorization.split_once(' ').unwrap_or((authorization, ""));
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("basic") && self.basic(credentials) {
+            return Ok(());
+        }
+        if scheme.eq_ignore_ascii_case("bearer") {
+            if self.bearer(credentials) {
+                return Ok(());
+            }
+            return Err(self.challenge(Some("invalid_token")));
+        }
+        Err(self.challenge(None))
+    }
+
+    fn basic(&self, encoded: &str) -> bool {
+        let Some(htpasswd) = &self.htpasswd else {
+            return false;
+        };
+        let decoded = BASE64
+            .decode(encoded)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        match decoded.as_deref().and_then(|text| text.split_once(':')) {
+            Some((user, password)) => htpasswd.verify(user, password),
+            None => false,
+        }
+    }
+
+    // Every token is compared in full, so the time taken says nothing
+    // about which one came close.
+    fn bearer(&self, token: &str) -> bool {
+        self.tokens.iter().fold(false, |found, candidate| {
+            found | constant_time_eq(candidate.as_bytes(), token.as_bytes())
+        })
+    }
+
+    fn challenge(&self, error: Option<&str>) -> Response {
+        let mut response = Response::error(StatusCode::Unauthorized);
+        if self.htpasswd.is_some() {
+            response.headers.append(
+                "WWW-Authenticate",
+                format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm),
+            );
+        }
+        if !self.tokens.is_empty() {
+            let mut bearer = format!("Bearer realm=\"{}\"", self.realm);
+            if let Some(error) = error {
+                bearer.push_str(&format!(", error=\"{}\"", error));
+            }
+            response.headers.append("WWW-Authenticate", bearer);
+        }
+        response
+    }
+}
+
+impl Middleware for Auth {
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        match self.authorize(&request) {
+            Ok(()) => next.run(request),
+            Err(challenge) => challenge,
+        }
+    }
+}
+
+// Takes as long for a near miss as for a wild guess; only the length of
+// the longer input shows.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff |= usize::from(x ^ y);
+    }
+    diff == 0
+}
+
+#[derive(Debug)]
+pub enum HtpasswdError {
+    Io(io::Error),
+    /// `line` is 1-based.
+    Invalid {
+        line: usize,
+        reason: String,
+    },
+}
+
+impl fmt::Display for HtpasswdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HtpasswdError::Io(e) => write!(f, "{}", e),
+            HtpasswdError::Invalid { line, reason } => write!(f, "line {}: {}", line, reason),
+        }
+    }
+}
+
+impl Error for HtpasswdError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HtpasswdError::Io(e) => Some(e),
+            HtpasswdError::Invalid { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for HtpasswdError {
+    fn from(e: io::Error) -> Self {
+        HtpasswdError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    use crate::request::RequestReader;
+
+    // "{SHA}" hash of "password", as written by `htpasswd -s`.
+    const SHA_PASSWORD: &str = "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=";
+
+    fn write_htpasswd(name: &str, contents: &str) -> PathBuf {
+        let path =
+            env::temp_dir().join(format!("web-service-auth-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn request(target: &str, authorization: Option<&str>) -> Request {
+        let header = authorization
+            .map(|value| format!("Authorization: {}\r\n", value))
+            .unwrap_or_default();
+        let raw = format!("GET {} HTTP/1.1\r\n{}\r\n", target, header);
+        RequestReader::new(raw.as_bytes())
+            .read_request()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn basic(user: &str, password: &str) -> String {
+        format!("Basic {}", BASE64.encode(format!("{}:{}", user, password)))
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let auth = Auth::new(vec![
+            AuthRule::public("/admin/login"),
+            AuthRule::protected("/admin"),
+        ]);
+        assert!(auth.is_protected("/admin"));
+        assert!(auth.is_protected("/admin/users"));
+        assert!(!auth.is_protected("/admin/login"));
+        assert!(!auth.is_protected("/administrator"));
+        assert!(!auth.is_protected("/"));
+    }
+
+    #[test]
+    fn protects_paths_however_they_are_spelled() {
+        let auth = Auth::new(vec![AuthRule::protected("/private")])
+            .bearer_tokens(vec!["s3cret".to_string()]);
+        for target in [
+            "/private/x",
+            "//private/x",
+            "/%70rivate/x",
+            "/public/../private/x",
+            "/./private//x",
+        ] {
+            let response = auth.authorize(&request(target, None)).unwrap_err();
+            assert_eq!(response.status, StatusCode::Unauthorized, "{}", target);
+        }
+        assert!(auth.authorize(&request("/private/../x", None)).is_ok());
+        let bad = auth.authorize(&request("/../private", None)).unwrap_err();
+        assert_eq!(bad.status, StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn checks_basic_credentials_against_htpasswd() {
+        let bcrypt = bcrypt::hash("hunter2", 4).unwrap();
+        let path = write_htpasswd(
+            "basic",
+            &format!("# users\n\nalice:{}\nbob:{}\n", bcrypt, SHA_PASSWORD),
+        );
+        let auth = Auth::new(vec![AuthRule::protected("/")])
+            .htpasswd(Htpasswd::load(&path).unwrap())
+            .realm("staff");
+
+        for good in [basic("alice", "hunter2"), basic("bob", "password")] {
+            assert!(
+                auth.authorize(&request("/", Some(&good))).is_ok(),
+                "{}",
+                good
+            );
+        }
+        // An unknown user costs a bcrypt check like a known one.
+        let dummy = auth
+            .htpasswd
+            .as_ref()
+            .unwrap()
+            .users
+            .read()
+            .unwrap()
+            .dummy
+            .clone();
+        assert!(dummy.starts_with("$2b$04$"), "{}", dummy);
+        for bad in [
+            basic("alice", "password"),
+            basic("bob", "passwords"),
+            basic("carol", "password"),
+            "Basic not-base64!".to_string(),
+            "Bearer password".to_string(),
+        ] {
+            let response = auth.authorize(&request("/", Some(&bad))).unwrap_err();
+            assert_eq!(response.status, StatusCode::Unauthorized, "{}", bad);
+            assert_eq!(
+                response.headers.get("WWW-Authenticate"),
+                Some("Basic realm=\"staff\", charset=\"UTF-8\"")
+            );
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn checks_bearer_tokens() {
+        let auth = Auth::new(vec![AuthRule::protected("/api")])
+            .bearer_tokens(vec!["first".to_string(), "second".to_string()]);
+        assert!(auth.authorize(&request("/", None)).is_ok());
+        assert!(auth
+            .authorize(&request("/api/x", Some("bearer second")))
+            .is_ok());
+
+        let missing = auth.authorize(&request("/api/x", None)).unwrap_err();
+        assert_eq!(
+            missing.headers.get("WWW-Authenticate"),
+            Some("Bearer realm=\"web-service\"")
+        );
+        let wrong = auth
+            .authorize(&request("/api/x", Some("Bearer secon")))
+            .unwrap_err();
+        assert_eq!(
+            wrong.headers.get("WWW-Authenticate"),
+            Some("Bearer realm=\"web-service\", error=\"invalid_token\"")
+        );
+    }
+
+    #[test]
+    fn reloads_and_refuses_unknown_hashes() {
+        let path = write_htpasswd("reload", "bob:{SHA}nope\n");
+        let htpasswd = Htpasswd::load(&path).unwrap();
+        assert!(!htpasswd.verify("bob", "password"));
+
+        fs::write(&path, format!("bob:{}\n", SHA_PASSWORD)).unwrap();
+        htpasswd.reload().unwrap();
+        assert!(htpasswd.verify("bob", "password"));
+
+        // A file that no longer parses leaves the users as they were.
+        fs::write(&path, "bob:$apr1$salt$hash\n").unwrap();
+        assert!(matches!(
+            htpasswd.reload(),
+            Err(HtpasswdError::Invalid { line: 1, .. })
+        ));
+        assert!(htpasswd.verify("bob", "password"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn compares_in_full() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+        assert!(!constant_time_eq(b"", b"token"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -0,0 +1,388 @@
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+use crate::access_log::json_string;
+use crate::date;
+use crate::headers;
+use crate::request::Request;
+use crate::response::Response;
+use crate::static_files::content_type;
+use crate::status::StatusCode;
+use crate::template::{Context, Template, Value};
+use crate::url;
+
+const LISTING: &str = r#"<!DOCTYPE html>
+<html lang="en">
+  <head>
+    <meta charset="utf-8">
+    <title>Index of {{ path }}</title>
+  </head>
+  <body>
+    <h1>Index of {{ path }}</h1>
+    <table>
+      <thead>
+        <tr>
+{% for column in columns %}
+          <th><a href="{{ column.href }}">{{ column.label }}</a></th>
+{% endfor %}
+        </tr>
+      </thead>
+      <tbody>
+{% if parent %}
+        <tr><td><a href="{{ parent }}">../</a></td><td></td><td></td><td></td></tr>
+{% endif %}
+{% for entry in entries %}
+        <tr><td><a href="{{ entry.href }}">{{ entry.name }}</a></td><td>{{ entry.size }}</td><td>{{ entry.modified }}</td><td>{{ entry.type }}</td></tr>
+{% endfor %}
+      </tbody>
+    </table>
+  </body>
+</html>
+"#;
+
+/// One file or directory in a listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    /// Zero for directories.
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// The media type of a file, or `directory`.
+    pub kind: &'static str,
+}
+
+/// The column a listing is sorted by, from its `sort` query parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Modified,
+    Type,
+}
+
+impl SortKey {
+    const ALL: [SortKey; 4] = [
+        SortKey::Name,
+        SortKey::Size,
+        SortKey::Modified,
+        SortKey::Type,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "mtime",
+            SortKey::Type => "type",
+        }
+    }
+
+    pub fn parse(text: &str) -> Option<SortKey> {
+        SortKey::ALL.into_iter().find(|key| key.as_str() == text)
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            SortKey::Name => "Name",
+            SortKey::Size => "Size",
+            SortKey::Modified => "Modified",
+            SortKey::Type => "Type",
+        }
+    }
+}
+
+/// The entries of `dir`, leaving out hidden files, names that are not
+/// UTF-8 and symlinks that lead outside `root`.
+pub fn read_entries(dir: &Path, root: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = match fs::canonicalize(entry.path()) {
+            Ok(path) if path.starts_with(root) => path,
+            _ => continue,
+        };
+        let Ok(metadata) = fs::metadata(&path) else {
+            continue;
+        };
+        let is_dir = metadata.is_dir();
+        entries.push(Entry {
+            kind: if is_dir {
+                "directory"
+            } else {
+                content_type(Path::new(&name))
+            },
+            name,
+            is_dir,
+            size: if is_dir { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Directories come first whatever the order; ties are broken by name.
+pub fn sort(entries: &mut [Entry], key: SortKey, descending: bool) {
+    entries.sort_by(|a, b| {
+        let by_key = match key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+            SortKey::Type => a.kind.cmp(b.kind),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let by_key = if descending { by_key.reverse() } else { by_key };
+        b.is_dir.cmp(&a.is_dir).then(by_key)
+    });
+}
+
+/// Lists `dir` as HTML, or as JSON when the request's `Accept` prefers it.
+///
+/// `path` is the decoded URL path of the directory, with leading and
+/// trailing slashes. The request's `sort` (`name`, `size`, `mtime` or
+/// `type`) and `order` (`asc` or `desc`) query parameters pick the order.
+pub fn response(
+    dir: &Path,
+    root: &Path,
+    path: &str,
+    request: Option<&Request>,
+) -> io::Result<Response> {
+    let mut entries = read_entries(dir, root)?;
+    let key = request
+        .and_then(|request| request.query("sort"))
+        .and_then(SortKey::parse)
+        .unwrap_or(SortKey::Name);
+    let descending = request.and_then(|request| request.query("order")) == Some("desc");
+    sort(&mut entries, key, descending);
+
+    let accept = request.and_then(|request| request.header("Accept"));
+    let response = if prefers_json(accept) {
+        Response::new(StatusCode::Ok)
+            .with_header("Content-Type", "application/json")
+            .with_body(json(path, &entries))
+    } else {
+        Response::html(StatusCode::Ok, html(path, &entries, key, descending))
+    };
+    Ok(response.with_header("Vary", "Accept"))
+}
+
+pub fn html(path: &str, entries: &[Entry], key: SortKey, descending: bool) -> String {
+    static TEMPLATE: OnceLock<Template> = OnceLock::new();
+    let template = TEMPLATE.get_or_init(|| {
+        Template::compile("autoindex", LISTING).expect("the listing template is valid")
+    });
+
+    // Each heading sorts by its column, flipping the order if it already
+    // does.
+    let columns: Vec<Value> = SortKey::ALL
+        .into_iter()
+        .map(|column| {
+            let order = if column == key && !descending {
+                "desc"
+            } else {
+                "asc"
+            };
+            Context::new()
+                .with("label", column.label())
+                .with("href", format!("?sort={}&order={}", column.as_str(), order))
+                .into()
+        })
+        .collect();
+    let rows: Vec<Value> = entries
+        .iter()
+        .map(|entry| {
+            let slash = if entry.is_dir { "/" } else { "" };
+            Context::new()
+                .with("name", format!("{}{}", entry.name, slash))
+                .with("href", href(path, &entry.name, entry.is_dir))
+                .with("size", (!entry.is_dir).then(|| entry.size.to_string()))
+                .with("modified", entry.modified.map(date::format_http_date))
+                .with("type", entry.kind)
+                .into()
+        })
+        .collect();
+    let context = Context::new()
+        .with("path", path)
+        .with("parent", parent(path))
+        .with("columns", columns)
+        .with("entries", rows);
+    template
+        .render(&context)
+        .expect("the listing template includes nothing")
+}
+
+pub fn json(path: &str, entries: &[Entry]) -> String {
+    let items: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "{{\"name\":{},\"type\":{},\"size\":{},\"modified\":{}}}",
+                json_string(&entry.name),
+                json_string(entry.kind),
+                if entry.is_dir {
+                    "null".to_string()
+                } else {
+                    entry.size.to_string()
+                },
+                entry.modified.map_or_else(
+                    || "null".to_string(),
+                    |time| json_string(&date::format_rfc3339(time))
+                )
+            )
+        })
+        .collect();
+    format!(
+        "{{\"path\":{},\"entries\":[{}]}}\n",
+        json_string(path),
+        items.join(",")
+    )
+}
+
+// The encoded URL of `name` within the directory at `path`.
+fn href(path: &str, name: &str, is_dir: bool) -> String {
+    let mut href = encode_path(path);
+    href.push_str(&url::percent_encode_segment(name));
+    if is_dir {
+        href.push('/');
+    }
+    href
+}
+
+// The encoded URL of the directory above `path`, if there is one.
+fn parent(path: &str) -> Option<String> {
+    let trimmed = path.trim_end_matches('/');
+    let (parent, _) = trimmed.rsplit_once('/')?;
+    Some(encode_path(&format!("{}/", parent)))
+}
+
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(url::percent_encode_segment)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Browsers ask for HTML; only a client that ranks JSON higher gets it.
+fn prefers_json(accept: Option<&str>) -> bool {
+    let Some(accept) = accept else {
+        return false;
+    };
+    let ranges = headers::quality_list(accept);
+    let quality = |media: &str| {
+        let (kind, _) = media.split_once('/').unwrap_or((media, ""));
+        let wildcard = format!("{}/*", kind);
+        let q = |range: &str| ranges.iter().find(|(r, _)| r == range).map(|&(_, q)| q);
+        q(media)
+            .or_else(|| q(&wildcard))
+            .or_else(|| q("*/*"))
+            .unwrap_or(0)
+    };
+    quality("application/json") > quality("text/html")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn entry(name: &str, is_dir: bool, size: u64, secs: u64) -> Entry {
+        Entry {
+            name: name.to_string(),
+            is_dir,
+            size,
+            modified: Some(UNIX_EPOCH + Duration::from_secs(secs)),
+            kind: if is_dir {
+                "directory"
+            } else {
+                content_type(Path::new(name))
+            },
+        }
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn sorts_directories_first() {
+        let mut entries = vec![
+            entry("b.txt", false, 10, 3),
+            entry("z", true, 0, 1),
+            entry("a.png", false, 30, 2),
+            entry("c.txt", false, 20, 1),
+        ];
+        sort(&mut entries, SortKey::Name, false);
+        assert_eq!(names(&entries), ["z", "a.png", "b.txt", "c.txt"]);
+        sort(&mut entries, SortKey::Name, true);
+        assert_eq!(names(&entries), ["z", "c.txt", "b.txt", "a.png"]);
+        sort(&mut entries, SortKey::Size, true);
+        assert_eq!(names(&entries), ["z", "a.png", "c.txt", "b.txt"]);
+        sort(&mut entries, SortKey::Modified, false);
+        assert_eq!(names(&entries), ["z", "c.txt", "a.png", "b.txt"]);
+        sort(&mut entries, SortKey::Type, false);
+        assert_eq!(names(&entries), ["z", "a.png", "b.txt", "c.txt"]);
+    }
+
+    #[test]
+    fn renders_escaped_html() {
+        let entries = [entry("sub dir", true, 0, 0), entry("<b>&.txt", false, 5, 0)];
+        let page = html("/a&b/", &entries, SortKey::Name, false);
+        assert!(
+            page.contains("<title>Index of /a&amp;b/</title>"),
+            "{}",
+            page
+        );
+        assert!(page.contains("<a href=\"/\">../</a>"), "{}", page);
+        assert!(
+            page.contains("<a href=\"/a%26b/sub%20dir/\">sub dir/</a>"),
+            "{}",
+            page
+        );
+        let file = "<a href=\"/a%26b/%3Cb%3E%26.txt\">&lt;b&gt;&amp;.txt</a></td><td>5</td>";
+        assert!(page.contains(file), "{}", page);
+        assert!(
+            page.contains("href=\"?sort=name&amp;order=desc\""),
+            "{}",
+            page
+        );
+        assert!(
+            page.contains("href=\"?sort=size&amp;order=asc\""),
+            "{}",
+            page
+        );
+        assert!(!html("/", &[], SortKey::Name, false).contains("../"));
+    }
+
+    #[test]
+    fn renders_json() {
+        let entries = [entry("d", true, 0, 0), entry("a\"b.txt", false, 5, 1)];
+        assert_eq!(
+            json("/x/", &entries),
+            "{\"path\":\"/x/\",\"entries\":[\
+             {\"name\":\"d\",\"type\":\"directory\",\"size\":null,\"modified\":\"1970-01-01T00:00:00.000Z\"},\
+             {\"name\":\"a\\\"b.txt\",\"type\":\"text/plain; charset=utf-8\",\"size\":5,\
+             \"modified\":\"1970-01-01T00:00:01.000Z\"}]}\n"
+        );
+    }
+
+    #[test]
+    fn negotiates_json_by_accept() {
+        assert!(!prefers_json(None));
+        assert!(prefers_json(Some("application/json")));
+        assert!(prefers_json(Some("application/*, text/html;q=0.5")));
+        assert!(!prefers_json(Some(
+            "text/html,application/xhtml+xml,*/*;q=0.8"
+        )));
+        assert!(!prefers_json(Some("*/*")));
+    }
+}
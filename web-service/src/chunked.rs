@@ -0,0 +1,109 @@
+use std::io::{self, Write};
+
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Parses a chunk-size line (without its line ending), ignoring any chunk
+/// extensions after `;`.
+pub fn parse_chunk_size(line: &[u8]) -> Option<usize> {
+    let size = match line.iter().position(|&b| b == b';') {
+        Some(end) => &line[..end],
+        None => line,
+    };
+    let size = std::str::from_utf8(size)
+        .ok()?
+        .trim_matches(|c| c == ' ' || c == '\t');
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    usize::from_str_radix(size, 16).ok()
+}
+
+/// Writes a body using `Transfer-Encoding: chunked`.
+///
+/// Writes are collected into chunks of up to 8 KiB; `flush` sends whatever
+/// is buffered as a chunk straight away. [`ChunkedWriter::finish`] must be
+/// called to write the terminating zero-length chunk.
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChunkedWriter {
+            inner,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    /// Sends any buffered data and the last chunk, returning the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chunk()?;
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        write!(self.inner, "{:X}\r\n", self.buf.len())?;
+        self.inner.write_all(&self.buf)?;
+        self.inner.write_all(b"\r\n")?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let room = CHUNK_SIZE - self.buf.len();
+        let n = room.min(data.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == CHUNK_SIZE {
+            self.write_chunk()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes_and_skips_extensions() {
+        assert_eq!(parse_chunk_size(b"1a"), Some(26));
+        assert_eq!(parse_chunk_size(b"FF;name=value"), Some(255));
+        assert_eq!(parse_chunk_size(b"0"), Some(0));
+        assert_eq!(parse_chunk_size(b""), None);
+        assert_eq!(parse_chunk_size(b"-1"), None);
+        assert_eq!(parse_chunk_size(b"0x10"), None);
+        assert_eq!(parse_chunk_size(b"ffffffffffffffffffff"), None);
+    }
+
+    #[test]
+    fn frames_writes_as_chunks() {
+        let mut writer = ChunkedWriter::new(Vec::new());
+        writer.write_all(b"hello ").unwrap();
+        writer.flush().unwrap();
+        writer.write_all(b"world").unwrap();
+        let out = writer.finish().unwrap();
+        assert_eq!(out, b"6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn splits_large_writes() {
+        let mut writer = ChunkedWriter::new(Vec::new());
+        writer.write_all(&vec![b'x'; CHUNK_SIZE + 1]).unwrap();
+        let out = writer.finish().unwrap();
+        assert!(out.starts_with(b"2000\r\n"));
+        assert!(out.ends_with(b"\r\n1\r\nx\r\n0\r\n\r\n"));
+    }
+}
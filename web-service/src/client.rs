@@ -0,0 +1,566 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::chunked;
+use crate::headers::Headers;
+use crate::request::{Method, Version};
+use crate::status::StatusCode;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Bounds on what a server may send back in a response head.
+const MAX_LINE: usize = 8 * 1024;
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// A blocking HTTP/1.1 client for plain `http://` URLs.
+///
+/// Connections are kept open after each response when the server allows
+/// it and reused by later requests to the same host and port. Every
+/// connect, read and write is bounded by the client's timeout.
+///
+/// ```no_run
+/// use web_service::client::Client;
+///
+/// let mut client = Client::new();
+/// let response = client.get("http://127.0.0.1:7878/").send()?;
+/// println!("{} {}", response.status, response.text());
+/// # Ok::<(), web_service::client::ClientError>(())
+/// ```
+pub struct Client {
+    timeout: Duration,
+    // At most one idle connection per `host:port`.
+    idle: HashMap<String, BufReader<TcpStream>>,
+}
+
+impl Client {
+    pub fn new() -> Client {
+        Client {
+            timeout: DEFAULT_TIMEOUT,
+            idle: HashMap::new(),
+        }
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn request(&mut self, method: Method, url: &str) -> RequestBuilder<'_> {
+        RequestBuilder {
+            client: self,
+            method,
+            url: url.to_string(),
+            headers: Headers::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn get(&mut self, url: &str) -> RequestBuilder<'_> {
+        self.request(Method::Get, url)
+    }
+
+    pub fn head(&mut self, url: &str) -> RequestBuilder<'_> {
+        self.request(Method::Head, url)
+    }
+
+    pub fn post(&mut self, url: &str) -> RequestBuilder<'_> {
+        self.request(Method::Post, url)
+    }
+
+    /// The number of open connections waiting to be reused.
+    pub fn idle_connections(&self) -> usize {
+        self.idle.len()
+    }
+
+    fn keep(&mut self, authority: &str, connection: Option<BufReader<TcpStream>>) {
+        if let Some(connection) = connection {
+            self.idle.insert(authority.to_string(), connection);
+        }
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client::new()
+    }
+}
+
+/// A request being put together; [`send`](RequestBuilder::send) performs it.
+pub struct RequestBuilder<'a> {
+    client: &'a mut Client,
+    method: Method,
+    url: String,
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+impl RequestBuilder<'_> {
+    /// Adds a header. `Host` and `Content-Length` are filled in unless set
+    /// here.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Sends the request and reads the whole response.
+    ///
+    /// A reused connection that turns out to have been closed by the server
+    /// while idle is replaced by a new one and the request sent again.
+    pub fn send(self) -> Result<ClientResponse, ClientError> {
+        let (authority, target) = split_url(&self.url)?;
+        let mut head = format!("{} {} HTTP/1.1\r\n", self.method, target);
+        if !self.headers.contains("Host") {
+            head.push_str(&format!("Host: {}\r\n", authority));
+        }
+        let sends_body = !self.body.is_empty()
+            || matches!(self.method, Method::Post | Method::Put | Method::Patch);
+        if sends_body && !self.headers.contains("Content-Length") {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        let mut message = head.into_bytes();
+        message.extend_from_slice(&self.body);
+
+        let client = self.client;
+        if let Some(connection) = client.idle.remove(authority) {
+            match exchange(connection, &message, self.method) {
+                Ok((response, connection)) => {
+                    client.keep(authority, connection);
+                    return Ok(response);
+                }
+                Err(e) if e.is_stale_connection() => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let connection = connect(authority, client.timeout, client.timeout)?;
+        let (response, connection) = exchange(connection, &message, self.method)?;
+        client.keep(authority, connection);
+        Ok(response)
+    }
+}
+
+/// A response read in full.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientResponse {
+    pub status: StatusCode,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// Trailer fields sent after a chunked body.
+    pub trailers: Headers,
+}
+
+impl ClientResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// The body as text, with invalid UTF-8 replaced.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    Timeout,
+    InvalidUrl(String),
+    /// The server closed the connection without sending a response.
+    ConnectionClosed,
+    InvalidResponse(&'static str),
+}
+
+impl ClientError {
+    // Whether a reused connection had gone away before the request reached
+    // the server, so that sending it again is safe.
+    pub(crate) fn is_stale_connection(&self) -> bool {
+        match self {
+            ClientError::ConnectionClosed => true,
+            ClientError::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "i/o error: {}", e),
+            ClientError::Timeout => f.write_str("timed out waiting for the server"),
+            ClientError::InvalidUrl(url) => write!(f, "invalid url {:?}", url),
+            ClientError::ConnectionClosed => f.write_str("connection closed before a response"),
+            ClientError::InvalidResponse(what) => write!(f, "invalid response: {}", what),
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ClientError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ClientError::Timeout,
+            _ => ClientError::Io(e),
+        }
+    }
+}
+
+// Splits `http://host:port/path?query` into the authority and the request
+// target.
+fn split_url(url: &str) -> Result<(&str, &str), ClientError> {
+    let invalid = || ClientError::InvalidUrl(url.to_string());
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (authority, target) = match rest.find(['/', '?']) {
+        Some(i) if rest[i..].starts_with('/') => (&rest[..i], &rest[i..]),
+        _ => return Err(invalid()),
+    };
+    if authority.is_empty() || authority.contains('@') {
+        return Err(invalid());
+    }
+    Ok((authority, target))
+}
+
+/// Opens a connection to `authority` (`host:port`, or `host` for port 80)
+/// whose reads and writes time out after `timeout`.
+pub(crate) fn connect(
+    authority: &str,
+    connect_timeout: Duration,
+    timeout: Duration,
+) -> Result<BufReader<TcpStream>, ClientError> {
+    let has_port = authority.contains(':') && !authority.ends_with(']');
+    let addrs = if has_port {
+        authority.to_socket_addrs()?
+    } else {
+        format!("{}:80", authority).to_socket_addrs()?
+    };
+    let mut last_error = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, connect_timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(BufReader::new(stream));
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error
+        .map(ClientError::from)
+        .unwrap_or_else(|| ClientError::InvalidUrl(format!("{} did not resolve", authority))))
+}
+
+// Sends `message` and reads the response, handing back the connection if
+// it can be reused.
+fn exchange(
+    mut connection: BufReader<TcpStream>,
+    message: &[u8],
+    method: Method,
+) -> Result<(ClientResponse, Option<BufReader<TcpStream>>), ClientError> {
+    connection.get_mut().write_all(message)?;
+    let (status, version, headers) = read_final_head(&mut connection)?;
+    let framing = Framing::of(method, status, &headers)?;
+    let mut body = Vec::new();
+    let mut trailers = Headers::new();
+    copy_body(&mut connection, framing, &mut body, &mut trailers)?;
+    let reusable = keeps_alive(version, &headers, framing);
+    let response = ClientResponse {
+        status,
+        version,
+        headers,
+        body,
+        trailers,
+    };
+    Ok((response, reusable.then_some(connection)))
+}
+
+/// How the body of a response is delimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Framing {
+    None,
+    Length(u64),
+    Chunked,
+    /// The body runs until the server closes the connection.
+    UntilClose,
+}
+
+impl Framing {
+    /// The framing of the response to a `method` request with the given
+    /// status and headers.
+    pub(crate) fn of(
+        method: Method,
+        status: StatusCode,
+        headers: &Headers,
+    ) -> Result<Framing, ClientError> {
+        if method == Method::Head
+            || status.forbids_body()
+            || status == StatusCode::SwitchingProtocols
+        {
+            return Ok(Framing::None);
+        }
+        let chunked = headers
+            .get_all("Transfer-Encoding")
+            .flat_map(|value| value.split(','))
+            .last()
+            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+        if chunked {
+            return Ok(Framing::Chunked);
+        }
+        match headers.get("Content-Length") {
+            Some(len) => len
+                .trim()
+                .parse()
+                .map(Framing::Length)
+                .map_err(|_| ClientError::InvalidResponse("bad content-length")),
+            None => Ok(Framing::UntilClose),
+        }
+    }
+}
+
+/// Whether the connection can carry another request once a response with
+/// this head and framing has been read.
+pub(crate) fn keeps_alive(version: Version, headers: &Headers, framing: Framing) -> bool {
+    framing != Framing::UntilClose
+        && !headers.has_token("Connection", "close")
+        && (version == Version::Http11 || headers.has_token("Connection", "keep-alive"))
+}
+
+/// Reads a response head, skipping any interim 1xx responses.
+pub(crate) fn read_final_head<R: BufRead>(
+    reader: &mut R,
+) -> Result<(StatusCode, Version, Headers), ClientError> {
+    loop {
+        let head = read_head(reader)?;
+        if head.0.code() >= 200 || head.0 == StatusCode::SwitchingProtocols {
+            return Ok(head);
+        }
+    }
+}
+
+fn read_head<R: BufRead>(reader: &mut R) -> Result<(StatusCode, Version, Headers), ClientError> {
+    let status_line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Err(ClientError::ConnectionClosed),
+    };
+    let invalid = || ClientError::InvalidResponse("malformed status line");
+    let mut parts = status_line.splitn(3, ' ');
+    let version = match parts.next() {
+        Some("HTTP/1.1") => Version::Http11,
+        Some("HTTP/1.0") => Version::Http10,
+        _ => return Err(invalid()),
+    };
+    let status = parts
+        .next()
+        .and_then(|code| code.parse().ok())
+        .and_then(StatusCode::from_code)
+        .ok_or_else(invalid)?;
+
+    let mut headers = Headers::new();
+    let mut size = 0;
+    loop {
+        let line = read_line(reader)?.ok_or(ClientError::InvalidResponse("head cut short"))?;
+        if line.is_empty() {
+            return Ok((status, version, headers));
+        }
+        size += line.len() + 2;
+        if size > MAX_HEAD_SIZE {
+            return Err(ClientError::InvalidResponse("header section too large"));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(ClientError::InvalidResponse("malformed header line"))?;
+        headers.append(name.trim(), value.trim());
+    }
+}
+
+/// Copies a body framed as `framing` from `reader` to `out`, decoding any
+/// chunking. Returns the number of body bytes copied.
+pub(crate) fn copy_body<R: BufRead, W: Write + ?Sized>(
+    reader: &mut R,
+    framing: Framing,
+    out: &mut W,
+    trailers: &mut Headers,
+) -> Result<u64, ClientError> {
+    match framing {
+        Framing::None => Ok(0),
+        Framing::Length(len) => {
+            let copied = io::copy(&mut reader.take(len), out)?;
+            if copied < len {
+                return Err(ClientError::InvalidResponse("body cut short"));
+            }
+            Ok(copied)
+        }
+        Framing::Chunked => copy_chunked(reader, out, trailers),
+        Framing::UntilClose => Ok(io::copy(reader, out)?),
+    }
+}
+
+fn copy_chunked<R: BufRead, W: Write + ?Sized>(
+    reader: &mut R,
+    out: &mut W,
+    trailers: &mut Headers,
+) -> Result<u64, ClientError> {
+    let cut_short = || ClientError::InvalidResponse("chunked body cut short");
+    let mut copied = 0;
+    loop {
+        let line = read_line(reader)?.ok_or_else(cut_short)?;
+        let size = chunked::parse_chunk_size(line.as_bytes())
+            .ok_or(ClientError::InvalidResponse("malformed chunk size"))?;
+        if size == 0 {
+            break;
+        }
+        let size = size as u64;
+        if io::copy(&mut reader.take(size), out)? < size {
+            return Err(cut_short());
+        }
+        copied += size;
+        if read_line(reader)?.as_deref() != Some("") {
+            return Err(ClientError::InvalidResponse("malformed chunk"));
+        }
+    }
+    loop {
+        let line = read_line(reader)?.ok_or_else(cut_short)?;
+        if line.is_empty() {
+            return Ok(copied);
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            trailers.append(name.trim(), value.trim());
+        }
+    }
+}
+
+// Reads a line without its line ending; `None` at the end of the stream.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>, ClientError> {
+    let mut line = Vec::new();
+    let read = reader
+        .take(MAX_LINE as u64 + 2)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(ClientError::InvalidResponse("line too long or cut short"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| ClientError::InvalidResponse("line is not utf-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    // Serves one accepted connection per entry of `connections`, answering
+    // each request on it with the next reply and then closing it.
+    fn serve(connections: Vec<Vec<&'static str>>) -> (SocketAddr, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let join = thread::spawn(move || {
+            for replies in connections {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                for reply in replies {
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap() > 2 {
+                        line.clear();
+                    }
+                    reader.get_mut().write_all(reply.as_bytes()).unwrap();
+                }
+            }
+        });
+        (addr, join)
+    }
+
+    #[test]
+    fn splits_urls() {
+        assert_eq!(
+            split_url("http://127.0.0.1:8080/a?b=c").unwrap(),
+            ("127.0.0.1:8080", "/a?b=c")
+        );
+        assert_eq!(
+            split_url("http://example.com/").unwrap(),
+            ("example.com", "/")
+        );
+        for bad in [
+            "https://x/",
+            "http://x",
+            "http:///",
+            "http://u@x/",
+            "http://x?q",
+        ] {
+            assert!(split_url(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn reads_each_kind_of_body_framing() {
+        let (addr, join) = serve(vec![vec![
+            "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;x=y\r\nde\r\n0\r\nX-Sum: 5\r\n\r\n",
+            "HTTP/1.0 404 Not Found\r\n\r\nuntil close",
+        ]]);
+        let url = format!("http://{}/", addr);
+        let mut client = Client::new().timeout(Duration::from_secs(5));
+
+        let response = client.get(&url).send().unwrap();
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(response.text(), "hello");
+        assert_eq!(client.idle_connections(), 1);
+
+        let response = client.get(&url).send().unwrap();
+        assert_eq!(response.body, b"abcde");
+        assert_eq!(response.trailers.get("x-sum"), Some("5"));
+
+        let response = client.get(&url).send().unwrap();
+        assert_eq!(response.status, StatusCode::NotFound);
+        assert_eq!(response.text(), "until close");
+        assert_eq!(client.idle_connections(), 0);
+        join.join().unwrap();
+    }
+
+    #[test]
+    fn retries_on_a_new_connection_when_the_idle_one_was_closed() {
+        let (addr, join) = serve(vec![
+            vec!["HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na"],
+            vec!["HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb"],
+        ]);
+        let url = format!("http://{}/", addr);
+        let mut client = Client::new().timeout(Duration::from_secs(5));
+
+        // The server closes the first connection after one reply.
+        assert_eq!(client.get(&url).send().unwrap().text(), "a");
+        assert_eq!(client.idle_connections(), 1);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(client.get(&url).send().unwrap().text(), "b");
+        join.join().unwrap();
+    }
+}
@@ -0,0 +1,367 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+use crate::headers;
+use crate::response::{Body, Response};
+use crate::status::StatusCode;
+
+/// Bodies smaller than this are sent as they are: compressing them saves
+/// little and costs a round of CPU per request.
+pub const DEFAULT_MIN_SIZE: u64 = 1024;
+pub const DEFAULT_LEVEL: u32 = 6;
+
+// The codings offered, most preferred first.
+const OFFERED: [Encoding; 2] = [Encoding::Gzip, Encoding::Deflate];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    /// The zlib format, which is what HTTP calls `deflate`.
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn matches(&self, coding: &str) -> bool {
+        match self {
+            Encoding::Gzip => {
+                coding.eq_ignore_ascii_case("gzip") || coding.eq_ignore_ascii_case("x-gzip")
+            }
+            Encoding::Deflate => coding.eq_ignore_ascii_case("deflate"),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Picks the coding to send from those `offered`, given the request's
+/// `Accept-Encoding` header.
+///
+/// The coding with the highest q-value wins, ties going to the one offered
+/// first. `*` stands for every coding not listed and `q=0` rules a coding
+/// out. Nothing is picked when the client prefers the unencoded
+/// representation, or sent no header at all.
+pub fn negotiate(accept_encoding: Option<&str>, offered: &[Encoding]) -> Option<Encoding> {
+    let preferences = headers::quality_list(accept_encoding?);
+    let quality = |matches: &dyn Fn(&str) -> bool| {
+        preferences
+            .iter()
+            .find(|(coding, _)| matches(coding))
+            .or_else(|| preferences.iter().find(|(coding, _)| coding == "*"))
+            .map(|&(_, q)| q)
+    };
+
+    // The unencoded representation is acceptable unless ruled out.
+    let identity = quality(&|coding| coding.eq_ignore_ascii_case("identity")).unwrap_or(1000);
+    let mut best: Option<(Encoding, u16)> = None;
+    for &encoding in offered {
+        let q = quality(&|coding| encoding.matches(coding)).unwrap_or(0);
+        if q > 0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((encoding, q));
+        }
+    }
+    best.filter(|&(_, q)| q >= identity)
+        .map(|(encoding, _)| encoding)
+}
+
+/// Whether a body of this media type is worth compressing: text and the
+/// structured formats built on it, but not images, archives or media,
+/// which are compressed already. Event streams are left alone so that each
+/// event goes out as soon as it is written.
+pub fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    if essence == "text/event-stream" {
+        return false;
+    }
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "application/x-ndjson"
+        )
+}
+
+/// Compresses response bodies for clients that accept it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    min_size: u64,
+    level: u32,
+}
+
+impl Compression {
+    pub fn new() -> Compression {
+        Compression {
+            min_size: DEFAULT_MIN_SIZE,
+            level: DEFAULT_LEVEL,
+        }
+    }
+
+    /// Bodies of known length below `bytes` are not compressed.
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// From 0 (store only) to 9 (smallest output).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `level` is over 9.
+    pub fn level(mut self, level: u32) -> Self {
+        assert!(level <= 9, "compression levels run from 0 to 9");
+        self.level = level;
+        self
+    }
+
+    /// Encodes `response` with the best coding `accept_encoding` allows.
+    ///
+    /// Only compressible media types are touched, and never partial
+    /// content, bodies already carrying a `Content-Encoding`, or responses
+    /// marked `no-transform`. Responses that could have been compressed
+    /// get `Vary: Accept-Encoding` whichever coding is sent, so caches keep
+    /// the variants apart. A strong `ETag` is made weak, since the encoded
+    /// bytes differ from those it was computed over.
+    pub fn apply(&self, accept_encoding: Option<&str>, mut response: Response) -> Response {
+        if response.status.forbids_body()
+            || response.status == StatusCode::PartialContent
+            || response.headers.contains("Content-Encoding")
+            || response.headers.has_token("Cache-Control", "no-transform")
+            || !response
+                .headers
+                .get("Content-Type")
+                .is_some_and(is_compressible)
+        {
+            return response;
+        }
+        let len = response.body.len().or_else(|| {
+            response
+                .headers
+                .get("Content-Length")
+                .and_then(|len| len.trim().parse().ok())
+        });
+        if len.is_some_and(|len| len < self.min_size) {
+            return response;
+        }
+
+        add_vary(&mut response, "Accept-Encoding");
+        let Some(encoding) = negotiate(accept_encoding, &OFFERED) else {
+            return response;
+        };
+        let level = flate2::Compression::new(self.level);
+        response.body = match response.body {
+            Body::Bytes(bytes) => {
+                let mut encoder = Encoder::new(encoding, Vec::new(), level);
+                let encoded = encoder
+                    .write_all(&bytes)
+                    .and_then(|()| encoder.finish())
+                    .expect("writing to a Vec cannot fail");
+                Body::Bytes(encoded)
+            }
+            body => Body::stream(move |out| {
+                let mut encoder = Encoder::new(encoding, out, level);
+                body.write_to(&mut encoder)?;
+                encoder.finish().map(drop)
+            }),
+        };
+        response.headers.remove("Content-Length");
+        response.headers.remove("Accept-Ranges");
+        response
+            .headers
+            .insert("Content-Encoding", encoding.as_str());
+        if let Some(etag) = response.headers.get("ETag") {
+            if !etag.starts_with("W/") {
+                let weak = format!("W/{}", etag);
+                response.headers.insert("ETag", weak);
+            }
+        }
+        response
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::new()
+    }
+}
+
+/// Adds `name` to the response's `Vary` header unless it is already there.
+pub fn add_vary(response: &mut Response, name: &str) {
+    if response.headers.has_token("Vary", name) || response.headers.has_token("Vary", "*") {
+        return;
+    }
+    let value = match response.headers.get("Vary") {
+        Some(vary) => format!("{}, {}", vary, name),
+        None => name.to_string(),
+    };
+    response.headers.insert("Vary", value);
+}
+
+enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Deflate(ZlibEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(encoding: Encoding, inner: W, level: flate2::Compression) -> Self {
+        match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(inner, level)),
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(inner, level)),
+        }
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(data),
+            Encoder::Deflate(encoder) => encoder.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Deflate(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use std::io::Read;
+
+    fn pick(header: &str) -> Option<Encoding> {
+        negotiate(Some(header), &OFFERED)
+    }
+
+    fn text(len: usize) -> Response {
+        Response::text(StatusCode::Ok, "all work and no play ".repeat(len / 21 + 1))
+    }
+
+    #[test]
+    fn negotiates_by_q_value() {
+        assert_eq!(negotiate(None, &OFFERED), None);
+        assert_eq!(pick("gzip, deflate, br"), Some(Encoding::Gzip));
+        assert_eq!(pick("deflate, gzip;q=0.8"), Some(Encoding::Deflate));
+        assert_eq!(
+            pick("GZIP;Q=0.5, deflate;q=0.6, identity;q=0.1"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(pick("x-gzip"), Some(Encoding::Gzip));
+        assert_eq!(pick("*"), Some(Encoding::Gzip));
+        assert_eq!(pick("*;q=0.5, gzip;q=0"), Some(Encoding::Deflate));
+        assert_eq!(pick("gzip;q=0, deflate;q=0"), None);
+        assert_eq!(pick("br"), None);
+        assert_eq!(pick("identity"), None);
+        assert_eq!(pick("gzip;q=0.5"), None);
+        assert_eq!(pick("gzip;q=0.5, identity;q=0.2"), Some(Encoding::Gzip));
+        assert_eq!(pick("gzip;q=2, deflate"), Some(Encoding::Deflate));
+        assert_eq!(pick(""), None);
+    }
+
+    #[test]
+    fn recognises_compressible_types() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/json"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(is_compressible("Application/LD+JSON"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/gzip"));
+        assert!(!is_compressible("text/event-stream"));
+    }
+
+    #[test]
+    fn compresses_large_text_bodies() {
+        let original = text(4096).body.into_bytes().unwrap();
+        let response =
+            Compression::new().apply(Some("gzip"), text(4096).with_header("ETag", "\"abc\""));
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.headers.get("ETag"), Some("W/\"abc\""));
+        let encoded = response.body.into_bytes().unwrap();
+        assert!(encoded.len() < original.len() / 10);
+        let mut decoded = Vec::new();
+        GzDecoder::new(&encoded[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, original);
+
+        let streamed = Response::ok()
+            .with_header("Content-Type", "application/json")
+            .with_body(Body::stream(|out| out.write_all(&[b' '; 5000])));
+        let response = Compression::new().apply(Some("deflate"), streamed);
+        assert_eq!(response.headers.get("Content-Encoding"), Some("deflate"));
+        let mut decoded = Vec::new();
+        ZlibDecoder::new(&response.body.into_bytes().unwrap()[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, [b' '; 5000]);
+    }
+
+    #[test]
+    fn leaves_other_responses_alone() {
+        let compression = Compression::new();
+        let small = compression.apply(Some("gzip"), text(100));
+        assert!(!small.headers.contains("Content-Encoding"));
+        assert!(!small.headers.contains("Vary"));
+
+        let image = Response::ok()
+            .with_header("Content-Type", "image/png")
+            .with_body(vec![0; 4096]);
+        let image = compression.apply(Some("gzip"), image);
+        assert!(!image.headers.contains("Content-Encoding"));
+
+        let partial = text(4096).with_status(StatusCode::PartialContent);
+        let partial = compression.apply(Some("gzip"), partial);
+        assert!(!partial.headers.contains("Content-Encoding"));
+
+        let declined = compression.apply(Some("identity"), text(4096));
+        assert!(!declined.headers.contains("Content-Encoding"));
+        assert_eq!(declined.headers.get("Vary"), Some("Accept-Encoding"));
+
+        let no_transform = text(4096).with_header("Cache-Control", "public, no-transform");
+        let no_transform = compression.apply(Some("gzip"), no_transform);
+        assert!(!no_transform.headers.contains("Content-Encoding"));
+    }
+
+    #[test]
+    fn extends_an_existing_vary() {
+        let response = text(4096).with_header("Vary", "Origin");
+        let response = Compression::new().apply(Some("gzip"), response);
+        assert_eq!(
+            response.headers.get("Vary"),
+            Some("Origin, Accept-Encoding")
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::fs::Metadata;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::date;
+use crate::request::{Method, Request};
+
+/// The validators of a representation, used to answer conditional requests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Validators {
+    /// A quoted entity tag, e.g. `"5f3a-1c"` or `W/"5f3a-1c"`.
+    pub etag: Option<String>,
+    /// Truncated to whole seconds, the resolution of HTTP dates.
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// A strong ETag built from a file's modification time and size, and
+    /// its modification time.
+    pub fn for_file(metadata: &Metadata) -> Validators {
+        let modified = metadata.modified().ok();
+        let since_epoch = modified
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or(Duration::ZERO);
+        Validators {
+            etag: Some(format!(
+                "\"{:x}.{:x}-{:x}\"",
+                since_epoch.as_secs(),
+                since_epoch.subsec_nanos(),
+                metadata.len()
+            )),
+            last_modified: modified
+                .map(|_| UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs())),
+        }
+    }
+
+    /// The `ETag` and `Last-Modified` header values to send.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = Vec::new();
+        if let Some(etag) = &self.etag {
+            headers.push(("ETag", etag.clone()));
+        }
+        if let Some(time) = self.last_modified {
+            headers.push(("Last-Modified", date::format_http_date(time)));
+        }
+        headers
+    }
+
+    /// Whether a GET or HEAD `request` can be answered with 304 Not
+    /// Modified.
+    ///
+    /// `If-None-Match` takes precedence: when it is present,
+    /// `If-Modified-Since` is ignored, as RFC 9110 requires.
+    pub fn not_modified(&self, request: &Request) -> bool {
+        if !matches!(request.method, Method::Get | Method::Head) {
+            return false;
+        }
+        if let Some(value) = request.header("If-None-Match") {
+            return match &self.etag {
+                Some(etag) => list_matches(value, etag, weak_eq),
+                None => value.trim() == "*",
+            };
+        }
+        match (request.header("If-Modified-Since"), self.last_modified) {
+            (Some(value), Some(modified)) => {
+                date::parse_http_date(value).is_some_and(|since| modified <= since)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether a `Range` in `request` may be honoured: true unless an
+    /// `If-Range` names a different version of the representation.
+    ///
+    /// `If-Range` needs a strong match, so weak entity tags never match and
+    /// a date must equal `Last-Modified` exactly.
+    pub fn if_range_matches(&self, request: &Request) -> bool {
+        let Some(value) = request.header("If-Range").map(str::trim) else {
+            return true;
+        };
+        if value.starts_with('"') || value.starts_with("W/") {
+            return self
+                .etag
+                .as_deref()
+                .is_some_and(|etag| strong_eq(value, etag));
+        }
+        match (date::parse_http_date(value), self.last_modified) {
+            (Some(date), Some(modified)) => date == modified,
+            _ => false,
+        }
+    }
+}
+
+/// Whether an `If-None-Match`-style list of entity tags contains one that
+/// matches `etag` under `eq`. `*` matches any current representation.
+pub fn list_matches(list: &str, etag: &str, eq: fn(&str, &str) -> bool) -> bool {
+    if list.trim() == "*" {
+        return true;
+    }
+    list.split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .any(|tag| eq(tag, etag))
+}
+
+/// Weak comparison: the opaque tags match, ignoring any `W/` prefix.
+pub fn weak_eq(a: &str, b: &str) -> bool {
+    opaque(a) == opaque(b)
+}
+
+/// Strong comparison: both tags are strong and identical.
+pub fn strong_eq(a: &str, b: &str) -> bool {
+    !a.starts_with("W/") && !b.starts_with("W/") && a == b
+}
+
+fn opaque(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::RequestReader;
+
+    fn request(method: &str, headers: &str) -> Request {
+        let raw = format!("{} / HTTP/1.1\r\n{}\r\n", method, headers);
+        RequestReader::new(raw.as_bytes())
+            .read_request()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn validators() -> Validators {
+        Validators {
+            etag: Some("\"abc\"".to_string()),
+            last_modified: date::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+        }
+    }
+
+    #[test]
+    fn matches_if_none_match() {
+        let v = validators();
+        assert!(v.not_modified(&request("GET", "If-None-Match: \"abc\"\r\n")));
+        assert!(v.not_modified(&request("HEAD", "If-None-Match: \"x\", W/\"abc\"\r\n")));
+        assert!(v.not_modified(&request("GET", "If-None-Match: *\r\n")));
+        assert!(!v.not_modified(&request("GET", "If-None-Match: \"other\"\r\n")));
+        assert!(!v.not_modified(&request("POST", "If-None-Match: \"abc\"\r\n")));
+    }
+
+    #[test]
+    fn if_none_match_overrides_if_modified_since() {
+        let v = validators();
+        let stale_tag =
+            "If-None-Match: \"old\"\r\nIf-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n";
+        assert!(!v.not_modified(&request("GET", stale_tag)));
+    }
+
+    #[test]
+    fn compares_modification_dates() {
+        let v = validators();
+        let at = "If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n";
+        let before = "If-Modified-Since: Sun, 06 Nov 1994 08:49:36 GMT\r\n";
+        let garbage = "If-Modified-Since: last tuesday\r\n";
+        assert!(v.not_modified(&request("GET", at)));
+        assert!(!v.not_modified(&request("GET", before)));
+        assert!(!v.not_modified(&request("GET", garbage)));
+        assert!(!v.not_modified(&request("GET", "")));
+    }
+
+    #[test]
+    fn checks_if_range() {
+        let v = validators();
+        assert!(v.if_range_matches(&request("GET", "")));
+        assert!(v.if_range_matches(&request("GET", "If-Range: \"abc\"\r\n")));
+        assert!(!v.if_range_matches(&request("GET", "If-Range: W/\"abc\"\r\n")));
+        assert!(!v.if_range_matches(&request("GET", "If-Range: \"old\"\r\n")));
+        let date = "If-Range: Sun, 06 Nov 1994 08:49:37 GMT\r\n";
+        let later = "If-Range: Sun, 06 Nov 1994 08:49:38 GMT\r\n";
+        assert!(v.if_range_matches(&request("GET", date)));
+        assert!(!v.if_range_matches(&request("GET", later)));
+    }
+
+    #[test]
+    fn compares_entity_tags() {
+        assert!(weak_eq("W/\"a\"", "\"a\""));
+        assert!(!strong_eq("W/\"a\"", "\"a\""));
+        assert!(strong_eq("\"a\"", "\"a\""));
+    }
+}
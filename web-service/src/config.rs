@@ -0,0 +1,1249 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::access_log::LogFormat;
+use crate::auth::{self, Access, AuthRule};
+use crate::compression::{self, Compression};
+use crate::proxy::{self, ProxyRoute};
+use crate::rate_limit::{self, RateLimitRule};
+use crate::request::{self, Limits};
+use crate::server::{self, Backend};
+use crate::static_files::CacheRule;
+
+pub const DEFAULT_PORT: u16 = 7878;
+pub const DEFAULT_BIND: &str = "127.0.0.1";
+pub const DEFAULT_HOME_PAGE: &str = "hello.html";
+pub const DEFAULT_NOT_FOUND_PAGE: &str = "404.html";
+
+// No timeout needs to be longer, and deadlines this far out cannot overflow
+// an `Instant`.
+const MAX_SECONDS: f64 = 365.0 * 24.0 * 60.0 * 60.0;
+
+pub const USAGE: &str = "\
+Usage: web-service [OPTIONS]
+
+Options:
+  -c, --config <FILE>            read settings from a TOML file
+  -b, --bind <ADDR>              address to listen on; repeat for several
+  -p, --port <PORT>              port for bind addresses without one
+  -w, --workers <N>              number of worker threads
+      --backend <NAME>           threads (a worker per connection) or
+                                 epoll (one event loop for all sockets)
+      --queue-capacity <N>       connections that may wait for a worker
+  -r, --root <DIR>               document root
+      --templates <DIR>          directory of page templates
+      --home-page <FILE>         template rendered for /
+      --not-found-page <FILE>    template rendered for missing paths
+      --dev                      reload templates when they change
+      --keep-alive-timeout <S>   seconds an idle connection is kept open
+      --read-timeout <S>         seconds to wait for each read of a request
+      --write-timeout <S>        seconds a write to the client may block
+      --header-timeout <S>       seconds a request head may take to arrive
+      --shutdown-timeout <S>     seconds to drain connections on shutdown
+      --max-request-line <N>     longest request line in bytes
+      --max-header-size <N>      largest header section in bytes
+      --max-body-size <N>        largest request body in bytes
+      --max-requests <N>         requests served per connection
+      --max-upgraded <N>         WebSocket and other upgraded
+                                 connections and event streams open at
+                                 once
+      --upgraded-idle-timeout <S>
+                                 seconds a read on an upgraded connection
+                                 may wait; WebSocket clients are pinged
+      --cache-control <PATH=VALUE>
+                                 Cache-Control for files under PATH (or
+                                 matching *.ext); repeat for several
+      --autoindex <DIR>          list DIR and the directories beneath it
+                                 when they have no index.html; repeat
+                                 for several
+      --compression <on|off>     compress text responses for clients that
+                                 accept gzip or deflate
+      --compression-min-size <N> smallest body in bytes worth compressing
+      --compression-level <N>    0 (fastest) to 9 (smallest)
+      --precompressed <on|off>   serve FILE.gz for FILE when it exists
+      --proxy <PREFIX=UPSTREAMS> forward PREFIX and the paths beneath it
+                                 to UPSTREAMS, a comma-separated list of
+                                 host:port; repeat for several
+      --proxy-connect-timeout <S>
+                                 seconds to wait for an upstream to accept
+      --proxy-timeout <S>        seconds to wait on each upstream read or
+                                 write before answering 504
+      --proxy-fail-timeout <S>   seconds a failed upstream is left out of
+                                 rotation
+      --rate-limit <PREFIX=RATE,BURST>
+                                 allow each client RATE requests a second
+                                 under PREFIX, in bursts of up to BURST;
+                                 repeat for several
+      --rate-limit-key-header <NAME>
+                                 also limit clients by the value of this
+                                 header when they send it
+      --protect <PREFIX>         require credentials under PREFIX; repeat
+                                 for several
+      --public <PREFIX>          leave PREFIX open; the first --protect or
+                                 --public prefix that matches decides
+      --htpasswd <FILE>          users for HTTP Basic, with bcrypt or {SHA}
+                                 password hashes; reread on SIGHUP
+      --bearer-token <TOKEN>     accept TOKEN as a bearer token; repeat
+                                 for several
+      --auth-realm <NAME>        realm named in authentication challenges
+      --access-log <FILE>        access log file, - for stdout or off
+      --log-format <FORMAT>      access log format: combined or json
+  -h, --help                     print this help
+
+Command-line options override the config file.";
+
+/// Validated server settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub bind: Vec<SocketAddr>,
+    pub backend: Backend,
+    pub workers: usize,
+    pub queue_capacity: usize,
+    pub root: PathBuf,
+    pub templates: PathBuf,
+    pub home_page: String,
+    pub not_found_page: String,
+    pub cache_control: Vec<CacheRule>,
+    /// Directories, as paths under the root, that may be listed.
+    pub autoindex: Vec<String>,
+    /// `None` when compression is turned off.
+    pub compression: Option<Compression>,
+    pub precompressed: bool,
+    pub proxy: Vec<ProxyRoute>,
+    pub proxy_connect_timeout: Duration,
+    pub proxy_timeout: Duration,
+    pub proxy_fail_timeout: Duration,
+    pub rate_limit: Vec<RateLimitRule>,
+    pub rate_limit_key_header: Option<String>,
+    /// Public and protected path prefixes, in the order they are tried.
+    pub auth: Vec<AuthRule>,
+    pub htpasswd: Option<PathBuf>,
+    pub bearer_tokens: Vec<String>,
+    pub auth_realm: String,
+    pub keep_alive_timeout: Duration,
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    pub shutdown_timeout: Duration,
+    pub limits: Limits,
+    pub max_requests_per_connection: usize,
+    pub max_upgraded: usize,
+    pub upgraded_idle_timeout: Duration,
+    pub access_log: LogTarget,
+    pub log_format: LogFormat,
+    /// Development mode: templates are recompiled when their files change.
+    pub dev: bool,
+}
+
+/// Where access log lines go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogTarget {
+    Stdout,
+    File(PathBuf),
+    Off,
+}
+
+impl LogTarget {
+    // `-` means stdout and `off` disables the log; anything else is a path.
+    fn parse(value: &str) -> LogTarget {
+        match value {
+            "-" => LogTarget::Stdout,
+            "off" => LogTarget::Off,
+            path => LogTarget::File(PathBuf::from(path)),
+        }
+    }
+}
+
+/// Settings as read from one source, before defaults are applied.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    pub bind: Option<Vec<String>>,
+    pub port: Option<u16>,
+    pub backend: Option<Backend>,
+    pub workers: Option<usize>,
+    pub queue_capacity: Option<usize>,
+    pub root: Option<PathBuf>,
+    pub templates: Option<PathBuf>,
+    pub home_page: Option<String>,
+    pub not_found_page: Option<String>,
+    pub cache_control: Option<Vec<CacheRule>>,
+    pub autoindex: Option<Vec<String>>,
+    pub compression: Option<bool>,
+    pub compression_min_size: Option<u64>,
+    pub compression_level: Option<u32>,
+    pub precompressed: Option<bool>,
+    pub proxy: Option<Vec<ProxyRoute>>,
+    pub proxy_connect_timeout: Option<f64>,
+    pub proxy_timeout: Option<f64>,
+    pub proxy_fail_timeout: Option<f64>,
+    pub rate_limit: Option<Vec<RateLimitRule>>,
+    pub rate_limit_key_header: Option<String>,
+    pub auth: Option<Vec<AuthRule>>,
+    pub htpasswd: Option<PathBuf>,
+    pub bearer_tokens: Option<Vec<String>>,
+    pub auth_realm: Option<String>,
+    pub keep_alive_timeout: Option<f64>,
+    pub read_timeout: Option<f64>,
+    pub write_timeout: Option<f64>,
+    pub header_timeout: Option<f64>,
+    pub shutdown_timeout: Option<f64>,
+    pub max_request_line: Option<usize>,
+    pub max_header_size: Option<usize>,
+    pub max_body_size: Option<usize>,
+    pub max_requests: Option<usize>,
+    pub max_upgraded: Option<usize>,
+    pub upgraded_idle_timeout: Option<f64>,
+    pub access_log: Option<String>,
+    pub log_format: Option<LogFormat>,
+    pub dev: Option<bool>,
+}
+
+impl Settings {
+    /// Reads a TOML config file. A relative `root`, template directory or
+    /// access log path is taken to be relative to the file.
+    pub fn from_file(path: &Path) -> Result<Settings, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io {
+            path: path.to_path_buf(),
+            error: e,
+        })?;
+        let mut settings: Settings = toml::from_str(&text).map_err(|e| ConfigError::Parse {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })?;
+        if let (Some(root), Some(dir)) = (&settings.root, path.parent()) {
+            settings.root = Some(dir.join(root));
+        }
+        if let (Some(templates), Some(dir)) = (&settings.templates, path.parent()) {
+            settings.templates = Some(dir.join(templates));
+        }
+        if let (Some(htpasswd), Some(dir)) = (&settings.htpasswd, path.parent()) {
+            settings.htpasswd = Some(dir.join(htpasswd));
+        }
+        if let (Some(log), Some(dir)) = (&settings.access_log, path.parent()) {
+            if let LogTarget::File(file) = LogTarget::parse(log) {
+                settings.access_log = Some(dir.join(file).to_string_lossy().into_owned());
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Values set in `other` win.
+    pub fn merge(self, other: Settings) -> Settings {
+        Settings {
+            bind: other.bind.or(self.bind),
+            port: other.port.or(self.port),
+            backend: other.backend.or(self.backend),
+            workers: other.workers.or(self.workers),
+            queue_capacity: other.queue_capacity.or(self.queue_capacity),
+            root: other.root.or(self.root),
+            templates: other.templates.or(self.templates),
+            home_page: other.home_page.or(self.home_page),
+            not_found_page: other.not_found_page.or(self.not_found_page),
+            cache_control: other.cache_control.or(self.cache_control),
+            autoindex: other.autoindex.or(self.autoindex),
+            compression: other.compression.or(self.compression),
+            compression_min_size: other.compression_min_size.or(self.compression_min_size),
+            compression_level: other.compression_level.or(self.compression_level),
+            precompressed: other.precompressed.or(self.precompressed),
+            proxy: other.proxy.or(self.proxy),
+            proxy_connect_timeout: other.proxy_connect_timeout.or(self.proxy_connect_timeout),
+            proxy_timeout: other.proxy_timeout.or(self.proxy_timeout),
+            proxy_fail_timeout: other.proxy_fail_timeout.or(self.proxy_fail_timeout),
+            rate_limit: other.rate_limit.or(self.rate_limit),
+            rate_limit_key_header: other.rate_limit_key_header.or(self.rate_limit_key_header),
+            auth: other.auth.or(self.auth),
+            htpasswd: other.htpasswd.or(self.htpasswd),
+            bearer_tokens: other.bearer_tokens.or(self.bearer_tokens),
+            auth_realm: other.auth_realm.or(self.auth_realm),
+            keep_alive_timeout: other.keep_alive_timeout.or(self.keep_alive_timeout),
+            read_timeout: other.read_timeout.or(self.read_timeout),
+            write_timeout: other.write_timeout.or(self.write_timeout),
+            header_timeout: other.header_timeout.or(self.header_timeout),
+            shutdown_timeout: other.shutdown_timeout.or(self.shutdown_timeout),
+            max_request_line: other.max_request_line.or(self.max_request_line),
+            max_header_size: other.max_header_size.or(self.max_header_size),
+            max_body_size: other.max_body_size.or(self.max_body_size),
+            max_requests: other.max_requests.or(self.max_requests),
+            max_upgraded: other.max_upgraded.or(self.max_upgraded),
+            upgraded_idle_timeout: other.upgraded_idle_timeout.or(self.upgraded_idle_timeout),
+            access_log: other.access_log.or(self.access_log),
+            log_format: other.log_format.or(self.log_format),
+            dev: other.dev.or(self.dev),
+        }
+    }
+}
+
+/// What the command line asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run(Box<Config>),
+    Help,
+}
+
+impl Config {
+    /// Builds the configuration from command-line arguments (without the
+    /// program name), reading the config file they name if any.
+    pub fn from_args<I>(args: I) -> Result<Command, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let Some((file, cli)) = parse_args(args)? else {
+            return Ok(Command::Help);
+        };
+        let settings = match file {
+            Some(path) => Settings::from_file(&path)?.merge(cli),
+            None => cli,
+        };
+        Config::from_settings(settings).map(|config| Command::Run(Box::new(config)))
+    }
+
+    pub fn from_settings(settings: Settings) -> Result<Config, ConfigError> {
+        let port = settings.port.unwrap_or(DEFAULT_PORT);
+        let bind = settings
+            .bind
+            .unwrap_or_else(|| vec![DEFAULT_BIND.to_string()]);
+        if bind.is_empty() {
+            return Err(invalid("bind", "at least one address is required"));
+        }
+        let mut addrs = Vec::new();
+        for entry in &bind {
+            for addr in resolve(entry, port)? {
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+        }
+
+        let workers = settings.workers.unwrap_or(server::DEFAULT_WORKERS);
+        if !(1..=1024).contains(&workers) {
+            return Err(invalid("workers", "must be between 1 and 1024"));
+        }
+        let queue_capacity = settings
+            .queue_capacity
+            .unwrap_or(server::DEFAULT_QUEUE_CAPACITY);
+        if queue_capacity == 0 {
+            return Err(invalid("queue-capacity", "must be at least 1"));
+        }
+        let max_requests_per_connection = settings
+            .max_requests
+            .unwrap_or(server::DEFAULT_MAX_REQUESTS_PER_CONNECTION);
+        if max_requests_per_connection == 0 {
+            return Err(invalid("max-requests", "must be at least 1"));
+        }
+
+        let limits = Limits {
+            max_request_line: at_least_one(
+                "max-request-line",
+                settings.max_request_line,
+                request::DEFAULT_MAX_REQUEST_LINE,
+            )?,
+            max_header_size: at_least_one(
+                "max-header-size",
+                settings.max_header_size,
+                request::DEFAULT_MAX_HEADER_SIZE,
+            )?,
+            max_body_size: settings
+                .max_body_size
+                .unwrap_or(request::DEFAULT_MAX_BODY_SIZE),
+            header_timeout: Some(seconds(
+                "header-timeout",
+                settings.header_timeout,
+                request::DEFAULT_HEADER_TIMEOUT,
+            )?),
+        };
+
+        let cache_control = settings.cache_control.unwrap_or_default();
+        for rule in &cache_control {
+            let printable = |text: &str| !text.is_empty() && !text.chars().any(char::is_control);
+            if !printable(&rule.path) || !printable(&rule.value) {
+                return Err(invalid(
+                    "cache-control",
+                    &format!("{:?} = {:?} is not a usable rule", rule.path, rule.value),
+                ));
+            }
+        }
+
+        let autoindex = settings.autoindex.unwrap_or_default();
+        for dir in &autoindex {
+            if !dir.starts_with('/') || dir.chars().any(char::is_control) {
+                return Err(invalid(
+                    "autoindex",
+                    &format!("{:?} is not a path under the root", dir),
+                ));
+            }
+        }
+
+        let proxy = settings.proxy.unwrap_or_default();
+        for route in &proxy {
+            let prefix = &route.prefix;
+            let capture = prefix
+                .split('/')
+                .any(|segment| segment.starts_with(':') || segment.starts_with('*'));
+            if !prefix.starts_with('/') || prefix.chars().any(char::is_control) || capture {
+                return Err(invalid(
+                    "proxy",
+                    &format!("{:?} is not a usable path prefix", prefix),
+                ));
+            }
+            if route.upstreams.is_empty() {
+                return Err(invalid(
+                    "proxy",
+                    &format!("{:?} needs at least one upstream", prefix),
+                ));
+            }
+            for upstream in &route.upstreams {
+                let port = upstream.rsplit_once(':').map(|(_, port)| port);
+                if port.is_none_or(|port| port.parse::<u16>().is_err()) {
+                    return Err(invalid(
+                        "proxy",
+                        &format!("upstream {:?} is not host:port", upstream),
+                    ));
+                }
+            }
+        }
+
+        let rate_limit = settings.rate_limit.unwrap_or_default();
+        for rule in &rate_limit {
+            if !rule.prefix.starts_with('/') || rule.prefix.chars().any(char::is_control) {
+                return Err(invalid(
+                    "rate-limit",
+                    &format!("{:?} is not a usable path prefix", rule.prefix),
+                ));
+            }
+            if rule.burst == 0 {
+                return Err(invalid(
+                    "rate-limit",
+                    &format!("burst for {:?} must be at least 1", rule.prefix),
+                ));
+            }
+            if !rule.per_second.is_finite() || rule.per_second < rate_limit::MIN_PER_SECOND {
+                return Err(invalid(
+                    "rate-limit",
+                    &format!(
+                        "rate for {:?} must be at least one request a day",
+                        rule.prefix
+                    ),
+                ));
+            }
+        }
+        let rate_limit_key_header = settings.rate_limit_key_header;
+        if let Some(name) = &rate_limit_key_header {
+            if name.is_empty() || !name.bytes().all(request::is_token_byte) {
+                return Err(invalid(
+                    "rate-limit-key-header",
+                    &format!("{:?} is not a header name", name),
+                ));
+            }
+        }
+
+        let auth = settings.auth.unwrap_or_default();
+        for rule in &auth {
+            if !rule.prefix.starts_with('/') || rule.prefix.chars().any(char::is_control) {
+                return Err(invalid(
+                    "auth",
+                    &format!("{:?} is not a usable path prefix", rule.prefix),
+                ));
+            }
+        }
+        let htpasswd = settings.htpasswd;
+        if let Some(path) = &htpasswd {
+            if !path.is_file() {
+                return Err(invalid(
+                    "htpasswd",
+                    &format!("{} is not a file", path.display()),
+                ));
+            }
+        }
+        let bearer_tokens = settings.bearer_tokens.unwrap_or_default();
+        if bearer_tokens
+            .iter()
+            .any(|token| token.is_empty() || !token.bytes().all(|b| b.is_ascii_graphic()))
+        {
+            // The tokens are secrets, so they are left out of the message.
+            return Err(invalid(
+                "bearer-tokens",
+                "tokens must be printable ASCII without spaces",
+            ));
+        }
+        let protected = auth.iter().any(|rule| rule.access == Access::Protected);
+        if protected && htpasswd.is_none() && bearer_tokens.is_empty() {
+            return Err(invalid(
+                "auth",
+                "protected paths need an htpasswd file or bearer tokens",
+            ));
+        }
+        let auth_realm = settings
+            .auth_realm
+            .unwrap_or_else(|| auth::DEFAULT_REALM.to_string());
+        if auth_realm
+            .chars()
+            .any(|c| c.is_control() || c == '"' || c == '\\')
+        {
+            return Err(invalid(
+                "auth-realm",
+                &format!("{:?} cannot be quoted in a challenge", auth_realm),
+            ));
+        }
+
+        let compression_level = settings
+            .compression_level
+            .unwrap_or(compression::DEFAULT_LEVEL);
+        if compression_level > 9 {
+            return Err(invalid("compression-level", "must be between 0 and 9"));
+        }
+        let compression = settings.compression.unwrap_or(true).then(|| {
+            Compression::new()
+                .min_size(
+                    settings
+                        .compression_min_size
+                        .unwrap_or(compression::DEFAULT_MIN_SIZE),
+                )
+                .level(compression_level)
+        });
+
+        let root = settings.root.unwrap_or_else(default_root);
+        if !root.is_dir() {
+            return Err(invalid(
+                "root",
+                &format!("{} is not a directory", root.display()),
+            ));
+        }
+        let templates = settings.templates.unwrap_or_else(default_templates);
+        if !templates.is_dir() {
+            return Err(invalid(
+                "templates",
+                &format!("{} is not a directory", templates.display()),
+            ));
+        }
+
+        Ok(Config {
+            bind: addrs,
+            backend: settings.backend.unwrap_or_default(),
+            workers,
+            queue_capacity,
+            root,
+            templates,
+            home_page: settings
+                .home_page
+                .unwrap_or_else(|| DEFAULT_HOME_PAGE.to_string()),
+            not_found_page: settings
+                .not_found_page
+                .unwrap_or_else(|| DEFAULT_NOT_FOUND_PAGE.to_string()),
+            cache_control,
+            autoindex,
+            compression,
+            precompressed: settings.precompressed.unwrap_or(false),
+            proxy,
+            proxy_connect_timeout: seconds(
+                "proxy-connect-timeout",
+                settings.proxy_connect_timeout,
+                proxy::DEFAULT_CONNECT_TIMEOUT,
+            )?,
+            proxy_timeout: seconds(
+                "proxy-timeout",
+                settings.proxy_timeout,
+                proxy::DEFAULT_TIMEOUT,
+            )?,
+            proxy_fail_timeout: seconds(
+                "proxy-fail-timeout",
+                settings.proxy_fail_timeout,
+                proxy::DEFAULT_FAIL_TIMEOUT,
+            )?,
+            rate_limit,
+            rate_limit_key_header,
+            auth,
+            htpasswd,
+            bearer_tokens,
+            auth_realm,
+            keep_alive_timeout: seconds(
+                "keep-alive-timeout",
+                settings.keep_alive_timeout,
+                server::DEFAULT_KEEP_ALIVE_TIMEOUT,
+            )?,
+            read_timeout: seconds(
+                "read-timeout",
+                settings.read_timeout,
+                server::DEFAULT_READ_TIMEOUT,
+            )?,
+            write_timeout: seconds(
+                "write-timeout",
+                settings.write_timeout,
+                server::DEFAULT_WRITE_TIMEOUT,
+            )?,
+            shutdown_timeout: seconds(
+                "shutdown-timeout",
+                settings.shutdown_timeout,
+                server::DEFAULT_SHUTDOWN_TIMEOUT,
+            )?,
+            limits,
+            max_requests_per_connection,
+            max_upgraded: at_least_one(
+                "max-upgraded",
+                settings.max_upgraded,
+                server::DEFAULT_MAX_UPGRADED,
+            )?,
+            upgraded_idle_timeout: seconds(
+                "upgraded-idle-timeout",
+                settings.upgraded_idle_timeout,
+                server::DEFAULT_UPGRADED_IDLE_TIMEOUT,
+            )?,
+            access_log: settings
+                .access_log
+                .as_deref()
+                .map_or(LogTarget::Stdout, LogTarget::parse),
+            log_format: settings.log_format.unwrap_or(LogFormat::Combined),
+            dev: settings.dev.unwrap_or(false),
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::from_settings(Settings::default()).expect("defaults are valid")
+    }
+}
+
+// The `public` directory shipped next to Cargo.toml.
+fn default_root() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("public")
+}
+
+// The `templates` directory shipped next to Cargo.toml.
+fn default_templates() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("templates")
+}
+
+// Accepts `host:port`, `[v6]:port`, or a bare host that takes `port`.
+fn resolve(entry: &str, port: u16) -> Result<Vec<SocketAddr>, ConfigError> {
+    if let Ok(addr) = entry.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+    let host = entry.trim_start_matches('[').trim_end_matches(']');
+    let resolved = if entry.contains(':') && !host.contains(':') {
+        entry.to_socket_addrs()
+    } else {
+        (host, port).to_socket_addrs()
+    };
+    match resolved {
+        Ok(addrs) => {
+            let addrs: Vec<SocketAddr> = addrs.collect();
+            if addrs.is_empty() {
+                Err(invalid(
+                    "bind",
+                    &format!("{:?} resolved to no addresses", entry),
+                ))
+            } else {
+                Ok(addrs)
+            }
+        }
+        Err(e) => Err(invalid("bind", &format!("{:?}: {}", entry, e))),
+    }
+}
+
+fn seconds(
+    field: &'static str,
+    value: Option<f64>,
+    default: Duration,
+) -> Result<Duration, ConfigError> {
+    match value {
+        None => Ok(default),
+        Some(secs) if secs > MAX_SECONDS => Err(invalid(field, "must be at most a year")),
+        Some(secs) if secs > 0.0 => Duration::try_from_secs_f64(secs)
+            .map_err(|_| invalid(field, "must be a positive number of seconds")),
+        Some(_) => Err(invalid(field, "must be a positive number of seconds")),
+    }
+}
+
+fn at_least_one(
+    field: &'static str,
+    value: Option<usize>,
+    default: usize,
+) -> Result<usize, ConfigError> {
+    match value {
+        None => Ok(default),
+        Some(0) => Err(invalid(field, "must be at least 1")),
+        Some(n) => Ok(n),
+    }
+}
+
+// Returns the config file path and the settings given as flags, or `None`
+// if help was requested.
+fn parse_args<I>(args: I) -> Result<Option<(Option<PathBuf>, Settings)>, ConfigError>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut file = None;
+    let mut settings = Settings::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        // Both `--flag value` and `--flag=value` are accepted.
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_string(), Some(value.to_string()))
+            }
+            _ => (arg, None),
+        };
+        if flag == "-h" || flag == "--help" {
+            return Ok(None);
+        }
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| ConfigError::Usage(format!("{} needs a value", flag)))
+        };
+        match flag.as_str() {
+            "-c" | "--config" => file = Some(PathBuf::from(value()?)),
+            "-b" | "--bind" => settings.bind.get_or_insert_with(Vec::new).push(value()?),
+            "-p" | "--port" => settings.port = Some(number(&flag, &value()?)?),
+            "-w" | "--workers" => settings.workers = Some(number(&flag, &value()?)?),
+            "--backend" => settings.backend = Some(value()?.parse().map_err(ConfigError::Usage)?),
+            "--queue-capacity" => settings.queue_capacity = Some(number(&flag, &value()?)?),
+            "-r" | "--root" => settings.root = Some(PathBuf::from(value()?)),
+            "--templates" => settings.templates = Some(PathBuf::from(value()?)),
+            "--dev" => {
+                settings.dev = Some(match &inline {
+                    Some(inline) => switch(&flag, inline)?,
+                    None => true,
+                })
+            }
+            "--home-page" => settings.home_page = Some(value()?),
+            "--not-found-page" => settings.not_found_page = Some(value()?),
+            "--keep-alive-timeout" => settings.keep_alive_timeout = Some(number(&flag, &value()?)?),
+            "--read-timeout" => settings.read_timeout = Some(number(&flag, &value()?)?),
+            "--write-timeout" => settings.write_timeout = Some(number(&flag, &value()?)?),
+            "--header-timeout" => settings.header_timeout = Some(number(&flag, &value()?)?),
+            "--shutdown-timeout" => settings.shutdown_timeout = Some(number(&flag, &value()?)?),
+            "--max-request-line" => settings.max_request_line = Some(number(&flag, &value()?)?),
+            "--max-header-size" => settings.max_header_size = Some(number(&flag, &value()?)?),
+            "--max-body-size" => settings.max_body_size = Some(number(&flag, &value()?)?),
+            "--max-requests" => settings.max_requests = Some(number(&flag, &value()?)?),
+            "--max-upgraded" => settings.max_upgraded = Some(number(&flag, &value()?)?),
+            "--upgraded-idle-timeout" => {
+                settings.upgraded_idle_timeout = Some(number(&flag, &value()?)?)
+            }
+            "--cache-control" => {
+                let rule = value()?;
+                let (path, value) = rule.split_once('=').ok_or_else(|| {
+                    ConfigError::Usage(format!("{} expects PATH=VALUE, got {:?}", flag, rule))
+                })?;
+                settings
+                    .cache_control
+                    .get_or_insert_with(Vec::new)
+                    .push(CacheRule::new(path, value));
+            }
+            "--autoindex" => settings
+                .autoindex
+                .get_or_insert_with(Vec::new)
+                .push(value()?),
+            "--compression" => settings.compression = Some(switch(&flag, &value()?)?),
+            "--compression-min-size" => {
+                settings.compression_min_size = Some(number(&flag, &value()?)?)
+            }
+            "--compression-level" => settings.compression_level = Some(number(&flag, &value()?)?),
+            "--precompressed" => settings.precompressed = Some(switch(&flag, &value()?)?),
+            "--proxy" => {
+                let route = value()?;
+                let (prefix, upstreams) = route.split_once('=').ok_or_else(|| {
+                    ConfigError::Usage(format!(
+                        "{} expects PREFIX=UPSTREAMS, got {:?}",
+                        flag, route
+                    ))
+                })?;
+                let upstreams = upstreams
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|upstream| !upstream.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                settings
+                    .proxy
+                    .get_or_insert_with(Vec::new)
+                    .push(ProxyRoute::new(prefix, upstreams));
+            }
+            "--proxy-connect-timeout" => {
+                settings.proxy_connect_timeout = Some(number(&flag, &value()?)?)
+            }
+            "--proxy-timeout" => settings.proxy_timeout = Some(number(&flag, &value()?)?),
+            "--proxy-fail-timeout" => settings.proxy_fail_timeout = Some(number(&flag, &value()?)?),
+            "--rate-limit" => {
+                let rule = value()?;
+                let parsed = rule.split_once('=').and_then(|(prefix, limit)| {
+                    let (rate, burst) = limit.split_once(',')?;
+                    let rate = rate.trim().parse().ok()?;
+                    let burst = burst.trim().parse().ok()?;
+                    Some(RateLimitRule::new(prefix, burst, rate))
+                });
+                let rule = parsed.ok_or_else(|| {
+                    ConfigError::Usage(format!(
+                        "{} expects PREFIX=RATE,BURST, got {:?}",
+                        flag, rule
+                    ))
+                })?;
+                settings.rate_limit.get_or_insert_with(Vec::new).push(rule);
+            }
+            "--rate-limit-key-header" => settings.rate_limit_key_header = Some(value()?),
+            "--protect" => settings
+                .auth
+                .get_or_insert_with(Vec::new)
+                .push(AuthRule::protected(value()?)),
+            "--public" => settings
+                .auth
+                .get_or_insert_with(Vec::new)
+                .push(AuthRule::public(value()?)),
+            "--htpasswd" => settings.htpasswd = Some(PathBuf::from(value()?)),
+            "--bearer-token" => settings
+                .bearer_tokens
+                .get_or_insert_with(Vec::new)
+                .push(value()?),
+            "--auth-realm" => settings.auth_realm = Some(value()?),
+            "--access-log" => settings.access_log = Some(value()?),
+            "--log-format" => {
+                settings.log_format = Some(value()?.parse().map_err(ConfigError::Usage)?)
+            }
+            _ => return Err(ConfigError::Usage(format!("unknown option {}", flag))),
+        }
+    }
+    Ok(Some((file, settings)))
+}
+
+fn number<T: FromStr>(flag: &str, value: &str) -> Result<T, ConfigError> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::Usage(format!("{} expects a number, got {:?}", flag, value)))
+}
+
+fn switch(flag: &str, value: &str) -> Result<bool, ConfigError> {
+    match value {
+        "on" | "true" | "yes" => Ok(true),
+        "off" | "false" | "no" => Ok(false),
+        _ => Err(ConfigError::Usage(format!(
+            "{} expects on or off, got {:?}",
+            flag, value
+        ))),
+    }
+}
+
+fn invalid(field: &'static str, reason: &str) -> ConfigError {
+    ConfigError::Invalid {
+        field,
+        reason: reason.to_string(),
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io { path: PathBuf, error: io::Error },
+    Parse { path: PathBuf, message: String },
+    Usage(String),
+    Invalid { field: &'static str, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => {
+                write!(f, "cannot read config file {}: {}", path.display(), error)
+            }
+            ConfigError::Parse { path, message } => {
+                write!(f, "invalid config file {}: {}", path.display(), message)
+            }
+            ConfigError::Usage(message) => f.write_str(message),
+            ConfigError::Invalid { field, reason } => write!(f, "invalid {}: {}", field, reason),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    fn run(line: &str) -> Result<Config, ConfigError> {
+        match Config::from_args(args(line))? {
+            Command::Run(config) => Ok(*config),
+            Command::Help => panic!("unexpected help"),
+        }
+    }
+
+    fn write_config(name: &str, contents: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "web-service-config-{}-{}",
+            std::process::id(),
+            name
+        ));
+        fs::create_dir_all(dir.join("site")).unwrap();
+        let path = dir.join("web-service.toml");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let config = run("").unwrap();
+        assert_eq!(config.bind, ["127.0.0.1:7878".parse().unwrap()]);
+        assert_eq!(config.workers, server::DEFAULT_WORKERS);
+        assert_eq!(config.backend, Backend::Threads);
+        assert_eq!(config.access_log, LogTarget::Stdout);
+        assert_eq!(config.log_format, LogFormat::Combined);
+        assert_eq!(config.compression, Some(Compression::new()));
+        assert!(!config.precompressed);
+        assert!(!config.dev);
+        assert!(config.autoindex.is_empty());
+        assert!(config.proxy.is_empty());
+        assert_eq!(config.proxy_timeout, proxy::DEFAULT_TIMEOUT);
+        assert!(config.rate_limit.is_empty());
+        assert!(config.root.ends_with("public"));
+        assert!(config.templates.ends_with("templates"));
+    }
+
+    #[test]
+    fn parses_flags() {
+        let config = run("-b 127.0.0.1 --bind=[::1]:9001 -p 9000 -w 3 --keep-alive-timeout 0.5 --log-format json").unwrap();
+        assert_eq!(
+            config.bind,
+            [
+                "127.0.0.1:9000".parse().unwrap(),
+                "[::1]:9001".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.workers, 3);
+        assert_eq!(config.keep_alive_timeout, Duration::from_millis(500));
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(Config::from_args(args("--help")).unwrap(), Command::Help);
+
+        let config = run("--header-timeout 2 --max-body-size 1024 --max-request-line 100").unwrap();
+        assert_eq!(config.limits.header_timeout, Some(Duration::from_secs(2)));
+        assert_eq!(config.limits.max_body_size, 1024);
+        assert_eq!(config.limits.max_request_line, 100);
+        assert_eq!(
+            config.limits.max_header_size,
+            request::DEFAULT_MAX_HEADER_SIZE
+        );
+
+        let config = run("--max-upgraded 10 --upgraded-idle-timeout 30").unwrap();
+        assert_eq!(config.max_upgraded, 10);
+        assert_eq!(config.upgraded_idle_timeout, Duration::from_secs(30));
+
+        let config =
+            run("--compression-min-size 10 --compression-level=9 --precompressed on").unwrap();
+        assert_eq!(
+            config.compression,
+            Some(Compression::new().min_size(10).level(9))
+        );
+        assert!(config.precompressed);
+        assert_eq!(run("--compression off").unwrap().compression, None);
+
+        assert!(run("--dev -w 2").unwrap().dev);
+        assert_eq!(
+            run("--autoindex /pub --autoindex=/downloads/")
+                .unwrap()
+                .autoindex,
+            ["/pub", "/downloads/"]
+        );
+        assert!(!run("--dev=off").unwrap().dev);
+        assert_eq!(run("--backend epoll").unwrap().backend, Backend::Epoll);
+    }
+
+    #[test]
+    fn cli_overrides_file() {
+        let path = write_config(
+            "override",
+            "bind = [\"127.0.0.1\"]\nport = 8000\nworkers = 2\nroot = \"site\"\ntemplates = \"site\"\nshutdown-timeout = 3\n\
+             access-log = \"logs/access.log\"\n",
+        );
+        let config = run(&format!("-c {} --port 8001", path.display())).unwrap();
+        assert_eq!(config.bind, ["127.0.0.1:8001".parse().unwrap()]);
+        assert_eq!(config.workers, 2);
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(3));
+        assert_eq!(config.root, path.parent().unwrap().join("site"));
+        assert_eq!(config.templates, path.parent().unwrap().join("site"));
+        assert_eq!(
+            config.access_log,
+            LogTarget::File(path.parent().unwrap().join("logs/access.log"))
+        );
+        let config = run(&format!("-c {} --access-log off", path.display())).unwrap();
+        assert_eq!(config.access_log, LogTarget::Off);
+    }
+
+    #[test]
+    fn reads_cache_rules() {
+        let path = write_config(
+            "cache",
+            "[[cache-control]]\npath = \"/assets/\"\nvalue = \"public, max-age=86400\"\n\n\
+             [[cache-control]]\npath = \"*.html\"\nvalue = \"no-cache\"\n",
+        );
+        let config = run(&format!("-c {}", path.display())).unwrap();
+        assert_eq!(
+            config.cache_control,
+            [
+                CacheRule::new("/assets/", "public, max-age=86400"),
+                CacheRule::new("*.html", "no-cache")
+            ]
+        );
+
+        // Rules given on the command line replace the file's.
+        let config = run(&format!("-c {} --cache-control /=no-store", path.display())).unwrap();
+        assert_eq!(config.cache_control, [CacheRule::new("/", "no-store")]);
+        assert!(matches!(
+            run("--cache-control no-equals-sign"),
+            Err(ConfigError::Usage(_))
+        ));
+        assert!(matches!(
+            run("--cache-control /="),
+            Err(ConfigError::Invalid { .. })
+        ));
+    }
+
+    #[test]
+    fn reads_proxy_routes() {
+        let path = write_config(
+            "proxy",
+            "proxy-timeout = 5\n\n[[proxy]]\nprefix = \"/api/\"\n\
+             upstreams = [\"127.0.0.1:9001\", \"127.0.0.1:9002\"]\nstrip-prefix = true\n",
+        );
+        let config = run(&format!("-c {}", path.display())).unwrap();
+        let mut route = ProxyRoute::new(
+            "/api/",
+            vec!["127.0.0.1:9001".to_string(), "127.0.0.1:9002".to_string()],
+        );
+        route.strip_prefix = true;
+        assert_eq!(config.proxy, [route]);
+        assert_eq!(config.proxy_timeout, Duration::from_secs(5));
+
+        let config =
+            run("--proxy /app=localhost:3000,localhost:3001 --proxy-connect-timeout 0.5").unwrap();
+        assert_eq!(
+            config.proxy,
+            [ProxyRoute::new(
+                "/app",
+                vec!["localhost:3000".to_string(), "localhost:3001".to_string()]
+            )]
+        );
+        assert_eq!(config.proxy_connect_timeout, Duration::from_millis(500));
+
+        assert!(matches!(run("--proxy /app"), Err(ConfigError::Usage(_))));
+        for bad in [
+            "/app=",
+            "app=localhost:3000",
+            "/:id=localhost:3000",
+            "/app=localhost",
+        ] {
+            assert!(
+                matches!(
+                    run(&format!("--proxy {}", bad)),
+                    Err(ConfigError::Invalid { field: "proxy", .. })
+                ),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn reads_rate_limits() {
+        let path = write_config(
+            "rate-limit",
+            "rate-limit-key-header = \"X-API-Key\"\n\n[[rate-limit]]\nprefix = \"/api/\"\n\
+             burst = 20\nper-second = 5\n",
+        );
+        let config = run(&format!("-c {}", path.display())).unwrap();
+        assert_eq!(config.rate_limit, [RateLimitRule::new("/api/", 20, 5.0)]);
+        assert_eq!(config.rate_limit_key_header.as_deref(), Some("X-API-Key"));
+
+        let config = run("--rate-limit /=0.5,10 --rate-limit=/login=1,3").unwrap();
+        assert_eq!(
+            config.rate_limit,
+            [
+                RateLimitRule::new("/", 10, 0.5),
+                RateLimitRule::new("/login", 3, 1.0)
+            ]
+        );
+        assert_eq!(config.rate_limit_key_header, None);
+
+        for bad in ["/", "/=5", "/=five,10", "/=5,-1"] {
+            assert!(
+                matches!(
+                    run(&format!("--rate-limit {}", bad)),
+                    Err(ConfigError::Usage(_))
+                ),
+                "{}",
+                bad
+            );
+        }
+        for bad in ["api=5,10", "/=0,10", "/=5,0", "/=inf,10", "/=1e-300,10"] {
+            assert!(
+                matches!(
+                    run(&format!("--rate-limit {}", bad)),
+                    Err(ConfigError::Invalid {
+                        field: "rate-limit",
+                        ..
+                    })
+                ),
+                "{}",
+                bad
+            );
+        }
+        assert!(matches!(
+            run("--rate-limit-key-header X:Key"),
+            Err(ConfigError::Invalid {
+                field: "rate-limit-key-header",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn reads_auth_rules() {
+        let path = write_config(
+            "auth",
+            "htpasswd = \"users\"\nbearer-tokens = [\"s3cret\"]\nauth-realm = \"staff\"\n\n\
+             [[auth]]\nprefix = \"/admin/login\"\naccess = \"public\"\n\n\
+             [[auth]]\nprefix = \"/admin/\"\naccess = \"protected\"\n",
+        );
+        let users = path.parent().unwrap().join("users");
+        fs::write(&users, "").unwrap();
+        let config = run(&format!("-c {}", path.display())).unwrap();
+        assert_eq!(
+            config.auth,
+            [
+                AuthRule::public("/admin/login"),
+                AuthRule::protected("/admin/")
+            ]
+        );
+        assert_eq!(config.htpasswd, Some(users.clone()));
+        assert_eq!(config.bearer_tokens, ["s3cret"]);
+        assert_eq!(config.auth_realm, "staff");
+
+        let config = run(&format!(
+            "--public /health --protect / --htpasswd {} --bearer-token a --bearer-token b",
+            users.display()
+        ))
+        .unwrap();
+        assert_eq!(
+            config.auth,
+            [AuthRule::public("/health"), AuthRule::protected("/")]
+        );
+        assert_eq!(config.bearer_tokens, ["a", "b"]);
+        assert_eq!(config.auth_realm, auth::DEFAULT_REALM);
+
+        for (args, field) in [
+            ("--protect /admin", "auth"),
+            ("--protect admin --bearer-token a", "auth"),
+            ("--htpasswd /no/such/file", "htpasswd"),
+            ("--protect / --bearer-token=", "bearer-tokens"),
+            ("--auth-realm \"", "auth-realm"),
+        ] {
+            match run(args) {
+                Err(ConfigError::Invalid { field: actual, .. }) => {
+                    assert_eq!(actual, field, "{}", args)
+                }
+                other => panic!("{}: {:?}", args, other),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        assert!(matches!(
+            run("-w 0"),
+            Err(ConfigError::Invalid {
+                field: "workers",
+                ..
+            })
+        ));
+        assert!(matches!(run("--port http"), Err(ConfigError::Usage(_))));
+        assert!(matches!(
+            run("--max-header-size 0"),
+            Err(ConfigError::Invalid {
+                field: "max-header-size",
+                ..
+            })
+        ));
+        assert!(matches!(run("--frobnicate"), Err(ConfigError::Usage(_))));
+        assert!(matches!(run("--workers"), Err(ConfigError::Usage(_))));
+        assert!(matches!(
+            run("--root /definitely/not/here"),
+            Err(ConfigError::Invalid { field: "root", .. })
+        ));
+        assert!(matches!(
+            run("--shutdown-timeout -1"),
+            Err(ConfigError::Invalid { .. })
+        ));
+        assert!(matches!(
+            run("--read-timeout 1e20"),
+            Err(ConfigError::Invalid {
+                field: "read-timeout",
+                ..
+            })
+        ));
+        assert!(matches!(
+            run("--shutdown-timeout 1e19"),
+            Err(ConfigError::Invalid {
+                field: "shutdown-timeout",
+                ..
+            })
+        ));
+        assert!(matches!(
+            run("--shutdown-timeout NaN"),
+            Err(ConfigError::Invalid { .. })
+        ));
+        assert!(matches!(
+            run("--compression-level 10"),
+            Err(ConfigError::Invalid {
+                field: "compression-level",
+                ..
+            })
+        ));
+        assert!(matches!(
+            run("--autoindex pub"),
+            Err(ConfigError::Invalid {
+                field: "autoindex",
+                ..
+            })
+        ));
+        assert!(matches!(
+            run("--precompressed maybe"),
+            Err(ConfigError::Usage(_))
+        ));
+        assert!(matches!(
+            run("--backend kqueue"),
+            Err(ConfigError::Usage(_))
+        ));
+        assert!(matches!(
+            run("--log-format xml"),
+            Err(ConfigError::Usage(_))
+        ));
+        assert!(matches!(
+            run("-c /definitely/not/here.toml"),
+            Err(ConfigError::Io { .. })
+        ));
+
+        let typo = write_config("typo", "wrokers = 4\n");
+        let err = run(&format!("-c {}", typo.display())).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse { .. }));
+        assert!(err.to_string().contains("wrokers"), "{}", err);
+    }
+}
@@ -0,0 +1,147 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::request::ParseError;
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// Why a request could not be served.
+///
+/// Each variant maps onto the status code sent back to the client, so a
+/// failure on one connection becomes a response rather than a panic.
+#[derive(Debug)]
+pub enum ServerError {
+    Io(io::Error),
+    Parse(ParseError),
+    NotFound,
+    TooLarge,
+    Timeout,
+}
+
+impl ServerError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ServerError::Io(_) => StatusCode::InternalServerError,
+            ServerError::Parse(ParseError::UnsupportedVersion) => {
+                StatusCode::HttpVersionNotSupported
+            }
+            ServerError::Parse(ParseError::UnsupportedTransferEncoding) => {
+                StatusCode::NotImplemented
+            }
+            ServerError::Parse(ParseError::RequestLineTooLong) => StatusCode::UriTooLong,
+            ServerError::Parse(ParseError::HeadersTooLarge) => {
+                StatusCode::RequestHeaderFieldsTooLarge
+            }
+            ServerError::Parse(_) => StatusCode::BadRequest,
+            ServerError::NotFound => StatusCode::NotFound,
+            ServerError::TooLarge => StatusCode::PayloadTooLarge,
+            ServerError::Timeout => StatusCode::RequestTimeout,
+        }
+    }
+
+    /// The plain-text error response for this failure.
+    pub fn response(&self) -> Response {
+        Response::error(self.status())
+    }
+
+    /// Whether the peer went away, in which case there is nobody left to
+    /// send a response to.
+    pub fn is_disconnect(&self) -> bool {
+        let ServerError::Io(e) = self else {
+            return false;
+        };
+        matches!(
+            e.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::UnexpectedEof
+        )
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Io(e) => write!(f, "i/o error: {}", e),
+            ServerError::Parse(e) => write!(f, "bad request: {}", e),
+            ServerError::NotFound => f.write_str("not found"),
+            ServerError::TooLarge => f.write_str("request too large"),
+            ServerError::Timeout => f.write_str("timed out waiting for the client"),
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServerError::Io(e) => Some(e),
+            ServerError::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        if is_timeout(&e) {
+            ServerError::Timeout
+        } else {
+            ServerError::Io(e)
+        }
+    }
+}
+
+impl From<ParseError> for ServerError {
+    fn from(e: ParseError) -> Self {
+        match e {
+            ParseError::Io(e) => ServerError::from(e),
+            ParseError::BodyTooLarge => ServerError::TooLarge,
+            ParseError::HeaderTimeout => ServerError::Timeout,
+            e => ServerError::Parse(e),
+        }
+    }
+}
+
+// Socket timeouts surface as `WouldBlock` on Unix and `TimedOut` elsewhere.
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_errors_to_statuses() {
+        let cases = [
+            (ServerError::Parse(ParseError::InvalidHeader), 400),
+            (ServerError::Parse(ParseError::UnsupportedVersion), 505),
+            (ServerError::Parse(ParseError::RequestLineTooLong), 414),
+            (ServerError::Parse(ParseError::HeadersTooLarge), 431),
+            (ParseError::BodyTooLarge.into(), 413),
+            (ParseError::HeaderTimeout.into(), 408),
+            (ServerError::NotFound, 404),
+            (ServerError::TooLarge, 413),
+            (ServerError::Timeout, 408),
+            (io::Error::other("disk on fire").into(), 500),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.status().code(), code, "{}", error);
+        }
+    }
+
+    #[test]
+    fn classifies_io_errors() {
+        let timeout = ServerError::from(ParseError::Io(io::ErrorKind::WouldBlock.into()));
+        assert!(matches!(timeout, ServerError::Timeout));
+
+        let reset = ServerError::from(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert!(reset.is_disconnect());
+        assert!(!ServerError::Parse(ParseError::InvalidChunk).is_disconnect());
+    }
+}
@@ -0,0 +1,896 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::access_log::Entry;
+use crate::error::ServerError;
+use crate::pool::ThreadPool;
+use crate::request::{Method, ParseError, Request, RequestReader, Version};
+use crate::response::Response;
+use crate::server::{
+    self, ConnectionConfig, ShutdownHandle, ShutdownReport, LINGER_MAX_BYTES, LINGER_TIMEOUT,
+};
+use crate::status::StatusCode;
+use crate::upgrade::{Upgrade, Upgraded};
+
+// Token of the eventfd workers use to wake the loop. Listeners take the
+// tokens from zero up and connections the ones after them.
+const WAKER: u64 = u64::MAX;
+// How often deadlines are checked.
+const TICK: Duration = Duration::from_millis(100);
+// How often handlers waiting for room in the pool's queue are retried.
+const RETRY: Duration = Duration::from_millis(10);
+const MAX_EVENTS: usize = 256;
+const READ_CHUNK: usize = 16 * 1024;
+// Caps how much one connection reads before others get a turn.
+const MAX_READ_BATCH: usize = 256 * 1024;
+// Responses pass from workers to the loop in chunks of about this size, with
+// at most `CHUNKS_IN_FLIGHT` queued per connection before the worker waits.
+const CHUNK_SIZE: usize = 16 * 1024;
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+const READABLE: u32 = libc::EPOLLIN as u32;
+const WRITABLE: u32 = libc::EPOLLOUT as u32;
+const CLOSED: u32 = (libc::EPOLLHUP | libc::EPOLLERR) as u32;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Serves `listeners` from a single epoll loop until shutdown is requested,
+/// then drains the connections still open.
+///
+/// The loop does all socket I/O itself. Workers from `pool` only run
+/// handlers, writing the response into a bounded channel that the loop
+/// empties onto the socket as it becomes writable.
+pub(crate) fn run(
+    listeners: Vec<TcpListener>,
+    config: &Arc<ConnectionConfig>,
+    pool: ThreadPool,
+    shutdown: &ShutdownHandle,
+    shutdown_timeout: Duration,
+) -> io::Result<ShutdownReport> {
+    let mut event_loop = EventLoop::new(listeners, config, &pool)?;
+    let mut events = Vec::with_capacity(MAX_EVENTS);
+    let mut deadline = None;
+    let mut aborted = 0;
+    loop {
+        if deadline.is_none() && shutdown.is_requested() {
+            deadline = Some(Instant::now() + shutdown_timeout);
+            let in_flight = event_loop.start_draining();
+            if in_flight > 0 {
+                eprintln!("draining {} connection(s)", in_flight);
+            }
+            config.upgrades.start_draining();
+        }
+        if let Some(deadline) = deadline {
+            if event_loop.connections.is_empty() {
+                break;
+            }
+            if Instant::now() >= deadline {
+                aborted = event_loop.abort_all();
+                eprintln!(
+                    "shutdown deadline passed, aborted {} connection(s)",
+                    aborted
+                );
+                break;
+            }
+        }
+
+        let timeout = if event_loop.backlog.is_empty() {
+            TICK
+        } else {
+            RETRY
+        };
+        event_loop.poller.wait(&mut events, timeout)?;
+        for event in &events {
+            // Copied out first: the struct is packed on some targets.
+            let (token, flags) = (event.u64, event.events);
+            event_loop.dispatch(token, flags, shutdown);
+        }
+        event_loop.retry_backlog();
+        event_loop.expire(Instant::now());
+    }
+
+    let completed = event_loop.completed;
+    drop(event_loop);
+    drop(pool);
+    if let Some(deadline) = deadline {
+        server::close_upgraded(&config.upgrades, deadline);
+    }
+    Ok(ShutdownReport { completed, aborted })
+}
+
+struct EventLoop<'a> {
+    poller: Poller,
+    notifier: Arc<Notifier>,
+    // Emptied once shutdown starts.
+    listeners: Vec<TcpListener>,
+    connections: HashMap<u64, Connection>,
+    next_token: u64,
+    config: &'a Arc<ConnectionConfig>,
+    pool: &'a ThreadPool,
+    // Handlers that did not fit in the pool's queue yet, oldest first. Holds
+    // at most as many as the queue does; requests beyond that are refused.
+    backlog: VecDeque<Job>,
+    draining: bool,
+    completed: usize,
+}
+
+struct Connection {
+    stream: TcpStream,
+    peer: Option<SocketAddr>,
+    reader: RequestReader<io::Empty>,
+    // The client has closed its side; what is buffered is all there is.
+    eof: bool,
+    served: usize,
+    phase: Phase,
+    // The events the socket is registered for.
+    interest: u32,
+}
+
+enum Phase {
+    // Waiting for a request to start.
+    Idle {
+        since: Instant,
+    },
+    // Part of a request has arrived.
+    Reading {
+        started: Instant,
+        last_read: Instant,
+    },
+    // A response is being produced and sent.
+    Writing(Box<Writing>),
+    // An error response has gone out; input is discarded until the client
+    // closes or the linger timeout passes.
+    Lingering {
+        until: Instant,
+        discarded: usize,
+    },
+}
+
+struct Writing {
+    // `None` once the worker has finished, or if the loop produced the
+    // response itself.
+    output: Option<Receiver<Output>>,
+    pending: Vec<u8>,
+    written: usize,
+    last_progress: Instant,
+    done: Option<Done>,
+}
+
+// What a worker hands the loop while producing a response.
+enum Output {
+    Data(Vec<u8>),
+    Done(Box<Done>),
+    // The response broke off part way; the connection is dropped.
+    Failed(ServerError),
+}
+
+struct Done {
+    status: StatusCode,
+    sent: u64,
+    then: Then,
+    entry: Option<Entry>,
+    started: Instant,
+}
+
+// What happens to the connection once a response is out.
+enum Then {
+    KeepOpen,
+    Close,
+    Linger,
+    // The connection leaves the loop for the protocol it switched to.
+    Upgrade(Upgrade),
+}
+
+impl<'a> EventLoop<'a> {
+    fn new(
+        listeners: Vec<TcpListener>,
+        config: &'a Arc<ConnectionConfig>,
+        pool: &'a ThreadPool,
+    ) -> io::Result<Self> {
+        let poller = Poller::new()?;
+        let notifier = Arc::new(Notifier::new()?);
+        poller.add(notifier.fd.as_raw_fd(), WAKER, READABLE)?;
+        for (token, listener) in listeners.iter().enumerate() {
+            listener.set_nonblocking(true)?;
+            poller.add(listener.as_raw_fd(), token as u64, READABLE)?;
+        }
+        Ok(EventLoop {
+            poller,
+            notifier,
+            next_token: listeners.len() as u64,
+            listeners,
+            connections: HashMap::new(),
+            config,
+            pool,
+            backlog: VecDeque::new(),
+            draining: false,
+            completed: 0,
+        })
+    }
+
+    fn dispatch(&mut self, token: u64, flags: u32, shutdown: &ShutdownHandle) {
+        if token == WAKER {
+            for token in self.notifier.take() {
+                self.advance(token);
+            }
+        } else if token < self.listeners.len() as u64 {
+            self.accept(token as usize, shutdown);
+        } else if flags & CLOSED != 0 {
+            // Nothing more can be sent or received.
+            if let Some(connection) = self.connections.remove(&token) {
+                self.close(connection);
+            }
+        } else {
+            self.advance(token);
+        }
+    }
+
+    fn accept(&mut self, index: usize, shutdown: &ShutdownHandle) {
+        // Checked before each accept so that the connection made to wake
+        // the loop for shutdown is never taken for a client.
+        while !shutdown.is_requested() {
+            let (stream, peer) = match self.listeners[index].accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("failed to accept connection: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = self.register(stream, peer) {
+                eprintln!("failed to register connection: {}", e);
+            }
+        }
+    }
+
+    fn register(&mut self, stream: TcpStream, peer: SocketAddr) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+        let token = self.next_token;
+        self.next_token += 1;
+        self.poller.add(stream.as_raw_fd(), token, READABLE)?;
+        let connection = Connection {
+            stream,
+            peer: Some(peer),
+            reader: RequestReader::new(io::empty()).with_limits(self.config.limits),
+            eof: false,
+            served: 0,
+            phase: Phase::Idle {
+                since: Instant::now(),
+            },
+            interest: READABLE,
+        };
+        self.connections.insert(token, connection);
+        Ok(())
+    }
+
+    // Moves the connection along as far as it can go without blocking,
+    // then registers for whatever it is waiting on.
+    fn advance(&mut self, token: u64) {
+        let Some(mut connection) = self.connections.remove(&token) else {
+            return;
+        };
+        if !self.drive(token, &mut connection) {
+            self.close(connection);
+            return;
+        }
+        let interest = match &connection.phase {
+            Phase::Writing(writing) if writing.written < writing.pending.len() => WRITABLE,
+            // Nothing to do until the worker has more output.
+            Phase::Writing(_) => 0,
+            _ => READABLE,
+        };
+        if interest != connection.interest {
+            if let Err(e) = self
+                .poller
+                .modify(connection.stream.as_raw_fd(), token, interest)
+            {
+                report(connection.peer, &e.into());
+                self.close(connection);
+                return;
+            }
+            connection.interest = interest;
+        }
+        self.connections.insert(token, connection);
+    }
+
+    // Returns false once the connection should be closed.
+    fn drive(&mut self, token: u64, connection: &mut Connection) -> bool {
+        loop {
+            match &mut connection.phase {
+                Phase::Idle { .. } | Phase::Reading { .. } => {
+                    let config = self.config;
+                    let parsed = connection
+                        .reader
+                        .parse_buffered_leaving_body(|request| config.router.streams_body(request));
+                    match parsed {
+                        Ok(Some((mut request, streamed))) => {
+                            // The worker reads a streamed body itself; the
+                            // loop leaves the socket's input alone until the
+                            // response is out.
+                            if let Some(framing) = streamed {
+                                let buffered = connection.reader.take_buffered();
+                                match server::streamed_body(
+                                    buffered,
+                                    &connection.stream,
+                                    framing,
+                                    config,
+                                ) {
+                                    Ok(body) => request.set_body_reader(body),
+                                    Err(e) => {
+                                        report(connection.peer, &e.into());
+                                        return false;
+                                    }
+                                }
+                            }
+                            self.start(token, connection, request);
+                            continue;
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            self.fail(connection, e.into());
+                            continue;
+                        }
+                    }
+                    match connection.fill() {
+                        Ok(0) if !connection.reader.is_mid_request() => return false,
+                        Ok(0) => self.fail(connection, ParseError::UnexpectedEof.into()),
+                        Ok(_) => {
+                            let now = Instant::now();
+                            match &mut connection.phase {
+                                Phase::Reading { last_read, .. } => *last_read = now,
+                                phase => {
+                                    *phase = Phase::Reading {
+                                        started: now,
+                                        last_read: now,
+                                    }
+                                }
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                        Err(e) => {
+                            report(connection.peer, &e.into());
+                            return false;
+                        }
+                    }
+                }
+                Phase::Writing(writing) => {
+                    while writing.written < writing.pending.len() {
+                        match (&connection.stream).write(&writing.pending[writing.written..]) {
+                            Ok(0) => return false,
+                            Ok(n) => {
+                                writing.written += n;
+                                writing.last_progress = Instant::now();
+                            }
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                            Err(e) => {
+                                report(connection.peer, &e.into());
+                                return false;
+                            }
+                        }
+                    }
+                    if let Some(done) = writing.done.take() {
+                        match self.finish(connection, done) {
+                            Then::KeepOpen => continue,
+                            Then::Close => return false,
+                            Then::Linger => {
+                                if connection.stream.shutdown(Shutdown::Write).is_err() {
+                                    return false;
+                                }
+                                connection.phase = Phase::Lingering {
+                                    until: Instant::now() + LINGER_TIMEOUT,
+                                    discarded: 0,
+                                };
+                                continue;
+                            }
+                            Then::Upgrade(upgrade) => {
+                                let _ = self.poller.delete(connection.stream.as_raw_fd());
+                                let buffered = connection.reader.buffered().to_vec();
+                                match connection
+                                    .stream
+                                    .try_clone()
+                                    .and_then(|stream| Upgraded::new(stream, buffered))
+                                {
+                                    Ok(upgraded) => upgrade.spawn(upgraded),
+                                    Err(e) => report(connection.peer, &e.into()),
+                                }
+                                return false;
+                            }
+                        }
+                    }
+                    let Some(output) = &writing.output else {
+                        return false;
+                    };
+                    match output.try_recv() {
+                        Ok(Output::Data(data)) => {
+                            writing.pending = data;
+                            writing.written = 0;
+                            writing.last_progress = Instant::now();
+                        }
+                        Ok(Output::Done(done)) => {
+                            writing.output = None;
+                            writing.done = Some(*done);
+                        }
+                        Ok(Output::Failed(error)) => {
+                            report(connection.peer, &error);
+                            return false;
+                        }
+                        Err(TryRecvError::Empty) => return true,
+                        // The worker went away without finishing.
+                        Err(TryRecvError::Disconnected) => return false,
+                    }
+                }
+                Phase::Lingering { discarded, .. } => {
+                    let mut buf = [0; 4096];
+                    match (&connection.stream).read(&mut buf) {
+                        Ok(0) => return false,
+                        Ok(n) => {
+                            *discarded += n;
+                            if *discarded >= LINGER_MAX_BYTES {
+                                return false;
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(_) => return false,
+                    }
+                }
+            }
+        }
+    }
+
+    // Hands `request` to a worker.
+    fn start(&mut self, token: u64, connection: &mut Connection, mut request: Request) {
+        connection.served += 1;
+        request.remote_addr = connection.peer.map(|peer| peer.ip());
+        // Every worker is busy and as many handlers again are waiting for
+        // one: turn the request away rather than queue without bound.
+        if self.backlog.len() >= self.pool.capacity() {
+            let entry = self
+                .config
+                .access_log
+                .as_ref()
+                .map(|_| Entry::new(request.remote_addr, SystemTime::now(), &request));
+            let response =
+                Response::error(StatusCode::ServiceUnavailable).with_header("Retry-After", "1");
+            self.reply(connection, response, request.method, request.version, entry);
+            return;
+        }
+        let streamed = request.has_streamed_body();
+        let keep_alive = server::wants_keep_alive(&request)
+            && connection.served < self.config.max_requests
+            && !self.draining
+            && !streamed;
+        let entry = self
+            .config
+            .access_log
+            .as_ref()
+            .map(|_| Entry::new(request.remote_addr, SystemTime::now(), &request));
+        let started = Instant::now();
+        let (sender, output) = mpsc::sync_channel(CHUNKS_IN_FLIGHT);
+        let mut writer = ChannelWriter {
+            sender,
+            buf: Vec::new(),
+            token,
+            notifier: Arc::clone(&self.notifier),
+        };
+        let config = Arc::clone(self.config);
+        self.submit(Box::new(move || {
+            let method = request.method;
+            let version = request.version;
+            let (mut response, keep_alive) = server::respond(&config, request, keep_alive);
+            let status = response.status;
+            let upgrade = response.upgrade.take();
+            let written = if response.detached {
+                Ok(0)
+            } else {
+                response.write_to(&mut writer, method, version)
+            };
+            let output = match written {
+                Ok(sent) => Output::Done(Box::new(Done {
+                    status,
+                    sent,
+                    then: match upgrade {
+                        Some(upgrade) => Then::Upgrade(upgrade),
+                        // The handler may have left part of the body unread.
+                        None if streamed => Then::Linger,
+                        None if keep_alive => Then::KeepOpen,
+                        None => Then::Close,
+                    },
+                    entry,
+                    started,
+                })),
+                Err(e) => Output::Failed(e.into()),
+            };
+            // The loop has dropped the connection if this fails.
+            let _ = writer.send(output);
+        }));
+        connection.phase = Phase::Writing(Box::new(Writing {
+            output: Some(output),
+            pending: Vec::new(),
+            written: 0,
+            last_progress: started,
+            done: None,
+        }));
+    }
+
+    // Answers a request that could not be read with the response for
+    // `error`, after which the connection lingers and closes.
+    fn fail(&mut self, connection: &mut Connection, error: ServerError) {
+        report(connection.peer, &error);
+        let entry = self
+            .config
+            .access_log
+            .as_ref()
+            .map(|_| Entry::unparsed(connection.peer.map(|peer| peer.ip()), SystemTime::now()));
+        self.reply(
+            connection,
+            error.response(),
+            Method::Get,
+            Version::Http11,
+            entry,
+        );
+    }
+
+    // Sends a response the loop produced itself, after which the connection
+    // lingers and closes.
+    fn reply(
+        &mut self,
+        connection: &mut Connection,
+        response: Response,
+        method: Method,
+        version: Version,
+        entry: Option<Entry>,
+    ) {
+        let status = response.status;
+        let mut pending = Vec::new();
+        let sent = response
+            .with_header("Connection", "close")
+            .write_to(&mut pending, method, version)
+            .expect("writing to a Vec cannot fail");
+        let now = Instant::now();
+        connection.phase = Phase::Writing(Box::new(Writing {
+            output: None,
+            pending,
+            written: 0,
+            last_progress: now,
+            done: Some(Done {
+                status,
+                sent,
+                then: Then::Linger,
+                entry,
+                started: now,
+            }),
+        }));
+    }
+
+    // Records a response that has been sent in full and readies the
+    // connection for what comes next.
+    fn finish(&mut self, connection: &mut Connection, done: Done) -> Then {
+        self.completed += 1;
+        if let (Some(log), Some(mut entry)) = (&self.config.access_log, done.entry) {
+            entry.status = done.status;
+            entry.bytes_sent = done.sent;
+            entry.latency = done.started.elapsed();
+            log.log(&entry);
+        }
+        if let Then::KeepOpen = done.then {
+            let now = Instant::now();
+            connection.phase = if connection.reader.buffered().is_empty() {
+                // Once draining, only requests already sent are answered.
+                if self.draining {
+                    return Then::Close;
+                }
+                Phase::Idle { since: now }
+            } else {
+                Phase::Reading {
+                    started: now,
+                    last_read: now,
+                }
+            };
+        }
+        done.then
+    }
+
+    fn submit(&mut self, job: Job) {
+        if !self.backlog.is_empty() {
+            self.backlog.push_back(job);
+        } else if let Err(job) = self.pool.try_execute(job) {
+            self.backlog.push_back(job);
+        }
+    }
+
+    fn retry_backlog(&mut self) {
+        while let Some(job) = self.backlog.pop_front() {
+            if let Err(job) = self.pool.try_execute(job) {
+                self.backlog.push_front(job);
+                break;
+            }
+        }
+    }
+
+    // Closes or fails the connections whose time is up.
+    fn expire(&mut self, now: Instant) {
+        let config = self.config;
+        let mut expired = Vec::new();
+        for (&token, connection) in &mut self.connections {
+            let timed_out = match &connection.phase {
+                Phase::Idle { since } => {
+                    // The first request is waited for as long as a read may
+                    // take; later ones only for the keep-alive timeout.
+                    let limit = if connection.served == 0 {
+                        config.read_timeout
+                    } else {
+                        config.keep_alive_timeout
+                    };
+                    (now - *since >= limit).then_some(None)
+                }
+                Phase::Reading { started, last_read } => {
+                    if now - *last_read >= config.read_timeout {
+                        Some(Some(ServerError::Timeout))
+                    } else {
+                        let slow_head = config.limits.header_timeout.is_some_and(|timeout| {
+                            now - *started > timeout && !connection.reader.has_head()
+                        });
+                        slow_head.then(|| Some(ParseError::HeaderTimeout.into()))
+                    }
+                }
+                Phase::Writing(writing) => (writing.written < writing.pending.len()
+                    && now - writing.last_progress >= config.write_timeout)
+                    .then_some(None),
+                Phase::Lingering { until, .. } => (now >= *until).then_some(None),
+            };
+            if let Some(error) = timed_out {
+                expired.push((token, error));
+            }
+        }
+        for (token, error) in expired {
+            let Some(mut connection) = self.connections.remove(&token) else {
+                continue;
+            };
+            match error {
+                // A client stalled mid-request is told why it is dropped.
+                Some(error) => {
+                    self.fail(&mut connection, error);
+                    self.connections.insert(token, connection);
+                    self.advance(token);
+                }
+                None => {
+                    if let Phase::Writing(_) = connection.phase {
+                        report(connection.peer, &ServerError::Timeout);
+                    }
+                    self.close(connection);
+                }
+            }
+        }
+    }
+
+    // Stops accepting and closes connections idle between requests.
+    // Returns how many connections are still open.
+    fn start_draining(&mut self) -> usize {
+        self.draining = true;
+        self.listeners.clear();
+        let idle: Vec<u64> = self
+            .connections
+            .iter()
+            .filter(|(_, c)| matches!(c.phase, Phase::Idle { .. }) && c.served > 0)
+            .map(|(&token, _)| token)
+            .collect();
+        for token in idle {
+            if let Some(connection) = self.connections.remove(&token) {
+                self.close(connection);
+            }
+        }
+        self.connections.len()
+    }
+
+    fn abort_all(&mut self) -> usize {
+        self.backlog.clear();
+        let aborted = self.connections.len();
+        for (_, connection) in mem::take(&mut self.connections) {
+            let _ = connection.stream.shutdown(Shutdown::Both);
+            self.close(connection);
+        }
+        aborted
+    }
+
+    // Dropping the connection also drops the receiving end of any worker
+    // still writing to it, so the worker stops too.
+    fn close(&mut self, connection: Connection) {
+        let _ = self.poller.delete(connection.stream.as_raw_fd());
+    }
+}
+
+impl Connection {
+    // Reads what the socket has for us, up to a limit. Returns how many bytes
+    // arrived, with zero meaning the client has finished sending.
+    fn fill(&mut self) -> io::Result<usize> {
+        if self.eof {
+            return Ok(0);
+        }
+        let mut chunk = [0; READ_CHUNK];
+        let mut total = 0;
+        while total < MAX_READ_BATCH {
+            match (&self.stream).read(&mut chunk) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                }
+                Ok(n) => {
+                    self.reader.feed(&chunk[..n]);
+                    total += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && total > 0 => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(total)
+    }
+}
+
+fn report(peer: Option<SocketAddr>, error: &ServerError) {
+    // Clients hanging up mid-response is routine.
+    if error.is_disconnect() {
+        return;
+    }
+    match peer {
+        Some(peer) => eprintln!("connection from {}: {}", peer, error),
+        None => eprintln!("connection error: {}", error),
+    }
+}
+
+// Passes a worker's output on to the loop.
+struct ChannelWriter {
+    sender: SyncSender<Output>,
+    buf: Vec<u8>,
+    token: u64,
+    notifier: Arc<Notifier>,
+}
+
+impl ChannelWriter {
+    // Blocks while the connection already has `CHUNKS_IN_FLIGHT` chunks
+    // queued, which holds a fast handler back to the pace of its client.
+    fn send(&self, output: Output) -> io::Result<()> {
+        self.sender
+            .send(output)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        self.notifier.notify(self.token);
+        Ok(())
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            let data = mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+            self.send(Output::Data(data))?;
+        }
+        Ok(())
+    }
+}
+
+// Tells the loop which connections have output from their workers.
+struct Notifier {
+    fd: OwnedFd,
+    ready: Mutex<Vec<u64>>,
+}
+
+impl Notifier {
+    fn new() -> io::Result<Notifier> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Notifier {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            ready: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn notify(&self, token: u64) {
+        self.ready.lock().unwrap().push(token);
+        let one: u64 = 1;
+        unsafe {
+            libc::write(
+                self.fd.as_raw_fd(),
+                &one as *const u64 as *const libc::c_void,
+                mem::size_of::<u64>(),
+            );
+        }
+    }
+
+    // Resets the eventfd before taking the tokens, so a notification that
+    // races with this wakes the loop again rather than being lost.
+    fn take(&self) -> Vec<u64> {
+        let mut count: u64 = 0;
+        unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                &mut count as *mut u64 as *mut libc::c_void,
+                mem::size_of::<u64>(),
+            );
+        }
+        let mut tokens = mem::take(&mut *self.ready.lock().unwrap());
+        tokens.sort_unstable();
+        tokens.dedup();
+        tokens
+    }
+}
+
+// A level-triggered epoll instance.
+struct Poller {
+    fd: OwnedFd,
+}
+
+impl Poller {
+    fn new() -> io::Result<Poller> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Poller {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    fn add(&self, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_ADD, fd, token, events)
+    }
+
+    fn modify(&self, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_MOD, fd, token, events)
+    }
+
+    fn delete(&self, fd: RawFd) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    fn control(&self, op: libc::c_int, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        let mut event = libc::epoll_event { events, u64: token };
+        if unsafe { libc::epoll_ctl(self.fd.as_raw_fd(), op, fd, &mut event) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Replaces `events` with those that are ready, waiting up to `timeout`
+    // for there to be any.
+    fn wait(&self, events: &mut Vec<libc::epoll_event>, timeout: Duration) -> io::Result<()> {
+        events.clear();
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        let n = unsafe {
+            libc::epoll_wait(
+                self.fd.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.capacity() as libc::c_int,
+                timeout,
+            )
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::Interrupted => Ok(()),
+                _ => Err(e),
+            };
+        }
+        // The kernel filled in the first `n` entries.
+        unsafe { events.set_len(n as usize) };
+        Ok(())
+    }
+}
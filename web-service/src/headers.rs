@@ -0,0 +1,173 @@
+/// An ordered HTTP header map with case-insensitive name lookup.
+///
+/// Insertion order and duplicate names are preserved so that headers can be
+/// written back out exactly as they were added.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the first value for `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns every value for `name`, in insertion order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Checks whether any value for `name` lists `token` in its
+    /// comma-separated elements, as `Connection: keep-alive, Upgrade` does.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|element| element.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Sets `name` to `value`, replacing any existing values.
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.remove(&name);
+        self.entries.push((name, value.into()));
+    }
+
+    /// Adds a value for `name`, keeping any existing values.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// Removes every value for `name`, returning the first one.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let mut removed = None;
+        self.entries.retain(|(n, v)| {
+            if n.eq_ignore_ascii_case(name) {
+                if removed.is_none() {
+                    removed = Some(v.clone());
+                }
+                false
+            } else {
+                true
+            }
+        });
+        removed
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Splits a list such as `Accept-Encoding: gzip;q=0.8, br` into its
+/// lowercased items and their q-values, in thousandths. Parameters other
+/// than `q` are ignored, and items with a malformed q-value dropped.
+pub fn quality_list(value: &str) -> Vec<(String, u16)> {
+    value
+        .split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';').map(str::trim);
+            let item = params.next().filter(|c| !c.is_empty())?;
+            let mut q = 1000;
+            for param in params {
+                if let Some((name, value)) = param.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        q = parse_qvalue(value.trim())?;
+                    }
+                }
+            }
+            Some((item.to_ascii_lowercase(), q))
+        })
+        .collect()
+}
+
+// `0`, `1`, or up to three decimals: `0.5`, `1.000`.
+fn parse_qvalue(text: &str) -> Option<u16> {
+    let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let fraction = format!("{:0<3}", fraction).parse::<u16>().ok()?;
+    match whole {
+        "0" => Some(fraction),
+        "1" if fraction == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_is_case_insensitive() {
+        let mut headers = Headers::new();
+        headers.append("Content-Type", "text/html");
+        assert_eq!(headers.get("content-type"), Some("text/html"));
+        assert_eq!(headers.get("CONTENT-TYPE"), Some("text/html"));
+        assert!(headers.get("content-length").is_none());
+    }
+
+    #[test]
+    fn insert_replaces_and_append_keeps() {
+        let mut headers = Headers::new();
+        headers.append("Accept", "text/html");
+        headers.append("accept", "application/json");
+        assert_eq!(
+            headers.get_all("ACCEPT").collect::<Vec<_>>(),
+            ["text/html", "application/json"]
+        );
+
+        headers.insert("Accept", "*/*");
+        assert_eq!(headers.get_all("accept").collect::<Vec<_>>(), ["*/*"]);
+        assert_eq!(headers.remove("accept"), Some("*/*".to_string()));
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn finds_tokens_in_lists() {
+        let mut headers = Headers::new();
+        headers.append("Connection", "Keep-Alive, Upgrade");
+        assert!(headers.has_token("connection", "keep-alive"));
+        assert!(headers.has_token("connection", "upgrade"));
+        assert!(!headers.has_token("connection", "close"));
+        assert!(!headers.has_token("upgrade", "upgrade"));
+    }
+
+    #[test]
+    fn parses_q_values() {
+        assert_eq!(parse_qvalue("1"), Some(1000));
+        assert_eq!(parse_qvalue("1.000"), Some(1000));
+        assert_eq!(parse_qvalue("0.25"), Some(250));
+        assert_eq!(parse_qvalue("0"), Some(0));
+        for bad in ["1.5", "0.1234", "-0", ".5", "", "0.x"] {
+            assert_eq!(parse_qvalue(bad), None, "{:?}", bad);
+        }
+        assert_eq!(
+            quality_list("text/html;level=1, */*;Q=0.1, bad;q=2"),
+            [("text/html".to_string(), 1000), ("*/*".to_string(), 100)]
+        );
+    }
+}
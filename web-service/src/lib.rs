@@ -0,0 +1,29 @@
+pub mod access_log;
+pub mod auth;
+pub mod autoindex;
+pub mod chunked;
+pub mod client;
+pub mod compression;
+pub mod conditional;
+pub mod config;
+pub mod date;
+pub mod error;
+mod event_loop;
+pub mod headers;
+pub mod middleware;
+pub mod pool;
+pub mod proxy;
+pub mod range;
+pub mod rate_limit;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod server;
+pub mod signal;
+pub mod sse;
+pub mod static_files;
+pub mod status;
+pub mod template;
+pub mod upgrade;
+pub mod url;
+pub mod websocket;
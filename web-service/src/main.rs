@@ -1,46 +1,257 @@
-use std::io::prelude::*;
-use std::net::TcpListener;
-use std::net::TcpStream;
+use std::env;
+use std::process;
+use std::thread;
+
+use web_service::access_log::AccessLog;
+use web_service::auth::{Auth, Htpasswd};
+use web_service::config::{self, Command, Config, LogTarget};
+use web_service::proxy::Proxy;
+use web_service::rate_limit::RateLimiter;
+use web_service::request::Request;
+use web_service::response::{Response, SERVER_NAME};
+use web_service::router::Router;
+use web_service::server::Server;
+use web_service::signal::{self, SIGHUP, SIGINT, SIGTERM};
+use web_service::sse::{Event, EventChannel};
+use web_service::static_files::StaticFiles;
+use web_service::status::StatusCode;
+use web_service::template::{Context, Templates};
+use web_service::url;
+use web_service::websocket::{self, Broadcast, Message, WebSocket};
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+    let config = match Config::from_args(env::args().skip(1)) {
+        Ok(Command::Run(config)) => *config,
+        Ok(Command::Help) => {
+            println!("{}", config::USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("web-service: {}", e);
+            eprintln!("Try 'web-service --help' for more information.");
+            process::exit(2);
+        }
+    };
+    let files = StaticFiles::new(&config.root)
+        .unwrap_or_else(|e| {
+            eprintln!("invalid document root {}: {}", config.root.display(), e);
+            process::exit(1);
+        })
+        .cache_rules(config.cache_control.clone())
+        .precompressed(config.precompressed)
+        .autoindex(config.autoindex.clone());
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+    let access_log = match &config.access_log {
+        LogTarget::Stdout => Some(AccessLog::stdout(config.log_format)),
+        LogTarget::File(path) => Some(AccessLog::file(path, config.log_format).unwrap_or_else(
+            |e| {
+                eprintln!("cannot open access log {}: {}", path.display(), e);
+                process::exit(1);
+            },
+        )),
+        LogTarget::Off => None,
+    };
 
-        handle_connection(stream);
-    }
-}
+    let htpasswd = config.htpasswd.as_ref().map(|path| {
+        Htpasswd::load(path).unwrap_or_else(|e| {
+            eprintln!("invalid htpasswd file {}: {}", path.display(), e);
+            process::exit(1);
+        })
+    });
 
-fn handle_connection(mut stream: TcpStream) {
-   let mut buffer = [0: 1024];
-   stream.read(&mut buffer).unwrap();
+    let templates = Templates::new(&config.templates)
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "invalid template directory {}: {}",
+                config.templates.display(),
+                e
+            );
+            process::exit(1);
+        })
+        .reload(config.dev);
 
-   let get = b"GET / HTTP/1.1\r\n";
+    // Every template is compiled now, so that a syntax error or a missing
+    // include stops startup instead of breaking pages later. With --dev,
+    // edits made after this are picked up as before.
+    let loaded = templates.load_all().and_then(|_| {
+        [&config.home_page, &config.not_found_page]
+            .into_iter()
+            .try_for_each(|name| templates.load(name))
+    });
+    if let Err(e) = loaded {
+        eprintln!("invalid templates in {}: {}", templates.dir().display(), e);
+        process::exit(1);
+    }
 
-    if buffer.starts_with(get){
-        let contents = fs::read_to_string("hello.html").unwrap();
+    let home = templates.clone();
+    let missing = templates.clone();
+    let home_page = config.home_page.clone();
+    let not_found_page = config.not_found_page.clone();
+    let fallback_page = config.not_found_page.clone();
+    let mut router =
+        Router::new().get("/", move |req| page(&home, req, StatusCode::Ok, &home_page));
+    // Proxied prefixes take precedence over files of the same path.
+    for route in &config.proxy {
+        let proxy = Proxy::new(route)
+            .connect_timeout(config.proxy_connect_timeout)
+            .timeout(config.proxy_timeout)
+            .fail_timeout(config.proxy_fail_timeout);
+        router = router.any_streaming(&route.pattern(), move |req| proxy.forward(req));
+    }
+    let room = Broadcast::new();
+    let events = EventChannel::new();
+    let chat_events = events.clone();
+    let subscriptions = events.clone();
+    let router = router
+        .get("/echo", websocket::handler(echo))
+        .get(
+            "/chat",
+            websocket::handler(move |socket| chat(&room, &chat_events, socket)),
+        )
+        .get("/events", move |req| subscriptions.subscribe(req))
+        .get("/*path", move |req| {
+            let response = files.serve_request(req, req.param("path").unwrap_or(""));
+            if response.status == StatusCode::NotFound {
+                page(&templates, req, StatusCode::NotFound, &not_found_page)
+            } else {
+                response
+            }
+        })
+        .not_found(move |req| page(&missing, req, StatusCode::NotFound, &fallback_page));
+    let mut server = Server::bind_all(&config.bind)
+        .unwrap_or_else(|e| {
+            eprintln!("web-service: {}", e);
+            process::exit(1);
+        })
+        .router(router)
+        .backend(config.backend)
+        .workers(config.workers)
+        .queue_capacity(config.queue_capacity)
+        .keep_alive_timeout(config.keep_alive_timeout)
+        .read_timeout(config.read_timeout)
+        .write_timeout(config.write_timeout)
+        .limits(config.limits)
+        .shutdown_timeout(config.shutdown_timeout)
+        .max_requests_per_connection(config.max_requests_per_connection)
+        .max_upgraded(config.max_upgraded)
+        .upgraded_idle_timeout(config.upgraded_idle_timeout);
+    if let Some(log) = &access_log {
+        server = server.access_log(log.clone());
+    }
+    if let Some(compression) = config.compression {
+        server = server.compression(compression);
+    }
+    // Rate limits come first, so that guessing passwords counts against a
+    // client's quota.
+    if !config.rate_limit.is_empty() {
+        let mut limiter = RateLimiter::new(config.rate_limit.clone());
+        if let Some(name) = &config.rate_limit_key_header {
+            limiter = limiter.key_header(name);
+        }
+        server = server.middleware(limiter);
+    }
+    if !config.auth.is_empty() {
+        let mut auth = Auth::new(config.auth.clone())
+            .bearer_tokens(config.bearer_tokens.clone())
+            .realm(&config.auth_realm);
+        if let Some(htpasswd) = &htpasswd {
+            auth = auth.htpasswd(htpasswd.clone());
+        }
+        server = server.middleware(auth);
+    }
+    for addr in server.local_addrs().unwrap_or_default() {
+        eprintln!("listening on http://{}", addr);
+    }
+
+    let signals = signal::subscribe(&[SIGHUP, SIGINT, SIGTERM]).unwrap_or_else(|e| {
+        eprintln!("web-service: cannot install signal handlers: {}", e);
+        process::exit(1);
+    });
+    let shutdown = server.shutdown_handle();
+    thread::spawn(move || {
+        for sig in signals {
+            if sig == SIGHUP {
+                // Lets logrotate move the access log out from under us.
+                if let Some(Err(e)) = access_log.as_ref().map(AccessLog::reopen) {
+                    eprintln!("failed to reopen access log: {}", e);
+                }
+                // Users can be added or removed without a restart.
+                if let Some(htpasswd) = &htpasswd {
+                    if let Err(e) = htpasswd.reload() {
+                        eprintln!(
+                            "failed to reload {}, keeping the old users: {}",
+                            htpasswd.path().display(),
+                            e
+                        );
+                    }
+                }
+            } else if !shutdown.is_requested() {
+                eprintln!("received {}, shutting down", signal::name(sig));
+                shutdown.shutdown();
+                // Event streams never end by themselves.
+                events.close();
+            } else {
+                // A second signal skips the drain.
+                eprintln!("received {} again, exiting immediately", signal::name(sig));
+                process::exit(1);
+            }
+        }
+    });
 
-        let response = format!(
-            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
-            contents.len(),
-            contents
-        );
+    if let Err(e) = server.run() {
+        eprintln!("web-service: {}", e);
+        process::exit(1);
+    }
+}
 
-        stream.write(response.as_bytes().unwrap());
-        stream.flush().unwrap();
-    }else {
-        let status_line = "HTTP/1.1 404 NOT FOUND";
-        let contents = fs::read_to_string("404.html").unwrap();
+// Renders one of the site's own pages with the given status.
+fn page(templates: &Templates, request: &Request, status: StatusCode, name: &str) -> Response {
+    let path = url::percent_decode(request.path()).unwrap_or_else(|| request.path().to_string());
+    let title = match status {
+        StatusCode::Ok => "Hello!",
+        _ => status.reason_phrase(),
+    };
+    let context = Context::new()
+        .with("title", title)
+        .with("path", path)
+        .with("server", SERVER_NAME);
+    match templates.render(name, &context) {
+        Ok(html) => Response::html(status, html),
+        Err(e) => {
+            eprintln!("failed to render {}: {}", name, e);
+            match status {
+                StatusCode::Ok => Response::error(StatusCode::InternalServerError),
+                status => Response::error(status),
+            }
+        }
+    }
+}
 
-        let response = format!(
-            "{}\r\nContent-Length: {}\r\n\r\n{}",
-            status_line,
-            contents.len(),
-            contents
-        );
+// Sends every message straight back.
+fn echo(mut socket: WebSocket) {
+    while let Ok(Some(message)) = socket.recv() {
+        if socket.send(message).is_err() {
+            break;
+        }
+    }
+}
 
-        stream.write(response.as_bytes()).unwrap();
-        stream.flush().unwrap();
+// Relays each text message to everyone in the room; see public/chat.html.
+// Read-only followers can get the same lines as server-sent events from
+// /events.
+fn chat(room: &Broadcast, events: &EventChannel, mut socket: WebSocket) {
+    let say = |line: String| {
+        events.send(Event::new(line.clone()));
+        room.send(&Message::Text(line));
+    };
+    let id = room.join(socket.sender());
+    let name = format!("guest-{}", id);
+    say(format!("{} joined", name));
+    while let Ok(Some(message)) = socket.recv() {
+        if let Message::Text(text) = message {
+            say(format!("{}: {}", name, text));
+        }
     }
+    room.leave(id);
+    say(format!("{} left", name));
 }
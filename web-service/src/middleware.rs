@@ -0,0 +1,147 @@
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::Router;
+
+/// Runs around the router for every request.
+///
+/// A middleware gets the request before the router does and decides what
+/// happens next: it can answer by itself without calling `next`, or pass
+/// the request on, changed or not, and change the response that comes
+/// back. Middleware added to a server earlier sits further out, so it sees
+/// the request first and the response last.
+///
+/// Closures of the form `|request: Request, next: Next| -> Response` are
+/// middleware too.
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, request: Request, next: Next<'_>) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(Request, Next<'_>) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        self(request, next)
+    }
+}
+
+/// The rest of the chain: the middleware after the current one, then the
+/// router.
+pub struct Next<'a> {
+    chain: &'a [Box<dyn Middleware>],
+    router: &'a Router,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(chain: &'a [Box<dyn Middleware>], router: &'a Router) -> Next<'a> {
+        Next { chain, router }
+    }
+
+    /// Passes `request` on and returns the response it gets.
+    pub fn run(self, request: Request) -> Response {
+        match self.chain.split_first() {
+            Some((middleware, rest)) => middleware.handle(request, Next::new(rest, self.router)),
+            None => self.router.handle(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    use crate::request::RequestReader;
+    use crate::status::StatusCode;
+
+    fn request(target: &str) -> Request {
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+        RequestReader::new(raw.as_bytes())
+            .read_request()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn run(chain: &[Box<dyn Middleware>], target: &str) -> Response {
+        let router = Router::new().get("/*path", |req| {
+            let greeting = req.header("X-Greeting").unwrap_or("none");
+            Response::text(StatusCode::Ok, greeting.to_string())
+        });
+        Next::new(chain, &router).run(request(target))
+    }
+
+    fn body(response: Response) -> String {
+        String::from_utf8(response.body.into_bytes().unwrap()).unwrap()
+    }
+
+    // Records when it sees the request and the response.
+    struct Trace {
+        name: &'static str,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Middleware for Trace {
+        fn handle(&self, request: Request, next: Next<'_>) -> Response {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("{} in", self.name));
+            let response = next.run(request);
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("{} out", self.name));
+            response
+        }
+    }
+
+    #[test]
+    fn runs_in_the_order_added() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let chain: Vec<Box<dyn Middleware>> = ["outer", "inner"]
+            .into_iter()
+            .map(|name| {
+                Box::new(Trace {
+                    name,
+                    events: events.clone(),
+                }) as Box<dyn Middleware>
+            })
+            .collect();
+        assert_eq!(run(&chain, "/").status, StatusCode::Ok);
+        assert_eq!(
+            *events.lock().unwrap(),
+            ["outer in", "inner in", "inner out", "outer out"]
+        );
+    }
+
+    #[test]
+    fn changes_requests_and_responses() {
+        let chain: Vec<Box<dyn Middleware>> = vec![
+            Box::new(|request: Request, next: Next| {
+                next.run(request).with_header("X-Served-By", "test")
+            }),
+            Box::new(|mut request: Request, next: Next| {
+                request.headers.insert("X-Greeting", "hello");
+                next.run(request)
+            }),
+        ];
+        let response = run(&chain, "/");
+        assert_eq!(response.headers.get("X-Served-By"), Some("test"));
+        assert_eq!(body(response), "hello");
+    }
+
+    #[test]
+    fn answers_without_going_further() {
+        let chain: Vec<Box<dyn Middleware>> = vec![
+            Box::new(|request: Request, next: Next| {
+                if request.path().starts_with("/private") {
+                    return Response::error(StatusCode::Forbidden);
+                }
+                next.run(request)
+            }),
+            Box::new(|_: Request, _: Next| -> Response { panic!("never reached") }),
+        ];
+        assert_eq!(run(&chain, "/private/x").status, StatusCode::Forbidden);
+        assert_eq!(run(&[], "/private/x").status, StatusCode::Ok);
+    }
+}
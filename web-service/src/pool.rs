@@ -0,0 +1,209 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of worker threads fed from a bounded job queue.
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    queue: Arc<Queue>,
+}
+
+impl ThreadPool {
+    /// Creates a pool of `size` workers whose queue holds at most `capacity`
+    /// jobs that have not been picked up yet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` or `capacity` is zero.
+    pub fn new(size: usize, capacity: usize) -> ThreadPool {
+        assert!(size > 0, "thread pool needs at least one worker");
+        assert!(
+            capacity > 0,
+            "thread pool queue needs room for at least one job"
+        );
+
+        let queue = Arc::new(Queue {
+            state: Mutex::new(QueueState {
+                jobs: VecDeque::with_capacity(capacity),
+                closed: false,
+            }),
+            capacity,
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        });
+
+        let workers = (0..size)
+            .map(|id| Worker::new(id, Arc::clone(&queue)))
+            .collect();
+
+        ThreadPool { workers, queue }
+    }
+
+    /// Queues `f`, blocking while the queue is full.
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.queue.state.lock().unwrap();
+        while state.jobs.len() >= self.queue.capacity {
+            state = self.queue.not_full.wait(state).unwrap();
+        }
+        state.jobs.push_back(Box::new(f));
+        self.queue.not_empty.notify_one();
+    }
+
+    /// Queues `f` if there is room, otherwise hands it straight back.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.queue.state.lock().unwrap();
+        if state.jobs.len() >= self.queue.capacity {
+            return Err(f);
+        }
+        state.jobs.push_back(Box::new(f));
+        self.queue.not_empty.notify_one();
+        Ok(())
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// How many jobs may wait in the queue for a worker.
+    pub fn capacity(&self) -> usize {
+        self.queue.capacity
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().closed = true;
+        self.queue.not_empty.notify_all();
+
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                thread.join().unwrap();
+            }
+        }
+    }
+}
+
+struct Queue {
+    state: Mutex<QueueState>,
+    capacity: usize,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+struct QueueState {
+    jobs: VecDeque<Job>,
+    closed: bool,
+}
+
+impl Queue {
+    // Blocks until a job is available; returns `None` once the pool is
+    // closed and every queued job has been handed out.
+    fn next_job(&self) -> Option<Job> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(job) = state.jobs.pop_front() {
+                self.not_full.notify_one();
+                return Some(job);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.not_empty.wait(state).unwrap();
+        }
+    }
+}
+
+struct Worker {
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(id: usize, queue: Arc<Queue>) -> Worker {
+        let thread = thread::Builder::new()
+            .name(format!("worker-{}", id))
+            .spawn(move || {
+                while let Some(job) = queue.next_job() {
+                    // A panicking job must not take the worker down with it.
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        eprintln!("worker {} recovered from a panicking job", id);
+                    }
+                }
+            })
+            .expect("failed to spawn worker thread");
+
+        Worker {
+            thread: Some(thread),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+
+    #[test]
+    fn runs_every_job_before_drop_returns() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(4, 2);
+        for _ in 0..50 {
+            let counter = Arc::clone(&counter);
+            pool.execute(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(pool);
+        assert_eq!(counter.load(Ordering::SeqCst), 50);
+    }
+
+    #[test]
+    fn try_execute_hands_back_job_when_full() {
+        let pool = ThreadPool::new(1, 1);
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let (started_tx, started_rx) = mpsc::channel();
+
+        // Occupy the only worker, then fill the single queue slot.
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        started_rx.recv().unwrap();
+        assert!(pool.try_execute(|| {}).is_ok());
+
+        let ran = Arc::new(AtomicUsize::new(0));
+        let job = {
+            let ran = Arc::clone(&ran);
+            move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        let rejected = pool.try_execute(job).expect_err("queue should be full");
+        rejected();
+        assert_eq!(ran.load(Ordering::SeqCst), 1);
+
+        release_tx.send(()).unwrap();
+    }
+
+    #[test]
+    fn survives_panicking_jobs() {
+        let pool = ThreadPool::new(1, 4);
+        pool.execute(|| panic!("boom"));
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(pool_thread_name()).unwrap());
+        assert_eq!(rx.recv().unwrap().as_deref(), Some("worker-0"));
+    }
+
+    fn pool_thread_name() -> Option<String> {
+        thread::current().name().map(str::to_string)
+    }
+}
@@ -0,0 +1,779 @@
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::chunked::ChunkedWriter;
+use crate::client::{self, ClientError, Framing};
+use crate::error::ServerError;
+use crate::headers::Headers;
+use crate::request::{BodyReader, Method, ParseError, Request};
+use crate::response::{Body, Response};
+use crate::status::StatusCode;
+use crate::url;
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Failures in a row before an upstream is taken out of rotation.
+pub const DEFAULT_MAX_FAILS: u32 = 1;
+/// How long an upstream stays out of rotation once it has failed.
+pub const DEFAULT_FAIL_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Idle connections kept open to each upstream.
+const MAX_IDLE_PER_UPSTREAM: usize = 16;
+
+// Headers that describe a single connection and so are never forwarded,
+// along with any that `Connection` names.
+const HOP_BY_HOP: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+    "Expect",
+];
+
+/// A path prefix forwarded to a set of upstream servers.
+///
+/// With `strip_prefix` the prefix is removed before the request goes
+/// upstream, so `/api/users` under `/api` is sent as `/users`. Either way
+/// the path is sent normalized, as it was routed: `//api/./users` goes
+/// upstream as `/api/users`, or `/users` with the prefix stripped.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ProxyRoute {
+    pub prefix: String,
+    pub upstreams: Vec<String>,
+    #[serde(default)]
+    pub strip_prefix: bool,
+}
+
+impl ProxyRoute {
+    pub fn new(prefix: impl Into<String>, upstreams: Vec<String>) -> ProxyRoute {
+        ProxyRoute {
+            prefix: prefix.into(),
+            upstreams,
+            strip_prefix: false,
+        }
+    }
+
+    /// The router pattern covering the prefix and everything beneath it.
+    pub fn pattern(&self) -> String {
+        format!("{}/*path", self.prefix.trim_end_matches('/'))
+    }
+}
+
+/// Forwards requests to upstream servers, taking them in turn.
+///
+/// Connections to each upstream are kept open and reused. An upstream that
+/// cannot be reached or fails mid-exchange `max_fails` times in a row is
+/// skipped for `fail_timeout`, unless every upstream is down, in which case
+/// they are all tried anyway.
+///
+/// Response bodies are streamed to the client as they arrive. So are
+/// request bodies when the proxy is routed with
+/// [`Router::any_streaming`](crate::router::Router::any_streaming): they go
+/// upstream with the client's `Content-Length`, or chunked if it sent them
+/// chunked, and always on a fresh connection since they can only be sent
+/// once. Bodies the server has already read in full are sent with a
+/// `Content-Length`.
+pub struct Proxy {
+    prefix: String,
+    strip_prefix: bool,
+    upstreams: Vec<Arc<Upstream>>,
+    next: AtomicUsize,
+    connect_timeout: Duration,
+    timeout: Duration,
+    max_fails: u32,
+    fail_timeout: Duration,
+}
+
+struct Upstream {
+    addr: String,
+    idle: Mutex<Vec<BufReader<TcpStream>>>,
+    health: Mutex<Health>,
+}
+
+#[derive(Default)]
+struct Health {
+    fails: u32,
+    down_until: Option<Instant>,
+}
+
+// Where an exchange with an upstream went wrong: before the request was
+// sent, when another upstream can safely be tried, or after. Reading a
+// streamed body from the client can fail too, which is no fault of the
+// upstream.
+enum Failure {
+    Connect(ClientError),
+    Exchange(ClientError),
+    Request(io::Error),
+}
+
+impl Proxy {
+    /// # Panics
+    ///
+    /// Panics if the route has no upstreams.
+    pub fn new(route: &ProxyRoute) -> Proxy {
+        assert!(
+            !route.upstreams.is_empty(),
+            "a proxy needs at least one upstream"
+        );
+        let upstreams = route
+            .upstreams
+            .iter()
+            .map(|addr| {
+                Arc::new(Upstream {
+                    addr: addr.clone(),
+                    idle: Mutex::new(Vec::new()),
+                    health: Mutex::new(Health::default()),
+                })
+            })
+            .collect();
+        Proxy {
+            prefix: route.prefix.trim_end_matches('/').to_string(),
+            strip_prefix: route.strip_prefix,
+            upstreams,
+            next: AtomicUsize::new(0),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            timeout: DEFAULT_TIMEOUT,
+            max_fails: DEFAULT_MAX_FAILS,
+            fail_timeout: DEFAULT_FAIL_TIMEOUT,
+        }
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// How long to wait on an upstream that has stopped reading or sending.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn max_fails(mut self, max_fails: u32) -> Self {
+        self.max_fails = max_fails.max(1);
+        self
+    }
+
+    pub fn fail_timeout(mut self, fail_timeout: Duration) -> Self {
+        self.fail_timeout = fail_timeout;
+        self
+    }
+
+    /// Sends `request` upstream and relays the answer.
+    ///
+    /// Upstreams that cannot be connected to are passed over for the next
+    /// one. Once a request has been sent it is not retried elsewhere: a
+    /// timeout is answered with `504 Gateway Timeout` and any other failure
+    /// with `502 Bad Gateway`.
+    pub fn forward(&self, request: &Request) -> Response {
+        let Some(target) = self.upstream_target(request) else {
+            return Response::error(StatusCode::BadRequest);
+        };
+        let mut body = request.take_body_reader();
+        let mut last_error = None;
+        for upstream in self.candidates() {
+            let message = self.upstream_request(request, &target, body.as_ref(), &upstream.addr);
+            match self.exchange(&upstream, &message, body.as_mut(), request.method) {
+                Ok(response) => {
+                    *upstream.health.lock().unwrap() = Health::default();
+                    return response;
+                }
+                Err(Failure::Connect(e)) => {
+                    self.failed(&upstream, &e);
+                    last_error = Some(e);
+                }
+                Err(Failure::Exchange(e)) => {
+                    self.failed(&upstream, &e);
+                    return gateway_error(&e);
+                }
+                Err(Failure::Request(e)) => {
+                    return ServerError::from(ParseError::from(e)).response();
+                }
+            }
+        }
+        match last_error {
+            Some(e) => gateway_error(&e),
+            None => Response::error(StatusCode::BadGateway),
+        }
+    }
+
+    // The upstreams in the order to try them: round-robin among those in
+    // rotation, followed by any that are down as a last resort.
+    fn candidates(&self) -> Vec<Arc<Upstream>> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let (mut up, down): (Vec<_>, Vec<_>) = (0..self.upstreams.len())
+            .map(|i| Arc::clone(&self.upstreams[(start + i) % self.upstreams.len()]))
+            .partition(|upstream| upstream.is_up(now));
+        up.extend(down);
+        up
+    }
+
+    fn failed(&self, upstream: &Upstream, error: &ClientError) {
+        eprintln!("upstream {}: {}", upstream.addr, error);
+        let mut health = upstream.health.lock().unwrap();
+        health.fails += 1;
+        if health.fails >= self.max_fails {
+            health.fails = 0;
+            health.down_until = Some(Instant::now() + self.fail_timeout);
+            eprintln!(
+                "upstream {} is down for {}s",
+                upstream.addr,
+                self.fail_timeout.as_secs()
+            );
+        }
+    }
+
+    // Tries an idle connection first, falling back to a fresh one if the
+    // upstream had closed it before the request arrived. An upstream can
+    // also close one just after a request arrived, so only requests that
+    // are safe to send twice, and whose body is not streamed, use idle
+    // connections; the rest always get a fresh one and are never resent.
+    fn exchange(
+        &self,
+        upstream: &Arc<Upstream>,
+        message: &[u8],
+        body: Option<&mut BodyReader>,
+        method: Method,
+    ) -> Result<Response, Failure> {
+        let pooled = if method.is_idempotent() && body.is_none() {
+            upstream.idle.lock().unwrap().pop()
+        } else {
+            None
+        };
+        if let Some(connection) = pooled {
+            match send(upstream, connection, message, None, method) {
+                Err(Failure::Exchange(e)) if e.is_stale_connection() => {}
+                result => return result,
+            }
+        }
+        let connection = client::connect(&upstream.addr, self.connect_timeout, self.timeout)
+            .map_err(Failure::Connect)?;
+        send(upstream, connection, message, body, method)
+    }
+
+    // The normalized path, without the prefix if it is stripped, encoded
+    // again and followed by the query. `None` if the path cannot be
+    // normalized.
+    fn upstream_target(&self, request: &Request) -> Option<String> {
+        let path = request.normalized_path()?;
+        let path = match path.strip_prefix(&self.prefix) {
+            Some(rest) if self.strip_prefix && (rest.is_empty() || rest.starts_with('/')) => rest,
+            _ => &path,
+        };
+        let mut target = path
+            .split('/')
+            .map(url::percent_encode_segment)
+            .collect::<Vec<_>>()
+            .join("/");
+        if target.is_empty() {
+            target.push('/');
+        }
+        if let Some(query) = request.query_string() {
+            target.push('?');
+            target.push_str(query);
+        }
+        Some(target)
+    }
+
+    // The request as sent to `authority`: hop-by-hop headers are dropped,
+    // `Host` names the upstream, and the `X-Forwarded-*` headers describe
+    // the original request.
+    fn upstream_request(
+        &self,
+        request: &Request,
+        target: &str,
+        body: Option<&BodyReader>,
+        authority: &str,
+    ) -> Vec<u8> {
+        let mut headers = Headers::new();
+        headers.insert("Host", authority);
+        for (name, value) in request.headers.iter() {
+            let replaced = [
+                "Host",
+                "Content-Length",
+                "X-Forwarded-Proto",
+                "X-Forwarded-Host",
+            ]
+            .iter()
+            .any(|replaced| name.eq_ignore_ascii_case(replaced));
+            if !replaced && !is_hop_by_hop(name, &request.headers) {
+                headers.append(name, value);
+            }
+        }
+        if let Some(ip) = request.remote_addr {
+            let mut forwarded_for: Vec<String> = request
+                .headers
+                .get_all("X-Forwarded-For")
+                .map(str::to_string)
+                .collect();
+            forwarded_for.push(ip.to_string());
+            headers.insert("X-Forwarded-For", forwarded_for.join(", "));
+        }
+        headers.insert("X-Forwarded-Proto", "http");
+        if let Some(host) = request.headers.get("Host") {
+            headers.insert("X-Forwarded-Host", host);
+        }
+        let has_body = !request.body.is_empty()
+            || request.headers.contains("Content-Length")
+            || request.headers.contains("Transfer-Encoding");
+        match body.map(BodyReader::content_length) {
+            Some(Some(len)) => headers.insert("Content-Length", len.to_string()),
+            Some(None) => headers.insert("Transfer-Encoding", "chunked"),
+            None if has_body => headers.insert("Content-Length", request.body.len().to_string()),
+            None => {}
+        }
+
+        let mut message = format!("{} {} HTTP/1.1\r\n", request.method.as_str(), target);
+        for (name, value) in headers.iter() {
+            message.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        message.push_str("\r\n");
+        let mut message = message.into_bytes();
+        message.extend_from_slice(&request.body);
+        message
+    }
+}
+
+impl Upstream {
+    fn is_up(&self, now: Instant) -> bool {
+        let health = self.health.lock().unwrap();
+        health.down_until.is_none_or(|until| now >= until)
+    }
+
+    fn release(&self, connection: BufReader<TcpStream>) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_PER_UPSTREAM {
+            idle.push(connection);
+        }
+    }
+}
+
+// Sends `message`, followed by `body` if it is streamed, and relays the
+// upstream's answer.
+fn send(
+    upstream: &Arc<Upstream>,
+    mut connection: BufReader<TcpStream>,
+    message: &[u8],
+    body: Option<&mut BodyReader>,
+    method: Method,
+) -> Result<Response, Failure> {
+    let upstream_failed = |e: io::Error| Failure::Exchange(e.into());
+    connection
+        .get_mut()
+        .write_all(message)
+        .map_err(upstream_failed)?;
+    if let Some(body) = body {
+        if body.content_length().is_some() {
+            copy_body(body, connection.get_mut())?;
+        } else {
+            let mut chunked = ChunkedWriter::new(connection.get_mut());
+            copy_body(body, &mut chunked)?;
+            chunked.finish().map_err(upstream_failed)?;
+        }
+    }
+    receive(upstream, connection, method).map_err(Failure::Exchange)
+}
+
+// Copies a streamed request body from the client to `out`.
+fn copy_body<W: Write>(body: &mut BodyReader, out: &mut W) -> Result<(), Failure> {
+    let mut buf = [0; 16 * 1024];
+    loop {
+        let n = match body.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Failure::Request(e)),
+        };
+        out.write_all(&buf[..n])
+            .map_err(|e| Failure::Exchange(e.into()))?;
+    }
+}
+
+// Turns the upstream's answer into a response whose body streams from the
+// connection, which goes back to the pool once the body has been read if
+// the upstream allows it.
+fn receive(
+    upstream: &Arc<Upstream>,
+    mut connection: BufReader<TcpStream>,
+    method: Method,
+) -> Result<Response, ClientError> {
+    let (status, version, headers) = client::read_final_head(&mut connection)?;
+    if status == StatusCode::SwitchingProtocols {
+        return Err(ClientError::InvalidResponse("unexpected protocol switch"));
+    }
+    let framing = Framing::of(method, status, &headers)?;
+    let reusable = client::keeps_alive(version, &headers, framing);
+
+    let mut response = Response::new(status);
+    for (name, value) in headers.iter() {
+        if !is_hop_by_hop(name, &headers) {
+            response.headers.append(name, value);
+        }
+    }
+    if let Framing::Chunked | Framing::UntilClose = framing {
+        response.headers.remove("Content-Length");
+    }
+    if framing == Framing::None {
+        if reusable {
+            upstream.release(connection);
+        }
+        return Ok(response);
+    }
+
+    let upstream = Arc::clone(upstream);
+    Ok(response.with_body(Body::stream(move |out| {
+        let mut trailers = Headers::new();
+        match client::copy_body(&mut connection, framing, out, &mut trailers) {
+            Ok(_) => {
+                if reusable {
+                    upstream.release(connection);
+                }
+                Ok(())
+            }
+            Err(ClientError::Io(e)) => Err(e),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    })))
+}
+
+fn is_hop_by_hop(name: &str, headers: &Headers) -> bool {
+    HOP_BY_HOP.iter().any(|hop| name.eq_ignore_ascii_case(hop))
+        || headers
+            .get_all("Connection")
+            .flat_map(|value| value.split(','))
+            .any(|listed| listed.trim().eq_ignore_ascii_case(name))
+}
+
+fn gateway_error(error: &ClientError) -> Response {
+    match error {
+        ClientError::Timeout => Response::error(StatusCode::GatewayTimeout),
+        _ => Response::error(StatusCode::BadGateway),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, Read};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    use crate::request::{Limits, RequestReader};
+
+    // An upstream that answers each request on each connection it accepts
+    // with `reply(request_head)`, until `connections` have been served.
+    fn upstream<F>(connections: usize, reply: F) -> (SocketAddr, thread::JoinHandle<()>)
+    where
+        F: Fn(&str) -> String + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                let stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                loop {
+                    let mut head = String::new();
+                    while reader.read_line(&mut head).unwrap_or(0) > 2 {}
+                    if head.trim().is_empty() {
+                        break;
+                    }
+                    let len = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .map_or(0, |len| len.parse().unwrap());
+                    let mut body = vec![0; len];
+                    if reader.read_exact(&mut body).is_err() {
+                        break;
+                    }
+                    head.push_str(&String::from_utf8(body).unwrap());
+                    // Chunked bodies are kept framed, up to the last chunk.
+                    if head.contains("Transfer-Encoding: chunked\r\n") {
+                        let mut line = String::new();
+                        while !head.ends_with("\r\n0\r\n\r\n")
+                            && reader.read_line(&mut line).unwrap_or(0) > 0
+                        {
+                            head.push_str(&line);
+                            line.clear();
+                        }
+                    }
+                    if (&stream).write_all(reply(&head).as_bytes()).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        (addr, handle)
+    }
+
+    fn request(raw: &str) -> Request {
+        let mut request = RequestReader::new(raw.as_bytes())
+            .read_request()
+            .unwrap()
+            .unwrap();
+        request.remote_addr = Some("192.0.2.7".parse().unwrap());
+        request
+    }
+
+    // A request whose body streams from `body`, as for a streaming route.
+    fn streamed(head: &str, body: &'static [u8]) -> Request {
+        let mut reader = RequestReader::new(head.as_bytes());
+        let (mut request, framing) = reader.read_request_leaving_body(|_| true).unwrap().unwrap();
+        request.set_body_reader(BodyReader::new(body, framing.unwrap(), &Limits::default()));
+        request
+    }
+
+    fn proxy(prefix: &str, upstreams: &[SocketAddr]) -> Proxy {
+        let upstreams = upstreams.iter().map(SocketAddr::to_string).collect();
+        Proxy::new(&ProxyRoute::new(prefix, upstreams))
+    }
+
+    fn body(response: Response) -> String {
+        String::from_utf8(response.body.into_bytes().unwrap()).unwrap()
+    }
+
+    // An echo of the request head, sent back as the response body.
+    fn echo(head: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: x-private\r\n\
+             X-Private: 1\r\n\r\n{}",
+            head.len(),
+            head
+        )
+    }
+
+    #[test]
+    fn rewrites_the_request_for_the_upstream() {
+        let (addr, _upstream) = upstream(1, echo);
+        let mut route = ProxyRoute::new("/api/", vec![addr.to_string()]);
+        route.strip_prefix = true;
+        let proxy = Proxy::new(&route);
+
+        let response = proxy.forward(&request(
+            "POST /api/users?page=2 HTTP/1.1\r\nHost: example.com\r\n\
+             X-Forwarded-For: 203.0.113.1\r\nConnection: keep-alive, x-secret\r\n\
+             X-Secret: 1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+        ));
+        assert_eq!(response.status, StatusCode::Ok);
+        assert!(!response.headers.contains("Connection"));
+        assert!(!response.headers.contains("X-Private"));
+        let head = body(response);
+        assert!(
+            head.starts_with("POST /users?page=2 HTTP/1.1\r\n"),
+            "{}",
+            head
+        );
+        for line in [
+            format!("Host: {}", addr),
+            "X-Forwarded-For: 203.0.113.1, 192.0.2.7".to_string(),
+            "X-Forwarded-Proto: http".to_string(),
+            "X-Forwarded-Host: example.com".to_string(),
+            "Content-Length: 3".to_string(),
+        ] {
+            assert!(
+                head.contains(&format!("{}\r\n", line)),
+                "{} in {}",
+                line,
+                head
+            );
+        }
+        assert!(!head.contains("X-Secret"), "{}", head);
+        assert!(!head.contains("chunked"), "{}", head);
+        assert!(head.ends_with("\r\n\r\nabc"), "{}", head);
+    }
+
+    #[test]
+    fn forwards_the_normalized_path() {
+        let (plain, _plain) = upstream(1, echo);
+        let plain = proxy("/api", &[plain]);
+        let (stripped, _stripped) = upstream(1, echo);
+        let mut route = ProxyRoute::new("/api", vec![stripped.to_string()]);
+        route.strip_prefix = true;
+        let stripped = Proxy::new(&route);
+
+        let sent = |proxy: &Proxy, target: &str| {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            let head = body(proxy.forward(&request(&raw)));
+            head.lines().next().unwrap().to_string()
+        };
+        assert_eq!(
+            sent(&plain, "//%61pi/./x/../a%20b/?q=%2F"),
+            "GET /api/a%20b/?q=%2F HTTP/1.1"
+        );
+        assert_eq!(sent(&stripped, "//api//users"), "GET /users HTTP/1.1");
+        assert_eq!(sent(&stripped, "/api/users/.."), "GET / HTTP/1.1");
+        assert_eq!(sent(&stripped, "/api?x"), "GET /?x HTTP/1.1");
+
+        let outside = stripped.forward(&request("GET /api/../../x HTTP/1.1\r\n\r\n"));
+        assert_eq!(outside.status, StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn streams_request_bodies_on_fresh_connections() {
+        // Closes after each answer, so a request sent on an idle connection
+        // would fail.
+        let (addr, _upstream) = upstream(3, |head| {
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                head.len(),
+                head
+            )
+        });
+        let proxy = proxy("/", &[addr]);
+
+        let sized = body(proxy.forward(&streamed(
+            "PUT /a HTTP/1.1\r\nContent-Length: 5\r\n\r\n",
+            b"hello",
+        )));
+        assert!(sized.contains("Content-Length: 5\r\n"), "{}", sized);
+        assert!(sized.ends_with("\r\n\r\nhello"), "{}", sized);
+
+        let chunked = body(proxy.forward(&streamed(
+            "POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"5\r\nhello\r\n1\r\n!\r\n0\r\n\r\n",
+        )));
+        assert!(
+            chunked.contains("Transfer-Encoding: chunked\r\n"),
+            "{}",
+            chunked
+        );
+        assert!(!chunked.contains("Content-Length"), "{}", chunked);
+        assert!(
+            chunked.ends_with("\r\n\r\n6\r\nhello!\r\n0\r\n\r\n"),
+            "{}",
+            chunked
+        );
+
+        // A client that stops short is answered for, and the upstream is
+        // not blamed.
+        let cut_short = proxy.forward(&streamed(
+            "POST /a HTTP/1.1\r\nContent-Length: 11\r\n\r\n",
+            b"hello",
+        ));
+        assert_eq!(cut_short.status, StatusCode::BadRequest);
+        assert!(proxy.upstreams[0].is_up(Instant::now()));
+    }
+
+    #[test]
+    fn streams_bodies_and_reuses_connections() {
+        let (addr, upstream) = upstream(1, |head| {
+            if head.starts_with("HEAD") {
+                "HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\n".to_string()
+            } else {
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 4\r\nstre\r\n5\r\naming\r\n0\r\n\r\n"
+                    .to_string()
+            }
+        });
+        let proxy = proxy("/", &[addr]);
+        for _ in 0..3 {
+            let response = proxy.forward(&request("GET /x HTTP/1.1\r\n\r\n"));
+            assert!(matches!(response.body, Body::Stream(_)));
+            assert!(!response.headers.contains("Transfer-Encoding"));
+            assert_eq!(body(response), "streaming");
+        }
+        let head = proxy.forward(&request("HEAD /x HTTP/1.1\r\n\r\n"));
+        assert_eq!(head.headers.get("Content-Length"), Some("9"));
+        assert!(head.body.is_empty());
+        drop(proxy);
+        // The upstream only accepts one connection, so every request above
+        // must have shared it.
+        upstream.join().unwrap();
+    }
+
+    #[test]
+    fn never_sends_unsafe_requests_twice() {
+        // Answers GETs and hangs up on anything else.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let posts = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::clone(&posts);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let seen = Arc::clone(&seen);
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(&stream);
+                    loop {
+                        let mut head = String::new();
+                        while reader.read_line(&mut head).unwrap_or(0) > 2 {}
+                        if !head.starts_with("GET") {
+                            if !head.is_empty() {
+                                seen.fetch_add(1, Ordering::SeqCst);
+                            }
+                            break;
+                        }
+                        let reply = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+                        (&stream).write_all(reply.as_bytes()).unwrap();
+                    }
+                });
+            }
+        });
+        let proxy = proxy("/", &[addr]);
+
+        let get = proxy.forward(&request("GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(get.status, StatusCode::Ok);
+        // The POST must not go out on the idle connection the GET left, or
+        // the hang-up would look stale and the POST would be sent again.
+        let post = proxy.forward(&request("POST / HTTP/1.1\r\n\r\n"));
+        assert_eq!(post.status, StatusCode::BadGateway);
+        assert_eq!(posts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn answers_bad_gateway_when_upstreams_are_unreachable() {
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let proxy = proxy("/", &[closed]);
+        let response = proxy.forward(&request("GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(response.status, StatusCode::BadGateway);
+
+        let (addr, _upstream) = upstream(1, |_| "nonsense\r\n\r\n".to_string());
+        let proxy = self::proxy("/", &[addr]);
+        let response = proxy.forward(&request("GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(response.status, StatusCode::BadGateway);
+    }
+
+    #[test]
+    fn answers_gateway_timeout_when_the_upstream_is_slow() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = proxy("/", &[addr]).timeout(Duration::from_millis(100));
+        let response = proxy.forward(&request("GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(response.status, StatusCode::GatewayTimeout);
+        drop(listener);
+    }
+
+    #[test]
+    fn takes_upstreams_in_turn_and_skips_failed_ones() {
+        let named = |name: &'static str| {
+            move |_: &str| format!("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n{}", name)
+        };
+        let (a, _a) = upstream(1, named("a"));
+        let (b, _b) = upstream(1, named("b"));
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let proxy = proxy("/", &[a, closed, b]);
+
+        let served: Vec<String> = (0..6)
+            .map(|_| body(proxy.forward(&request("GET / HTTP/1.1\r\n\r\n"))))
+            .collect();
+        // The closed upstream's turn goes to the next one, after which it
+        // is out of rotation.
+        assert_eq!(served, ["a", "b", "b", "a", "b", "b"]);
+    }
+}
@@ -0,0 +1,271 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::response::Body;
+
+// More ranges than this in one request are ignored and the whole
+// representation is sent instead; they serve no real client and make
+// cheap amplification attacks.
+const MAX_RANGES: usize = 32;
+
+/// An inclusive range of byte offsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start > self.end
+    }
+
+    /// The `Content-Range` value for this range of a `total`-byte
+    /// representation.
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+/// How to answer a request carrying a `Range` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// The header is malformed or uses another unit: ignore it and send
+    /// the whole representation.
+    Ignore,
+    /// Send these ranges, in ascending order and none overlapping.
+    Ranges(Vec<ByteRange>),
+    /// No range overlaps the representation: answer 416.
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header against a representation of `len` bytes.
+///
+/// Accepts `bytes=` followed by a comma-separated list of `first-last`,
+/// `first-` and `-suffix` specs. Ranges that reach past the end are
+/// clipped to it, and ranges that overlap or touch are merged, so the
+/// parts sent never add up to more than the representation.
+pub fn parse_range(header: &str, len: u64) -> RangeRequest {
+    let Some(specs) = header.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Ignore;
+    };
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        count += 1;
+        if count > MAX_RANGES {
+            return RangeRequest::Ignore;
+        }
+        let Some((first, last)) = spec.split_once('-') else {
+            return RangeRequest::Ignore;
+        };
+        let range = match (parse_offset(first), parse_offset(last)) {
+            // -suffix: the final `suffix` bytes.
+            (None, Some(suffix)) if first.is_empty() => {
+                if suffix == 0 || len == 0 {
+                    continue;
+                }
+                ByteRange {
+                    start: len.saturating_sub(suffix),
+                    end: len - 1,
+                }
+            }
+            // first-
+            (Some(start), None) if last.is_empty() => {
+                if start >= len {
+                    continue;
+                }
+                ByteRange {
+                    start,
+                    end: len - 1,
+                }
+            }
+            // first-last
+            (Some(start), Some(end)) if start <= end => {
+                if start >= len {
+                    continue;
+                }
+                ByteRange {
+                    start,
+                    end: end.min(len - 1),
+                }
+            }
+            _ => return RangeRequest::Ignore,
+        };
+        ranges.push(range);
+    }
+    if count == 0 {
+        RangeRequest::Ignore
+    } else if ranges.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Ranges(coalesce(ranges))
+    }
+}
+
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+fn parse_offset(text: &str) -> Option<u64> {
+    if text.is_empty() || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+/// A body holding just `range` of `file`.
+pub fn single_range_body(mut file: File, range: ByteRange) -> io::Result<Body> {
+    file.seek(SeekFrom::Start(range.start))?;
+    Ok(Body::File {
+        file,
+        len: range.len(),
+    })
+}
+
+/// A `multipart/byteranges` body holding each of `ranges` of `file`, and
+/// its exact length so it can be sent with `Content-Length`.
+pub fn multipart_body(
+    file: File,
+    ranges: Vec<ByteRange>,
+    content_type: &str,
+    total: u64,
+    boundary: &str,
+) -> (Body, u64) {
+    let headers: Vec<String> = ranges
+        .iter()
+        .map(|range| {
+            format!(
+                "--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                boundary,
+                content_type,
+                range.content_range(total)
+            )
+        })
+        .collect();
+    let closing = format!("--{}--\r\n", boundary);
+    let len = headers.iter().map(|h| h.len() as u64).sum::<u64>()
+        + ranges.iter().map(|r| r.len() + 2).sum::<u64>()
+        + closing.len() as u64;
+
+    let body = Body::stream(move |out| {
+        let mut file = file;
+        for (range, head) in ranges.iter().zip(&headers) {
+            out.write_all(head.as_bytes())?;
+            file.seek(SeekFrom::Start(range.start))?;
+            let copied = io::copy(&mut (&mut file).take(range.len()), out)?;
+            if copied < range.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "file shrank while it was being sent",
+                ));
+            }
+            out.write_all(b"\r\n")?;
+        }
+        out.write_all(closing.as_bytes())
+    });
+    (body, len)
+}
+
+/// A boundary unlikely to appear in any file served.
+pub fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("web-service-{:016x}{:08x}", nanos, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(pairs: &[(u64, u64)]) -> RangeRequest {
+        RangeRequest::Ranges(
+            pairs
+                .iter()
+                .map(|&(start, end)| ByteRange { start, end })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn parses_range_specs() {
+        assert_eq!(parse_range("bytes=0-499", 1000), ranges(&[(0, 499)]));
+        assert_eq!(parse_range("bytes=500-", 1000), ranges(&[(500, 999)]));
+        assert_eq!(parse_range("bytes=-200", 1000), ranges(&[(800, 999)]));
+        assert_eq!(parse_range("bytes=-2000", 1000), ranges(&[(0, 999)]));
+        assert_eq!(parse_range("bytes=900-5000", 1000), ranges(&[(900, 999)]));
+        assert_eq!(
+            parse_range("bytes=0-0, 10-19 ,-1", 1000),
+            ranges(&[(0, 0), (10, 19), (999, 999)])
+        );
+    }
+
+    #[test]
+    fn merges_overlapping_ranges() {
+        assert_eq!(
+            parse_range("bytes=-1,500-599,0-9,550-,10-19", 1000),
+            ranges(&[(0, 19), (500, 999)])
+        );
+        let overlapping = format!("bytes={}", vec!["0-"; MAX_RANGES].join(","));
+        assert_eq!(parse_range(&overlapping, 1000), ranges(&[(0, 999)]));
+        assert_eq!(
+            parse_range("bytes=0-4,6-9", 1000),
+            ranges(&[(0, 4), (6, 9)])
+        );
+    }
+
+    #[test]
+    fn skips_unsatisfiable_specs() {
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range("bytes=2000-3000,-0", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=-5", 0), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=5000-,0-9", 1000), ranges(&[(0, 9)]));
+    }
+
+    #[test]
+    fn ignores_malformed_headers() {
+        for header in [
+            "items=0-9",
+            "bytes=",
+            "bytes=9-0",
+            "bytes=a-b",
+            "bytes=1",
+            "bytes=--5",
+            "bytes=+1-2",
+        ] {
+            assert_eq!(
+                parse_range(header, 1000),
+                RangeRequest::Ignore,
+                "{}",
+                header
+            );
+        }
+        let many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse_range(&many, 1000), RangeRequest::Ignore);
+    }
+}
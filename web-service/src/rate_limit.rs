@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// How often buckets that have refilled are dropped.
+pub const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The slowest rate a rule may refill at: one request a day.
+pub const MIN_PER_SECOND: f64 = 1.0 / 86_400.0;
+
+/// A token bucket for each client under a path prefix: a client may make
+/// `burst` requests at once, and earns back `per_second` of them every
+/// second.
+///
+/// The prefix matches whole path segments, so `/api` covers `/api` and
+/// `/api/users` but not `/apis`. Paths are matched once normalized, so
+/// `//api` and `/%61pi` count against `/api` as well.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RateLimitRule {
+    pub prefix: String,
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl RateLimitRule {
+    pub fn new(prefix: impl Into<String>, burst: u32, per_second: f64) -> RateLimitRule {
+        RateLimitRule {
+            prefix: prefix.into(),
+            burst,
+            per_second,
+        }
+    }
+
+    /// `path` should be normalized; see [`Request::normalized_path`].
+    pub fn matches(&self, path: &str) -> bool {
+        let prefix = self.prefix.trim_end_matches('/');
+        match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+/// Limits how often each client may make requests, by the first rule whose
+/// prefix matches the path. Paths no rule matches are not limited.
+///
+/// Clients are told apart by address. With `key_header`, a client that
+/// sends the header also has a bucket for its value, and a request needs
+/// a token from both: a key is limited wherever it is used from, and an
+/// address cannot get around its own limit by sending a new key each
+/// time.
+///
+/// A bucket that has refilled is no different from a new one, so those
+/// are dropped every `sweep_interval` and memory only grows with the
+/// number of clients active at once.
+pub struct RateLimiter {
+    limits: Vec<Limit>,
+    key_header: Option<String>,
+    sweep_interval: Duration,
+    next_sweep: Mutex<Instant>,
+}
+
+struct Limit {
+    rule: RateLimitRule,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rule: &RateLimitRule, now: Instant) {
+        let earned = now.saturating_duration_since(self.updated).as_secs_f64() * rule.per_second;
+        self.tokens = (self.tokens + earned).min(f64::from(rule.burst));
+        self.updated = now;
+    }
+
+    fn is_full(&self, rule: &RateLimitRule, now: Instant) -> bool {
+        let earned = now.saturating_duration_since(self.updated).as_secs_f64() * rule.per_second;
+        self.tokens + earned >= f64::from(rule.burst)
+    }
+}
+
+/// Where a client stands after a request under a limited path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Until the bucket is full again.
+    pub reset: Duration,
+    /// Set when the request was refused: until the next one is allowed.
+    pub retry_after: Option<Duration>,
+}
+
+impl Quota {
+    pub fn is_exceeded(&self) -> bool {
+        self.retry_after.is_some()
+    }
+
+    /// The `429 Too Many Requests` response for a refused request.
+    pub fn reject(&self) -> Response {
+        let mut response = Response::error(StatusCode::TooManyRequests);
+        if let Some(retry_after) = self.retry_after {
+            response
+                .headers
+                .insert("Retry-After", whole_seconds(retry_after).to_string());
+        }
+        self.add_headers(&mut response);
+        response
+    }
+
+    /// Adds the `RateLimit-*` headers describing the quota.
+    pub fn add_headers(&self, response: &mut Response) {
+        let headers = &mut response.headers;
+        headers.insert("RateLimit-Limit", self.limit.to_string());
+        headers.insert("RateLimit-Remaining", self.remaining.to_string());
+        headers.insert("RateLimit-Reset", whole_seconds(self.reset).to_string());
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rules: Vec<RateLimitRule>) -> RateLimiter {
+        RateLimiter {
+            limits: rules
+                .into_iter()
+                .map(|rule| Limit {
+                    rule,
+                    buckets: Mutex::new(HashMap::new()),
+                })
+                .collect(),
+            key_header: None,
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            next_sweep: Mutex::new(Instant::now() + DEFAULT_SWEEP_INTERVAL),
+        }
+    }
+
+    /// Also limits clients by this header, such as `X-API-Key`, when they
+    /// send it.
+    pub fn key_header(mut self, name: impl Into<String>) -> Self {
+        self.key_header = Some(name.into());
+        self
+    }
+
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.sweep_interval = interval;
+        *self.next_sweep.get_mut().unwrap() = Instant::now() + interval;
+        self
+    }
+
+    /// Takes a token for `request`, returning `None` if its path is not
+    /// limited.
+    pub fn check(&self, request: &Request) -> Option<Quota> {
+        self.check_at(request, Instant::now())
+    }
+
+    fn check_at(&self, request: &Request, now: Instant) -> Option<Quota> {
+        self.sweep(now);
+        // A path that cannot be normalized never reaches a route, so the raw
+        // one is as good as any for counting it.
+        let path = request
+            .normalized_path()
+            .unwrap_or_else(|| request.path().to_string());
+        let limit = self.limits.iter().find(|limit| limit.rule.matches(&path))?;
+        let rule = &limit.rule;
+        let keys = self.keys(request);
+        let mut buckets = limit.buckets.lock().unwrap();
+        for key in &keys {
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+                tokens: f64::from(rule.burst),
+                updated: now,
+            });
+            bucket.refill(rule, now);
+        }
+        // Every bucket follows the same rule, so the emptiest one decides.
+        let allowed = keys.iter().all(|key| buckets[key].tokens >= 1.0);
+        let mut tokens = f64::from(rule.burst);
+        for key in &keys {
+            let bucket = buckets.get_mut(key).unwrap();
+            if allowed {
+                bucket.tokens -= 1.0;
+            }
+            tokens = tokens.min(bucket.tokens);
+        }
+        let retry_after = if allowed {
+            None
+        } else {
+            Some(seconds(1.0 - tokens, rule))
+        };
+        Some(Quota {
+            limit: rule.burst,
+            remaining: tokens as u32,
+            reset: seconds(f64::from(rule.burst) - tokens, rule),
+            retry_after,
+        })
+    }
+
+    // The buckets a request takes a token from: its address's, and its
+    // key's when it sends one.
+    fn keys(&self, request: &Request) -> Vec<String> {
+        let mut keys = vec![match request.remote_addr {
+            Some(addr) => addr.to_string(),
+            None => "-".to_string(),
+        }];
+        let key = self
+            .key_header
+            .as_deref()
+            .and_then(|name| request.header(name));
+        if let Some(key) = key {
+            // Prefixed so that a key can never pass for an address.
+            keys.push(format!("key {}", key));
+        }
+        keys
+    }
+
+    // Drops every bucket that has filled back up, at most once an interval.
+    fn sweep(&self, now: Instant) {
+        {
+            let mut next_sweep = self.next_sweep.lock().unwrap();
+            if now < *next_sweep {
+                return;
+            }
+            *next_sweep = now + self.sweep_interval;
+        }
+        for limit in &self.limits {
+            let rule = &limit.rule;
+            limit
+                .buckets
+                .lock()
+                .unwrap()
+                .retain(|_, bucket| !bucket.is_full(rule, now));
+        }
+    }
+
+    #[cfg(test)]
+    fn buckets(&self) -> usize {
+        self.limits
+            .iter()
+            .map(|limit| limit.buckets.lock().unwrap().len())
+            .sum()
+    }
+}
+
+// Answers clients over their quota with 429, and tells everyone under a
+// limited path where they stand.
+impl Middleware for RateLimiter {
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        let Some(quota) = self.check(&request) else {
+            return next.run(request);
+        };
+        let mut response = if quota.is_exceeded() {
+            quota.reject()
+        } else {
+            next.run(request)
+        };
+        quota.add_headers(&mut response);
+        response
+    }
+}
+
+// How long `rule` takes to earn back `tokens`. Rules built in code are not
+// held to `MIN_PER_SECOND`, so a rate slow enough to overflow a `Duration`
+// waits for as long as one can.
+fn seconds(tokens: f64, rule: &RateLimitRule) -> Duration {
+    Duration::try_from_secs_f64(tokens / rule.per_second).unwrap_or(Duration::MAX)
+}
+
+// Header values are whole seconds; rounding down would invite a retry that
+// is still refused.
+fn whole_seconds(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_add(u64::from(duration.subsec_nanos() > 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::request::RequestReader;
+
+    fn request(target: &str, from: &str, headers: &str) -> Request {
+        let raw = format!("GET {} HTTP/1.1\r\n{}\r\n", target, headers);
+        let mut request = RequestReader::new(raw.as_bytes())
+            .read_request()
+            .unwrap()
+            .unwrap();
+        request.remote_addr = Some(from.parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn allows_a_burst_then_refills() {
+        let limiter = RateLimiter::new(vec![RateLimitRule::new("/api", 2, 0.5)]);
+        let req = request("/api/users", "10.0.0.1", "");
+        let start = Instant::now();
+
+        let first = limiter.check_at(&req, start).unwrap();
+        assert_eq!((first.limit, first.remaining), (2, 1));
+        assert_eq!(first.reset, Duration::from_secs(2));
+        assert!(!first.is_exceeded());
+        assert!(!limiter.check_at(&req, start).unwrap().is_exceeded());
+
+        let refused = limiter.check_at(&req, start).unwrap();
+        assert_eq!(refused.retry_after, Some(Duration::from_secs(2)));
+        assert_eq!(refused.remaining, 0);
+
+        let later = start + Duration::from_secs(1);
+        let refused = limiter.check_at(&req, later).unwrap();
+        assert_eq!(refused.retry_after, Some(Duration::from_secs(1)));
+        assert!(!limiter
+            .check_at(&req, later + Duration::from_secs(1))
+            .unwrap()
+            .is_exceeded());
+    }
+
+    #[test]
+    fn limits_only_matching_paths() {
+        let limiter = RateLimiter::new(vec![
+            RateLimitRule::new("/api/", 1, 1.0),
+            RateLimitRule::new("/", 100, 100.0),
+        ]);
+        let now = Instant::now();
+        let quota = |target| limiter.check_at(&request(target, "10.0.0.1", ""), now);
+
+        assert_eq!(quota("/api").unwrap().limit, 1);
+        assert!(quota("/api/users").unwrap().is_exceeded());
+        assert_eq!(quota("/apis").unwrap().limit, 100);
+
+        let api_only = RateLimiter::new(vec![RateLimitRule::new("/api", 1, 1.0)]);
+        assert_eq!(api_only.check(&request("/", "10.0.0.1", "")), None);
+    }
+
+    #[test]
+    fn limits_paths_however_they_are_spelled() {
+        let limiter = RateLimiter::new(vec![RateLimitRule::new("/api", 3, 1.0)]);
+        let now = Instant::now();
+        let quota = |target| {
+            limiter
+                .check_at(&request(target, "10.0.0.1", ""), now)
+                .unwrap()
+        };
+
+        assert_eq!(quota("//api/users").remaining, 2);
+        assert_eq!(quota("/%61pi/users").remaining, 1);
+        assert_eq!(quota("/static/../api/./users").remaining, 0);
+        assert!(quota("/api").is_exceeded());
+    }
+
+    #[test]
+    fn keys_clients_by_address_or_header() {
+        let limiter =
+            RateLimiter::new(vec![RateLimitRule::new("/", 1, 1.0)]).key_header("X-API-Key");
+        let now = Instant::now();
+        let exceeded = |from, headers| {
+            limiter
+                .check_at(&request("/", from, headers), now)
+                .unwrap()
+                .is_exceeded()
+        };
+
+        assert!(!exceeded("10.0.0.1", ""));
+        assert!(exceeded("10.0.0.1", ""));
+        assert!(!exceeded("10.0.0.2", ""));
+        // A key is limited wherever it is used from.
+        assert!(!exceeded("10.0.0.3", "X-API-Key: alpha\r\n"));
+        assert!(exceeded("10.0.0.4", "X-API-Key: alpha\r\n"));
+        // A refused request takes nothing, so 10.0.0.4 still has its token.
+        assert!(!exceeded("10.0.0.4", ""));
+        // A new key does not get an address past its own limit.
+        assert!(exceeded("10.0.0.3", "X-API-Key: bravo\r\n"));
+        assert!(exceeded("10.0.0.1", "X-API-Key: charlie\r\n"));
+    }
+
+    #[test]
+    fn sweeps_refilled_buckets() {
+        let limiter = RateLimiter::new(vec![RateLimitRule::new("/", 2, 1.0)])
+            .sweep_interval(Duration::from_secs(10));
+        let start = Instant::now();
+        limiter.check_at(&request("/", "10.0.0.1", ""), start);
+        limiter.check_at(
+            &request("/", "10.0.0.2", ""),
+            start + Duration::from_secs(9),
+        );
+        limiter.check_at(
+            &request("/", "10.0.0.2", ""),
+            start + Duration::from_secs(9),
+        );
+        assert_eq!(limiter.buckets(), 2);
+
+        // Only 10.0.0.1 has refilled by the time of the sweep.
+        limiter.check_at(
+            &request("/", "10.0.0.2", ""),
+            start + Duration::from_secs(10),
+        );
+        assert_eq!(limiter.buckets(), 1);
+        limiter.check_at(
+            &request("/", "10.0.0.3", ""),
+            start + Duration::from_secs(20),
+        );
+        assert_eq!(limiter.buckets(), 1);
+    }
+
+    #[test]
+    fn copes_with_very_slow_rates() {
+        let limiter = RateLimiter::new(vec![RateLimitRule::new("/", 1, 1e-300)]);
+        let req = request("/", "10.0.0.1", "");
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(&req, now).unwrap().reset, Duration::MAX);
+        let refused = limiter.check_at(&req, now).unwrap();
+        assert_eq!(refused.retry_after, Some(Duration::MAX));
+        assert_eq!(
+            refused.reject().headers.get("Retry-After"),
+            Some(u64::MAX.to_string().as_str())
+        );
+    }
+
+    #[test]
+    fn rejects_with_retry_after_and_quota_headers() {
+        let quota = Quota {
+            limit: 10,
+            remaining: 0,
+            reset: Duration::from_millis(9500),
+            retry_after: Some(Duration::from_millis(200)),
+        };
+        let response = quota.reject();
+        assert_eq!(response.status, StatusCode::TooManyRequests);
+        assert_eq!(response.headers.get("Retry-After"), Some("1"));
+        assert_eq!(response.headers.get("RateLimit-Limit"), Some("10"));
+        assert_eq!(response.headers.get("RateLimit-Remaining"), Some("0"));
+        assert_eq!(response.headers.get("RateLimit-Reset"), Some("10"));
+    }
+}
@@ -0,0 +1,1421 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::chunked;
+use crate::headers::Headers;
+use crate::url;
+
+const READ_CHUNK: usize = 4096;
+// Chunk-size lines are short; extensions are allowed but not unbounded.
+const MAX_CHUNK_LINE: usize = 1024;
+
+pub const DEFAULT_MAX_REQUEST_LINE: usize = 8 * 1024;
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 32 * 1024;
+pub const DEFAULT_MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+pub const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bounds on what a client may send, so that one connection cannot tie up
+/// unbounded memory or time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Longest request line accepted, not counting its line ending.
+    pub max_request_line: usize,
+    /// Largest header section accepted, excluding the request line. Also
+    /// bounds the trailers of a chunked body.
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+    /// How long a request head may take to arrive once its first byte has.
+    pub header_timeout: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_request_line: DEFAULT_MAX_REQUEST_LINE,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            header_timeout: Some(DEFAULT_HEADER_TIMEOUT),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+        }
+    }
+
+    /// Whether sending the request twice has the same effect as sending it
+    /// once, so that it can be retried (RFC 9110 §9.2.2).
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            Method::Get
+                | Method::Head
+                | Method::Put
+                | Method::Delete
+                | Method::Options
+                | Method::Trace
+        )
+    }
+}
+
+impl FromStr for Method {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "CONNECT" => Ok(Method::Connect),
+            "OPTIONS" => Ok(Method::Options),
+            "TRACE" => Ok(Method::Trace),
+            "PATCH" => Ok(Method::Patch),
+            _ if !s.is_empty() && s.bytes().all(is_token_byte) => {
+                Err(ParseError::UnknownMethod(s.to_string()))
+            }
+            _ => Err(ParseError::InvalidRequestLine),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "HTTP/1.0" => Ok(Version::Http10),
+            "HTTP/1.1" => Ok(Version::Http11),
+            _ => {
+                let digits = s.strip_prefix("HTTP/").map(|v| v.as_bytes());
+                match digits {
+                    Some([major, b'.', minor])
+                        if major.is_ascii_digit() && minor.is_ascii_digit() =>
+                    {
+                        Err(ParseError::UnsupportedVersion)
+                    }
+                    _ => Err(ParseError::InvalidVersion),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// Trailer fields sent after a chunked body.
+    pub trailers: Headers,
+    /// Path parameters captured by the matching route.
+    pub params: HashMap<String, String>,
+    /// Decoded query-string pairs, filled in by the router.
+    pub query: Vec<(String, String)>,
+    /// The address of the connected client, filled in by the server.
+    pub remote_addr: Option<IpAddr>,
+    // Set, and `body` left empty, for routes that stream the body.
+    streamed: StreamedBody,
+}
+
+impl Request {
+    /// The request target without its query string.
+    pub fn path(&self) -> &str {
+        match self.target.split_once('?') {
+            Some((path, _)) => path,
+            None => &self.target,
+        }
+    }
+
+    /// The path decoded and in canonical form, as routes match it; `None`
+    /// if it cannot be decoded or climbs above the root. See
+    /// [`url::normalize_path`].
+    pub fn normalized_path(&self) -> Option<String> {
+        url::normalize_path(self.path())
+    }
+
+    pub fn query_string(&self) -> Option<&str> {
+        self.target.split_once('?').map(|(_, query)| query)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    /// Returns the first query-string value for `name`.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Takes the body of a request to a route added with
+    /// [`Router::any_streaming`], which is left on the connection rather
+    /// than read into `body`. `None` for other requests, and once taken.
+    ///
+    /// [`Router::any_streaming`]: crate::router::Router::any_streaming
+    pub fn take_body_reader(&self) -> Option<BodyReader> {
+        self.streamed.0.as_ref()?.lock().unwrap().take()
+    }
+
+    pub(crate) fn set_body_reader(&mut self, reader: BodyReader) {
+        self.streamed = StreamedBody(Some(Arc::new(Mutex::new(Some(reader)))));
+    }
+
+    /// Whether the body was left on the connection for the handler.
+    pub fn has_streamed_body(&self) -> bool {
+        self.streamed.0.is_some()
+    }
+}
+
+// A body left on the connection. It is shared so that `Request` stays
+// `Clone`, and whichever copy takes it first reads it.
+#[derive(Clone, Default)]
+struct StreamedBody(Option<Arc<Mutex<Option<BodyReader>>>>);
+
+impl PartialEq for StreamedBody {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        }
+    }
+}
+
+impl Eq for StreamedBody {}
+
+impl fmt::Debug for StreamedBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.is_some() { "Some(..)" } else { "None" })
+    }
+}
+
+/// A request body read straight off the connection as the handler asks
+/// for it, with any chunked framing taken off.
+///
+/// The server's size limit still applies, and trailers after a chunked
+/// body are read and dropped. Failures are `io::Error`s wrapping the
+/// [`ParseError`] that describes them, or the socket's own errors.
+pub struct BodyReader {
+    inner: BufReader<Box<dyn Read + Send>>,
+    content_length: Option<u64>,
+    state: BodyState,
+    // Body bytes still allowed before the size limit is reached.
+    allowance: usize,
+    max_trailer_size: usize,
+}
+
+enum BodyState {
+    // Bytes left of a body of known length, or of the current chunk, whose
+    // CRLF is due once none are left.
+    Length(u64),
+    Chunk(u64),
+    // The next chunk-size line is due.
+    ChunkSize,
+    // Trailer lines are due, with this many bytes of them still allowed.
+    Trailers(usize),
+    Done,
+}
+
+impl BodyReader {
+    pub(crate) fn new(
+        source: impl Read + Send + 'static,
+        framing: Framing,
+        limits: &Limits,
+    ) -> BodyReader {
+        let (content_length, state) = match framing {
+            Framing::None => (Some(0), BodyState::Done),
+            Framing::Length(len) => (Some(len as u64), BodyState::Length(len as u64)),
+            Framing::Chunked => (None, BodyState::ChunkSize),
+        };
+        BodyReader {
+            inner: BufReader::new(Box::new(source)),
+            content_length,
+            state,
+            allowance: limits.max_body_size,
+            max_trailer_size: limits.max_header_size,
+        }
+    }
+
+    /// The length the client declared, or `None` for a chunked body.
+    pub fn content_length(&self) -> Option<u64> {
+        self.content_length
+    }
+
+    // Reads the next chunk-size line.
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let line = self.read_line(MAX_CHUNK_LINE, ParseError::InvalidChunk)?;
+        let size =
+            chunked::parse_chunk_size(&line).ok_or_else(|| body_error(ParseError::InvalidChunk))?;
+        if size > 0 {
+            if size > self.allowance {
+                return Err(body_error(ParseError::BodyTooLarge));
+            }
+            self.allowance -= size;
+            self.state = BodyState::Chunk(size as u64);
+            return Ok(());
+        }
+        self.state = BodyState::Trailers(self.max_trailer_size);
+        Ok(())
+    }
+
+    // Reads the next trailer line, given how many bytes of them are allowed.
+    fn next_trailer(&mut self, remaining: usize) -> io::Result<()> {
+        let line = self.read_line(remaining, ParseError::HeadersTooLarge)?;
+        self.state = if line.is_empty() {
+            BodyState::Done
+        } else {
+            BodyState::Trailers(
+                remaining
+                    .checked_sub(line.len() + 2)
+                    .ok_or_else(|| body_error(ParseError::HeadersTooLarge))?,
+            )
+        };
+        Ok(())
+    }
+
+    // Returns the next line without its line ending, failing with
+    // `too_long` if none ends within `max` bytes.
+    fn read_line(&mut self, max: usize, too_long: ParseError) -> io::Result<Vec<u8>> {
+        let mut line = Vec::new();
+        (&mut self.inner)
+            .take(max as u64 + 2)
+            .read_until(b'\n', &mut line)?;
+        if line.pop() != Some(b'\n') {
+            return Err(body_error(if line.len() > max {
+                too_long
+            } else {
+                ParseError::UnexpectedEof
+            }));
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Ok(line)
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let left = match self.state {
+                BodyState::Done | BodyState::Length(0) => return Ok(0),
+                BodyState::ChunkSize => {
+                    self.next_chunk()?;
+                    continue;
+                }
+                BodyState::Trailers(remaining) => {
+                    self.next_trailer(remaining)?;
+                    continue;
+                }
+                BodyState::Length(left) | BodyState::Chunk(left) => left,
+            };
+            let max = left.min(buf.len() as u64) as usize;
+            let n = self.inner.read(&mut buf[..max])?;
+            if n == 0 {
+                return Err(body_error(ParseError::UnexpectedEof));
+            }
+            match &mut self.state {
+                BodyState::Length(left) => *left -= n as u64,
+                BodyState::Chunk(left) => {
+                    *left -= n as u64;
+                    if *left == 0 {
+                        if !self.read_line(0, ParseError::InvalidChunk)?.is_empty() {
+                            return Err(body_error(ParseError::InvalidChunk));
+                        }
+                        self.state = BodyState::ChunkSize;
+                    }
+                }
+                _ => unreachable!(),
+            }
+            return Ok(n);
+        }
+    }
+}
+
+impl fmt::Debug for BodyReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyReader")
+            .field("content_length", &self.content_length)
+            .finish_non_exhaustive()
+    }
+}
+
+fn body_error(e: ParseError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Io(io::Error),
+    /// The peer closed the connection part way through a request.
+    UnexpectedEof,
+    InvalidRequestLine,
+    UnknownMethod(String),
+    InvalidTarget,
+    InvalidVersion,
+    UnsupportedVersion,
+    InvalidHeader,
+    InvalidContentLength,
+    UnsupportedTransferEncoding,
+    /// Both `Content-Length` and `Transfer-Encoding` were sent.
+    AmbiguousLength,
+    InvalidChunk,
+    RequestLineTooLong,
+    HeadersTooLarge,
+    BodyTooLarge,
+    /// The head did not arrive within [`Limits::header_timeout`].
+    HeaderTimeout,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io(e) => write!(f, "i/o error while reading request: {}", e),
+            ParseError::UnexpectedEof => f.write_str("connection closed mid-request"),
+            ParseError::InvalidRequestLine => f.write_str("malformed request line"),
+            ParseError::UnknownMethod(m) => write!(f, "unknown method {:?}", m),
+            ParseError::InvalidTarget => f.write_str("malformed request target"),
+            ParseError::InvalidVersion => f.write_str("malformed http version"),
+            ParseError::UnsupportedVersion => f.write_str("unsupported http version"),
+            ParseError::InvalidHeader => f.write_str("malformed header line"),
+            ParseError::InvalidContentLength => f.write_str("invalid content-length"),
+            ParseError::UnsupportedTransferEncoding => f.write_str("unsupported transfer-encoding"),
+            ParseError::AmbiguousLength => {
+                f.write_str("both content-length and transfer-encoding present")
+            }
+            ParseError::InvalidChunk => f.write_str("malformed chunked body"),
+            ParseError::RequestLineTooLong => f.write_str("request line too long"),
+            ParseError::HeadersTooLarge => f.write_str("header section too large"),
+            ParseError::BodyTooLarge => f.write_str("body too large"),
+            ParseError::HeaderTimeout => f.write_str("request head took too long to arrive"),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> Self {
+        // A `BodyReader` failure carries the parse error underneath.
+        if e.get_ref().is_some_and(|inner| inner.is::<ParseError>()) {
+            return *e.into_inner().unwrap().downcast::<ParseError>().unwrap();
+        }
+        ParseError::Io(e)
+    }
+}
+
+/// Reads HTTP/1.x requests off a byte stream.
+///
+/// Bytes are buffered across calls, so a request may arrive split over any
+/// number of reads, and bytes past the end of one request are kept for the next.
+pub struct RequestReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    // How far `buf` has already been searched for the end of the head.
+    scanned: usize,
+    limits: Limits,
+    // A request whose chunked body is being decoded from `buf` as it
+    // arrives, so that each byte of it is only looked at once.
+    partial: Option<(Request, BodyState)>,
+}
+
+impl<R> RequestReader<R> {
+    /// Appends bytes received by other means, for callers that do their own
+    /// (typically non-blocking) reads and parse with [`parse_buffered`].
+    ///
+    /// [`parse_buffered`]: RequestReader::parse_buffered
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Parses the next request out of the buffered bytes without reading
+    /// from the stream.
+    ///
+    /// Returns `Ok(None)` while the request is incomplete, to be retried once
+    /// more bytes have been fed. A chunked body is decoded as it arrives, so
+    /// the bytes of it seen so far leave the buffer. Limits are enforced as
+    /// the bytes arrive, but the header timeout is left to the caller.
+    pub fn parse_buffered(&mut self) -> Result<Option<Request>, ParseError> {
+        Ok(self
+            .parse_buffered_leaving_body(|_| false)?
+            .map(|(request, _)| request))
+    }
+
+    /// Like [`parse_buffered`], except that once the head is in, a request
+    /// for which `leave_body` is true is returned without its body, which
+    /// stays buffered. Its framing is returned with it.
+    ///
+    /// [`parse_buffered`]: RequestReader::parse_buffered
+    pub(crate) fn parse_buffered_leaving_body(
+        &mut self,
+        leave_body: impl FnOnce(&Request) -> bool,
+    ) -> Result<Option<(Request, Option<Framing>)>, ParseError> {
+        if let Some((request, state)) = self.partial.take() {
+            return self.continue_chunked(request, state);
+        }
+        self.skip_leading_newlines();
+        let end = self.find_head_end();
+        self.check_head_size(end)?;
+        let Some(head_len) = end else {
+            return Ok(None);
+        };
+        let (method, target, version, headers) = parse_head(&self.buf[..head_len])?;
+        let framing = body_framing(&headers, &self.limits)?;
+        let mut request = Request {
+            method,
+            target,
+            version,
+            headers,
+            body: Vec::new(),
+            trailers: Headers::new(),
+            params: HashMap::new(),
+            query: Vec::new(),
+            remote_addr: None,
+            streamed: StreamedBody::default(),
+        };
+        if framing != Framing::None && leave_body(&request) {
+            self.buf.drain(..head_len);
+            self.scanned = 0;
+            return Ok(Some((request, Some(framing))));
+        }
+        let len = match framing {
+            Framing::None => 0,
+            Framing::Length(len) => len,
+            Framing::Chunked => {
+                self.buf.drain(..head_len);
+                self.scanned = 0;
+                return self.continue_chunked(request, BodyState::ChunkSize);
+            }
+        };
+        if self.buf.len() - head_len < len {
+            return Ok(None);
+        }
+        request.body = self.buf[head_len..head_len + len].to_vec();
+        self.buf.drain(..head_len + len);
+        self.scanned = 0;
+        Ok(Some((request, None)))
+    }
+
+    // Decodes what has arrived of the chunked body of `request`, keeping it
+    // for the next call until the body is complete.
+    fn continue_chunked(
+        &mut self,
+        mut request: Request,
+        mut state: BodyState,
+    ) -> Result<Option<(Request, Option<Framing>)>, ParseError> {
+        let used = decode_chunked(&self.buf, &mut state, &mut request, &self.limits)?;
+        self.buf.drain(..used);
+        if !matches!(state, BodyState::Done) {
+            self.partial = Some((request, state));
+            return Ok(None);
+        }
+        Ok(Some((request, None)))
+    }
+
+    /// Whether the head of the next request has been buffered in full, so
+    /// that only its body, if any, is still to come.
+    pub fn has_head(&mut self) -> bool {
+        if self.partial.is_some() {
+            return true;
+        }
+        self.skip_leading_newlines();
+        self.find_head_end().is_some()
+    }
+
+    /// Whether any of a request not yet returned has been received, buffered
+    /// or already decoded.
+    pub fn is_mid_request(&self) -> bool {
+        !self.buf.is_empty() || self.partial.is_some()
+    }
+
+    // Clients may send stray CRLFs between pipelined requests.
+    fn skip_leading_newlines(&mut self) {
+        let skip = self
+            .buf
+            .iter()
+            .take_while(|&&b| b == b'\r' || b == b'\n')
+            .count();
+        if skip > 0 {
+            self.buf.drain(..skip);
+            self.scanned = 0;
+        }
+    }
+
+    // Returns the length of the head including its terminating blank line.
+    fn find_head_end(&mut self) -> Option<usize> {
+        let start = self.scanned.saturating_sub(3);
+        let found = self.buf[start..]
+            .iter()
+            .enumerate()
+            .filter(|(_, &b)| b == b'\n')
+            .find_map(|(i, _)| {
+                let end = start + i + 1;
+                match &self.buf[end..] {
+                    [b'\n', ..] => Some(end + 1),
+                    [b'\r', b'\n', ..] => Some(end + 2),
+                    _ => None,
+                }
+            });
+        if found.is_none() {
+            self.scanned = self.buf.len();
+        }
+        found
+    }
+
+    // Checks the request line and header section against the limits, given
+    // the length of the head if it has been fully buffered.
+    fn check_head_size(&self, head_len: Option<usize>) -> Result<(), ParseError> {
+        let max_line = self.limits.max_request_line;
+        let line_len = match self.buf.iter().take(max_line + 2).position(|&b| b == b'\n') {
+            Some(pos) => pos,
+            None if self.buf.len() > max_line + 1 => return Err(ParseError::RequestLineTooLong),
+            None => return Ok(()),
+        };
+        let line = &self.buf[..line_len];
+        if line.strip_suffix(b"\r").unwrap_or(line).len() > max_line {
+            return Err(ParseError::RequestLineTooLong);
+        }
+        let headers_len = head_len.unwrap_or(self.buf.len()) - (line_len + 1);
+        // Allow for the blank line that ends the head.
+        if headers_len > self.limits.max_header_size + 2 {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> RequestReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            scanned: 0,
+            limits: Limits::default(),
+            partial: None,
+        }
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Bytes read off the stream that are not part of any request returned
+    /// so far, such as the start of a pipelined request.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Takes the bytes [`buffered`](RequestReader::buffered) returns,
+    /// leaving nothing buffered.
+    pub(crate) fn take_buffered(&mut self) -> Vec<u8> {
+        self.scanned = 0;
+        std::mem::take(&mut self.buf)
+    }
+
+    /// Reads the next request.
+    ///
+    /// Returns `Ok(None)` if the stream ends cleanly before a request starts.
+    ///
+    /// The header timeout is checked between reads, so a head that stalls
+    /// completely is only noticed once the underlying read times out.
+    pub fn read_request(&mut self) -> Result<Option<Request>, ParseError> {
+        Ok(self
+            .read_request_leaving_body(|_| false)?
+            .map(|(request, _)| request))
+    }
+
+    /// Like [`read_request`], except that a request for which `leave_body`
+    /// is true is returned as soon as its head is in, with its body left
+    /// unread and its framing returned with it.
+    ///
+    /// [`read_request`]: RequestReader::read_request
+    pub(crate) fn read_request_leaving_body(
+        &mut self,
+        leave_body: impl FnOnce(&Request) -> bool,
+    ) -> Result<Option<(Request, Option<Framing>)>, ParseError> {
+        let mut started = None;
+        let head_len = loop {
+            self.skip_leading_newlines();
+            if !self.buf.is_empty() && started.is_none() {
+                started = Some(Instant::now());
+            }
+            let end = self.find_head_end();
+            self.check_head_size(end)?;
+            if let Some(len) = end {
+                break len;
+            }
+            if let (Some(started), Some(timeout)) = (started, self.limits.header_timeout) {
+                if started.elapsed() > timeout {
+                    return Err(ParseError::HeaderTimeout);
+                }
+            }
+            if self.fill()? == 0 {
+                return if self.buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(ParseError::UnexpectedEof)
+                };
+            }
+        };
+
+        let head: Vec<u8> = self.buf.drain(..head_len).collect();
+        self.scanned = 0;
+        let (method, target, version, headers) = parse_head(&head)?;
+        let framing = body_framing(&headers, &self.limits)?;
+        let mut request = Request {
+            method,
+            target,
+            version,
+            headers,
+            body: Vec::new(),
+            trailers: Headers::new(),
+            params: HashMap::new(),
+            query: Vec::new(),
+            remote_addr: None,
+            streamed: StreamedBody::default(),
+        };
+        if framing != Framing::None && leave_body(&request) {
+            return Ok(Some((request, Some(framing))));
+        }
+
+        request.body = match framing {
+            Framing::Chunked => self.read_chunked_body(&mut request.trailers)?,
+            Framing::Length(len) => self.read_exact_body(len)?,
+            Framing::None => Vec::new(),
+        };
+        Ok(Some((request, None)))
+    }
+
+    fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0; READ_CHUNK];
+        loop {
+            match self.inner.read(&mut chunk) {
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Buffers at least `len` bytes.
+    fn fill_to(&mut self, len: usize) -> Result<(), ParseError> {
+        while self.buf.len() < len {
+            if self.fill()? == 0 {
+                return Err(ParseError::UnexpectedEof);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_exact_body(&mut self, len: usize) -> Result<Vec<u8>, ParseError> {
+        self.fill_to(len)?;
+        Ok(self.buf.drain(..len).collect())
+    }
+
+    // Returns the next line without its line ending, failing with
+    // `too_long` if no line ending turns up within `max` bytes.
+    fn read_line(
+        &mut self,
+        max: usize,
+        too_long: fn() -> ParseError,
+    ) -> Result<Vec<u8>, ParseError> {
+        let mut searched = 0;
+        loop {
+            if let Some(pos) = self.buf[searched..].iter().position(|&b| b == b'\n') {
+                let mut line: Vec<u8> = self.buf.drain(..searched + pos + 1).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Ok(line);
+            }
+            searched = self.buf.len();
+            if searched > max {
+                return Err(too_long());
+            }
+            if self.fill()? == 0 {
+                return Err(ParseError::UnexpectedEof);
+            }
+        }
+    }
+
+    fn read_chunked_body(&mut self, trailers: &mut Headers) -> Result<Vec<u8>, ParseError> {
+        let mut body = Vec::new();
+        loop {
+            let line = self.read_line(MAX_CHUNK_LINE, || ParseError::InvalidChunk)?;
+            let size = chunked::parse_chunk_size(&line).ok_or(ParseError::InvalidChunk)?;
+            if size == 0 {
+                break;
+            }
+            if size > self.limits.max_body_size - body.len() {
+                return Err(ParseError::BodyTooLarge);
+            }
+            let framed = size.checked_add(2).ok_or(ParseError::InvalidChunk)?;
+            self.fill_to(framed)?;
+            if &self.buf[size..framed] != b"\r\n" {
+                return Err(ParseError::InvalidChunk);
+            }
+            body.extend(self.buf.drain(..size));
+            self.buf.drain(..2);
+        }
+        let mut remaining = self.limits.max_header_size;
+        loop {
+            let line = self.read_line(remaining, || ParseError::HeadersTooLarge)?;
+            if line.is_empty() {
+                return Ok(body);
+            }
+            remaining = remaining
+                .checked_sub(line.len() + 2)
+                .ok_or(ParseError::HeadersTooLarge)?;
+            parse_header_line(trailers, &line)?;
+        }
+    }
+}
+
+// Decodes as much of a chunked body as `buf` holds into `request`, resuming
+// from `state`. Returns how many bytes were used; anything after them is a
+// line or chunk ending that has not fully arrived.
+fn decode_chunked(
+    buf: &[u8],
+    state: &mut BodyState,
+    request: &mut Request,
+    limits: &Limits,
+) -> Result<usize, ParseError> {
+    let mut used = 0;
+    loop {
+        let rest = &buf[used..];
+        match *state {
+            BodyState::ChunkSize => {
+                let Some((line, len)) =
+                    buffered_line(rest, MAX_CHUNK_LINE, ParseError::InvalidChunk)?
+                else {
+                    return Ok(used);
+                };
+                let size = chunked::parse_chunk_size(line).ok_or(ParseError::InvalidChunk)?;
+                if size > limits.max_body_size - request.body.len() {
+                    return Err(ParseError::BodyTooLarge);
+                }
+                *state = if size == 0 {
+                    BodyState::Trailers(limits.max_header_size)
+                } else {
+                    BodyState::Chunk(size as u64)
+                };
+                used += len;
+            }
+            BodyState::Chunk(0) => {
+                if rest.len() < 2 {
+                    return Ok(used);
+                }
+                if &rest[..2] != b"\r\n" {
+                    return Err(ParseError::InvalidChunk);
+                }
+                *state = BodyState::ChunkSize;
+                used += 2;
+            }
+            BodyState::Chunk(left) => {
+                if rest.is_empty() {
+                    return Ok(used);
+                }
+                let n = rest.len().min(left as usize);
+                request.body.extend_from_slice(&rest[..n]);
+                *state = BodyState::Chunk(left - n as u64);
+                used += n;
+            }
+            BodyState::Trailers(remaining) => {
+                let Some((line, len)) =
+                    buffered_line(rest, remaining, ParseError::HeadersTooLarge)?
+                else {
+                    return Ok(used);
+                };
+                used += len;
+                if line.is_empty() {
+                    *state = BodyState::Done;
+                    return Ok(used);
+                }
+                *state = BodyState::Trailers(
+                    remaining
+                        .checked_sub(line.len() + 2)
+                        .ok_or(ParseError::HeadersTooLarge)?,
+                );
+                parse_header_line(&mut request.trailers, line)?;
+            }
+            BodyState::Length(_) | BodyState::Done => unreachable!(),
+        }
+    }
+}
+
+// Splits the first line off `buf`, returning it without its line ending along
+// with its length including that. `None` until the line ends, unless it has
+// already run past `max`.
+fn buffered_line(
+    buf: &[u8],
+    max: usize,
+    too_long: ParseError,
+) -> Result<Option<(&[u8], usize)>, ParseError> {
+    match buf.iter().position(|&b| b == b'\n') {
+        Some(end) => {
+            let line = &buf[..end];
+            Ok(Some((line.strip_suffix(b"\r").unwrap_or(line), end + 1)))
+        }
+        None if buf.len() > max + 1 => Err(too_long),
+        None => Ok(None),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Framing {
+    None,
+    Length(usize),
+    Chunked,
+}
+
+// Works out how the body that follows `headers` is delimited.
+fn body_framing(headers: &Headers, limits: &Limits) -> Result<Framing, ParseError> {
+    if headers.contains("Transfer-Encoding") {
+        // Accepting both would let a proxy and this server disagree on
+        // where the body ends.
+        if headers.contains("Content-Length") {
+            return Err(ParseError::AmbiguousLength);
+        }
+        if !is_chunked(headers) {
+            return Err(ParseError::UnsupportedTransferEncoding);
+        }
+        return Ok(Framing::Chunked);
+    }
+    match content_length(headers)? {
+        Some(len) if len > limits.max_body_size => Err(ParseError::BodyTooLarge),
+        Some(len) => Ok(Framing::Length(len)),
+        None => Ok(Framing::None),
+    }
+}
+
+fn parse_head(head: &[u8]) -> Result<(Method, String, Version, Headers), ParseError> {
+    let mut lines = head
+        .split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+
+    let request_line = lines.next().ok_or(ParseError::InvalidRequestLine)?;
+    let request_line =
+        std::str::from_utf8(request_line).map_err(|_| ParseError::InvalidRequestLine)?;
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v), None) => (m, t, v),
+        _ => return Err(ParseError::InvalidRequestLine),
+    };
+    let method: Method = method.parse()?;
+    if target.is_empty() || target.bytes().any(|b| b.is_ascii_control()) {
+        return Err(ParseError::InvalidTarget);
+    }
+    let version: Version = version.parse()?;
+
+    let mut headers = Headers::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        parse_header_line(&mut headers, line)?;
+    }
+
+    Ok((method, target.to_string(), version, headers))
+}
+
+// Only a lone `chunked` coding is supported.
+fn is_chunked(headers: &Headers) -> bool {
+    let mut codings = headers
+        .get_all("Transfer-Encoding")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty());
+    matches!(
+        (codings.next(), codings.next()),
+        (Some(coding), None) if coding.eq_ignore_ascii_case("chunked")
+    )
+}
+
+fn content_length(headers: &Headers) -> Result<Option<usize>, ParseError> {
+    let mut length = None;
+    for value in headers.get_all("Content-Length") {
+        for part in value.split(',').map(str::trim) {
+            if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::InvalidContentLength);
+            }
+            let parsed: usize = part.parse().map_err(|_| ParseError::InvalidContentLength)?;
+            match length {
+                Some(existing) if existing != parsed => {
+                    return Err(ParseError::InvalidContentLength)
+                }
+                _ => length = Some(parsed),
+            }
+        }
+    }
+    Ok(length)
+}
+
+fn parse_header_line(headers: &mut Headers, line: &[u8]) -> Result<(), ParseError> {
+    // Obsolete line folding is rejected rather than unfolded.
+    if line.starts_with(b" ") || line.starts_with(b"\t") {
+        return Err(ParseError::InvalidHeader);
+    }
+    let line = std::str::from_utf8(line).map_err(|_| ParseError::InvalidHeader)?;
+    let (name, value) = line.split_once(':').ok_or(ParseError::InvalidHeader)?;
+    if name.is_empty() || !name.bytes().all(is_token_byte) {
+        return Err(ParseError::InvalidHeader);
+    }
+    let value = value.trim_matches(|c| c == ' ' || c == '\t');
+    if value.bytes().any(|b| b.is_ascii_control() && b != b'\t') {
+        return Err(ParseError::InvalidHeader);
+    }
+    headers.append(name, value);
+    Ok(())
+}
+
+pub(crate) fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out its input a few bytes at a time, like a slow socket.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.step.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    fn parse(raw: &[u8]) -> Result<Option<Request>, ParseError> {
+        RequestReader::new(raw).read_request()
+    }
+
+    #[test]
+    fn parses_request_line_and_headers() {
+        let req =
+            parse(b"GET /index.html?lang=en HTTP/1.1\r\nHost: localhost\r\nX-Thing:  a b \r\n\r\n")
+                .unwrap()
+                .unwrap();
+        assert_eq!(req.method, Method::Get);
+        assert_eq!(req.target, "/index.html?lang=en");
+        assert_eq!(req.path(), "/index.html");
+        assert_eq!(req.query_string(), Some("lang=en"));
+        assert_eq!(req.version, Version::Http11);
+        assert_eq!(req.header("host"), Some("localhost"));
+        assert_eq!(req.header("x-thing"), Some("a b"));
+        assert!(req.body.is_empty());
+    }
+
+    #[test]
+    fn reads_body_split_across_reads() {
+        let raw = b"POST /submit HTTP/1.0\r\nContent-Length: 11\r\n\r\nhello world";
+        let mut reader = RequestReader::new(Trickle { data: raw, step: 3 });
+        let req = reader.read_request().unwrap().unwrap();
+        assert_eq!(req.method, Method::Post);
+        assert_eq!(req.version, Version::Http10);
+        assert_eq!(req.body, b"hello world");
+        assert!(reader.read_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn keeps_bytes_of_following_request() {
+        let raw = b"GET /a HTTP/1.1\r\n\r\n\r\nGET /b HTTP/1.1\n\n";
+        let mut reader = RequestReader::new(&raw[..]);
+        assert_eq!(reader.read_request().unwrap().unwrap().target, "/a");
+        assert_eq!(reader.read_request().unwrap().unwrap().target, "/b");
+        assert!(reader.read_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn parses_bytes_fed_piecemeal() {
+        let raw = b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc\
+            PUT /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nhi\r\n0\r\nX-Sum: 1\r\n\r\nGET /c";
+        let mut reader = RequestReader::new(io::empty());
+        let mut requests = Vec::new();
+        for &byte in raw.iter() {
+            reader.feed(&[byte]);
+            if let Some(request) = reader.parse_buffered().unwrap() {
+                requests.push(request);
+            }
+        }
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].body, b"abc");
+        assert_eq!(requests[1].target, "/b");
+        assert_eq!(requests[1].body, b"hi");
+        assert_eq!(requests[1].trailers.get("X-Sum"), Some("1"));
+        assert_eq!(reader.buffered(), b"GET /c");
+        assert!(!reader.has_head());
+    }
+
+    #[test]
+    fn checks_limits_before_buffered_requests_complete() {
+        let limits = Limits {
+            max_request_line: 16,
+            max_body_size: 4,
+            ..Limits::default()
+        };
+        let mut reader = RequestReader::new(io::empty()).with_limits(limits);
+        reader.feed(b"GET /a-rather-long-path");
+        assert!(matches!(
+            reader.parse_buffered(),
+            Err(ParseError::RequestLineTooLong)
+        ));
+
+        let mut reader = RequestReader::new(io::empty()).with_limits(limits);
+        reader.feed(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n");
+        assert!(matches!(
+            reader.parse_buffered(),
+            Err(ParseError::BodyTooLarge)
+        ));
+    }
+
+    #[test]
+    fn handles_heads_larger_than_one_read() {
+        let mut raw = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..500 {
+            raw.extend_from_slice(format!("X-Header-{}: {}\r\n", i, "v".repeat(20)).as_bytes());
+        }
+        raw.extend_from_slice(b"\r\n");
+        let req = parse(&raw).unwrap().unwrap();
+        assert_eq!(req.headers.len(), 500);
+        assert_eq!(req.header("x-header-499").map(str::len), Some(20));
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert!(matches!(
+            parse(b"GET /\r\n\r\n"),
+            Err(ParseError::InvalidRequestLine)
+        ));
+        assert!(matches!(
+            parse(b"BREW / HTTP/1.1\r\n\r\n"),
+            Err(ParseError::UnknownMethod(_))
+        ));
+        assert!(matches!(
+            parse(b"GET / HTTP/2.0\r\n\r\n"),
+            Err(ParseError::UnsupportedVersion)
+        ));
+        assert!(matches!(
+            parse(b"GET / HTTQ\r\n\r\n"),
+            Err(ParseError::InvalidVersion)
+        ));
+        assert!(matches!(
+            parse(b"GET / HTTP/1.1\r\nBad Header: x\r\n\r\n"),
+            Err(ParseError::InvalidHeader)
+        ));
+        assert!(matches!(
+            parse(b"GET / HTTP/1.1\r\nNoColon\r\n\r\n"),
+            Err(ParseError::InvalidHeader)
+        ));
+        assert!(matches!(
+            parse(b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\nabcd"),
+            Err(ParseError::InvalidContentLength)
+        ));
+        assert!(matches!(
+            parse(b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"),
+            Err(ParseError::InvalidContentLength)
+        ));
+    }
+
+    #[test]
+    fn decodes_chunked_bodies_with_trailers() {
+        let raw = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nChecksum: abc\r\n\r\n\
+            GET /next HTTP/1.1\r\n\r\n";
+        let mut reader = RequestReader::new(Trickle { data: raw, step: 4 });
+        let req = reader.read_request().unwrap().unwrap();
+        assert_eq!(req.body, b"hello world");
+        assert_eq!(req.trailers.get("checksum"), Some("abc"));
+        assert_eq!(reader.read_request().unwrap().unwrap().target, "/next");
+    }
+
+    // Reads a request whose body is left behind, then the body through a
+    // `BodyReader`.
+    fn read_streamed(raw: &'static [u8], limits: Limits) -> (Request, io::Result<Vec<u8>>) {
+        let mut reader = RequestReader::new(Trickle { data: raw, step: 4 }).with_limits(limits);
+        let (request, framing) = reader
+            .read_request_leaving_body(|request| request.path() == "/stream")
+            .unwrap()
+            .unwrap();
+        assert!(request.body.is_empty());
+        let source = io::Cursor::new(reader.take_buffered()).chain(reader.into_inner());
+        let mut body = BodyReader::new(source, framing.unwrap(), &limits);
+        let mut read = Vec::new();
+        let result = body.read_to_end(&mut read).map(|_| read);
+        (request, result)
+    }
+
+    #[test]
+    fn leaves_bodies_for_streaming_routes() {
+        let limits = Limits::default();
+        let (request, body) = read_streamed(
+            b"PUT /stream HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello worldGET /",
+            limits,
+        );
+        assert_eq!(request.method, Method::Put);
+        assert_eq!(body.unwrap(), b"hello world");
+
+        let (_, body) = read_streamed(
+            b"POST /stream HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nChecksum: abc\r\n\r\n",
+            limits,
+        );
+        assert_eq!(body.unwrap(), b"hello world");
+
+        // Other requests are read in full as usual.
+        let raw = b"POST /other HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi";
+        let mut reader = RequestReader::new(&raw[..]);
+        let (request, framing) = reader
+            .read_request_leaving_body(|request| request.path() == "/stream")
+            .unwrap()
+            .unwrap();
+        assert_eq!((request.body.as_slice(), framing), (&b"hi"[..], None));
+    }
+
+    #[test]
+    fn reports_streamed_body_errors() {
+        let error = |raw, limits| {
+            let (_, body) = read_streamed(raw, limits);
+            ParseError::from(body.unwrap_err())
+        };
+        let limits = Limits::default();
+        assert!(matches!(
+            error(
+                b"POST /stream HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello",
+                limits
+            ),
+            ParseError::UnexpectedEof
+        ));
+        assert!(matches!(
+            error(
+                b"POST /stream HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nhiX\r\n",
+                limits
+            ),
+            ParseError::InvalidChunk
+        ));
+        let small = Limits {
+            max_body_size: 4,
+            ..limits
+        };
+        assert!(matches!(
+            error(
+                b"POST /stream HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                  3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n",
+                small
+            ),
+            ParseError::BodyTooLarge
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_transfer_encodings() {
+        assert!(matches!(
+            parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"),
+            Err(ParseError::UnsupportedTransferEncoding)
+        ));
+        assert!(matches!(
+            parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n"),
+            Err(ParseError::AmbiguousLength)
+        ));
+        assert!(matches!(
+            parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"),
+            Err(ParseError::InvalidChunk)
+        ));
+        assert!(matches!(
+            parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabcd\r\n0\r\n\r\n"),
+            Err(ParseError::InvalidChunk)
+        ));
+        assert!(matches!(
+            parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nab"),
+            Err(ParseError::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn reports_truncated_requests() {
+        assert!(matches!(
+            parse(b"GET / HTTP/1.1\r\nHost: x"),
+            Err(ParseError::UnexpectedEof)
+        ));
+        assert!(matches!(
+            parse(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort"),
+            Err(ParseError::UnexpectedEof)
+        ));
+        assert!(parse(b"").unwrap().is_none());
+    }
+
+    fn parse_limited(raw: &[u8], limits: Limits) -> Result<Option<Request>, ParseError> {
+        RequestReader::new(Trickle { data: raw, step: 7 })
+            .with_limits(limits)
+            .read_request()
+    }
+
+    #[test]
+    fn enforces_size_limits() {
+        let limits = Limits {
+            max_request_line: 20,
+            max_header_size: 32,
+            max_body_size: 4,
+            header_timeout: None,
+        };
+        let ok = b"GET /123456 HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\n\r\nbody";
+        assert!(parse_limited(ok, limits).unwrap().is_some());
+
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(100));
+        assert!(matches!(
+            parse_limited(long_line.as_bytes(), limits),
+            Err(ParseError::RequestLineTooLong)
+        ));
+        // Rejected before the line ending ever arrives.
+        let endless = vec![b'a'; 1000];
+        assert!(matches!(
+            parse_limited(&endless, limits),
+            Err(ParseError::RequestLineTooLong)
+        ));
+
+        let big_headers = format!("GET / HTTP/1.1\r\nX-Filler: {}\r\n\r\n", "a".repeat(40));
+        assert!(matches!(
+            parse_limited(big_headers.as_bytes(), limits),
+            Err(ParseError::HeadersTooLarge)
+        ));
+
+        let big_body = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        assert!(matches!(
+            parse_limited(big_body, limits),
+            Err(ParseError::BodyTooLarge)
+        ));
+        let big_chunks = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        assert!(matches!(
+            parse_limited(big_chunks, limits),
+            Err(ParseError::BodyTooLarge)
+        ));
+        let big_trailers = format!(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nX-Trailer: {}\r\n\r\n",
+            "a".repeat(40)
+        );
+        assert!(matches!(
+            parse_limited(big_trailers.as_bytes(), limits),
+            Err(ParseError::HeadersTooLarge)
+        ));
+    }
+
+    #[test]
+    fn enforces_header_timeout() {
+        // Sleeps before every read, like a client drip-feeding its head.
+        struct Slow<'a>(&'a [u8]);
+
+        impl Read for Slow<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                std::thread::sleep(Duration::from_millis(20));
+                let n = buf.len().min(self.0.len()).min(1);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let limits = Limits {
+            header_timeout: Some(Duration::from_millis(50)),
+            ..Limits::default()
+        };
+        let result = RequestReader::new(Slow(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"))
+            .with_limits(limits)
+            .read_request();
+        assert!(matches!(result, Err(ParseError::HeaderTimeout)));
+    }
+}
@@ -0,0 +1,421 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::time::SystemTime;
+
+use crate::chunked::ChunkedWriter;
+use crate::date;
+use crate::headers::Headers;
+use crate::request::{Method, Version};
+use crate::status::StatusCode;
+use crate::upgrade::{Upgrade, Upgraded};
+
+pub const SERVER_NAME: &str = concat!("web-service/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Body,
+    /// Set on `101 Switching Protocols` responses to take over the
+    /// connection once the response has gone out.
+    pub upgrade: Option<Upgrade>,
+    /// Sends the response from a thread of its own rather than a worker's,
+    /// for bodies that stay open indefinitely such as event streams. The
+    /// connection is closed once it has been sent.
+    pub detached: bool,
+}
+
+impl Response {
+    pub fn new(status: StatusCode) -> Self {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::default(),
+            upgrade: None,
+            detached: false,
+        }
+    }
+
+    pub fn ok() -> Self {
+        Response::new(StatusCode::Ok)
+    }
+
+    pub fn not_found() -> Self {
+        Response::new(StatusCode::NotFound)
+    }
+
+    /// A plain-text response whose body is the status line's reason phrase.
+    pub fn error(status: StatusCode) -> Self {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(format!("{}\n", status))
+    }
+
+    pub fn html(status: StatusCode, body: impl Into<Body>) -> Self {
+        Response::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(body)
+    }
+
+    pub fn text(status: StatusCode, body: impl Into<Body>) -> Self {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(body)
+    }
+
+    /// Switches the connection to `protocol`, handing it to `f` once the
+    /// response has been sent.
+    pub fn switching_protocols<F>(protocol: &str, f: F) -> Self
+    where
+        F: FnOnce(Upgraded) + Send + 'static,
+    {
+        let mut response = Response::new(StatusCode::SwitchingProtocols)
+            .with_header("Connection", "Upgrade")
+            .with_header("Upgrade", protocol);
+        response.upgrade = Some(Upgrade::new(f));
+        response
+    }
+
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Body>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Writes the response as the answer to a request with the given
+    /// method and version.
+    ///
+    /// `Date` and `Server` are filled in unless already set, as is the
+    /// body framing. Bodies of unknown length are sent chunked to HTTP/1.1
+    /// clients unless the handler set `Content-Length` itself; an HTTP/1.0
+    /// client gets the raw bytes and the connection must then be closed to
+    /// mark the end of the body.
+    ///
+    /// Returns the number of body bytes sent, not counting any chunk
+    /// framing.
+    pub fn write_to<W: Write>(
+        self,
+        writer: &mut W,
+        method: Method,
+        version: Version,
+    ) -> io::Result<u64> {
+        let mut headers = self.headers;
+        if !headers.contains("Date") {
+            headers.insert("Date", date::format_http_date(SystemTime::now()));
+        }
+        if !headers.contains("Server") {
+            headers.insert("Server", SERVER_NAME);
+        }
+
+        let body = if self.status.forbids_body() {
+            headers.remove("Transfer-Encoding");
+            if self.status != StatusCode::NotModified {
+                headers.remove("Content-Length");
+            }
+            None
+        } else {
+            Some(self.body)
+        };
+
+        let chunked = match body.as_ref().map(Body::len) {
+            None => false,
+            Some(Some(len)) => {
+                if !headers.contains("Content-Length") {
+                    headers.insert("Content-Length", len.to_string());
+                }
+                false
+            }
+            Some(None) if headers.contains("Content-Length") => false,
+            Some(None) if version == Version::Http11 => {
+                headers.insert("Transfer-Encoding", "chunked");
+                true
+            }
+            Some(None) => false,
+        };
+
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+        for (name, value) in headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())?;
+
+        let sent = match body {
+            _ if method == Method::Head => 0,
+            None => 0,
+            Some(Body::Stream(stream)) if chunked => {
+                let mut chunks = ChunkedWriter::new(&mut *writer);
+                let mut counted = CountingWriter::new(&mut chunks);
+                stream(&mut counted)?;
+                let sent = counted.count;
+                chunks.finish()?;
+                sent
+            }
+            Some(body) => {
+                let mut counted = CountingWriter::new(&mut *writer);
+                body.write_to(&mut counted)?;
+                counted.count
+            }
+        };
+        writer.flush()?;
+        Ok(sent)
+    }
+}
+
+// Counts the bytes that pass through to `inner`.
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        CountingWriter { inner, count: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(data)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes a streamed body. It is handed a writer that takes care of the
+/// transfer framing.
+pub type StreamFn = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static>;
+
+/// A response body. Files are streamed to the client rather than read into
+/// memory up front, and `Stream` bodies are produced while they are sent.
+pub enum Body {
+    Bytes(Vec<u8>),
+    File { file: File, len: u64 },
+    Stream(StreamFn),
+}
+
+impl Body {
+    /// A body of unknown length written by `f`.
+    pub fn stream<F>(f: F) -> Body
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+    {
+        Body::Stream(Box::new(f))
+    }
+
+    /// The length of the body, if it is known up front.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File { len, .. } => Some(*len),
+            Body::Stream(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// Returns the body if it is already in memory.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes),
+            Body::File { .. } | Body::Stream(_) => None,
+        }
+    }
+
+    /// Reads the whole body into memory.
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(self.len().unwrap_or(0) as usize);
+        self.write_to(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub(crate) fn write_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Body::Bytes(bytes) => writer.write_all(&bytes),
+            Body::File { file, len } => {
+                let copied = io::copy(&mut file.take(len), writer)?;
+                if copied < len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "file shrank while it was being sent",
+                    ));
+                }
+                Ok(())
+            }
+            Body::Stream(stream) => stream(writer),
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::Bytes(Vec::new())
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => write!(f, "Body::Bytes({} bytes)", bytes.len()),
+            Body::File { len, .. } => write!(f, "Body::File({} bytes)", len),
+            Body::Stream(_) => f.write_str("Body::Stream"),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Self {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Self {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Self {
+        Body::Bytes(text.as_bytes().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Serializes `response`, checking for and then dropping the `Date` and
+    // `Server` lines so the rest can be compared exactly.
+    fn serialize(response: Response, method: Method, version: Version) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out, method, version).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\r\nDate: "), "{}", out);
+        assert!(
+            out.contains(&format!("\r\nServer: {}\r\n", SERVER_NAME)),
+            "{}",
+            out
+        );
+        out.split_inclusive("\r\n")
+            .filter(|line| !line.starts_with("Date: ") && !line.starts_with("Server: "))
+            .collect()
+    }
+
+    fn report() -> Response {
+        Response::ok().with_body(Body::stream(|out| {
+            for line in 1..=2 {
+                writeln!(out, "line {}", line)?;
+            }
+            Ok(())
+        }))
+    }
+
+    #[test]
+    fn known_lengths_get_content_length() {
+        let out = serialize(Response::ok().with_body("hi"), Method::Get, Version::Http11);
+        assert_eq!(out, "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi");
+    }
+
+    #[test]
+    fn reports_body_bytes_sent() {
+        let mut out = Vec::new();
+        let sent = report()
+            .write_to(&mut out, Method::Get, Version::Http11)
+            .unwrap();
+        assert_eq!(sent, 14);
+        let sent = Response::ok()
+            .with_body("hi")
+            .write_to(&mut out, Method::Head, Version::Http11)
+            .unwrap();
+        assert_eq!(sent, 0);
+    }
+
+    #[test]
+    fn streams_are_chunked_for_http_11() {
+        let out = serialize(report(), Method::Get, Version::Http11);
+        assert_eq!(
+            out,
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nE\r\nline 1\nline 2\n\r\n0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn streams_with_a_declared_length_are_not_chunked() {
+        let out = serialize(
+            report().with_header("Content-Length", "14"),
+            Method::Get,
+            Version::Http11,
+        );
+        assert_eq!(
+            out,
+            "HTTP/1.1 200 OK\r\nContent-Length: 14\r\n\r\nline 1\nline 2\n"
+        );
+    }
+
+    #[test]
+    fn streams_are_sent_raw_to_http_10() {
+        let out = serialize(report(), Method::Get, Version::Http10);
+        assert_eq!(out, "HTTP/1.1 200 OK\r\n\r\nline 1\nline 2\n");
+    }
+
+    #[test]
+    fn head_responses_have_no_body() {
+        let out = serialize(report(), Method::Head, Version::Http11);
+        assert_eq!(out, "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n");
+        let out = serialize(
+            Response::ok().with_body("hi"),
+            Method::Head,
+            Version::Http11,
+        );
+        assert_eq!(out, "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n");
+    }
+
+    #[test]
+    fn bodiless_statuses_drop_the_body() {
+        let out = serialize(
+            Response::new(StatusCode::NoContent).with_body("ignored"),
+            Method::Get,
+            Version::Http11,
+        );
+        assert_eq!(out, "HTTP/1.1 204 No Content\r\n\r\n");
+    }
+
+    #[test]
+    fn keeps_explicit_headers() {
+        let mut out = Vec::new();
+        Response::error(StatusCode::NotFound)
+            .with_header("Server", "custom")
+            .with_header("Date", "Sun, 06 Nov 1994 08:49:37 GMT")
+            .write_to(&mut out, Method::Get, Version::Http11)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Server: custom\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+             Content-Length: 14\r\n\r\n404 Not Found\n"
+        );
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+
+use crate::request::{Method, Request};
+use crate::response::Response;
+use crate::status::StatusCode;
+use crate::url;
+
+pub type Handler = Box<dyn Fn(&Request) -> Response + Send + Sync + 'static>;
+
+/// Dispatches requests to handlers by method and path pattern.
+///
+/// Patterns are made of `/`-separated segments: literal text, `:name` to
+/// capture one segment, or a trailing `*name` to capture the rest of the
+/// path. Routes are tried in the order they were added.
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Handler,
+}
+
+struct Route {
+    /// `None` matches every method.
+    method: Option<Method>,
+    pattern: Pattern,
+    handler: Handler,
+    // The body is left on the connection for the handler.
+    streams_body: bool,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router {
+            routes: Vec::new(),
+            not_found: Box::new(|_| Response::error(StatusCode::NotFound)),
+        }
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `pattern` is malformed.
+    pub fn route<F>(self, method: Method, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.add(Some(method), pattern, handler)
+    }
+
+    /// Routes requests with any method to `handler`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` is malformed.
+    pub fn any<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.add(None, pattern, handler)
+    }
+
+    /// Routes requests with any method to `handler` without reading their
+    /// bodies first. The body is left on the connection for the handler to
+    /// read with [`Request::take_body_reader`], so it can be passed on as
+    /// it arrives; middleware sees the request with an empty `body`. The
+    /// connection is closed after the response.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` is malformed.
+    pub fn any_streaming<F>(mut self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self = self.add(None, pattern, handler);
+        self.routes.last_mut().unwrap().streams_body = true;
+        self
+    }
+
+    fn add<F>(mut self, method: Option<Method>, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        let pattern = Pattern::parse(pattern)
+            .unwrap_or_else(|e| panic!("invalid route pattern {:?}: {}", pattern, e));
+        self.routes.push(Route {
+            method,
+            pattern,
+            handler: Box::new(handler),
+            streams_body: false,
+        });
+        self
+    }
+
+    // Whether the route `request` will reach leaves its body on the
+    // connection. Asked once the head has arrived, before the body is read.
+    pub(crate) fn streams_body(&self, request: &Request) -> bool {
+        let Some(path) = request.normalized_path() else {
+            return false;
+        };
+        self.routes
+            .iter()
+            .find(|route| {
+                route.method.is_none_or(|method| method == request.method)
+                    && route.pattern.matches(&path).is_some()
+            })
+            .is_some_and(|route| route.streams_body)
+    }
+
+    pub fn get<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<F>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    /// Sets the handler used when no route matches the path.
+    pub fn not_found<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.not_found = Box::new(handler);
+        self
+    }
+
+    pub fn handle(&self, mut request: Request) -> Response {
+        request.query = request
+            .query_string()
+            .map(url::parse_query)
+            .unwrap_or_default();
+
+        // Matching the canonical path means `//admin` and `/%61dmin` reach
+        // the same route as `/admin`, and are seen as such by middleware.
+        let Some(path) = request.normalized_path() else {
+            return Response::error(StatusCode::BadRequest);
+        };
+        let mut allowed: Vec<Method> = Vec::new();
+        let mut fallback = None;
+        for route in &self.routes {
+            let Some(params) = route.pattern.matches(&path) else {
+                continue;
+            };
+            let Some(method) = route.method.filter(|&method| method != request.method) else {
+                request.params = params;
+                return (route.handler)(&request);
+            };
+            // HEAD is answered by the GET handler; the body is dropped on write.
+            if request.method == Method::Head && method == Method::Get && fallback.is_none() {
+                fallback = Some((route, params));
+            }
+            if !allowed.contains(&method) {
+                allowed.push(method);
+            }
+        }
+
+        if let Some((route, params)) = fallback {
+            request.params = params;
+            return (route.handler)(&request);
+        }
+        if allowed.is_empty() {
+            return (self.not_found)(&request);
+        }
+        if allowed.contains(&Method::Get) && !allowed.contains(&Method::Head) {
+            allowed.push(Method::Head);
+        }
+        let allow: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+        Response::error(StatusCode::MethodNotAllowed).with_header("Allow", allow.join(", "))
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router::new()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+#[derive(Debug)]
+struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Result<Pattern, &'static str> {
+        if !pattern.starts_with('/') {
+            return Err("pattern must start with '/'");
+        }
+        let raw: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+        let mut segments = Vec::with_capacity(raw.len());
+        for (i, segment) in raw.iter().enumerate() {
+            let parsed = if let Some(name) = segment.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = segment.strip_prefix('*') {
+                if i + 1 != raw.len() {
+                    return Err("wildcard must be the last segment");
+                }
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Literal(segment.to_string())
+            };
+            if let Segment::Param(name) | Segment::Wildcard(name) = &parsed {
+                if name.is_empty() {
+                    return Err("captures need a name");
+                }
+            }
+            segments.push(parsed);
+        }
+        Ok(Pattern { segments })
+    }
+
+    // `path` is normalized, so its segments are already decoded.
+    fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let mut params = HashMap::new();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Literal(literal) => {
+                    if parts.get(i)? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    params.insert(name.clone(), parts.get(i)?.to_string());
+                }
+                Segment::Wildcard(name) => {
+                    params.insert(name.clone(), parts.get(i..).unwrap_or_default().join("/"));
+                    return Some(params);
+                }
+            }
+        }
+        if parts.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::RequestReader;
+
+    fn request(method: &str, target: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\n\r\n", method, target);
+        RequestReader::new(raw.as_bytes())
+            .read_request()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn body(response: Response) -> Vec<u8> {
+        response.body.into_bytes().unwrap()
+    }
+
+    fn echo_params(request: &Request) -> Response {
+        let mut pairs: Vec<String> = request
+            .params
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        pairs.sort();
+        Response::ok().with_body(pairs.join("&"))
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .get("/", |_| Response::ok().with_body("home"))
+            .get("/users/:id", echo_params)
+            .route(Method::Delete, "/users/:id", |_| Response::ok())
+            .get("/static/*path", echo_params)
+            .get("/search", |req| {
+                Response::ok().with_body(req.query("q").unwrap_or("").to_string())
+            })
+    }
+
+    #[test]
+    fn matches_literals_and_captures() {
+        let router = router();
+        assert_eq!(body(router.handle(request("GET", "/"))), b"home");
+        assert_eq!(body(router.handle(request("GET", "/users/42"))), b"id=42");
+        assert_eq!(
+            body(router.handle(request("GET", "/users/a%20b"))),
+            b"id=a b"
+        );
+        assert_eq!(
+            body(router.handle(request("GET", "/static/css/site.css"))),
+            b"path=css/site.css"
+        );
+        assert_eq!(body(router.handle(request("GET", "/static/"))), b"path=");
+    }
+
+    #[test]
+    fn extracts_query_values() {
+        let router = router();
+        let response = router.handle(request("GET", "/search?q=hello+world&page=2"));
+        assert_eq!(body(response), b"hello world");
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let router = router();
+        assert_eq!(
+            router.handle(request("GET", "/users")).status,
+            StatusCode::NotFound
+        );
+        assert_eq!(
+            router.handle(request("GET", "/users/1/posts")).status,
+            StatusCode::NotFound
+        );
+        assert_eq!(
+            router.handle(request("GET", "/nope")).status,
+            StatusCode::NotFound
+        );
+    }
+
+    #[test]
+    fn wrong_method_is_405_with_allow() {
+        let router = router();
+        let response = router.handle(request("POST", "/users/1"));
+        assert_eq!(response.status, StatusCode::MethodNotAllowed);
+        assert_eq!(response.headers.get("allow"), Some("GET, DELETE, HEAD"));
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        let router = router();
+        assert_eq!(router.handle(request("HEAD", "/")).status, StatusCode::Ok);
+    }
+
+    #[test]
+    fn any_matches_every_method() {
+        let router = router().any("/api/*rest", |req| {
+            Response::ok().with_body(req.method.as_str())
+        });
+        for method in ["GET", "PUT", "PATCH", "OPTIONS"] {
+            let response = router.handle(request(method, "/api/things"));
+            assert_eq!(body(response), method.as_bytes());
+        }
+    }
+
+    #[test]
+    fn knows_which_routes_stream_bodies() {
+        let router = router()
+            .post("/upload/form", |_| Response::ok())
+            .any_streaming("/upload/*rest", |_| Response::ok());
+        assert!(router.streams_body(&request("PUT", "/upload/big")));
+        assert!(router.streams_body(&request("POST", "//upload/./big")));
+        assert!(!router.streams_body(&request("POST", "/upload/form")));
+        assert!(!router.streams_body(&request("POST", "/users/1")));
+    }
+
+    #[test]
+    #[should_panic(expected = "wildcard must be the last segment")]
+    fn rejects_misplaced_wildcard() {
+        let _ = Router::new().get("/a/*rest/b", |_| Response::ok());
+    }
+}
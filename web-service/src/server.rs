@@ -0,0 +1,1486 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::ToSocketAddrs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Deserialize;
+
+use crate::access_log::{AccessLog, Entry};
+use crate::compression::Compression;
+use crate::error::ServerError;
+use crate::event_loop;
+use crate::middleware::{Middleware, Next};
+use crate::pool::ThreadPool;
+use crate::request::{BodyReader, Framing, Limits, Method, Request, RequestReader, Version};
+use crate::response::Response;
+use crate::router::Router;
+use crate::status::StatusCode;
+use crate::upgrade::{Upgrade, Upgraded, Upgrades};
+
+pub const DEFAULT_WORKERS: usize = 8;
+pub const DEFAULT_QUEUE_CAPACITY: usize = 64;
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 100;
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_MAX_UPGRADED: usize = 1024;
+pub const DEFAULT_UPGRADED_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub(crate) const LINGER_TIMEOUT: Duration = Duration::from_secs(1);
+pub(crate) const LINGER_MAX_BYTES: usize = 1024 * 1024;
+
+/// How connections are multiplexed onto threads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Each connection has a worker to itself for as long as it is open.
+    #[default]
+    Threads,
+    /// One thread waits on every socket with epoll; workers are only
+    /// borrowed to run handlers, so idle and slow connections cost none.
+    Epoll,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "threads" => Ok(Backend::Threads),
+            "epoll" => Ok(Backend::Epoll),
+            _ => Err(format!(
+                "unknown backend {:?}, expected threads or epoll",
+                s
+            )),
+        }
+    }
+}
+
+pub struct Server {
+    listeners: Vec<TcpListener>,
+    backend: Backend,
+    workers: usize,
+    queue_capacity: usize,
+    shutdown_timeout: Duration,
+    shutdown: ShutdownHandle,
+    connection: Arc<ConnectionConfig>,
+}
+
+// Everything a worker needs to serve one connection.
+pub(crate) struct ConnectionConfig {
+    pub(crate) router: Router,
+    pub(crate) keep_alive_timeout: Duration,
+    pub(crate) read_timeout: Duration,
+    pub(crate) write_timeout: Duration,
+    pub(crate) limits: Limits,
+    pub(crate) max_requests: usize,
+    pub(crate) access_log: Option<AccessLog>,
+    pub(crate) compression: Option<Compression>,
+    pub(crate) middleware: Vec<Box<dyn Middleware>>,
+    pub(crate) upgrades: Upgrades,
+}
+
+impl Server {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Server> {
+        Server::with_listeners(vec![TcpListener::bind(addr)?])
+    }
+
+    /// Listens on every address in `addrs`, serving them all from one pool.
+    pub fn bind_all(addrs: &[SocketAddr]) -> io::Result<Server> {
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no addresses to listen on",
+            ));
+        }
+        let listeners = addrs
+            .iter()
+            .map(|addr| {
+                TcpListener::bind(addr)
+                    .map_err(|e| io::Error::new(e.kind(), format!("cannot bind {}: {}", addr, e)))
+            })
+            .collect::<io::Result<_>>()?;
+        Server::with_listeners(listeners)
+    }
+
+    fn with_listeners(listeners: Vec<TcpListener>) -> io::Result<Server> {
+        let addrs = listeners
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<io::Result<_>>()?;
+        Ok(Server {
+            listeners,
+            backend: Backend::default(),
+            shutdown: ShutdownHandle::new(addrs),
+            workers: DEFAULT_WORKERS,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            connection: Arc::new(ConnectionConfig {
+                router: Router::new(),
+                keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+                read_timeout: DEFAULT_READ_TIMEOUT,
+                write_timeout: DEFAULT_WRITE_TIMEOUT,
+                limits: Limits::default(),
+                max_requests: DEFAULT_MAX_REQUESTS_PER_CONNECTION,
+                access_log: None,
+                compression: None,
+                middleware: Vec::new(),
+                upgrades: Upgrades::new(DEFAULT_MAX_UPGRADED, DEFAULT_UPGRADED_IDLE_TIMEOUT),
+            }),
+        })
+    }
+
+    pub fn router(mut self, router: Router) -> Self {
+        self.connection_mut().router = router;
+        self
+    }
+
+    /// Records every request in `log`. Nothing is logged by default.
+    pub fn access_log(mut self, log: AccessLog) -> Self {
+        self.connection_mut().access_log = Some(log);
+        self
+    }
+
+    /// Compresses responses for clients that accept it. Bodies are sent as
+    /// they are by default.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.connection_mut().compression = Some(compression);
+        self
+    }
+
+    /// Runs `middleware` around the router for every request. Each one
+    /// wraps those added after it, so the first added sees requests first
+    /// and responses last.
+    pub fn middleware(mut self, middleware: impl Middleware) -> Self {
+        self.connection_mut().middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// How long a persistent connection may sit idle between requests.
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_mut().keep_alive_timeout = timeout;
+        self
+    }
+
+    /// How long to wait for each read once a request has started arriving,
+    /// and for the first request on a new connection.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_mut().read_timeout = timeout;
+        self
+    }
+
+    /// How long a single write to the client may block.
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_mut().write_timeout = timeout;
+        self
+    }
+
+    /// How long a request head may take to arrive once it has started,
+    /// however steadily its bytes trickle in.
+    pub fn header_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_mut().limits.header_timeout = Some(timeout);
+        self
+    }
+
+    /// Size limits on request lines, headers and bodies. Requests over them
+    /// are answered with 414, 431 or 413.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.connection_mut().limits = limits;
+        self
+    }
+
+    /// The number of requests served on one connection before it is closed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero.
+    pub fn max_requests_per_connection(mut self, max: usize) -> Self {
+        assert!(max > 0, "connections must be allowed at least one request");
+        self.connection_mut().max_requests = max;
+        self
+    }
+
+    /// Serves connections with `backend`; threads by default.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// How many connections may wait for a worker. The epoll backend holds
+    /// up to as many requests again while the queue is full, and answers
+    /// any beyond that with 503 Service Unavailable.
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity;
+        self
+    }
+
+    /// The number of connections that may have switched to another
+    /// protocol or be sending a detached response, such as an event
+    /// stream, at once. Any beyond that are answered with 503.
+    pub fn max_upgraded(mut self, max: usize) -> Self {
+        self.connection_mut().upgrades.max = max;
+        self
+    }
+
+    /// How long a read on an upgraded connection may wait for the client.
+    /// WebSocket clients that stay quiet this long are pinged, and dropped
+    /// if they stay quiet as long again.
+    pub fn upgraded_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_mut().upgrades.idle_timeout = timeout;
+        self
+    }
+
+    /// How long to wait for in-flight connections once shutdown starts
+    /// before they are forcibly closed.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// The address of the first listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(TcpListener::local_addr).collect()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    fn connection_mut(&mut self) -> &mut ConnectionConfig {
+        Arc::get_mut(&mut self.connection).expect("server is not running yet")
+    }
+
+    // Hands connections from `listener` to the pool until shutdown.
+    fn accept(&self, listener: &TcpListener, pool: &ThreadPool, tracker: &Arc<Tracker>) {
+        for stream in listener.incoming() {
+            if self.shutdown.is_requested() {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            let guard = match tracker.register(&stream) {
+                Ok(guard) => guard,
+                Err(e) => {
+                    eprintln!("failed to track connection: {}", e);
+                    continue;
+                }
+            };
+            let config = Arc::clone(&self.connection);
+            pool.execute(move || {
+                // Connections still queued when the drain deadline passes
+                // were already counted as aborted.
+                if guard.is_aborted() {
+                    return;
+                }
+                let peer = stream.peer_addr();
+                match handle_connection(stream, &config, &guard) {
+                    Ok(()) => {}
+                    // Clients hanging up mid-response is routine.
+                    Err(e) if e.is_disconnect() => {}
+                    Err(e) => match peer {
+                        Ok(peer) => eprintln!("connection from {}: {}", peer, e),
+                        Err(_) => eprintln!("connection error: {}", e),
+                    },
+                }
+            });
+        }
+    }
+
+    /// Accepts connections until shutdown is requested, then drains the
+    /// connections already accepted and joins every worker.
+    pub fn run(self) -> io::Result<ShutdownReport> {
+        let report = match self.backend {
+            Backend::Threads => self.run_threads()?,
+            Backend::Epoll => event_loop::run(
+                self.listeners,
+                &self.connection,
+                ThreadPool::new(self.workers, self.queue_capacity),
+                &self.shutdown,
+                self.shutdown_timeout,
+            )?,
+        };
+        eprintln!(
+            "shut down: {} request(s) completed, {} aborted",
+            report.completed, report.aborted
+        );
+        Ok(report)
+    }
+
+    fn run_threads(self) -> io::Result<ShutdownReport> {
+        let pool = ThreadPool::new(self.workers, self.queue_capacity);
+        let tracker = Arc::new(Tracker::default());
+
+        // The first listener is served from this thread and any others
+        // from their own.
+        thread::scope(|scope| {
+            for (i, listener) in self.listeners.iter().enumerate().skip(1) {
+                let (server, pool, tracker) = (&self, &pool, &tracker);
+                let spawned = thread::Builder::new()
+                    .name(format!("accept-{}", i))
+                    .spawn_scoped(scope, move || server.accept(listener, pool, tracker));
+                if let Err(e) = spawned {
+                    // Stops the listeners already running so the scope can end.
+                    self.shutdown.shutdown();
+                    return Err(e);
+                }
+            }
+            self.accept(&self.listeners[0], &pool, &tracker);
+            Ok(())
+        })?;
+        drop(self.listeners);
+
+        let deadline = Instant::now() + self.shutdown_timeout;
+        let upgrades = &self.connection.upgrades;
+        let in_flight = tracker.start_draining();
+        if in_flight > 0 {
+            eprintln!("draining {} connection(s)", in_flight);
+        }
+        upgrades.start_draining();
+        if !tracker.wait_closed(deadline) {
+            let aborted = tracker.abort_all();
+            eprintln!(
+                "shutdown deadline passed, aborted {} connection(s)",
+                aborted
+            );
+        }
+        drop(pool);
+        close_upgraded(upgrades, deadline);
+
+        Ok(ShutdownReport {
+            completed: tracker.completed.load(Ordering::SeqCst),
+            aborted: tracker.aborted.load(Ordering::SeqCst),
+        })
+    }
+}
+
+/// Request counts reported once the server has shut down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownReport {
+    pub completed: usize,
+    pub aborted: usize,
+}
+
+/// Asks a running [`Server`] to stop accepting connections and drain.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<ShutdownState>,
+}
+
+struct ShutdownState {
+    requested: AtomicBool,
+    addrs: Vec<SocketAddr>,
+}
+
+impl ShutdownHandle {
+    fn new(addrs: Vec<SocketAddr>) -> Self {
+        ShutdownHandle {
+            inner: Arc::new(ShutdownState {
+                requested: AtomicBool::new(false),
+                addrs,
+            }),
+        }
+    }
+
+    pub fn shutdown(&self) {
+        if self.inner.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Each accept loop is blocked in `accept`; a throwaway connection
+        // wakes it so it can notice the flag.
+        for &addr in &self.inner.addrs {
+            let mut addr = addr;
+            match addr.ip() {
+                IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+                IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+                _ => {}
+            }
+            let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Default)]
+struct Tracker {
+    state: Mutex<TrackerState>,
+    closed: Condvar,
+    completed: AtomicUsize,
+    aborted: AtomicUsize,
+}
+
+#[derive(Default)]
+struct TrackerState {
+    next_id: u64,
+    open: HashMap<u64, TrackedConnection>,
+    draining: bool,
+}
+
+struct TrackedConnection {
+    stream: TcpStream,
+    // Waiting between keep-alive requests.
+    idle: bool,
+}
+
+impl Tracker {
+    fn register(self: &Arc<Self>, stream: &TcpStream) -> io::Result<ConnectionGuard> {
+        let handle = stream.try_clone()?;
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.open.insert(
+            id,
+            TrackedConnection {
+                stream: handle,
+                idle: false,
+            },
+        );
+        Ok(ConnectionGuard {
+            tracker: Arc::clone(self),
+            id,
+        })
+    }
+
+    // Stops idle keep-alive connections from waiting for another request
+    // and returns how many connections are still open.
+    fn start_draining(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.draining = true;
+        for connection in state.open.values().filter(|c| c.idle) {
+            let _ = connection.stream.shutdown(Shutdown::Read);
+        }
+        state.open.len()
+    }
+
+    // Returns false if connections are still open at the deadline.
+    fn wait_closed(&self, deadline: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while !state.open.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.closed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    // Closes every open socket so blocked reads and writes fail fast.
+    fn abort_all(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let aborted = state.open.len();
+        for (_, connection) in state.open.drain() {
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+        self.aborted.fetch_add(aborted, Ordering::SeqCst);
+        aborted
+    }
+}
+
+struct ConnectionGuard {
+    tracker: Arc<Tracker>,
+    id: u64,
+}
+
+impl ConnectionGuard {
+    fn is_aborted(&self) -> bool {
+        !self
+            .tracker
+            .state
+            .lock()
+            .unwrap()
+            .open
+            .contains_key(&self.id)
+    }
+
+    fn is_draining(&self) -> bool {
+        self.tracker.state.lock().unwrap().draining
+    }
+
+    // Marks the connection as waiting for its next request. Once the server
+    // is draining, the read side is closed straight away instead.
+    fn set_idle(&self, idle: bool) {
+        let mut state = self.tracker.state.lock().unwrap();
+        let draining = state.draining;
+        if let Some(connection) = state.open.get_mut(&self.id) {
+            connection.idle = idle;
+            if idle && draining {
+                let _ = connection.stream.shutdown(Shutdown::Read);
+            }
+        }
+    }
+
+    fn request_completed(&self) {
+        self.tracker.completed.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut state = self.tracker.state.lock().unwrap();
+        state.open.remove(&self.id);
+        if state.open.is_empty() {
+            self.tracker.closed.notify_all();
+        }
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    config: &ConnectionConfig,
+    guard: &ConnectionGuard,
+) -> Result<(), ServerError> {
+    stream.set_write_timeout(Some(config.write_timeout))?;
+    let remote_addr = stream.peer_addr().ok().map(|addr| addr.ip());
+    let timed = TimedStream {
+        stream: &stream,
+        wait_timeout: config.read_timeout,
+        read_timeout: config.read_timeout,
+        waiting: true,
+        current: None,
+    };
+    let mut reader = RequestReader::new(timed).with_limits(config.limits);
+    let mut writer = &stream;
+
+    for served in 1..=config.max_requests {
+        // The first request is always waited for: the client was accepted
+        // before any shutdown began and deserves an answer.
+        let waiting = reader.buffered().is_empty();
+        if served > 1 && waiting {
+            guard.set_idle(true);
+        }
+        let timed = reader.get_mut();
+        timed.waiting = waiting;
+        if served > 1 {
+            timed.wait_timeout = config.keep_alive_timeout;
+        }
+        let read = reader.read_request_leaving_body(|request| config.router.streams_body(request));
+        let (mut request, streamed) = match read {
+            Ok(Some(read)) => read,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                let error = ServerError::from(e);
+                match error {
+                    // A client that goes quiet between requests is done
+                    // with the connection; one that stalls mid-request
+                    // is told why it is being dropped.
+                    ServerError::Timeout if reader.buffered().is_empty() => return Ok(()),
+                    ServerError::Io(_) => return Err(error),
+                    _ => {}
+                }
+                let time = SystemTime::now();
+                let started = Instant::now();
+                let response = error.response().with_header("Connection", "close");
+                let sent = response.write_to(&mut writer, Method::Get, Version::Http11)?;
+                guard.request_completed();
+                linger(&stream);
+                if let Some(log) = &config.access_log {
+                    let mut entry = Entry::unparsed(remote_addr, time);
+                    entry.status = error.status();
+                    entry.bytes_sent = sent;
+                    entry.latency = started.elapsed();
+                    log.log(&entry);
+                }
+                return Err(error);
+            }
+        };
+        guard.set_idle(false);
+        if let Some(framing) = streamed {
+            let buffered = reader.take_buffered();
+            request.set_body_reader(streamed_body(buffered, &stream, framing, config)?);
+        }
+        request.remote_addr = remote_addr;
+        let started = Instant::now();
+        let entry = config
+            .access_log
+            .as_ref()
+            .map(|_| Entry::new(remote_addr, SystemTime::now(), &request));
+
+        // Where a streamed body ends is up to the handler, so nothing can
+        // follow it on the connection.
+        let keep_alive = wants_keep_alive(&request)
+            && served < config.max_requests
+            && !guard.is_draining()
+            && streamed.is_none();
+        let method = request.method;
+        let version = request.version;
+        let (mut response, keep_alive) = respond(config, request, keep_alive);
+        let status = response.status;
+        let upgrade = response.upgrade.take();
+        let sent = if response.detached {
+            0
+        } else {
+            response.write_to(&mut writer, method, version)?
+        };
+        guard.request_completed();
+        if let (Some(log), Some(mut entry)) = (&config.access_log, entry) {
+            entry.status = status;
+            entry.bytes_sent = sent;
+            entry.latency = started.elapsed();
+            log.log(&entry);
+        }
+
+        if let Some(upgrade) = upgrade {
+            let buffered = reader.buffered().to_vec();
+            upgrade.spawn(Upgraded::new(stream.try_clone()?, buffered)?);
+            return Ok(());
+        }
+        if streamed.is_some() {
+            // The handler may have left part of the body unread.
+            linger(&stream);
+            break;
+        }
+        if !keep_alive {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Runs the middleware and handler for `request` and decides whether the
+// connection stays open afterwards, given whether it otherwise could. The
+// response's Connection header is set to match.
+pub(crate) fn respond(
+    config: &ConnectionConfig,
+    request: Request,
+    keep_alive: bool,
+) -> (Response, bool) {
+    let method = request.method;
+    let version = request.version;
+    let accept_encoding = config
+        .compression
+        .and_then(|_| request.header("Accept-Encoding").map(str::to_string));
+
+    // A panicking handler or middleware fails its own request, not the
+    // worker.
+    let chain = Next::new(&config.middleware, &config.router);
+    let mut response = match panic::catch_unwind(AssertUnwindSafe(|| chain.run(request))) {
+        Ok(response) => response,
+        Err(_) => {
+            eprintln!("handler panicked; responding with 500");
+            Response::error(StatusCode::InternalServerError).with_header("Connection", "close")
+        }
+    };
+    // The connection belongs to the new protocol once the response is out.
+    if let Some(upgrade) = &mut response.upgrade {
+        if response.status == StatusCode::SwitchingProtocols {
+            if upgrade.reserve(&config.upgrades) {
+                return (response, false);
+            }
+            response = Response::error(StatusCode::ServiceUnavailable);
+        }
+        response.upgrade = None;
+    }
+    if let Some(compression) = &config.compression {
+        response = compression.apply(accept_encoding.as_deref(), response);
+    }
+    // An HTTP/1.0 client can only tell where a body of unknown length
+    // ends by the connection closing.
+    let keep_alive = keep_alive
+        && !response.headers.has_token("Connection", "close")
+        && (version == Version::Http11
+            || response.body.len().is_some()
+            || response.headers.contains("Content-Length"));
+    if !keep_alive {
+        response.headers.insert("Connection", "close");
+    } else if version == Version::Http10 {
+        response.headers.insert("Connection", "keep-alive");
+    }
+    if response.detached {
+        return (detach(config, response, method, version), false);
+    }
+    (response, keep_alive)
+}
+
+// Hands `response` to a thread that sends it once the connection is free.
+// What is left carries its status for the access log and the hand-over,
+// and is not itself sent.
+fn detach(
+    config: &ConnectionConfig,
+    response: Response,
+    method: Method,
+    version: Version,
+) -> Response {
+    let status = response.status;
+    let mut response = response.with_header("Connection", "close");
+    response.detached = false;
+    let mut upgrade = Upgrade::detached(response, method, version, config.write_timeout);
+    if !upgrade.reserve(&config.upgrades) {
+        return Response::error(StatusCode::ServiceUnavailable).with_header("Connection", "close");
+    }
+    let mut stand_in = Response::new(status);
+    stand_in.upgrade = Some(upgrade);
+    stand_in.detached = true;
+    stand_in
+}
+
+// Waits until `deadline` for the handlers of upgraded connections, whose
+// read sides were closed when shutdown began, to let go of them, then
+// closes any left.
+pub(crate) fn close_upgraded(upgrades: &Upgrades, deadline: Instant) {
+    if !upgrades.wait_closed(deadline) {
+        let aborted = upgrades.abort_all();
+        eprintln!(
+            "shutdown deadline passed, closed {} upgraded connection(s)",
+            aborted
+        );
+    }
+}
+
+// Closing a socket with unread input resets the connection, which can
+// destroy a response the client has not read yet. After an error response,
+// stop sending and briefly discard whatever the client is still sending.
+fn linger(stream: &TcpStream) {
+    if stream.shutdown(Shutdown::Write).is_err() {
+        return;
+    }
+    let deadline = Instant::now() + LINGER_TIMEOUT;
+    let mut discarded = 0;
+    let mut buf = [0; 4096];
+    while discarded < LINGER_MAX_BYTES {
+        let now = Instant::now();
+        if now >= deadline || stream.set_read_timeout(Some(deadline - now)).is_err() {
+            return;
+        }
+        match (&*stream).read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => discarded += n,
+        }
+    }
+}
+
+// The body of a request to a streaming route: whatever of it was read along
+// with the head, then the rest straight from the socket.
+pub(crate) fn streamed_body(
+    buffered: Vec<u8>,
+    stream: &TcpStream,
+    framing: Framing,
+    config: &ConnectionConfig,
+) -> io::Result<BodyReader> {
+    let socket = PolledStream {
+        stream: stream.try_clone()?,
+        timeout: config.read_timeout,
+    };
+    Ok(BodyReader::new(
+        io::Cursor::new(buffered).chain(socket),
+        framing,
+        &config.limits,
+    ))
+}
+
+// Reads from a socket once it is readable, failing if it stays quiet for
+// `timeout`. Works whether or not the socket is non-blocking, as it is
+// under the epoll backend.
+struct PolledStream {
+    stream: TcpStream,
+    timeout: Duration,
+}
+
+impl Read for PolledStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = self.timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        loop {
+            let mut fd = libc::pollfd {
+                fd: self.stream.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            match unsafe { libc::poll(&mut fd, 1, timeout) } {
+                0 => return Err(io::ErrorKind::TimedOut.into()),
+                n if n < 0 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+                _ => match (&self.stream).read(buf) {
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                        ) => {}
+                    result => return result,
+                },
+            }
+        }
+    }
+}
+
+// Reads from the socket with one timeout while waiting for a request to
+// start and another once its bytes are arriving.
+struct TimedStream<'a> {
+    stream: &'a TcpStream,
+    wait_timeout: Duration,
+    read_timeout: Duration,
+    waiting: bool,
+    // The timeout the socket is currently set to.
+    current: Option<Duration>,
+}
+
+impl Read for TimedStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = if self.waiting {
+            self.wait_timeout
+        } else {
+            self.read_timeout
+        };
+        if self.current != Some(timeout) {
+            self.stream.set_read_timeout(Some(timeout))?;
+            self.current = Some(timeout);
+        }
+        let n = self.stream.read(buf)?;
+        if n > 0 {
+            self.waiting = false;
+        }
+        Ok(n)
+    }
+}
+
+// HTTP/1.1 connections persist unless either side says otherwise; HTTP/1.0
+// ones only when the client asks.
+pub(crate) fn wants_keep_alive(request: &Request) -> bool {
+    match request.version {
+        Version::Http11 => !request.headers.has_token("Connection", "close"),
+        Version::Http10 => request.headers.has_token("Connection", "keep-alive"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::prelude::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use crate::request::ParseError;
+    use crate::sse::{Event, EventChannel};
+
+    const BACKENDS: [Backend; 2] = [Backend::Threads, Backend::Epoll];
+
+    fn server(backend: Backend) -> Server {
+        Server::bind("127.0.0.1:0")
+            .unwrap()
+            .workers(2)
+            .backend(backend)
+    }
+
+    fn start(
+        server: Server,
+    ) -> (
+        SocketAddr,
+        ShutdownHandle,
+        thread::JoinHandle<ShutdownReport>,
+    ) {
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let join = thread::spawn(move || server.run().unwrap());
+        (addr, handle, join)
+    }
+
+    // Sends `raw` in one write and returns the status lines received
+    // before the server closed the connection.
+    fn exchange(addr: SocketAddr, raw: &str) -> Vec<String> {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.write_all(raw.as_bytes()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+            .lines()
+            .filter(|line| line.starts_with("HTTP/1.1 "))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn finishes_in_flight_requests_before_exiting() {
+        for backend in BACKENDS {
+            let (addr, handle, join) =
+                start(server(backend).shutdown_timeout(Duration::from_secs(5)));
+
+            let statuses = exchange(addr, "garbage\r\n\r\n");
+            assert_eq!(statuses, ["HTTP/1.1 400 Bad Request"]);
+
+            handle.shutdown();
+            let report = join.join().unwrap();
+            assert_eq!(
+                report,
+                ShutdownReport {
+                    completed: 1,
+                    aborted: 0
+                }
+            );
+            assert!(TcpStream::connect(addr).is_err());
+        }
+    }
+
+    #[test]
+    fn aborts_connections_left_at_the_deadline() {
+        for backend in BACKENDS {
+            let (addr, handle, join) =
+                start(server(backend).shutdown_timeout(Duration::from_millis(100)));
+
+            // Connects but never sends a request, so the connection stays open.
+            let mut idle = TcpStream::connect(addr).unwrap();
+            thread::sleep(Duration::from_millis(50));
+
+            handle.shutdown();
+            let report = join.join().unwrap();
+            assert_eq!(
+                report,
+                ShutdownReport {
+                    completed: 0,
+                    aborted: 1
+                }
+            );
+            let mut buf = [0; 1];
+            assert_eq!(idle.read(&mut buf).unwrap_or(0), 0);
+        }
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order() {
+        for backend in BACKENDS {
+            let router = Router::new()
+                .get("/a", |_| Response::ok())
+                .get("/b", |_| Response::new(StatusCode::MethodNotAllowed));
+            let (addr, handle, join) = start(server(backend).router(router));
+
+            let statuses = exchange(
+                addr,
+                "GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /a HTTP/1.1\r\nConnection: close\r\n\r\n",
+            );
+            assert_eq!(
+                statuses,
+                [
+                    "HTTP/1.1 200 OK",
+                    "HTTP/1.1 405 Method Not Allowed",
+                    "HTTP/1.1 200 OK"
+                ]
+            );
+
+            handle.shutdown();
+            assert_eq!(join.join().unwrap().completed, 3);
+        }
+    }
+
+    #[test]
+    fn http_10_closes_unless_asked_to_keep_alive() {
+        for backend in BACKENDS {
+            let (addr, handle, join) = start(server(backend));
+
+            let twice = "GET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\n\r\n";
+            assert_eq!(exchange(addr, twice).len(), 1);
+
+            let kept = "GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET / HTTP/1.0\r\n\r\n";
+            assert_eq!(exchange(addr, kept).len(), 2);
+
+            handle.shutdown();
+            join.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn closes_after_max_requests() {
+        for backend in BACKENDS {
+            let (addr, handle, join) = start(server(backend).max_requests_per_connection(2));
+
+            let statuses = exchange(addr, &"GET / HTTP/1.1\r\n\r\n".repeat(3));
+            assert_eq!(statuses.len(), 2);
+
+            handle.shutdown();
+            join.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn closes_idle_connections_after_timeout() {
+        for backend in BACKENDS {
+            let (addr, handle, join) =
+                start(server(backend).keep_alive_timeout(Duration::from_millis(100)));
+
+            let started = Instant::now();
+            let statuses = exchange(addr, "GET / HTTP/1.1\r\n\r\n");
+            assert_eq!(statuses.len(), 1);
+            assert!(started.elapsed() >= Duration::from_millis(100));
+
+            handle.shutdown();
+            join.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn shutdown_closes_idle_keep_alive_connections() {
+        for backend in BACKENDS {
+            let (addr, handle, join) =
+                start(server(backend).shutdown_timeout(Duration::from_secs(5)));
+
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            let mut buf = [0; 256];
+            assert!(client.read(&mut buf).unwrap() > 0);
+
+            handle.shutdown();
+            let report = join.join().unwrap();
+            assert_eq!(
+                report,
+                ShutdownReport {
+                    completed: 1,
+                    aborted: 0
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn serves_every_bound_address() {
+        for backend in BACKENDS {
+            let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+            let server = Server::bind_all(&[any, any])
+                .unwrap()
+                .workers(2)
+                .backend(backend);
+            let addrs = server.local_addrs().unwrap();
+            assert_eq!(addrs.len(), 2);
+            let (_, handle, join) = start(server);
+
+            for addr in addrs {
+                assert_eq!(
+                    exchange(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n"),
+                    ["HTTP/1.1 404 Not Found"]
+                );
+            }
+
+            handle.shutdown();
+            assert_eq!(join.join().unwrap().completed, 2);
+        }
+    }
+
+    #[test]
+    fn contains_panicking_handlers() {
+        for backend in BACKENDS {
+            let router = Router::new()
+                .get("/boom", |_| panic!("handler bug"))
+                .get("/ok", |_| Response::ok());
+            let (addr, handle, join) = start(server(backend).router(router));
+
+            let statuses = exchange(addr, "GET /boom HTTP/1.1\r\n\r\nGET /ok HTTP/1.1\r\n\r\n");
+            assert_eq!(statuses, ["HTTP/1.1 500 Internal Server Error"]);
+            let statuses = exchange(addr, "GET /ok HTTP/1.1\r\nConnection: close\r\n\r\n");
+            assert_eq!(statuses, ["HTTP/1.1 200 OK"]);
+
+            handle.shutdown();
+            assert_eq!(join.join().unwrap().completed, 2);
+        }
+    }
+
+    #[test]
+    fn runs_middleware_before_the_router() {
+        for backend in BACKENDS {
+            let router = Router::new().get("/*path", |_| Response::ok());
+            let server = server(backend)
+                .router(router)
+                .middleware(|request: Request, next: Next| {
+                    if request.path().starts_with("/private") {
+                        return Response::error(StatusCode::Forbidden);
+                    }
+                    next.run(request)
+                })
+                .middleware(|request: Request, _: Next| -> Response {
+                    if request.path() == "/boom" {
+                        panic!("middleware bug");
+                    }
+                    Response::new(StatusCode::NoContent)
+                });
+            let (addr, handle, join) = start(server);
+
+            let statuses = exchange(
+                addr,
+                "GET /private HTTP/1.1\r\n\r\nGET /public HTTP/1.1\r\n\r\nGET /boom HTTP/1.1\r\n\r\n",
+            );
+            assert_eq!(
+                statuses,
+                [
+                    "HTTP/1.1 403 Forbidden",
+                    "HTTP/1.1 204 No Content",
+                    "HTTP/1.1 500 Internal Server Error"
+                ]
+            );
+
+            handle.shutdown();
+            join.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn times_out_stalled_requests() {
+        for backend in BACKENDS {
+            let (addr, handle, join) =
+                start(server(backend).read_timeout(Duration::from_millis(100)));
+
+            let statuses = exchange(addr, "GET / HTTP/1.1\r\nHost: exa");
+            assert_eq!(statuses, ["HTTP/1.1 408 Request Timeout"]);
+
+            handle.shutdown();
+            join.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_requests_over_the_limits() {
+        for backend in BACKENDS {
+            let limits = Limits {
+                max_request_line: 64,
+                max_header_size: 64,
+                max_body_size: 16,
+                ..Limits::default()
+            };
+            let (addr, handle, join) = start(server(backend).limits(limits));
+
+            let long_target = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(100));
+            assert_eq!(exchange(addr, &long_target), ["HTTP/1.1 414 URI Too Long"]);
+            let big_header = format!("GET / HTTP/1.1\r\nX-Big: {}\r\n\r\n", "a".repeat(100));
+            assert_eq!(
+                exchange(addr, &big_header),
+                ["HTTP/1.1 431 Request Header Fields Too Large"]
+            );
+            let big_body = format!(
+                "POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n{}",
+                "a".repeat(17)
+            );
+            assert_eq!(
+                exchange(addr, &big_body),
+                ["HTTP/1.1 413 Payload Too Large"]
+            );
+
+            handle.shutdown();
+            join.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn cuts_off_slowly_dripped_heads() {
+        for backend in BACKENDS {
+            let (addr, handle, join) = start(
+                server(backend)
+                    .read_timeout(Duration::from_secs(5))
+                    .header_timeout(Duration::from_millis(200)),
+            );
+
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let started = Instant::now();
+            // Each byte arrives well within the read timeout, but the head as a
+            // whole never completes.
+            for _ in 0..40 {
+                if client.write_all(b"X").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+            let mut response = String::new();
+            let _ = client.read_to_string(&mut response);
+            assert!(
+                response.starts_with("HTTP/1.1 408 Request Timeout"),
+                "{:?}",
+                response
+            );
+            assert!(started.elapsed() < Duration::from_secs(2));
+
+            handle.shutdown();
+            join.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn hands_streaming_routes_the_body_as_it_arrives() {
+        for backend in BACKENDS {
+            let router = Router::new().any_streaming("/upload", |req| {
+                let mut start = Vec::new();
+                let body = req.take_body_reader().unwrap();
+                match body.take(5).read_to_end(&mut start) {
+                    Ok(_) => Response::ok().with_body(start),
+                    Err(e) => ServerError::from(ParseError::from(e)).response(),
+                }
+            });
+            let (addr, handle, join) = start(server(backend).router(router));
+
+            // Answered from the first bytes, long before the rest would
+            // have arrived.
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            client
+                .write_all(b"POST /upload HTTP/1.1\r\nContent-Length: 1000000\r\n\r\nhello")
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            assert!(response.contains("Connection: close\r\n"), "{}", response);
+            assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+            drop(client);
+
+            // Nothing can follow a streamed body on the connection.
+            let chunked = "POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                           3\r\nabc\r\n0\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+            assert_eq!(exchange(addr, chunked), ["HTTP/1.1 200 OK"]);
+            let malformed = "POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n";
+            assert_eq!(exchange(addr, malformed), ["HTTP/1.1 400 Bad Request"]);
+
+            handle.shutdown();
+            assert_eq!(join.join().unwrap().completed, 3);
+        }
+    }
+
+    #[test]
+    fn event_loop_turns_requests_away_once_the_backlog_is_full() {
+        let (started, handling) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let (started, released) = (Mutex::new(started), Mutex::new(released));
+        let router = Router::new().get("/slow", move |_| {
+            started.lock().unwrap().send(()).unwrap();
+            released.lock().unwrap().recv().unwrap();
+            Response::ok()
+        });
+        let (addr, handle, join) = start(
+            server(Backend::Epoll)
+                .workers(1)
+                .queue_capacity(1)
+                .router(router),
+        );
+
+        let send = || {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            client
+                .write_all(b"GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            client
+        };
+        // One request runs, one waits in the pool's queue and one in the
+        // loop's backlog.
+        let mut waiting = vec![send()];
+        handling.recv().unwrap();
+        for _ in 0..2 {
+            waiting.push(send());
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let refused = exchange(addr, "GET /slow HTTP/1.1\r\n\r\n");
+        assert_eq!(refused, ["HTTP/1.1 503 Service Unavailable"]);
+
+        for mut client in waiting {
+            release.send(()).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        }
+        handle.shutdown();
+        assert_eq!(join.join().unwrap().completed, 4);
+    }
+
+    #[test]
+    fn caps_upgraded_connections_and_closes_them_on_shutdown() {
+        for backend in BACKENDS {
+            let router = Router::new().get("/up", |_| {
+                Response::switching_protocols("test", |mut connection| {
+                    let mut buf = [0; 16];
+                    let quiet = matches!(
+                        connection.read(&mut buf),
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock
+                    );
+                    if quiet {
+                        let _ = connection.write_all(b"quiet");
+                        let _ = connection.read_to_end(&mut Vec::new());
+                    }
+                })
+            });
+            let (addr, handle, join) = start(
+                server(backend)
+                    .router(router)
+                    .max_upgraded(1)
+                    .upgraded_idle_timeout(Duration::from_millis(100))
+                    .shutdown_timeout(Duration::from_secs(5)),
+            );
+
+            let mut upgraded = TcpStream::connect(addr).unwrap();
+            upgraded
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            upgraded.write_all(b"GET /up HTTP/1.1\r\n\r\n").unwrap();
+            let mut head = [0; 12];
+            upgraded.read_exact(&mut head).unwrap();
+            assert_eq!(&head, b"HTTP/1.1 101");
+
+            let full = exchange(addr, "GET /up HTTP/1.1\r\nConnection: close\r\n\r\n");
+            assert_eq!(full, ["HTTP/1.1 503 Service Unavailable"]);
+
+            // Reads on the upgraded socket time out, and shutdown ends the
+            // handler's wait for more.
+            let mut rest = String::new();
+            let mut buf = [0; 1024];
+            while !rest.ends_with("quiet") {
+                let n = upgraded.read(&mut buf).unwrap();
+                assert!(n > 0, "{}", rest);
+                rest.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+            }
+            handle.shutdown();
+            assert_eq!(join.join().unwrap().completed, 2);
+            assert_eq!(upgraded.read(&mut buf).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn sends_event_streams_without_holding_a_worker() {
+        for backend in BACKENDS {
+            let channel = EventChannel::new();
+            let subscriptions = channel.clone();
+            let router = Router::new()
+                .get("/", |_| Response::ok())
+                .get("/events", move |req| subscriptions.subscribe(req));
+            let (addr, handle, join) = start(
+                server(backend)
+                    .workers(1)
+                    .router(router)
+                    .shutdown_timeout(Duration::from_secs(5)),
+            );
+
+            let mut events = TcpStream::connect(addr).unwrap();
+            events
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            events.write_all(b"GET /events HTTP/1.1\r\n\r\n").unwrap();
+            let mut received = String::new();
+            let mut buf = [0; 1024];
+            let mut sent = false;
+            while !received.contains("data: hello") {
+                if !sent && received.contains("\r\n\r\n") {
+                    channel.send(Event::new("hello"));
+                    sent = true;
+                }
+                let n = events.read(&mut buf).unwrap();
+                assert!(n > 0, "{}", received);
+                received.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+            }
+            assert!(received.starts_with("HTTP/1.1 200 OK\r\n"), "{}", received);
+
+            // The only worker is free for other requests.
+            let statuses = exchange(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+            assert_eq!(statuses, ["HTTP/1.1 200 OK"]);
+
+            // Shutdown closes the stream rather than waiting it out.
+            let started = Instant::now();
+            handle.shutdown();
+            assert_eq!(join.join().unwrap().completed, 2);
+            assert!(started.elapsed() < Duration::from_secs(2));
+            events.read_to_end(&mut Vec::new()).unwrap();
+        }
+    }
+
+    #[test]
+    fn event_loop_decodes_large_chunked_bodies_as_they_arrive() {
+        const LEN: usize = 8 * 1024 * 1024;
+        let router = Router::new().post("/len", |req| {
+            Response::ok().with_body(req.body.len().to_string())
+        });
+        let (addr, handle, join) = start(server(Backend::Epoll).router(router));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let started = Instant::now();
+        client
+            .write_all(
+                b"POST /len HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let chunk = vec![b'z'; 4096];
+        for _ in 0..LEN / chunk.len() {
+            write!(client, "{:x}\r\n", chunk.len()).unwrap();
+            client.write_all(&chunk).unwrap();
+            client.write_all(b"\r\n").unwrap();
+        }
+        client.write_all(b"0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with(&LEN.to_string()), "{response}");
+        // Decoding the whole body again after every read took many seconds.
+        assert!(started.elapsed() < Duration::from_secs(3));
+
+        handle.shutdown();
+        join.join().unwrap();
+    }
+
+    #[test]
+    fn streams_large_bodies_to_slow_readers() {
+        const LEN: usize = 4 * 1024 * 1024;
+        for backend in BACKENDS {
+            let router = Router::new()
+                .get("/bytes", |_| Response::ok().with_body(vec![b'x'; LEN]))
+                .post("/echo", |req| Response::ok().with_body(req.body.clone()));
+            let (addr, handle, join) = start(server(backend).router(router));
+
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let upload = vec![b'y'; 300_000];
+            write!(
+                client,
+                "POST /echo HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+                upload.len()
+            )
+            .unwrap();
+            client.write_all(&upload).unwrap();
+            client
+                .write_all(b"GET /bytes HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            // Lets the server fill the socket buffers before reading.
+            thread::sleep(Duration::from_millis(200));
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            assert_eq!(
+                response.iter().filter(|&&b| b == b'y').count(),
+                upload.len()
+            );
+            assert_eq!(response.iter().filter(|&&b| b == b'x').count(), LEN);
+
+            handle.shutdown();
+            assert_eq!(join.join().unwrap().completed, 2);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::FromRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::{mem, ptr};
+
+pub use libc::{SIGHUP, SIGINT, SIGTERM};
+
+// Write end of the self-pipe; -1 until `subscribe` has been called.
+static PIPE_WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    let fd = PIPE_WRITE_FD.load(Ordering::Relaxed);
+    if fd < 0 {
+        return;
+    }
+    // Only async-signal-safe calls are allowed here: forward the signal
+    // number through the pipe and leave errno as we found it.
+    unsafe {
+        let errno = *libc::__errno_location();
+        let byte = signal as u8;
+        libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+        *libc::__errno_location() = errno;
+    }
+}
+
+/// Routes the given signals to the returned channel instead of their
+/// default action.
+///
+/// Delivery happens on a background thread, so receivers are free to take
+/// locks, log or do I/O. Only one subscription may exist per process.
+pub fn subscribe(signals: &[libc::c_int]) -> io::Result<Receiver<libc::c_int>> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let [read_fd, write_fd] = fds;
+    let reader = unsafe { File::from_raw_fd(read_fd) };
+
+    if PIPE_WRITE_FD
+        .compare_exchange(-1, write_fd, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        unsafe { libc::close(write_fd) };
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "signals are already subscribed",
+        ));
+    }
+
+    for &signal in signals {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new()
+        .name("signals".into())
+        .spawn(move || forward(reader, tx))?;
+    Ok(rx)
+}
+
+fn forward(mut reader: File, tx: mpsc::Sender<libc::c_int>) {
+    let mut byte = [0u8; 1];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(1) => {
+                if tx.send(libc::c_int::from(byte[0])).is_err() {
+                    return;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            _ => return,
+        }
+    }
+}
+
+pub fn name(signal: libc::c_int) -> &'static str {
+    match signal {
+        SIGHUP => "SIGHUP",
+        SIGINT => "SIGINT",
+        SIGTERM => "SIGTERM",
+        _ => "signal",
+    }
+}
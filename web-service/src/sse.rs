@@ -0,0 +1,441 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::request::Request;
+use crate::response::{Body, Response};
+use crate::status::StatusCode;
+
+/// How long a stream may sit idle before a comment is sent to keep
+/// intermediaries from timing it out and to notice clients that have gone.
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// Events an [`EventChannel`] keeps for clients that reconnect.
+pub const DEFAULT_REPLAY_CAPACITY: usize = 256;
+/// Streams an [`EventChannel`] feeds at once before turning new
+/// subscribers away.
+pub const DEFAULT_MAX_SUBSCRIBERS: usize = 1024;
+
+// Events queued for one subscriber before it is dropped as too slow.
+const SUBSCRIBER_QUEUE: usize = 64;
+
+/// One server-sent event.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    pub id: Option<String>,
+    /// The event type; clients treat events without one as `message`.
+    pub event: Option<String>,
+    pub data: String,
+    /// How long the client should wait before reconnecting.
+    pub retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Event {
+        Event {
+            data: data.into(),
+            ..Event::default()
+        }
+    }
+
+    pub fn with_id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn with_event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// The event as it goes on the wire. Each line of `data` gets a field
+    /// of its own; line breaks in the id and type, which would end the
+    /// field early, are dropped.
+    pub fn encode(&self) -> String {
+        let single_line = |value: &str| value.replace(['\r', '\n'], "");
+        let mut frame = String::new();
+        if let Some(event) = &self.event {
+            frame.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        for line in self.data.split('\n') {
+            frame.push_str(&format!(
+                "data: {}\n",
+                line.strip_suffix('\r').unwrap_or(line)
+            ));
+        }
+        if let Some(id) = &self.id {
+            frame.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(retry) = self.retry {
+            frame.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        frame.push('\n');
+        frame
+    }
+}
+
+/// A `text/event-stream` response fed by a channel.
+///
+/// The response stays open, writing each event as it arrives, until every
+/// sender has been dropped. It is sent as a detached response, so it does
+/// not hold a worker while it is open, and counts towards the server's
+/// limit on upgraded connections.
+pub struct EventStream {
+    events: Receiver<Event>,
+    backlog: Vec<Event>,
+    keep_alive: Duration,
+    subscription: Option<Subscription>,
+}
+
+impl EventStream {
+    pub fn new(events: Receiver<Event>) -> EventStream {
+        EventStream {
+            events,
+            backlog: Vec::new(),
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            subscription: None,
+        }
+    }
+
+    pub fn keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = interval;
+        self
+    }
+
+    /// Events sent before any from the channel, such as those a
+    /// reconnecting client missed.
+    pub fn backlog(mut self, events: Vec<Event>) -> Self {
+        self.backlog = events;
+        self
+    }
+}
+
+impl From<EventStream> for Response {
+    fn from(stream: EventStream) -> Response {
+        let EventStream {
+            events,
+            backlog,
+            keep_alive,
+            subscription,
+        } = stream;
+        let body = Body::stream(move |out| {
+            // Leaves the channel however the stream ends, which is usually
+            // a failed write once the client has gone.
+            let _subscription = subscription;
+            // Gets the head to the client before the first event.
+            out.flush()?;
+            for event in &backlog {
+                send(out, event)?;
+            }
+            loop {
+                match events.recv_timeout(keep_alive) {
+                    Ok(event) => send(out, &event)?,
+                    Err(RecvTimeoutError::Timeout) => {
+                        out.write_all(b":\n\n")?;
+                        out.flush()?;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                }
+            }
+        });
+        let mut response = Response::ok()
+            .with_header("Content-Type", "text/event-stream")
+            .with_header("Cache-Control", "no-cache")
+            .with_body(body);
+        response.detached = true;
+        response
+    }
+}
+
+fn send(out: &mut dyn Write, event: &Event) -> io::Result<()> {
+    out.write_all(event.encode().as_bytes())?;
+    out.flush()
+}
+
+/// Publishes events to every client subscribed to it, keeping the most
+/// recent ones so that a client reconnecting with `Last-Event-ID` picks up
+/// where it left off.
+///
+/// A subscriber that falls too far behind is dropped; its stream ends once
+/// it has caught up with what was queued, and the client reconnects and
+/// resumes from the replay buffer.
+#[derive(Clone)]
+pub struct EventChannel {
+    state: Arc<Mutex<ChannelState>>,
+    keep_alive: Duration,
+}
+
+struct ChannelState {
+    replay: VecDeque<Event>,
+    replay_capacity: usize,
+    next_event_id: u64,
+    subscribers: Vec<(u64, SyncSender<Event>)>,
+    max_subscribers: usize,
+    next_subscriber_id: u64,
+    closed: bool,
+}
+
+// Removes a subscriber from its channel when its stream ends.
+struct Subscription {
+    state: Arc<Mutex<ChannelState>>,
+    id: u64,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.subscribers.retain(|(id, _)| *id != self.id);
+    }
+}
+
+impl EventChannel {
+    pub fn new() -> EventChannel {
+        EventChannel {
+            state: Arc::new(Mutex::new(ChannelState {
+                replay: VecDeque::new(),
+                replay_capacity: DEFAULT_REPLAY_CAPACITY,
+                next_event_id: 1,
+                subscribers: Vec::new(),
+                max_subscribers: DEFAULT_MAX_SUBSCRIBERS,
+                next_subscriber_id: 0,
+                closed: false,
+            })),
+            keep_alive: DEFAULT_KEEP_ALIVE,
+        }
+    }
+
+    pub fn replay_capacity(self, capacity: usize) -> Self {
+        self.state.lock().unwrap().replay_capacity = capacity;
+        self
+    }
+
+    /// Subscribers past this many get `503 Service Unavailable`.
+    pub fn max_subscribers(self, max: usize) -> Self {
+        self.state.lock().unwrap().max_subscribers = max;
+        self
+    }
+
+    pub fn keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = interval;
+        self
+    }
+
+    /// Sends `event` to every subscriber. Events without an id are
+    /// numbered, since only events with ids can be resumed from.
+    pub fn send(&self, mut event: Event) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+        if event.id.is_none() {
+            event.id = Some(state.next_event_id.to_string());
+            state.next_event_id += 1;
+        }
+        // A full queue means the subscriber is too slow to keep.
+        state
+            .subscribers
+            .retain(|(_, subscriber)| subscriber.try_send(event.clone()).is_ok());
+        if state.replay_capacity > 0 {
+            if state.replay.len() == state.replay_capacity {
+                state.replay.pop_front();
+            }
+            state.replay.push_back(event);
+        }
+    }
+
+    /// The stream for a new subscriber.
+    ///
+    /// Only later events are sent, unless the request carries
+    /// `Last-Event-ID`: then the stream starts with the events after that
+    /// one, or with the whole replay buffer if it is no longer there. Once
+    /// the channel is closed, subscribers get `204 No Content`, which tells
+    /// browsers to stop reconnecting. While the channel has as many
+    /// subscribers as it allows, new ones get `503 Service Unavailable`.
+    pub fn subscribe(&self, request: &Request) -> Response {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Response::new(StatusCode::NoContent);
+        }
+        if state.subscribers.len() >= state.max_subscribers {
+            return Response::error(StatusCode::ServiceUnavailable);
+        }
+        let backlog = match request.header("Last-Event-ID").map(str::trim) {
+            Some(last) => {
+                let seen = state
+                    .replay
+                    .iter()
+                    .position(|event| event.id.as_deref() == Some(last))
+                    .map_or(0, |i| i + 1);
+                state.replay.iter().skip(seen).cloned().collect()
+            }
+            None => Vec::new(),
+        };
+        let (sender, events) = mpsc::sync_channel(SUBSCRIBER_QUEUE);
+        let id = state.next_subscriber_id;
+        state.next_subscriber_id += 1;
+        state.subscribers.push((id, sender));
+        let mut stream = EventStream::new(events)
+            .keep_alive(self.keep_alive)
+            .backlog(backlog);
+        stream.subscription = Some(Subscription {
+            state: Arc::clone(&self.state),
+            id,
+        });
+        stream.into()
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.state.lock().unwrap().subscribers.len()
+    }
+
+    /// Ends every stream once it has sent what is queued for it, and turns
+    /// new subscribers away.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.subscribers.clear();
+    }
+}
+
+impl Default for EventChannel {
+    fn default() -> Self {
+        EventChannel::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    use crate::request::RequestReader;
+
+    fn request(headers: &str) -> Request {
+        let raw = format!("GET /events HTTP/1.1\r\n{}\r\n", headers);
+        RequestReader::new(raw.as_bytes())
+            .read_request()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn body(response: Response) -> String {
+        String::from_utf8(response.body.into_bytes().unwrap()).unwrap()
+    }
+
+    // Fails every write, like a socket whose client has gone.
+    struct Gone;
+
+    impl Write for Gone {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[test]
+    fn encodes_each_field() {
+        let event = Event::new("first\nsecond\r\nthird")
+            .with_event("up\ndate")
+            .with_id("7")
+            .with_retry(Duration::from_secs(3));
+        assert_eq!(
+            event.encode(),
+            "event: update\ndata: first\ndata: second\ndata: third\nid: 7\nretry: 3000\n\n"
+        );
+        assert_eq!(Event::new("").encode(), "data: \n\n");
+    }
+
+    #[test]
+    fn streams_events_until_the_senders_hang_up() {
+        let (sender, events) = mpsc::channel();
+        let response: Response = EventStream::new(events)
+            .keep_alive(Duration::from_millis(20))
+            .backlog(vec![Event::new("missed")])
+            .into();
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("text/event-stream")
+        );
+        sender.send(Event::new("one").with_event("tick")).unwrap();
+        let sending = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(60));
+            sender.send(Event::new("two")).unwrap();
+        });
+        let text = body(response);
+        sending.join().unwrap();
+        assert!(
+            text.starts_with("data: missed\n\nevent: tick\ndata: one\n\n:\n\n"),
+            "{:?}",
+            text
+        );
+        assert!(text.ends_with("data: two\n\n"), "{:?}", text);
+    }
+
+    #[test]
+    fn resumes_after_the_last_event_id() {
+        let channel = EventChannel::new().replay_capacity(3);
+        for data in ["a", "b", "c", "d"] {
+            channel.send(Event::new(data));
+        }
+        // Events 2 to 4 are still in the buffer.
+        let resumed = channel.subscribe(&request("Last-Event-ID: 2\r\n"));
+        let expired = channel.subscribe(&request("Last-Event-ID: 1\r\n"));
+        let fresh = channel.subscribe(&request(""));
+        assert_eq!(channel.subscribers(), 3);
+        channel.send(Event::new("e").with_id("custom"));
+        channel.close();
+
+        assert_eq!(
+            body(resumed),
+            "data: c\nid: 3\n\ndata: d\nid: 4\n\ndata: e\nid: custom\n\n"
+        );
+        assert!(body(expired).starts_with("data: b\nid: 2\n\n"));
+        assert_eq!(body(fresh), "data: e\nid: custom\n\n");
+        assert_eq!(
+            channel.subscribe(&request("")).status,
+            StatusCode::NoContent
+        );
+    }
+
+    #[test]
+    fn drops_subscribers_that_disconnect_or_fall_behind() {
+        let channel = EventChannel::new();
+        let gone = channel.subscribe(&request(""));
+        let _slow = channel.subscribe(&request(""));
+        assert_eq!(channel.subscribers(), 2);
+
+        let Body::Stream(stream) = gone.body else {
+            panic!("event streams are streamed");
+        };
+        assert!(stream(&mut Gone).is_err());
+        assert_eq!(channel.subscribers(), 1);
+
+        for i in 0..=SUBSCRIBER_QUEUE {
+            channel.send(Event::new(i.to_string()));
+        }
+        assert_eq!(channel.subscribers(), 0);
+    }
+
+    #[test]
+    fn turns_subscribers_away_once_full() {
+        let channel = EventChannel::new().max_subscribers(1);
+        let first = channel.subscribe(&request(""));
+        assert!(first.detached);
+        assert_eq!(
+            channel.subscribe(&request("")).status,
+            StatusCode::ServiceUnavailable
+        );
+        // The place is free again once the first stream ends.
+        drop(first);
+        assert_eq!(channel.subscribe(&request("")).status, StatusCode::Ok);
+    }
+}
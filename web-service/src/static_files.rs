@@ -0,0 +1,673 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::autoindex;
+use crate::compression::{self, Encoding};
+use crate::conditional::Validators;
+use crate::error::ServerError;
+use crate::range::{self, RangeRequest};
+use crate::request::{Method, Request};
+use crate::response::{Body, Response};
+use crate::status::StatusCode;
+
+const INDEX_FILE: &str = "index.html";
+
+/// Serves files from beneath a document root.
+///
+/// Request paths are resolved so that nothing outside the root can be
+/// reached, whether through `..` segments or through symlinks. Files carry
+/// `ETag` and `Last-Modified` validators, and `Cache-Control` is set from the
+/// first matching [`CacheRule`]. Directories without an `index.html` are
+/// not found unless listings are turned on for them.
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: PathBuf,
+    cache_rules: Vec<CacheRule>,
+    precompressed: bool,
+    autoindex: Vec<String>,
+}
+
+impl StaticFiles {
+    /// Fails if `root` does not exist or is not a directory.
+    pub fn new(root: impl AsRef<Path>) -> io::Result<StaticFiles> {
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("document root {} is not a directory", root.display()),
+            ));
+        }
+        Ok(StaticFiles {
+            root,
+            cache_rules: Vec::new(),
+            precompressed: false,
+            autoindex: Vec::new(),
+        })
+    }
+
+    /// Rules are tried in order and the first match sets `Cache-Control`.
+    pub fn cache_rules(mut self, rules: Vec<CacheRule>) -> Self {
+        self.cache_rules = rules;
+        self
+    }
+
+    /// Serves `style.css.gz` in place of `style.css`, with
+    /// `Content-Encoding: gzip`, to clients that accept gzip whenever the
+    /// sibling exists. Off by default.
+    pub fn precompressed(mut self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    /// Lists directories that have no `index.html` when their path under
+    /// the root is, or is beneath, one of `dirs`: `/` lists every
+    /// directory. Nothing is listed by default.
+    pub fn autoindex(mut self, dirs: Vec<String>) -> Self {
+        self.autoindex = dirs;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Maps an already percent-decoded request path onto a file under the
+    /// root, substituting `index.html` for directories.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, ResolveError> {
+        match self.locate(path)? {
+            Located::File(file) => Ok(file),
+            Located::Dir(_) => Err(ResolveError::NotFound),
+        }
+    }
+
+    // Like `resolve`, but hands back directories that have no index file.
+    fn locate(&self, path: &str) -> Result<Located, ResolveError> {
+        if path.contains('\0') {
+            return Err(ResolveError::Forbidden);
+        }
+        let mut candidate = self.root.clone();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(part) => candidate.push(part),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => return Err(ResolveError::Forbidden),
+            }
+        }
+
+        let mut resolved = fs::canonicalize(&candidate).map_err(|_| ResolveError::NotFound)?;
+        if !resolved.starts_with(&self.root) {
+            return Err(ResolveError::Forbidden);
+        }
+        if resolved.is_dir() {
+            let index = resolved.join(INDEX_FILE);
+            if !index.exists() {
+                return Ok(Located::Dir(resolved));
+            }
+            resolved = fs::canonicalize(index).map_err(|_| ResolveError::NotFound)?;
+            if !resolved.starts_with(&self.root) {
+                return Err(ResolveError::Forbidden);
+            }
+        }
+        if !resolved.is_file() {
+            return Err(ResolveError::NotFound);
+        }
+        Ok(Located::File(resolved))
+    }
+
+    pub fn serve(&self, path: &str) -> Response {
+        self.respond(path, None)
+    }
+
+    /// Like [`StaticFiles::serve`], but answers with 304 Not Modified when
+    /// the request's `If-None-Match` or `If-Modified-Since` allows it, and
+    /// with 206 Partial Content for a satisfiable `Range`.
+    pub fn serve_request(&self, request: &Request, path: &str) -> Response {
+        self.respond(path, Some(request))
+    }
+
+    fn respond(&self, path: &str, request: Option<&Request>) -> Response {
+        let file = match self.locate(path) {
+            Ok(Located::File(file)) => file,
+            Ok(Located::Dir(dir)) => return self.listing(&dir, path, request),
+            Err(ResolveError::NotFound) => return ServerError::NotFound.response(),
+            Err(ResolveError::Forbidden) => return Response::error(StatusCode::Forbidden),
+        };
+        let content_type = content_type(&file);
+        let gzipped = self.gzipped_sibling(&file);
+        let accepts_gzip = request.is_some_and(|request| {
+            compression::negotiate(request.header("Accept-Encoding"), &[Encoding::Gzip]).is_some()
+        });
+        let served = match &gzipped {
+            Some(gzipped) if accepts_gzip => gzipped,
+            _ => &file,
+        };
+        let metadata = match fs::metadata(served) {
+            Ok(metadata) => metadata,
+            Err(e) => return file_error(served, e),
+        };
+        let validators = Validators::for_file(&metadata);
+
+        let mut response = match request {
+            Some(request) if validators.not_modified(request) => {
+                Response::new(StatusCode::NotModified)
+            }
+            _ => match ranged_response(served, content_type, request, &validators) {
+                Ok(response) => response,
+                Err(e) => return file_error(served, e),
+            },
+        };
+        for (name, value) in validators.headers() {
+            response.headers.insert(name, value);
+        }
+        if gzipped.is_some() {
+            compression::add_vary(&mut response, "Accept-Encoding");
+            let encoded = matches!(response.status, StatusCode::Ok | StatusCode::PartialContent);
+            if served != &file && encoded {
+                response.headers.insert("Content-Encoding", "gzip");
+            }
+        }
+        if let Some(value) = self.cache_control(&file) {
+            response.headers.insert("Cache-Control", value);
+        }
+        response
+    }
+
+    fn listing(&self, dir: &Path, path: &str, request: Option<&Request>) -> Response {
+        let relative = dir.strip_prefix(&self.root).unwrap_or(dir);
+        let dir_path = format!("/{}", relative.to_string_lossy());
+        let listed = self.autoindex.iter().any(|prefix| {
+            let prefix = prefix.trim_end_matches('/');
+            dir_path == prefix || dir_path.starts_with(&format!("{}/", prefix))
+        });
+        if !listed {
+            return ServerError::NotFound.response();
+        }
+
+        let mut url_path = String::from("/");
+        for component in Path::new(path).components() {
+            if let Component::Normal(part) = component {
+                url_path.push_str(&part.to_string_lossy());
+                url_path.push('/');
+            }
+        }
+        autoindex::response(dir, &self.root, &url_path, request)
+            .unwrap_or_else(|e| file_error(dir, e))
+    }
+
+    // The `.gz` file stored next to a resolved file, if precompressed
+    // files are enabled and it exists within the root.
+    fn gzipped_sibling(&self, file: &Path) -> Option<PathBuf> {
+        if !self.precompressed {
+            return None;
+        }
+        let mut name = file.as_os_str().to_owned();
+        name.push(".gz");
+        let sibling = fs::canonicalize(name).ok()?;
+        (sibling.starts_with(&self.root) && sibling.is_file()).then_some(sibling)
+    }
+
+    // The `Cache-Control` value for a resolved file, if any rule matches
+    // its path under the root.
+    fn cache_control(&self, file: &Path) -> Option<&str> {
+        let relative = file.strip_prefix(&self.root).ok()?;
+        let path = format!("/{}", relative.to_string_lossy());
+        self.cache_rules
+            .iter()
+            .find(|rule| rule.matches(&path))
+            .map(|rule| rule.value.as_str())
+    }
+}
+
+// The whole file, or just the parts a `Range` header asks for.
+fn ranged_response(
+    path: &Path,
+    content_type: &str,
+    request: Option<&Request>,
+    validators: &Validators,
+) -> io::Result<Response> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let range = request
+        .filter(|request| request.method == Method::Get && validators.if_range_matches(request))
+        .and_then(|request| request.header("Range"))
+        .map_or(RangeRequest::Ignore, |header| {
+            range::parse_range(header, len)
+        });
+
+    let response = match range {
+        RangeRequest::Ignore => Response::ok()
+            .with_header("Content-Type", content_type)
+            .with_body(Body::File { file, len }),
+        RangeRequest::Unsatisfiable => Response::error(StatusCode::RangeNotSatisfiable)
+            .with_header("Content-Range", format!("bytes */{}", len)),
+        RangeRequest::Ranges(ranges) if ranges.len() == 1 => {
+            Response::new(StatusCode::PartialContent)
+                .with_header("Content-Type", content_type)
+                .with_header("Content-Range", ranges[0].content_range(len))
+                .with_body(range::single_range_body(file, ranges[0])?)
+        }
+        RangeRequest::Ranges(ranges) => {
+            let boundary = range::boundary();
+            let (body, body_len) =
+                range::multipart_body(file, ranges, content_type, len, &boundary);
+            Response::new(StatusCode::PartialContent)
+                .with_header(
+                    "Content-Type",
+                    format!("multipart/byteranges; boundary={}", boundary),
+                )
+                .with_header("Content-Length", body_len.to_string())
+                .with_body(body)
+        }
+    };
+    Ok(response.with_header("Accept-Ranges", "bytes"))
+}
+
+fn file_error(file: &Path, e: io::Error) -> Response {
+    eprintln!("failed to open {}: {}", file.display(), e);
+    ServerError::from(e).response()
+}
+
+/// Sets `Cache-Control` on files whose path under the document root matches
+/// `path`.
+///
+/// A pattern such as `*.css` matches the end of the path; anything else is
+/// a prefix, so `/assets/` covers everything beneath that directory. Paths
+/// are those of the files actually served, so a request for `/docs/` is
+/// matched as `/docs/index.html`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheRule {
+    pub path: String,
+    pub value: String,
+}
+
+impl CacheRule {
+    pub fn new(path: impl Into<String>, value: impl Into<String>) -> CacheRule {
+        CacheRule {
+            path: path.into(),
+            value: value.into(),
+        }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        match self.path.strip_prefix('*') {
+            Some(suffix) => path.ends_with(suffix),
+            None => path.starts_with(&self.path),
+        }
+    }
+}
+
+enum Located {
+    File(PathBuf),
+    /// A directory with no index file.
+    Dir(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolveError {
+    NotFound,
+    Forbidden,
+}
+
+/// Builds a response that streams `path` with the given status.
+pub fn file_response(status: StatusCode, path: &Path) -> io::Result<Response> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    Ok(Response::new(status)
+        .with_header("Content-Type", content_type(path))
+        .with_body(Body::File { file, len }))
+}
+
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json" | "map") => "application/json",
+        Some("txt" | "log") => "text/plain; charset=utf-8",
+        Some("md") => "text/markdown; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        Some("tar") => "application/x-tar",
+        Some("mp3") => "audio/mpeg",
+        Some("mp4") => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::symlink;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn scratch_dir() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!(
+            "web-service-static-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn site() -> (PathBuf, StaticFiles) {
+        let dir = scratch_dir();
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("index.html"), "<h1>home</h1>").unwrap();
+        fs::write(root.join("docs/index.html"), "docs").unwrap();
+        fs::write(root.join("docs/guide.txt"), "guide").unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        (dir, StaticFiles::new(&root).unwrap())
+    }
+
+    fn body(response: Response) -> Vec<u8> {
+        response.body.into_bytes().unwrap()
+    }
+
+    #[test]
+    fn serves_files_with_content_type() {
+        let (_dir, files) = site();
+        let response = files.serve("docs/guide.txt");
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(
+            response.headers.get("content-type"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert!(matches!(response.body, Body::File { len: 5, .. }));
+        assert_eq!(body(response), b"guide");
+    }
+
+    #[test]
+    fn serves_index_for_directories() {
+        let (_dir, files) = site();
+        assert_eq!(body(files.serve("")), b"<h1>home</h1>");
+        assert_eq!(body(files.serve("docs/")), b"docs");
+        assert_eq!(body(files.serve("/docs")), b"docs");
+    }
+
+    #[test]
+    fn blocks_parent_traversal() {
+        let (_dir, files) = site();
+        assert_eq!(files.resolve("../secret.txt"), Err(ResolveError::Forbidden));
+        assert_eq!(
+            files.resolve("docs/../../secret.txt"),
+            Err(ResolveError::Forbidden)
+        );
+        assert_eq!(files.resolve("docs/\0"), Err(ResolveError::Forbidden));
+        assert_eq!(files.serve("missing.html").status, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn blocks_percent_encoded_traversal_through_the_router() {
+        use crate::request::RequestReader;
+        use crate::router::Router;
+
+        let (_dir, files) = site();
+        let router = Router::new().get("/static/*path", move |req| {
+            files.serve(req.param("path").unwrap_or(""))
+        });
+        // The first resolves to /secret.txt, outside the route; the second
+        // hides a slash in a segment.
+        for (target, status) in [
+            ("/static/%2e%2e/secret.txt", StatusCode::NotFound),
+            (
+                "/static/docs%2F..%2F..%2Fsecret.txt",
+                StatusCode::BadRequest,
+            ),
+        ] {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            let request = RequestReader::new(raw.as_bytes())
+                .read_request()
+                .unwrap()
+                .unwrap();
+            assert_eq!(router.handle(request).status, status, "{}", target);
+        }
+    }
+
+    #[test]
+    fn blocks_symlinks_that_escape_the_root() {
+        let (dir, files) = site();
+        symlink(dir.join("secret.txt"), files.root().join("leak.txt")).unwrap();
+        symlink(&dir, files.root().join("up")).unwrap();
+        symlink("docs/guide.txt", files.root().join("inside.txt")).unwrap();
+
+        assert_eq!(files.resolve("leak.txt"), Err(ResolveError::Forbidden));
+        assert_eq!(files.resolve("up/secret.txt"), Err(ResolveError::Forbidden));
+        assert_eq!(body(files.serve("inside.txt")), b"guide");
+    }
+
+    #[test]
+    fn answers_conditional_requests() {
+        use crate::request::RequestReader;
+
+        let (_dir, files) = site();
+        let files = files.cache_rules(vec![
+            CacheRule::new("*.html", "no-cache"),
+            CacheRule::new("/docs/", "public, max-age=3600"),
+        ]);
+        let first = files.serve("docs/guide.txt");
+        let etag = first.headers.get("ETag").unwrap().to_string();
+        let modified = first.headers.get("Last-Modified").unwrap().to_string();
+        assert_eq!(
+            first.headers.get("Cache-Control"),
+            Some("public, max-age=3600")
+        );
+        assert_eq!(
+            files.serve("").headers.get("Cache-Control"),
+            Some("no-cache")
+        );
+
+        let get = |headers: String| {
+            let raw = format!("GET /docs/guide.txt HTTP/1.1\r\n{}\r\n", headers);
+            let request = RequestReader::new(raw.as_bytes())
+                .read_request()
+                .unwrap()
+                .unwrap();
+            files.serve_request(&request, "docs/guide.txt")
+        };
+        let cached = get(format!("If-None-Match: {}\r\n", etag));
+        assert_eq!(cached.status, StatusCode::NotModified);
+        assert_eq!(cached.headers.get("ETag"), Some(etag.as_str()));
+        assert_eq!(
+            cached.headers.get("Cache-Control"),
+            Some("public, max-age=3600")
+        );
+        assert!(cached.body.is_empty());
+
+        let cached = get(format!("If-Modified-Since: {}\r\n", modified));
+        assert_eq!(cached.status, StatusCode::NotModified);
+        let changed = get("If-None-Match: \"stale\"\r\n".to_string());
+        assert_eq!(changed.status, StatusCode::Ok);
+        assert_eq!(body(changed), b"guide");
+    }
+
+    fn get_with(files: &StaticFiles, path: &str, headers: &str) -> Response {
+        use crate::request::RequestReader;
+
+        let raw = format!("GET /{} HTTP/1.1\r\n{}\r\n", path, headers);
+        let request = RequestReader::new(raw.as_bytes())
+            .read_request()
+            .unwrap()
+            .unwrap();
+        files.serve_request(&request, path)
+    }
+
+    #[test]
+    fn serves_byte_ranges() {
+        let (_dir, files) = site();
+        fs::write(files.root().join("digits.txt"), "0123456789").unwrap();
+
+        let full = get_with(&files, "digits.txt", "");
+        assert_eq!(full.headers.get("Accept-Ranges"), Some("bytes"));
+
+        let part = get_with(&files, "digits.txt", "Range: bytes=2-4\r\n");
+        assert_eq!(part.status, StatusCode::PartialContent);
+        assert_eq!(part.headers.get("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(body(part), b"234");
+
+        let tail = get_with(&files, "digits.txt", "Range: bytes=-3\r\n");
+        assert_eq!(body(tail), b"789");
+
+        let unsatisfiable = get_with(&files, "digits.txt", "Range: bytes=10-\r\n");
+        assert_eq!(unsatisfiable.status, StatusCode::RangeNotSatisfiable);
+        assert_eq!(
+            unsatisfiable.headers.get("Content-Range"),
+            Some("bytes */10")
+        );
+    }
+
+    #[test]
+    fn serves_multiple_ranges_as_multipart() {
+        let (_dir, files) = site();
+        fs::write(files.root().join("digits.txt"), "0123456789").unwrap();
+
+        let response = get_with(&files, "digits.txt", "Range: bytes=0-1,8-\r\n");
+        assert_eq!(response.status, StatusCode::PartialContent);
+        let content_type = response.headers.get("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+        let declared: usize = response
+            .headers
+            .get("Content-Length")
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body = String::from_utf8(body(response)).unwrap();
+        assert_eq!(body.len(), declared);
+        assert_eq!(
+            body,
+            format!(
+                "--{b}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+                 --{b}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n\
+                 --{b}--\r\n",
+                b = boundary
+            )
+        );
+    }
+
+    #[test]
+    fn if_range_falls_back_to_the_whole_file() {
+        let (_dir, files) = site();
+        let etag = files
+            .serve("docs/guide.txt")
+            .headers
+            .get("ETag")
+            .unwrap()
+            .to_string();
+
+        let current = format!("Range: bytes=0-1\r\nIf-Range: {}\r\n", etag);
+        let response = get_with(&files, "docs/guide.txt", &current);
+        assert_eq!(response.status, StatusCode::PartialContent);
+        assert_eq!(body(response), b"gu");
+
+        let stale = "Range: bytes=0-1\r\nIf-Range: \"stale\"\r\n";
+        let response = get_with(&files, "docs/guide.txt", stale);
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(body(response), b"guide");
+    }
+
+    #[test]
+    fn serves_precompressed_siblings() {
+        let (_dir, files) = site();
+        let files = files.precompressed(true);
+        fs::write(files.root().join("docs/guide.txt.gz"), "not really gzip").unwrap();
+
+        let gzipped = get_with(&files, "docs/guide.txt", "Accept-Encoding: br, gzip\r\n");
+        assert_eq!(gzipped.status, StatusCode::Ok);
+        assert_eq!(gzipped.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(gzipped.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(
+            gzipped.headers.get("Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(body(gzipped), b"not really gzip");
+
+        let plain = get_with(&files, "docs/guide.txt", "Accept-Encoding: gzip;q=0\r\n");
+        assert!(!plain.headers.contains("Content-Encoding"));
+        assert_eq!(plain.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(body(plain), b"guide");
+
+        // Without a sibling, or with the option off, nothing changes.
+        let index = get_with(&files, "docs/", "Accept-Encoding: gzip\r\n");
+        assert!(!index.headers.contains("Vary"));
+        let files = files.precompressed(false);
+        let off = get_with(&files, "docs/guide.txt", "Accept-Encoding: gzip\r\n");
+        assert!(!off.headers.contains("Content-Encoding"));
+        assert_eq!(body(off), b"guide");
+    }
+
+    #[test]
+    fn lists_only_enabled_directories() {
+        let (_dir, files) = site();
+        let root = files.root().to_path_buf();
+        fs::create_dir_all(root.join("pub/nested")).unwrap();
+        fs::write(root.join("pub/a.txt"), "aaa").unwrap();
+        fs::write(root.join("pub/.hidden"), "").unwrap();
+        fs::create_dir_all(root.join("private")).unwrap();
+
+        assert_eq!(files.serve("pub/").status, StatusCode::NotFound);
+        let files = files.autoindex(vec!["/pub".to_string()]);
+        assert_eq!(files.serve("private").status, StatusCode::NotFound);
+        assert_eq!(files.serve("pub/nested/").status, StatusCode::Ok);
+        // Directories with an index are served as before.
+        assert_eq!(body(files.serve("docs/")), b"docs");
+
+        let html = String::from_utf8(body(files.serve("pub"))).unwrap();
+        assert!(
+            html.contains("<a href=\"/pub/a.txt\">a.txt</a>"),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("<a href=\"/pub/nested/\">nested/</a>"),
+            "{}",
+            html
+        );
+        assert!(!html.contains("hidden"), "{}", html);
+
+        let json = get_with(&files, "pub/", "Accept: application/json\r\n");
+        assert_eq!(json.headers.get("Content-Type"), Some("application/json"));
+        assert_eq!(json.headers.get("Vary"), Some("Accept"));
+        let json = String::from_utf8(body(json)).unwrap();
+        assert!(
+            json.starts_with("{\"path\":\"/pub/\",\"entries\":[{\"name\":\"nested\""),
+            "{}",
+            json
+        );
+    }
+
+    #[test]
+    fn maps_extensions_to_mime_types() {
+        assert_eq!(content_type(Path::new("a.CSS")), "text/css; charset=utf-8");
+        assert_eq!(content_type(Path::new("a.png")), "image/png");
+        assert_eq!(
+            content_type(Path::new("Makefile")),
+            "application/octet-stream"
+        );
+    }
+}
@@ -0,0 +1,942 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+// Deep enough for any real layout, shallow enough to stop a template that
+// includes itself.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// A value that templates can interpolate, test and loop over.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Str(String),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+    /// False for null, `false`, and empty strings, lists and maps.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Bool(b) => *b,
+            Value::Str(s) => !s.is_empty(),
+            Value::List(items) => !items.is_empty(),
+            Value::Map(map) => !map.is_empty(),
+        }
+    }
+
+    // A map field, or a list element by index.
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(map) => map.get(key),
+            Value::List(items) => key.parse().ok().and_then(|i: usize| items.get(i)),
+            _ => None,
+        }
+    }
+
+    // Lists and maps have no text of their own.
+    fn text(&self) -> Cow<'_, str> {
+        match self {
+            Value::Str(s) => Cow::Borrowed(s),
+            Value::Bool(b) => Cow::Owned(b.to_string()),
+            Value::Null | Value::List(_) | Value::Map(_) => Cow::Borrowed(""),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Str(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        Value::Str(n.to_string())
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Str(n.to_string())
+    }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Self {
+        Value::Str(n.to_string())
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Self {
+        Value::List(items.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl From<Context> for Value {
+    fn from(context: Context) -> Self {
+        Value::Map(context.values)
+    }
+}
+
+/// The named values a template is rendered with.
+///
+/// Also the easiest way to build a map value:
+/// `Context::new().with("name", "a").with("size", 3_u64)`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Context {
+    values: BTreeMap<String, Value>,
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, name: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.values.insert(name.into(), value.into());
+        self
+    }
+}
+
+/// A compiled template.
+///
+/// The syntax is a small subset of Jinja:
+///
+/// - `{{ user.name }}` interpolates a value, HTML-escaped; `{{ html | raw }}`
+///   skips the escaping.
+/// - `{% if path %}…{% else %}…{% endif %}` tests a value's truthiness;
+///   `{% if not path %}` negates it.
+/// - `{% for item in items %}…{% endfor %}` repeats for each element, with
+///   `loop.index` (from 1), `loop.first` and `loop.last` in scope.
+/// - `{% include "header.html" %}` renders another template in place, with
+///   the same values.
+/// - `{# … #}` is a comment.
+///
+/// A newline straight after a `{% … %}` tag is dropped, so tags on lines of
+/// their own leave no blank lines behind. Missing values render as nothing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    name: String,
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Var {
+        path: Vec<String>,
+        raw: bool,
+    },
+    If {
+        path: Vec<String>,
+        negate: bool,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        name: String,
+        path: Vec<String>,
+        body: Vec<Node>,
+    },
+    Include(String),
+}
+
+impl Template {
+    /// Parses `source`; `name` is only used in error messages.
+    pub fn compile(name: &str, source: &str) -> Result<Template, TemplateError> {
+        let tokens = tokenize(name, source)?;
+        let mut parser = Parser {
+            name,
+            tokens: tokens.into_iter(),
+        };
+        let (nodes, end) = parser.block(&[])?;
+        debug_assert!(end.is_none());
+        Ok(Template {
+            name: name.to_string(),
+            nodes,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // The names of the templates this one includes, at any depth of its
+    // blocks.
+    fn includes(&self) -> Vec<&str> {
+        fn walk<'a>(nodes: &'a [Node], names: &mut Vec<&'a str>) {
+            for node in nodes {
+                match node {
+                    Node::Include(name) => names.push(name),
+                    Node::If {
+                        then, otherwise, ..
+                    } => {
+                        walk(then, names);
+                        walk(otherwise, names);
+                    }
+                    Node::For { body, .. } => walk(body, names),
+                    Node::Text(_) | Node::Var { .. } => {}
+                }
+            }
+        }
+        let mut names = Vec::new();
+        walk(&self.nodes, &mut names);
+        names
+    }
+
+    /// Renders a template that includes no others; see
+    /// [`Templates::render`] for those that do.
+    pub fn render(&self, context: &Context) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        Renderer {
+            templates: None,
+            depth: 0,
+        }
+        .render(self, &mut Scope::new(context), &mut out)?;
+        Ok(out)
+    }
+}
+
+#[derive(Debug)]
+enum Token {
+    Text(String),
+    Expr { source: String, line: usize },
+    Tag { source: String, line: usize },
+}
+
+fn tokenize(name: &str, source: &str) -> Result<Vec<Token>, TemplateError> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    let mut line = 1;
+    while let Some(start) = find_open(rest) {
+        if start > 0 {
+            tokens.push(Token::Text(rest[..start].to_string()));
+        }
+        line += rest[..start].matches('\n').count();
+        let kind = rest.as_bytes()[start + 1];
+        let close = match kind {
+            b'{' => "}}",
+            b'%' => "%}",
+            _ => "#}",
+        };
+        let inner_start = start + 2;
+        let Some(len) = rest[inner_start..].find(close) else {
+            return Err(syntax(
+                name,
+                line,
+                format!("unclosed {}", &rest[start..inner_start]),
+            ));
+        };
+        let inner = rest[inner_start..inner_start + len].trim().to_string();
+        let token_line = line;
+        line += rest[inner_start..inner_start + len].matches('\n').count();
+        rest = &rest[inner_start + len + 2..];
+        match kind {
+            b'{' => tokens.push(Token::Expr {
+                source: inner,
+                line: token_line,
+            }),
+            b'%' => {
+                tokens.push(Token::Tag {
+                    source: inner,
+                    line: token_line,
+                });
+                if let Some(after) = rest
+                    .strip_prefix("\r\n")
+                    .or_else(|| rest.strip_prefix('\n'))
+                {
+                    rest = after;
+                    line += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Text(rest.to_string()));
+    }
+    Ok(tokens)
+}
+
+// The offset of the next `{{`, `{%` or `{#`.
+fn find_open(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    text.match_indices('{')
+        .map(|(i, _)| i)
+        .find(|&i| matches!(bytes.get(i + 1), Some(b'{' | b'%' | b'#')))
+}
+
+struct Parser<'a> {
+    name: &'a str,
+    tokens: std::vec::IntoIter<Token>,
+}
+
+impl Parser<'_> {
+    // Parses nodes up to one of the `until` tags, which is returned along
+    // with them. An empty `until` reads to the end of the template.
+    fn block(&mut self, until: &[&str]) -> Result<(Vec<Node>, Option<String>), TemplateError> {
+        let mut nodes = Vec::new();
+        while let Some(token) = self.tokens.next() {
+            match token {
+                Token::Text(text) => nodes.push(Node::Text(text)),
+                Token::Expr { source, line } => nodes.push(self.expr(&source, line)?),
+                Token::Tag { source, line } => {
+                    let words: Vec<&str> = source.split_whitespace().collect();
+                    let keyword = words.first().copied().unwrap_or("");
+                    if until.contains(&keyword) {
+                        if words.len() > 1 {
+                            return Err(self.error(line, format!("unexpected {:?}", source)));
+                        }
+                        return Ok((nodes, Some(keyword.to_string())));
+                    }
+                    nodes.push(self.tag(&words, line)?);
+                }
+            }
+        }
+        match until.last() {
+            None => Ok((nodes, None)),
+            Some(end) => Err(self.error(0, format!("missing {{% {} %}}", end))),
+        }
+    }
+
+    fn expr(&self, source: &str, line: usize) -> Result<Node, TemplateError> {
+        let mut parts = source.split('|').map(str::trim);
+        let path = self.path(parts.next().unwrap_or(""), line)?;
+        let mut raw = false;
+        for filter in parts {
+            match filter {
+                "raw" => raw = true,
+                _ => return Err(self.error(line, format!("unknown filter {:?}", filter))),
+            }
+        }
+        Ok(Node::Var { path, raw })
+    }
+
+    fn tag(&mut self, words: &[&str], line: usize) -> Result<Node, TemplateError> {
+        match words {
+            ["if", rest @ ..] => {
+                let (negate, path) = match rest {
+                    ["not", path] => (true, path),
+                    [path] => (false, path),
+                    _ => return Err(self.error(line, "expected {% if [not] value %}")),
+                };
+                let path = self.path(path, line)?;
+                let (then, end) = self
+                    .block(&["else", "endif"])
+                    .map_err(|e| self.at_line(e, line))?;
+                let otherwise = match end.as_deref() {
+                    Some("else") => self.block(&["endif"]).map_err(|e| self.at_line(e, line))?.0,
+                    _ => Vec::new(),
+                };
+                Ok(Node::If {
+                    path,
+                    negate,
+                    then,
+                    otherwise,
+                })
+            }
+            ["for", name, "in", path] => {
+                if !is_identifier(name) || *name == "loop" {
+                    return Err(self.error(line, format!("{:?} cannot name a loop variable", name)));
+                }
+                let path = self.path(path, line)?;
+                let (body, _) = self.block(&["endfor"]).map_err(|e| self.at_line(e, line))?;
+                Ok(Node::For {
+                    name: name.to_string(),
+                    path,
+                    body,
+                })
+            }
+            ["include", name] => match name.strip_prefix('"').and_then(|n| n.strip_suffix('"')) {
+                Some(name) if !name.is_empty() => Ok(Node::Include(name.to_string())),
+                _ => Err(self.error(line, "expected {% include \"name\" %}")),
+            },
+            _ => Err(self.error(line, format!("unknown tag {:?}", words.join(" ")))),
+        }
+    }
+
+    fn path(&self, text: &str, line: usize) -> Result<Vec<String>, TemplateError> {
+        let parts: Vec<String> = text.split('.').map(str::to_string).collect();
+        if parts.iter().all(|part| is_identifier(part)) {
+            Ok(parts)
+        } else {
+            Err(self.error(line, format!("{:?} is not a value name", text)))
+        }
+    }
+
+    fn error(&self, line: usize, message: impl Into<String>) -> TemplateError {
+        syntax(self.name, line, message.into())
+    }
+
+    // Blocks left open are reported at the tag that opened them.
+    fn at_line(&self, error: TemplateError, line: usize) -> TemplateError {
+        match error {
+            TemplateError::Syntax {
+                name,
+                line: 0,
+                message,
+            } => TemplateError::Syntax {
+                name,
+                line,
+                message,
+            },
+            e => e,
+        }
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn syntax(name: &str, line: usize, message: String) -> TemplateError {
+    TemplateError::Syntax {
+        name: name.to_string(),
+        line,
+        message,
+    }
+}
+
+// The context plus the loop variables currently in scope, innermost last.
+struct Scope<'a> {
+    context: &'a Context,
+    locals: Vec<(String, Value)>,
+}
+
+impl<'a> Scope<'a> {
+    fn new(context: &'a Context) -> Self {
+        Scope {
+            context,
+            locals: Vec::new(),
+        }
+    }
+
+    fn lookup(&self, path: &[String]) -> Option<&Value> {
+        let (first, rest) = path.split_first()?;
+        let start = self
+            .locals
+            .iter()
+            .rev()
+            .find(|(name, _)| name == first)
+            .map(|(_, value)| value)
+            .or_else(|| self.context.values.get(first))?;
+        rest.iter().try_fold(start, |value, key| value.get(key))
+    }
+}
+
+struct Renderer<'a> {
+    templates: Option<&'a Templates>,
+    depth: usize,
+}
+
+impl Renderer<'_> {
+    fn render(
+        &self,
+        template: &Template,
+        scope: &mut Scope,
+        out: &mut String,
+    ) -> Result<(), TemplateError> {
+        self.nodes(&template.nodes, scope, out)
+    }
+
+    fn nodes(
+        &self,
+        nodes: &[Node],
+        scope: &mut Scope,
+        out: &mut String,
+    ) -> Result<(), TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Var { path, raw } => {
+                    let value = scope.lookup(path).map(Value::text).unwrap_or_default();
+                    if *raw {
+                        out.push_str(&value);
+                    } else {
+                        escape_html_into(&value, out);
+                    }
+                }
+                Node::If {
+                    path,
+                    negate,
+                    then,
+                    otherwise,
+                } => {
+                    let truthy = scope.lookup(path).is_some_and(Value::is_truthy);
+                    let branch = if truthy != *negate { then } else { otherwise };
+                    self.nodes(branch, scope, out)?;
+                }
+                Node::For { name, path, body } => {
+                    let items = match scope.lookup(path) {
+                        Some(Value::List(items)) => items.clone(),
+                        _ => Vec::new(),
+                    };
+                    let count = items.len();
+                    for (i, item) in items.into_iter().enumerate() {
+                        let status = Context::new()
+                            .with("index", i + 1)
+                            .with("first", i == 0)
+                            .with("last", i + 1 == count);
+                        scope.locals.push(("loop".to_string(), status.into()));
+                        scope.locals.push((name.clone(), item));
+                        let rendered = self.nodes(body, scope, out);
+                        scope.locals.truncate(scope.locals.len() - 2);
+                        rendered?;
+                    }
+                }
+                Node::Include(name) => self.include(name, scope, out)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn include(
+        &self,
+        name: &str,
+        scope: &mut Scope,
+        out: &mut String,
+    ) -> Result<(), TemplateError> {
+        let Some(templates) = self.templates else {
+            return Err(TemplateError::Include {
+                name: name.to_string(),
+                reason: "no template directory to include from".to_string(),
+            });
+        };
+        if self.depth >= MAX_INCLUDE_DEPTH {
+            return Err(TemplateError::Include {
+                name: name.to_string(),
+                reason: format!("includes nested more than {} deep", MAX_INCLUDE_DEPTH),
+            });
+        }
+        let template = templates.get(name)?;
+        Renderer {
+            templates: self.templates,
+            depth: self.depth + 1,
+        }
+        .render(&template, scope, out)
+    }
+}
+
+/// Escapes text for use in HTML content and quoted attribute values.
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    escape_html_into(text, &mut out);
+    out
+}
+
+fn escape_html_into(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// The templates in one directory, compiled on first use and cached.
+///
+/// With reloading on, each use checks the file's modification time and
+/// recompiles it when it has changed, so pages can be edited while the
+/// server runs. Clones share the cache.
+#[derive(Debug, Clone)]
+pub struct Templates {
+    dir: PathBuf,
+    reload: bool,
+    cache: Arc<Mutex<HashMap<String, Compiled>>>,
+}
+
+#[derive(Debug, Clone)]
+struct Compiled {
+    template: Arc<Template>,
+    modified: Option<SystemTime>,
+}
+
+impl Templates {
+    /// Fails if `dir` does not exist or is not a directory.
+    pub fn new(dir: impl AsRef<Path>) -> io::Result<Templates> {
+        let dir = fs::canonicalize(dir)?;
+        if !dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("template directory {} is not a directory", dir.display()),
+            ));
+        }
+        Ok(Templates {
+            dir,
+            reload: false,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Recompiles templates whose files have changed. Off by default.
+    pub fn reload(mut self, enabled: bool) -> Self {
+        self.reload = enabled;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Compiles `name` now, so that a missing file or a syntax error shows
+    /// up at startup rather than on the first request.
+    pub fn load(&self, name: &str) -> Result<(), TemplateError> {
+        self.get(name).map(drop)
+    }
+
+    /// Compiles every file under the directory, partials included, and
+    /// checks that each include names one of them. Returns the names
+    /// compiled. Files and directories starting with `.` are skipped.
+    pub fn load_all(&self) -> Result<Vec<String>, TemplateError> {
+        let mut names = Vec::new();
+        self.find(Path::new(""), &mut names)?;
+        names.sort();
+        for name in &names {
+            let template = self.get(name)?;
+            for include in template.includes() {
+                if !names.iter().any(|name| name == include) {
+                    return Err(TemplateError::Include {
+                        name: include.to_string(),
+                        reason: format!("included by {} but not found", name),
+                    });
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    // Adds the names of the files under `relative` to `names`.
+    fn find(&self, relative: &Path, names: &mut Vec<String>) -> Result<(), TemplateError> {
+        let io_error = |error| TemplateError::Io {
+            name: relative.display().to_string(),
+            error,
+        };
+        for entry in fs::read_dir(self.dir.join(relative)).map_err(io_error)? {
+            let entry = entry.map_err(io_error)?;
+            let file_name = entry.file_name();
+            if file_name.to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = relative.join(&file_name);
+            if entry.file_type().map_err(io_error)?.is_dir() {
+                self.find(&path, names)?;
+            } else {
+                let name = path.to_str().ok_or_else(|| {
+                    TemplateError::InvalidName(path.to_string_lossy().into_owned())
+                })?;
+                names.push(name.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// The compiled template `name`, a path relative to the directory.
+    pub fn get(&self, name: &str) -> Result<Arc<Template>, TemplateError> {
+        let cached = self.cache.lock().unwrap().get(name).cloned();
+        if let Some(cached) = &cached {
+            if !self.reload {
+                return Ok(cached.template.clone());
+            }
+        }
+
+        let path = self.path(name)?;
+        let io_error = |error| TemplateError::Io {
+            name: name.to_string(),
+            error,
+        };
+        let modified = fs::metadata(&path).map_err(io_error)?.modified().ok();
+        if let Some(cached) = cached {
+            if modified.is_some() && cached.modified == modified {
+                return Ok(cached.template);
+            }
+        }
+        let source = fs::read_to_string(&path).map_err(io_error)?;
+        let template = Arc::new(Template::compile(name, &source)?);
+        self.cache.lock().unwrap().insert(
+            name.to_string(),
+            Compiled {
+                template: template.clone(),
+                modified,
+            },
+        );
+        Ok(template)
+    }
+
+    /// Renders `name`, resolving its includes from the same directory.
+    pub fn render(&self, name: &str, context: &Context) -> Result<String, TemplateError> {
+        let template = self.get(name)?;
+        let mut out = String::new();
+        Renderer {
+            templates: Some(self),
+            depth: 0,
+        }
+        .render(&template, &mut Scope::new(context), &mut out)?;
+        Ok(out)
+    }
+
+    // Names may not climb out of the directory.
+    fn path(&self, name: &str) -> Result<PathBuf, TemplateError> {
+        let mut path = self.dir.clone();
+        for component in Path::new(name).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                _ => return Err(TemplateError::InvalidName(name.to_string())),
+            }
+        }
+        if path == self.dir {
+            return Err(TemplateError::InvalidName(name.to_string()));
+        }
+        Ok(path)
+    }
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    Io {
+        name: String,
+        error: io::Error,
+    },
+    /// `line` is 1-based.
+    Syntax {
+        name: String,
+        line: usize,
+        message: String,
+    },
+    InvalidName(String),
+    Include {
+        name: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Io { name, error } => {
+                write!(f, "cannot read template {}: {}", name, error)
+            }
+            TemplateError::Syntax {
+                name,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", name, line, message),
+            TemplateError::InvalidName(name) => write!(f, "invalid template name {:?}", name),
+            TemplateError::Include { name, reason } => {
+                write!(f, "cannot include {}: {}", name, reason)
+            }
+        }
+    }
+}
+
+impl Error for TemplateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TemplateError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::File;
+    use std::time::Duration;
+
+    fn render(source: &str, context: &Context) -> String {
+        Template::compile("test", source)
+            .unwrap()
+            .render(context)
+            .unwrap()
+    }
+
+    fn syntax_line(source: &str) -> usize {
+        match Template::compile("test", source) {
+            Err(TemplateError::Syntax { line, .. }) => line,
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn interpolates_and_escapes() {
+        let context = Context::new()
+            .with("path", "/<script>&\"'")
+            .with("user", Context::new().with("name", "Ada"))
+            .with("count", 3_u64);
+        assert_eq!(
+            render(
+                "{{ path }} {{path|raw}} {{ user.name }} {{ count }}{{ missing.x }}",
+                &context
+            ),
+            "/&lt;script&gt;&amp;&quot;&#39; /<script>&\"' Ada 3"
+        );
+        assert_eq!(render("{# note #}a { b } {c", &context), "a { b } {c");
+    }
+
+    #[test]
+    fn renders_conditionals() {
+        let source = "{% if user %}hi {{ user }}{% else %}nobody{% endif %}|{% if not items %}empty{% endif %}";
+        let context = Context::new()
+            .with("user", "bo")
+            .with("items", Vec::<Value>::new());
+        assert_eq!(render(source, &context), "hi bo|empty");
+        assert_eq!(
+            render(source, &Context::new().with("user", "")),
+            "nobody|empty"
+        );
+    }
+
+    #[test]
+    fn renders_loops() {
+        let files = vec![
+            Context::new().with("name", "a.txt"),
+            Context::new().with("name", "b<.txt"),
+        ];
+        let source = "<ul>\n{% for file in files %}\n<li>{{ loop.index }} {{ file.name }}{% if not loop.last %},{% endif %}</li>\n{% endfor %}\n</ul>";
+        assert_eq!(
+            render(source, &Context::new().with("files", files)),
+            "<ul>\n<li>1 a.txt,</li>\n<li>2 b&lt;.txt</li>\n</ul>"
+        );
+        let nested =
+            "{% for row in rows %}{% for cell in row %}{{ cell }}{% endfor %};{% endfor %}";
+        let rows = vec![vec!["1", "2"], vec!["3"]];
+        assert_eq!(render(nested, &Context::new().with("rows", rows)), "12;3;");
+    }
+
+    #[test]
+    fn reports_syntax_errors_with_lines() {
+        assert_eq!(syntax_line("a\n{{ oops"), 2);
+        assert_eq!(syntax_line("\n\n{% if x %}\nunfinished"), 3);
+        assert_eq!(syntax_line("{% endif %}"), 1);
+        assert_eq!(syntax_line("a\nb\n{{ x | shout }}"), 3);
+        assert_eq!(syntax_line("{% for in items %}{% endfor %}"), 1);
+        assert_eq!(syntax_line("{{ a-b }}"), 1);
+        assert_eq!(syntax_line("{% include header %}"), 1);
+    }
+
+    #[test]
+    fn loads_every_template_up_front() {
+        let dir = env::temp_dir().join(format!("web-service-load-all-{}", std::process::id()));
+        fs::create_dir_all(dir.join("parts")).unwrap();
+        fs::write(dir.join("page.html"), "{% include \"parts/_nav.html\" %}").unwrap();
+        fs::write(dir.join("parts/_nav.html"), "<nav>{{ title }}</nav>").unwrap();
+        fs::write(dir.join(".page.html.swp"), "{{ oops").unwrap();
+
+        let templates = Templates::new(&dir).unwrap();
+        assert_eq!(
+            templates.load_all().unwrap(),
+            ["page.html", "parts/_nav.html"]
+        );
+
+        // A partial no page renders yet is still checked.
+        fs::write(dir.join("parts/_footer.html"), "{% if x %}").unwrap();
+        assert!(matches!(
+            Templates::new(&dir).unwrap().load_all(),
+            Err(TemplateError::Syntax { name, .. }) if name == "parts/_footer.html"
+        ));
+        fs::write(
+            dir.join("parts/_footer.html"),
+            "{% include \"_gone.html\" %}",
+        )
+        .unwrap();
+        assert!(matches!(
+            Templates::new(&dir).unwrap().load_all(),
+            Err(TemplateError::Include { name, .. }) if name == "_gone.html"
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn includes_and_reloads_templates() {
+        let dir = env::temp_dir().join(format!("web-service-templates-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("_header.html"), "<h1>{{ title }}</h1>\n").unwrap();
+        fs::write(
+            dir.join("page.html"),
+            "{% include \"_header.html\" %}\nbody",
+        )
+        .unwrap();
+        fs::write(dir.join("loop.html"), "{% include \"loop.html\" %}").unwrap();
+
+        let templates = Templates::new(&dir).unwrap().reload(true);
+        let context = Context::new().with("title", "Home");
+        assert_eq!(
+            templates.render("page.html", &context).unwrap(),
+            "<h1>Home</h1>\nbody"
+        );
+        assert!(matches!(
+            templates.render("loop.html", &context),
+            Err(TemplateError::Include { .. })
+        ));
+        assert!(matches!(
+            templates.render("../page.html", &context),
+            Err(TemplateError::InvalidName(_))
+        ));
+        assert!(matches!(
+            templates.load("missing.html"),
+            Err(TemplateError::Io { .. })
+        ));
+
+        // Editing a partial shows up in the pages that include it.
+        let header = dir.join("_header.html");
+        fs::write(&header, "<h2>{{ title }}</h2>\n").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(5);
+        File::options()
+            .write(true)
+            .open(&header)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(
+            templates.render("page.html", &context).unwrap(),
+            "<h2>Home</h2>\nbody"
+        );
+
+        // Without reloading, the first compilation sticks.
+        let fixed = Templates::new(&dir).unwrap();
+        assert_eq!(
+            fixed.render("page.html", &context).unwrap(),
+            "<h2>Home</h2>\nbody"
+        );
+        fs::write(&header, "changed").unwrap();
+        assert_eq!(
+            fixed.render("page.html", &context).unwrap(),
+            "<h2>Home</h2>\nbody"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::request::{Method, Version};
+use crate::response::Response;
+
+/// Takes over a connection once a `101 Switching Protocols` response has
+/// been sent on it.
+pub struct Upgrade {
+    run: Box<dyn FnOnce(Upgraded) + Send + 'static>,
+    // Whether the new owner reads from the client, and so can be asked to
+    // wind down at shutdown by closing the read side.
+    reads: bool,
+    // Taken before the switch is sent, so that a full server can still
+    // answer with an error instead.
+    slot: Option<Slot>,
+}
+
+impl Upgrade {
+    pub fn new<F>(f: F) -> Upgrade
+    where
+        F: FnOnce(Upgraded) + Send + 'static,
+    {
+        Upgrade {
+            run: Box::new(f),
+            reads: true,
+            slot: None,
+        }
+    }
+
+    // Sends `response` once the connection has been handed over, for
+    // responses that stay open indefinitely. Nothing more is read from the
+    // client, so shutdown closes the connection instead of waiting for it.
+    pub(crate) fn detached(
+        response: Response,
+        method: Method,
+        version: Version,
+        write_timeout: Duration,
+    ) -> Upgrade {
+        let mut upgrade = Upgrade::new(move |mut connection: Upgraded| {
+            // Failing writes mean the client has gone, which is how these
+            // responses usually end.
+            if connection
+                .stream
+                .set_write_timeout(Some(write_timeout))
+                .is_ok()
+            {
+                let _ = response.write_to(&mut connection, method, version);
+            }
+        });
+        upgrade.reads = false;
+        upgrade
+    }
+
+    // Holds a place among `upgrades` for this connection. Returns false if
+    // there is none to be had.
+    pub(crate) fn reserve(&mut self, upgrades: &Upgrades) -> bool {
+        self.slot = upgrades.reserve(self.reads);
+        self.slot.is_some()
+    }
+
+    // Runs the new protocol on a thread of its own, so that a long-lived
+    // connection never ties up a worker.
+    pub(crate) fn spawn(self, connection: Upgraded) {
+        let peer = connection.peer_addr().ok();
+        let Upgrade { run, slot, .. } = self;
+        let slot = slot.expect("upgrades are reserved before the switch is sent");
+        let spawned = slot.track(&connection.stream).and_then(|()| {
+            thread::Builder::new()
+                .name("upgraded".to_string())
+                .spawn(move || {
+                    run(connection);
+                    drop(slot);
+                })
+        });
+        if let Err(e) = spawned {
+            match peer {
+                Some(peer) => eprintln!("connection from {}: cannot upgrade: {}", peer, e),
+                None => eprintln!("cannot upgrade connection: {}", e),
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Upgrade")
+    }
+}
+
+/// A connection handed over by the server after a protocol switch.
+///
+/// Reads return whatever the client sent after its request that the server
+/// had already buffered before going on to the socket. The socket is
+/// blocking; reads on it time out after the server's upgraded idle timeout
+/// and writes have no timeout. When the server shuts down, the read side is
+/// closed so that the handler sees its client hang up, and the socket is
+/// closed outright if the handler still has it at the shutdown deadline.
+pub struct Upgraded {
+    stream: TcpStream,
+    buffered: Vec<u8>,
+    pos: usize,
+}
+
+impl Upgraded {
+    pub(crate) fn new(stream: TcpStream, buffered: Vec<u8>) -> io::Result<Upgraded> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(Upgraded {
+            stream,
+            buffered,
+            pos: 0,
+        })
+    }
+
+    /// The underlying socket, for setting timeouts or cloning a handle to
+    /// write from another thread.
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.buffered.len() {
+            let n = (&self.buffered[self.pos..]).read(buf)?;
+            self.pos += n;
+            if self.pos == self.buffered.len() {
+                self.buffered = Vec::new();
+                self.pos = 0;
+            }
+            return Ok(n);
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Connections handed over to threads of their own, upgraded ones and
+/// detached responses, counted so that there can only be so many and
+/// closed when the server shuts down.
+pub(crate) struct Upgrades {
+    pub(crate) max: usize,
+    pub(crate) idle_timeout: Duration,
+    open: Arc<Open>,
+}
+
+#[derive(Default)]
+struct Open {
+    state: Mutex<OpenState>,
+    closed: Condvar,
+}
+
+#[derive(Default)]
+struct OpenState {
+    next_id: u64,
+    connections: HashMap<u64, OpenConnection>,
+    draining: bool,
+}
+
+struct OpenConnection {
+    // `None` until the response has gone out and the socket is handed over.
+    stream: Option<TcpStream>,
+    reads: bool,
+}
+
+impl OpenConnection {
+    // Closes the read side of a connection whose owner reads, so that it
+    // sees its client hang up, and the whole of any other. Returns false
+    // in the second case, since there is nothing left to wait for.
+    fn start_draining(&self) -> bool {
+        let Some(stream) = &self.stream else {
+            return true;
+        };
+        if self.reads {
+            let _ = stream.shutdown(Shutdown::Read);
+        } else {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        self.reads
+    }
+}
+
+impl Upgrades {
+    pub(crate) fn new(max: usize, idle_timeout: Duration) -> Upgrades {
+        Upgrades {
+            max,
+            idle_timeout,
+            open: Arc::default(),
+        }
+    }
+
+    // Returns `None` if there are `max` connections handed over already or
+    // the server is shutting down.
+    fn reserve(&self, reads: bool) -> Option<Slot> {
+        let mut state = self.open.state.lock().unwrap();
+        if state.draining || state.connections.len() >= self.max {
+            return None;
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.connections.insert(
+            id,
+            OpenConnection {
+                stream: None,
+                reads,
+            },
+        );
+        Some(Slot {
+            open: Arc::clone(&self.open),
+            id,
+            idle_timeout: self.idle_timeout,
+        })
+    }
+
+    // Winds down every connection handed over and turns new ones away.
+    pub(crate) fn start_draining(&self) {
+        let mut state = self.open.state.lock().unwrap();
+        state.draining = true;
+        state
+            .connections
+            .retain(|_, connection| connection.start_draining());
+        if state.connections.is_empty() {
+            self.open.closed.notify_all();
+        }
+    }
+
+    // Returns false if connections are still open at the deadline.
+    pub(crate) fn wait_closed(&self, deadline: Instant) -> bool {
+        let mut state = self.open.state.lock().unwrap();
+        while !state.connections.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self
+                .open
+                .closed
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+        true
+    }
+
+    // Closes every socket handed over so blocked reads and writes fail
+    // fast.
+    pub(crate) fn abort_all(&self) -> usize {
+        let state = self.open.state.lock().unwrap();
+        for connection in state.connections.values() {
+            if let Some(stream) = &connection.stream {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        state.connections.len()
+    }
+}
+
+// One connection's place among those handed over, given up when its thread
+// is done with it.
+struct Slot {
+    open: Arc<Open>,
+    id: u64,
+    idle_timeout: Duration,
+}
+
+impl Slot {
+    fn track(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(self.idle_timeout))?;
+        let handle = stream.try_clone()?;
+        let mut state = self.open.state.lock().unwrap();
+        let draining = state.draining;
+        let Some(connection) = state.connections.get_mut(&self.id) else {
+            return Ok(());
+        };
+        connection.stream = Some(handle);
+        // Shutdown began while the response was going out.
+        if draining && !connection.start_draining() {
+            state.connections.remove(&self.id);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut state = self.open.state.lock().unwrap();
+        state.connections.remove(&self.id);
+        if state.connections.is_empty() {
+            self.open.closed.notify_all();
+        }
+    }
+}
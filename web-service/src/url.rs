@@ -0,0 +1,145 @@
+/// Decodes `%XX` escapes. Returns `None` for malformed escapes or if the
+/// result is not valid UTF-8.
+pub fn percent_decode(input: &str) -> Option<String> {
+    String::from_utf8(percent_decode_bytes(input)?).ok()
+}
+
+pub fn percent_decode_bytes(input: &str) -> Option<Vec<u8>> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            let hex = std::str::from_utf8(hex).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(out)
+}
+
+/// Escapes everything but unreserved characters, so that `segment` can be
+/// used as one segment of a URL path.
+pub fn percent_encode_segment(segment: &str) -> String {
+    let mut out = String::with_capacity(segment.len());
+    for &b in segment.as_bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// Decodes a request path and puts it in canonical form: no empty, `.` or
+/// `..` segments, and a trailing slash only where the path names a
+/// directory. This is the path routes and path prefixes are matched
+/// against, so that `//admin/` and `/%61dmin/x/..` are both `/admin/`.
+///
+/// Returns `None` if an escape is malformed, the result is not UTF-8, a
+/// segment holds an encoded `/`, or `..` climbs above the root.
+pub fn normalize_path(path: &str) -> Option<String> {
+    let mut segments = Vec::new();
+    let mut directory = false;
+    for segment in path.split('/') {
+        let segment = percent_decode(segment)?;
+        directory = matches!(segment.as_str(), "" | "." | "..");
+        match segment.as_str() {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            _ if segment.contains('/') => return None,
+            _ => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    if directory && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+/// Splits an `application/x-www-form-urlencoded` query string into decoded
+/// key/value pairs. Pairs that fail to decode are skipped.
+pub fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let key = percent_decode(&key.replace('+', " "))?;
+            let value = percent_decode(&value.replace('+', " "))?;
+            Some((key, value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(percent_decode("a%20b%2Fc").as_deref(), Some("a b/c"));
+        assert_eq!(percent_decode("%e4%bd%a0").as_deref(), Some("你"));
+        assert_eq!(percent_decode("100%"), None);
+        assert_eq!(percent_decode("%zz"), None);
+    }
+
+    #[test]
+    fn encodes_path_segments() {
+        assert_eq!(percent_encode_segment("a b/c?.txt"), "a%20b%2Fc%3F.txt");
+        assert_eq!(percent_encode_segment("你"), "%E4%BD%A0");
+        let name = "50% off & <more>";
+        assert_eq!(
+            percent_decode(&percent_encode_segment(name)).as_deref(),
+            Some(name)
+        );
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        for (path, normalized) in [
+            ("/", "/"),
+            ("", "/"),
+            ("/admin", "/admin"),
+            ("/admin/", "/admin/"),
+            ("//admin//users", "/admin/users"),
+            ("/%61dmin/x", "/admin/x"),
+            ("/a%20b", "/a b"),
+            ("/./admin/.", "/admin/"),
+            ("/x/../admin/users/..", "/admin/"),
+            ("/x/%2E%2E/admin", "/admin"),
+            ("/x/..", "/"),
+        ] {
+            assert_eq!(
+                normalize_path(path).as_deref(),
+                Some(normalized),
+                "{}",
+                path
+            );
+        }
+        for path in ["/..", "/x/../..", "/%2e%2e", "/a%2Fb", "/100%", "/%ff"] {
+            assert_eq!(normalize_path(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn parses_query_pairs() {
+        assert_eq!(
+            parse_query("q=rust+lang&empty=&flag&x=%26"),
+            vec![
+                ("q".to_string(), "rust lang".to_string()),
+                ("empty".to_string(), String::new()),
+                ("flag".to_string(), String::new()),
+                ("x".to_string(), "&".to_string()),
+            ]
+        );
+    }
+}
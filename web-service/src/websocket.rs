@@ -0,0 +1,783 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+
+use crate::request::{Method, Request, Version};
+use crate::response::Response;
+use crate::status::StatusCode;
+use crate::upgrade::Upgraded;
+
+/// Messages larger than this, once reassembled from their fragments, close
+/// the connection with [`MESSAGE_TOO_BIG`].
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+/// Sends to a client that has stopped reading fail after this long.
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Close codes from RFC 6455 section 7.4.1.
+pub const NORMAL_CLOSURE: u16 = 1000;
+pub const GOING_AWAY: u16 = 1001;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const INVALID_PAYLOAD: u16 = 1007;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+
+// Appended to the client's key before hashing it for `Sec-WebSocket-Accept`.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const VERSION: &str = "13";
+// Control frames must fit their payload in the first length byte.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Answers a WebSocket handshake, running `handler` on the connection once
+/// the switch has gone out.
+///
+/// Requests that are not WebSocket upgrades, or ask for a protocol version
+/// other than 13, get `426 Upgrade Required`; a malformed key gets `400`.
+pub fn accept<F>(request: &Request, handler: F) -> Response
+where
+    F: FnOnce(WebSocket) + Send + 'static,
+{
+    let upgrade = request.method == Method::Get
+        && request.version == Version::Http11
+        && request.headers.has_token("Upgrade", "websocket")
+        && request.headers.has_token("Connection", "upgrade");
+    if !upgrade {
+        return Response::error(StatusCode::UpgradeRequired)
+            .with_header("Connection", "Upgrade")
+            .with_header("Upgrade", "websocket");
+    }
+    if request.header("Sec-WebSocket-Version").map(str::trim) != Some(VERSION) {
+        return Response::error(StatusCode::UpgradeRequired)
+            .with_header("Sec-WebSocket-Version", VERSION);
+    }
+    let key = request.header("Sec-WebSocket-Key").map(str::trim);
+    let Some(key) = key.filter(|key| BASE64.decode(key).is_ok_and(|nonce| nonce.len() == 16))
+    else {
+        return Response::error(StatusCode::BadRequest);
+    };
+    Response::switching_protocols("websocket", move |connection| {
+        handler(WebSocket::new(connection))
+    })
+    .with_header("Sec-WebSocket-Accept", accept_key(key))
+}
+
+/// Wraps `f` as a route handler that upgrades each request and runs `f`
+/// on the resulting socket.
+pub fn handler<F>(f: F) -> impl Fn(&Request) -> Response + Send + Sync + 'static
+where
+    F: Fn(WebSocket) + Send + Sync + 'static,
+{
+    let f = Arc::new(f);
+    move |request| {
+        let f = Arc::clone(&f);
+        accept(request, move |socket| f(socket))
+    }
+}
+
+/// The value of `Sec-WebSocket-Accept` answering `key`.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    BASE64.encode(sha1.finalize())
+}
+
+/// The server end of a WebSocket connection.
+///
+/// Pings are answered and fragmented messages reassembled inside
+/// [`recv`](WebSocket::recv), which has to keep being called for that to
+/// happen. A client that stays quiet for as long as a read may wait is
+/// pinged there too, and the connection fails if it stays quiet as long
+/// again. Sending goes through a [`Sender`], which can be cloned and used
+/// from other threads while this one waits for messages.
+pub struct WebSocket {
+    reader: BufReader<Upgraded>,
+    sender: Sender,
+    peer: Option<SocketAddr>,
+    max_message_size: usize,
+    // A ping has gone out since the client last sent anything.
+    pinged: bool,
+    // The closing handshake has finished, or the connection failed.
+    closed: bool,
+}
+
+impl WebSocket {
+    fn new(connection: Upgraded) -> WebSocket {
+        let peer = connection.peer_addr().ok();
+        // Only fails if the process is out of file descriptors, in which
+        // case every write reports the error.
+        let writer = connection.stream().try_clone().and_then(|stream| {
+            stream.set_write_timeout(Some(DEFAULT_WRITE_TIMEOUT))?;
+            Ok(stream)
+        });
+        WebSocket {
+            reader: BufReader::new(connection),
+            sender: Sender {
+                state: Arc::new(Mutex::new(SenderState {
+                    stream: writer,
+                    close_sent: false,
+                })),
+            },
+            peer,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            pinged: false,
+            closed: false,
+        }
+    }
+
+    pub fn max_message_size(mut self, max: usize) -> Self {
+        self.max_message_size = max;
+        self
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// A handle for sending on this connection from anywhere.
+    pub fn sender(&self) -> Sender {
+        self.sender.clone()
+    }
+
+    pub fn send(&self, message: Message) -> Result<(), WebSocketError> {
+        self.sender.send(message)
+    }
+
+    /// Starts the closing handshake. [`recv`](WebSocket::recv) returns
+    /// `None` once the client has answered.
+    pub fn close(&self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        self.sender.close(code, reason)
+    }
+
+    /// Waits for the next message. Returns `None` once the connection has
+    /// closed, whether with a closing handshake or not.
+    ///
+    /// A client that breaks the protocol is sent a close frame saying why
+    /// and the error is returned; the connection is finished after that.
+    pub fn recv(&mut self) -> Result<Option<Message>, WebSocketError> {
+        if self.closed {
+            return Ok(None);
+        }
+        match self.read_message() {
+            Ok(Some(message)) => Ok(Some(message)),
+            Ok(None) => {
+                self.finish();
+                Ok(None)
+            }
+            Err(e) => {
+                if let Some(code) = e.close_code() {
+                    let _ = self.sender.close(code, "");
+                }
+                self.finish();
+                Err(e)
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        self.closed = true;
+        let _ = self.reader.get_ref().stream().shutdown(Shutdown::Both);
+    }
+
+    fn read_message(&mut self) -> Result<Option<Message>, WebSocketError> {
+        // The opcode and payload so far of a fragmented message.
+        let mut partial: Option<(u8, Vec<u8>)> = None;
+        loop {
+            let Some(frame) = self.read_frame()? else {
+                return Ok(None);
+            };
+            match frame.opcode {
+                // Not answered once we have sent a close frame.
+                PING => {
+                    let _ = self.sender.write_frame(PONG, &frame.payload);
+                    continue;
+                }
+                PONG => continue,
+                CLOSE => {
+                    let code = close_code(&frame.payload)?;
+                    // Echoes the client's code unless we started the close.
+                    let _ = self.sender.close(code.unwrap_or(NORMAL_CLOSURE), "");
+                    return Ok(None);
+                }
+                TEXT | BINARY if partial.is_some() => {
+                    return Err(WebSocketError::Protocol(
+                        "new message inside a fragmented one",
+                    ))
+                }
+                TEXT | BINARY => partial = Some((frame.opcode, frame.payload)),
+                CONTINUATION => match &mut partial {
+                    Some((_, payload)) => payload.extend_from_slice(&frame.payload),
+                    None => return Err(WebSocketError::Protocol("continuation of no message")),
+                },
+                _ => return Err(WebSocketError::Protocol("unknown opcode")),
+            }
+            match partial.take() {
+                Some((_, payload)) if payload.len() > self.max_message_size => {
+                    return Err(WebSocketError::MessageTooBig)
+                }
+                Some((TEXT, payload)) if frame.fin => {
+                    let text =
+                        String::from_utf8(payload).map_err(|_| WebSocketError::InvalidUtf8)?;
+                    return Ok(Some(Message::Text(text)));
+                }
+                Some((_, payload)) if frame.fin => return Ok(Some(Message::Binary(payload))),
+                unfinished => partial = unfinished,
+            }
+        }
+    }
+
+    // Returns `None` if the client closed the connection between frames.
+    fn read_frame(&mut self) -> Result<Option<Frame>, WebSocketError> {
+        let mut head = [0; 2];
+        loop {
+            match self.reader.read(&mut head[..1]) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => return Ok(None),
+                Err(e) if is_timeout(&e) && !self.pinged => {
+                    self.pinged = true;
+                    let _ = self.sender.ping(&[]);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.pinged = false;
+        self.reader.read_exact(&mut head[1..])?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        if head[0] & 0x70 != 0 {
+            return Err(WebSocketError::Protocol("reserved bits set"));
+        }
+        if head[1] & 0x80 == 0 {
+            return Err(WebSocketError::Protocol("client frames must be masked"));
+        }
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut len = [0; 2];
+                self.reader.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0; 8];
+                self.reader.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => len as u64,
+        };
+        if opcode & 0x8 != 0 && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(WebSocketError::Protocol("malformed control frame"));
+        }
+        if len > self.max_message_size as u64 {
+            return Err(WebSocketError::MessageTooBig);
+        }
+        let mut mask = [0; 4];
+        self.reader.read_exact(&mut mask)?;
+        let mut payload = vec![0; len as usize];
+        self.reader.read_exact(&mut payload)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            payload,
+        }))
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// The status code of a close frame, checked against those a client may
+// send.
+fn close_code(payload: &[u8]) -> Result<Option<u16>, WebSocketError> {
+    let (code, reason) = match payload {
+        [] => return Ok(None),
+        [_] => return Err(WebSocketError::Protocol("truncated close code")),
+        [high, low, reason @ ..] => (u16::from_be_bytes([*high, *low]), reason),
+    };
+    let valid = matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999);
+    if !valid {
+        return Err(WebSocketError::Protocol("invalid close code"));
+    }
+    if std::str::from_utf8(reason).is_err() {
+        return Err(WebSocketError::InvalidUtf8);
+    }
+    Ok(Some(code))
+}
+
+/// Sends messages on a WebSocket connection. Clones share the connection,
+/// and each frame is written whole.
+#[derive(Clone)]
+pub struct Sender {
+    state: Arc<Mutex<SenderState>>,
+}
+
+struct SenderState {
+    stream: io::Result<TcpStream>,
+    close_sent: bool,
+}
+
+impl Sender {
+    pub fn send(&self, message: Message) -> Result<(), WebSocketError> {
+        match message {
+            Message::Text(text) => self.write_frame(TEXT, text.as_bytes()),
+            Message::Binary(data) => self.write_frame(BINARY, &data),
+        }
+    }
+
+    pub fn send_text(&self, text: &str) -> Result<(), WebSocketError> {
+        self.write_frame(TEXT, text.as_bytes())
+    }
+
+    /// Sends a ping; the client's pong is consumed by
+    /// [`WebSocket::recv`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `data` is over 125 bytes.
+    pub fn ping(&self, data: &[u8]) -> Result<(), WebSocketError> {
+        assert!(data.len() <= MAX_CONTROL_PAYLOAD, "ping payload too long");
+        self.write_frame(PING, data)
+    }
+
+    /// Sends a close frame, unless one has been sent already. Nothing can
+    /// be sent after it.
+    pub fn close(&self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        payload.truncate(MAX_CONTROL_PAYLOAD);
+        let mut state = self.state.lock().unwrap();
+        if state.close_sent {
+            return Ok(());
+        }
+        state.close_sent = true;
+        write_frame(&mut state, CLOSE, &payload)
+    }
+
+    /// Whether a close frame has gone out.
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().close_sent
+    }
+
+    fn write_frame(&self, opcode: u8, payload: &[u8]) -> Result<(), WebSocketError> {
+        let mut state = self.state.lock().unwrap();
+        if state.close_sent {
+            return Err(WebSocketError::Closed);
+        }
+        write_frame(&mut state, opcode, payload)
+    }
+}
+
+fn write_frame(state: &mut SenderState, opcode: u8, payload: &[u8]) -> Result<(), WebSocketError> {
+    let stream = match &mut state.stream {
+        Ok(stream) => stream,
+        Err(e) => return Err(io::Error::new(e.kind(), e.to_string()).into()),
+    };
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)?;
+    Ok(())
+}
+
+/// Keeps a set of connections to send the same messages to, such as the
+/// members of a chat room or the viewers of a live dashboard.
+#[derive(Clone, Default)]
+pub struct Broadcast {
+    members: Arc<Mutex<Vec<(u64, Sender)>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Broadcast {
+    pub fn new() -> Broadcast {
+        Broadcast::default()
+    }
+
+    /// Adds a member, returning the id to [`leave`](Broadcast::leave) with.
+    pub fn join(&self, sender: Sender) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.members.lock().unwrap().push((id, sender));
+        id
+    }
+
+    pub fn leave(&self, id: u64) {
+        self.members
+            .lock()
+            .unwrap()
+            .retain(|(member, _)| *member != id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Sends `message` to every member, dropping those it cannot reach.
+    ///
+    /// A member whose client has stopped reading holds the send up until
+    /// its write times out, but members can join and leave meanwhile.
+    pub fn send(&self, message: &Message) {
+        let members = self.members.lock().unwrap().clone();
+        let unreachable: Vec<u64> = members
+            .into_iter()
+            .filter(|(_, sender)| sender.send(message.clone()).is_err())
+            .map(|(id, _)| id)
+            .collect();
+        if !unreachable.is_empty() {
+            self.members
+                .lock()
+                .unwrap()
+                .retain(|(id, _)| !unreachable.contains(id));
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WebSocketError {
+    Io(io::Error),
+    /// The client broke RFC 6455.
+    Protocol(&'static str),
+    /// A text message or close reason was not UTF-8.
+    InvalidUtf8,
+    MessageTooBig,
+    /// The connection has been closed, so nothing more can be sent.
+    Closed,
+}
+
+impl WebSocketError {
+    // The close code telling the client why the connection is being
+    // failed.
+    fn close_code(&self) -> Option<u16> {
+        match self {
+            WebSocketError::Protocol(_) => Some(PROTOCOL_ERROR),
+            WebSocketError::InvalidUtf8 => Some(INVALID_PAYLOAD),
+            WebSocketError::MessageTooBig => Some(MESSAGE_TOO_BIG),
+            WebSocketError::Io(_) | WebSocketError::Closed => None,
+        }
+    }
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebSocketError::Io(e) => write!(f, "i/o error: {}", e),
+            WebSocketError::Protocol(reason) => write!(f, "protocol error: {}", reason),
+            WebSocketError::InvalidUtf8 => f.write_str("text is not valid UTF-8"),
+            WebSocketError::MessageTooBig => f.write_str("message too big"),
+            WebSocketError::Closed => f.write_str("connection closed"),
+        }
+    }
+}
+
+impl Error for WebSocketError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WebSocketError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WebSocketError {
+    fn from(e: io::Error) -> Self {
+        WebSocketError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::request::RequestReader;
+
+    fn request(headers: &str) -> Request {
+        let raw = format!("GET /echo HTTP/1.1\r\nHost: example.com\r\n{}\r\n", headers);
+        RequestReader::new(raw.as_bytes())
+            .read_request()
+            .unwrap()
+            .unwrap()
+    }
+
+    const HANDSHAKE: &str = "Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+         Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n";
+
+    // A masked client frame.
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    // Reads one unmasked server frame as (opcode, payload).
+    fn read_server_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).unwrap();
+        assert_eq!(head[0] & 0x80, 0x80, "server frames are never fragmented");
+        assert_eq!(head[1] & 0x80, 0, "server frames are unmasked");
+        let len = match head[1] {
+            126 => {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            }
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0F, payload)
+    }
+
+    // Connects a client socket to a `WebSocket` that starts out with
+    // `buffered` already read, as if it had followed the handshake.
+    fn connect(buffered: &[u8]) -> (TcpStream, WebSocket) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let connection = Upgraded::new(server, buffered.to_vec()).unwrap();
+        (client, WebSocket::new(connection))
+    }
+
+    #[test]
+    fn computes_the_accept_key_from_the_rfc() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn validates_the_handshake() {
+        let response = accept(&request(HANDSHAKE), |_| {});
+        assert_eq!(response.status, StatusCode::SwitchingProtocols);
+        assert_eq!(response.headers.get("Upgrade"), Some("websocket"));
+        assert_eq!(response.headers.get("Connection"), Some("Upgrade"));
+        assert_eq!(
+            response.headers.get("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        assert!(response.upgrade.is_some());
+
+        let plain = accept(&request(""), |_| {});
+        assert_eq!(plain.status, StatusCode::UpgradeRequired);
+        assert!(plain.upgrade.is_none());
+
+        let old = accept(&request(&HANDSHAKE.replace(": 13", ": 8")), |_| {});
+        assert_eq!(old.status, StatusCode::UpgradeRequired);
+        assert_eq!(old.headers.get("Sec-WebSocket-Version"), Some("13"));
+
+        let short_key = HANDSHAKE.replace("dGhlIHNhbXBsZSBub25jZQ==", "c2hvcnQ=");
+        let bad = accept(&request(&short_key), |_| {});
+        assert_eq!(bad.status, StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn reassembles_fragments_and_answers_pings() {
+        // The first fragment arrived with the handshake.
+        let (mut client, mut socket) = connect(&frame(false, TEXT, b"Hel"));
+        client
+            .write_all(&frame(false, CONTINUATION, b"lo, "))
+            .unwrap();
+        client
+            .write_all(&frame(true, PING, b"are you there"))
+            .unwrap();
+        client
+            .write_all(&frame(true, CONTINUATION, b"world"))
+            .unwrap();
+        let binary: Vec<u8> = (0..=255).cycle().take(300).collect();
+        client.write_all(&frame(true, BINARY, &binary)).unwrap();
+
+        let message = socket.recv().unwrap();
+        assert_eq!(message, Some(Message::Text("Hello, world".to_string())));
+        assert_eq!(
+            read_server_frame(&mut client),
+            (PONG, b"are you there".to_vec())
+        );
+        assert_eq!(
+            socket.recv().unwrap(),
+            Some(Message::Binary(binary.clone()))
+        );
+
+        socket.send(Message::Binary(binary.clone())).unwrap();
+        assert_eq!(read_server_frame(&mut client), (BINARY, binary));
+    }
+
+    #[test]
+    fn completes_the_closing_handshake() {
+        let (mut client, mut socket) = connect(&[]);
+        let handle = thread::spawn(move || {
+            let received = socket.recv().unwrap();
+            (received, socket.sender().is_closed())
+        });
+        let mut close = GOING_AWAY.to_be_bytes().to_vec();
+        close.extend_from_slice(b"bye");
+        client.write_all(&frame(true, CLOSE, &close)).unwrap();
+        assert_eq!(
+            read_server_frame(&mut client),
+            (CLOSE, GOING_AWAY.to_be_bytes().to_vec())
+        );
+        // The server closes the TCP connection once the handshake is done.
+        assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+        assert_eq!(handle.join().unwrap(), (None, true));
+    }
+
+    #[test]
+    fn fails_connections_that_break_the_protocol() {
+        let unmasked = [0x81, 0x02, b'h', b'i'];
+        let (mut client, mut socket) = connect(&unmasked);
+        assert!(matches!(socket.recv(), Err(WebSocketError::Protocol(_))));
+        assert_eq!(
+            read_server_frame(&mut client),
+            (CLOSE, PROTOCOL_ERROR.to_be_bytes().to_vec())
+        );
+        assert_eq!(socket.recv().unwrap(), None);
+
+        let (mut client, socket) = connect(&frame(true, TEXT, &[0xFF, 0xFE]));
+        let mut socket = socket.max_message_size(64);
+        assert!(matches!(socket.recv(), Err(WebSocketError::InvalidUtf8)));
+        assert_eq!(
+            read_server_frame(&mut client).1,
+            INVALID_PAYLOAD.to_be_bytes()
+        );
+
+        let (mut client, socket) = connect(&frame(true, BINARY, &[0; 65]));
+        let mut socket = socket.max_message_size(64);
+        assert!(matches!(socket.recv(), Err(WebSocketError::MessageTooBig)));
+        assert_eq!(
+            read_server_frame(&mut client).1,
+            MESSAGE_TOO_BIG.to_be_bytes()
+        );
+        assert!(matches!(
+            socket.send(Message::Text("late".to_string())),
+            Err(WebSocketError::Closed)
+        ));
+    }
+
+    #[test]
+    fn pings_quiet_clients_and_gives_up_on_silent_ones() {
+        let (mut client, mut socket) = connect(&[]);
+        socket
+            .reader
+            .get_ref()
+            .stream()
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let handle = thread::spawn(move || {
+            let answered = socket.recv().unwrap();
+            let silent = socket.recv();
+            (answered, silent)
+        });
+        assert_eq!(read_server_frame(&mut client), (PING, Vec::new()));
+        client.write_all(&frame(true, PONG, b"")).unwrap();
+        client.write_all(&frame(true, TEXT, b"still here")).unwrap();
+        // Pinged again once the message is in, but never answers.
+        assert_eq!(read_server_frame(&mut client), (PING, Vec::new()));
+        let (answered, silent) = handle.join().unwrap();
+        assert_eq!(answered, Some(Message::Text("still here".to_string())));
+        assert!(
+            matches!(&silent, Err(WebSocketError::Io(e)) if is_timeout(e)),
+            "{:?}",
+            silent
+        );
+        assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn broadcasts_to_members_until_they_leave() {
+        let room = Broadcast::new();
+        let (mut alice, alice_socket) = connect(&[]);
+        let (mut bob, bob_socket) = connect(&[]);
+        let alice_id = room.join(alice_socket.sender());
+        room.join(bob_socket.sender());
+
+        room.send(&Message::Text("hello all".to_string()));
+        for client in [&mut alice, &mut bob] {
+            assert_eq!(read_server_frame(client), (TEXT, b"hello all".to_vec()));
+        }
+        room.leave(alice_id);
+        bob_socket.close(NORMAL_CLOSURE, "").unwrap();
+        // Bob can no longer be sent to, so he is dropped as well.
+        room.send(&Message::Text("anyone?".to_string()));
+        assert!(room.is_empty());
+    }
+
+    #[test]
+    fn lets_members_come_and_go_while_a_send_is_stuck() {
+        let room = Broadcast::new();
+        let (stalled, stalled_socket) = connect(&[]);
+        room.join(stalled_socket.sender());
+        let sending = {
+            let room = room.clone();
+            thread::spawn(move || room.send(&Message::Binary(vec![0; 32 << 20])))
+        };
+        // The client reads none of it, so the send stops part way.
+        thread::sleep(Duration::from_millis(100));
+        assert!(!sending.is_finished());
+        let (_other, other_socket) = connect(&[]);
+        let id = room.join(other_socket.sender());
+        assert_eq!(room.len(), 2);
+        room.leave(id);
+
+        drop(stalled);
+        sending.join().unwrap();
+        assert!(room.is_empty());
+    }
+}
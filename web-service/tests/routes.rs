@@ -0,0 +1,564 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use flate2::read::GzDecoder;
+use web_service::client::Client;
+use web_service::request::Method;
+use web_service::status::StatusCode;
+
+const MANIFEST_DIR: &str = env!("CARGO_MANIFEST_DIR");
+
+// The web-service binary listening on an ephemeral port, stopped with
+// SIGTERM when dropped.
+struct TestServer {
+    child: Child,
+    addr: SocketAddr,
+}
+
+impl TestServer {
+    fn start(args: &[&str]) -> TestServer {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_web-service"))
+            .args(["--bind", "127.0.0.1:0", "--access-log", "off"])
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("web-service starts");
+        let mut stderr = BufReader::new(child.stderr.take().unwrap());
+        let mut line = String::new();
+        stderr.read_line(&mut line).unwrap();
+        let addr = line
+            .trim()
+            .strip_prefix("listening on http://")
+            .unwrap_or_else(|| panic!("unexpected startup output {:?}", line))
+            .parse()
+            .unwrap();
+        // Keeps the pipe drained so the server never blocks on logging.
+        thread::spawn(move || {
+            let _ = std::io::copy(&mut stderr, &mut std::io::sink());
+        });
+        TestServer { child, addr }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        unsafe { libc::kill(self.child.id() as libc::pid_t, libc::SIGTERM) };
+        let _ = self.child.wait();
+    }
+}
+
+fn client() -> Client {
+    Client::new().timeout(Duration::from_secs(5))
+}
+
+// A scratch document root with a directory that has no index.html.
+fn scratch_root(name: &str) -> PathBuf {
+    let root = env::temp_dir().join(format!(
+        "web-service-routes-{}-{}",
+        std::process::id(),
+        name
+    ));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("files/nested")).unwrap();
+    fs::write(root.join("index.html"), "<p>scratch</p>").unwrap();
+    fs::write(root.join("files/a.txt"), "alpha").unwrap();
+    fs::write(root.join("files/b.txt"), "bravo!").unwrap();
+    root
+}
+
+fn public_file(name: &str) -> Vec<u8> {
+    fs::read(Path::new(MANIFEST_DIR).join("public").join(name)).unwrap()
+}
+
+// Sends raw bytes and returns everything the server sends back.
+fn raw_exchange(addr: SocketAddr, raw: &[u8]) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(raw).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn checks_pages(server: &TestServer) {
+    let mut client = client();
+
+    let hello = client.get(&server.url("/")).send().unwrap();
+    assert_eq!(hello.status, StatusCode::Ok);
+    assert_eq!(
+        hello.header("Content-Type"),
+        Some("text/html; charset=utf-8")
+    );
+    assert!(hello.header("Date").is_some());
+    assert!(hello.header("Server").unwrap().starts_with("web-service/"));
+    assert_eq!(
+        hello.header("Content-Length"),
+        Some(hello.body.len().to_string().as_str())
+    );
+    let text = hello.text();
+    assert!(text.contains("<title>Hello!</title>"), "{}", text);
+    assert!(text.contains("<h1>Hello!</h1>"), "{}", text);
+    assert!(text.contains("<p>Hi from Rust</p>"), "{}", text);
+
+    let head = client.head(&server.url("/")).send().unwrap();
+    assert_eq!(head.status, StatusCode::Ok);
+    assert!(head.body.is_empty());
+    assert_eq!(
+        head.header("Content-Length"),
+        hello.header("Content-Length")
+    );
+
+    let missing = client.get(&server.url("/no/such/page")).send().unwrap();
+    assert_eq!(missing.status, StatusCode::NotFound);
+    assert_eq!(
+        missing.header("Content-Type"),
+        Some("text/html; charset=utf-8")
+    );
+    let text = missing.text();
+    assert!(text.contains("<title>Not Found</title>"), "{}", text);
+    assert!(text.contains("<code>/no/such/page</code>"), "{}", text);
+
+    let post = client.post(&server.url("/")).body("x").send().unwrap();
+    assert_eq!(post.status, StatusCode::MethodNotAllowed);
+    assert_eq!(post.header("Allow"), Some("GET, HEAD"));
+
+    // Every response above went over the same connection.
+    assert_eq!(client.idle_connections(), 1);
+}
+
+#[test]
+fn serves_hello_and_not_found_pages() {
+    let server = TestServer::start(&[]);
+    checks_pages(&server);
+}
+
+#[test]
+fn serves_pages_from_the_epoll_backend() {
+    let server = TestServer::start(&["--backend", "epoll"]);
+    checks_pages(&server);
+}
+
+#[test]
+fn escapes_request_paths_in_the_not_found_page() {
+    let server = TestServer::start(&[]);
+    let response = client()
+        .get(&server.url("/%3Cscript%3Ealert(1)%3C/script%3E"))
+        .send()
+        .unwrap();
+    assert_eq!(response.status, StatusCode::NotFound);
+    let text = response.text();
+    assert!(!text.contains("<script>"), "{}", text);
+    assert!(
+        text.contains("&lt;script&gt;alert(1)&lt;/script&gt;"),
+        "{}",
+        text
+    );
+}
+
+#[test]
+fn serves_static_files_with_validators_and_ranges() {
+    let server = TestServer::start(&["--compression", "off"]);
+    let mut client = client();
+    let css = public_file("style.css");
+
+    let response = client.get(&server.url("/style.css")).send().unwrap();
+    assert_eq!(response.status, StatusCode::Ok);
+    assert_eq!(
+        response.header("Content-Type"),
+        Some("text/css; charset=utf-8")
+    );
+    assert_eq!(response.header("Accept-Ranges"), Some("bytes"));
+    assert!(response.header("Last-Modified").is_some());
+    assert_eq!(response.body, css);
+    let etag = response.header("ETag").unwrap().to_string();
+
+    let cached = client
+        .get(&server.url("/style.css"))
+        .header("If-None-Match", etag.as_str())
+        .send()
+        .unwrap();
+    assert_eq!(cached.status, StatusCode::NotModified);
+    assert!(cached.body.is_empty());
+
+    let range = client
+        .get(&server.url("/style.css"))
+        .header("Range", "bytes=0-9")
+        .send()
+        .unwrap();
+    assert_eq!(range.status, StatusCode::PartialContent);
+    assert_eq!(
+        range.header("Content-Range"),
+        Some(format!("bytes 0-9/{}", css.len()).as_str())
+    );
+    assert_eq!(range.body, &css[..10]);
+
+    // Paths that climb above the root are refused before any route sees
+    // them.
+    let outside = client.get(&server.url("/../Cargo.toml")).send().unwrap();
+    assert_eq!(outside.status, StatusCode::BadRequest);
+    let doubled = client.get(&server.url("//style.css")).send().unwrap();
+    assert_eq!(doubled.body, css);
+}
+
+#[test]
+fn compresses_for_clients_that_accept_it() {
+    let server = TestServer::start(&["--compression-min-size", "16"]);
+    let mut client = client();
+
+    let response = client
+        .get(&server.url("/style.css"))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .unwrap();
+    assert_eq!(response.status, StatusCode::Ok);
+    assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+    assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+    let mut decoded = Vec::new();
+    GzDecoder::new(&response.body[..])
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, public_file("style.css"));
+
+    let plain = client.get(&server.url("/style.css")).send().unwrap();
+    assert_eq!(plain.header("Content-Encoding"), None);
+    assert_eq!(plain.body, public_file("style.css"));
+}
+
+#[test]
+fn lists_directories_when_enabled() {
+    let root = scratch_root("autoindex");
+    let root_arg = root.to_str().unwrap();
+    let server = TestServer::start(&["--root", root_arg, "--autoindex", "/files"]);
+    let mut client = client();
+
+    // `/` stays the hello page whatever the root holds.
+    let hello = client.get(&server.url("/")).send().unwrap();
+    assert!(hello.text().contains("<h1>Hello!</h1>"));
+    let index = client.get(&server.url("/index.html")).send().unwrap();
+    assert_eq!(index.text(), "<p>scratch</p>");
+
+    let listing = client.get(&server.url("/files/")).send().unwrap();
+    assert_eq!(listing.status, StatusCode::Ok);
+    let html = listing.text();
+    for entry in ["nested/", "a.txt", "b.txt"] {
+        assert!(html.contains(entry), "{} missing from {}", entry, html);
+    }
+
+    let json = client
+        .request(Method::Get, &server.url("/files/?sort=size&order=desc"))
+        .header("Accept", "application/json")
+        .send()
+        .unwrap();
+    assert_eq!(json.header("Content-Type"), Some("application/json"));
+    let text = json.text();
+    let (b, a) = (
+        text.find("\"b.txt\"").unwrap(),
+        text.find("\"a.txt\"").unwrap(),
+    );
+    assert!(b < a, "{}", text);
+
+    let file = client.get(&server.url("/files/a.txt")).send().unwrap();
+    assert_eq!(file.text(), "alpha");
+
+    drop(server);
+    let server = TestServer::start(&["--root", root_arg]);
+    let unlisted = client.get(&server.url("/files/")).send().unwrap();
+    assert_eq!(unlisted.status, StatusCode::NotFound);
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn rejects_malformed_requests() {
+    for backend in ["threads", "epoll"] {
+        let server = TestServer::start(&["--backend", backend]);
+        let response = raw_exchange(server.addr, b"NOT A REQUEST\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+        let response = raw_exchange(server.addr, b"GET / HTTP/2.0\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"),
+            "{}",
+            response
+        );
+    }
+}
+
+#[test]
+fn closes_when_the_client_asks() {
+    let server = TestServer::start(&[]);
+    let mut client = client();
+    let response = client
+        .get(&server.url("/"))
+        .header("Connection", "close")
+        .send()
+        .unwrap();
+    assert_eq!(response.status, StatusCode::Ok);
+    assert_eq!(response.header("Connection"), Some("close"));
+    assert_eq!(client.idle_connections(), 0);
+}
+
+#[test]
+fn forwards_proxied_prefixes_upstream() {
+    let upstream = TestServer::start(&["--compression", "off"]);
+    for backend in ["threads", "epoll"] {
+        let proxy_arg = format!("/style.css={}", upstream.addr);
+        let server = TestServer::start(&["--backend", backend, "--proxy", &proxy_arg]);
+        let mut client = client();
+
+        let response = client.get(&server.url("/style.css")).send().unwrap();
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(response.body, public_file("style.css"));
+        assert!(response.header("ETag").is_some());
+        let missing = client.get(&server.url("/style.css/nope")).send().unwrap();
+        assert_eq!(missing.status, StatusCode::NotFound);
+        // Paths outside the prefix are still served locally.
+        let hello = client.get(&server.url("/")).send().unwrap();
+        assert!(hello.text().contains("<h1>Hello!</h1>"));
+        // Request bodies stream upstream, after which the connection closes.
+        let post = client
+            .post(&server.url("/style.css"))
+            .body("x".repeat(100_000))
+            .send()
+            .unwrap();
+        assert_eq!(post.status, StatusCode::MethodNotAllowed);
+        assert_eq!(post.header("Connection"), Some("close"));
+    }
+
+    let server = TestServer::start(&["--proxy", "/api=127.0.0.1:1"]);
+    let response = client().get(&server.url("/api/x")).send().unwrap();
+    assert_eq!(response.status, StatusCode::BadGateway);
+}
+
+#[test]
+fn limits_clients_over_their_rate() {
+    for backend in ["threads", "epoll"] {
+        let server = TestServer::start(&[
+            "--backend",
+            backend,
+            "--rate-limit",
+            "/style.css=0.01,2",
+            "--rate-limit-key-header",
+            "X-API-Key",
+        ]);
+        let mut client = client();
+
+        for remaining in ["1", "0"] {
+            let response = client.get(&server.url("/style.css")).send().unwrap();
+            assert_eq!(response.status, StatusCode::Ok);
+            assert_eq!(response.header("RateLimit-Limit"), Some("2"));
+            assert_eq!(response.header("RateLimit-Remaining"), Some(remaining));
+        }
+        let limited = client.get(&server.url("/style.css")).send().unwrap();
+        assert_eq!(limited.status, StatusCode::TooManyRequests);
+        assert_eq!(limited.header("Retry-After"), Some("100"));
+        assert_eq!(limited.header("RateLimit-Remaining"), Some("0"));
+
+        // Other paths are unaffected, but sending a key does not get the
+        // same address past its limit.
+        let hello = client.get(&server.url("/")).send().unwrap();
+        assert_eq!(hello.status, StatusCode::Ok);
+        assert_eq!(hello.header("RateLimit-Limit"), None);
+        let keyed = client
+            .get(&server.url("/style.css"))
+            .header("X-API-Key", "alpha")
+            .send()
+            .unwrap();
+        assert_eq!(keyed.status, StatusCode::TooManyRequests);
+    }
+}
+
+#[test]
+fn challenges_requests_for_protected_paths() {
+    let htpasswd = env::temp_dir().join(format!(
+        "web-service-routes-{}-htpasswd",
+        std::process::id()
+    ));
+    // "password", hashed as by `htpasswd -s`.
+    fs::write(&htpasswd, "bob:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n").unwrap();
+    for backend in ["threads", "epoll"] {
+        let server = TestServer::start(&[
+            "--backend",
+            backend,
+            "--public",
+            "/style.css",
+            "--protect",
+            "/",
+            "--htpasswd",
+            htpasswd.to_str().unwrap(),
+            "--bearer-token",
+            "s3cret",
+        ]);
+        let mut client = client();
+
+        let open = client.get(&server.url("/style.css")).send().unwrap();
+        assert_eq!(open.status, StatusCode::Ok);
+        let challenged = client.get(&server.url("/")).send().unwrap();
+        assert_eq!(challenged.status, StatusCode::Unauthorized);
+        let challenges: Vec<&str> = challenged.headers.get_all("WWW-Authenticate").collect();
+        assert_eq!(
+            challenges,
+            [
+                "Basic realm=\"web-service\", charset=\"UTF-8\"",
+                "Bearer realm=\"web-service\""
+            ]
+        );
+
+        for authorization in ["Basic Ym9iOnBhc3N3b3Jk", "Bearer s3cret"] {
+            let response = client
+                .get(&server.url("/"))
+                .header("Authorization", authorization)
+                .send()
+                .unwrap();
+            assert_eq!(response.status, StatusCode::Ok, "{}", authorization);
+        }
+        let wrong = client
+            .get(&server.url("/"))
+            .header("Authorization", "Basic Ym9iOmd1ZXNz")
+            .send()
+            .unwrap();
+        assert_eq!(wrong.status, StatusCode::Unauthorized);
+    }
+    fs::remove_file(htpasswd).unwrap();
+}
+
+// Opens a WebSocket to `path`, sending `first` right behind the handshake,
+// and returns the socket positioned after the 101 response.
+fn websocket(server: &TestServer, path: &str, first: &[u8]) -> BufReader<TcpStream> {
+    let stream = TcpStream::connect(server.addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut handshake = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        path, server.addr
+    )
+    .into_bytes();
+    handshake.extend_from_slice(first);
+    (&stream).write_all(&handshake).unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut head = String::new();
+    while !head.ends_with("\r\n\r\n") {
+        assert!(reader.read_line(&mut head).unwrap() > 0, "{}", head);
+    }
+    assert!(
+        head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"),
+        "{}",
+        head
+    );
+    assert!(
+        head.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"),
+        "{}",
+        head
+    );
+    reader
+}
+
+// A masked text frame, as clients send them.
+fn text_frame(text: &str) -> Vec<u8> {
+    let mask = [1, 2, 3, 4];
+    let mut frame = vec![0x81, 0x80 | text.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(text.bytes().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+}
+
+fn read_text_frame(reader: &mut BufReader<TcpStream>) -> String {
+    let mut head = [0; 2];
+    reader.read_exact(&mut head).unwrap();
+    assert_eq!(head[0], 0x81);
+    let mut text = vec![0; head[1] as usize];
+    reader.read_exact(&mut text).unwrap();
+    String::from_utf8(text).unwrap()
+}
+
+#[test]
+fn echoes_and_broadcasts_over_websockets() {
+    for backend in ["threads", "epoll"] {
+        let server = TestServer::start(&["--backend", backend]);
+
+        let mut echo = websocket(&server, "/echo", &text_frame("early"));
+        assert_eq!(read_text_frame(&mut echo), "early");
+        echo.get_mut().write_all(&text_frame("hello")).unwrap();
+        assert_eq!(read_text_frame(&mut echo), "hello");
+
+        let mut alice = websocket(&server, "/chat", &[]);
+        assert_eq!(read_text_frame(&mut alice), "guest-0 joined");
+        let mut bob = websocket(&server, "/chat", &[]);
+        assert_eq!(read_text_frame(&mut alice), "guest-1 joined");
+        assert_eq!(read_text_frame(&mut bob), "guest-1 joined");
+        bob.get_mut().write_all(&text_frame("hi alice")).unwrap();
+        assert_eq!(read_text_frame(&mut alice), "guest-1: hi alice");
+        assert_eq!(read_text_frame(&mut bob), "guest-1: hi alice");
+
+        // Plain requests to a WebSocket route are turned away.
+        let response = client().get(&server.url("/echo")).send().unwrap();
+        assert_eq!(response.status, StatusCode::UpgradeRequired);
+    }
+}
+
+// Reads from `stream` until what has arrived contains `expected`.
+fn read_until(stream: &mut TcpStream, expected: &str) -> String {
+    let mut received = Vec::new();
+    let mut buf = [0; 1024];
+    while !String::from_utf8_lossy(&received).contains(expected) {
+        let n = stream.read(&mut buf).unwrap();
+        assert!(
+            n > 0,
+            "closed before {:?}: {}",
+            expected,
+            String::from_utf8_lossy(&received)
+        );
+        received.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8(received).unwrap()
+}
+
+#[test]
+fn streams_chat_lines_as_server_sent_events() {
+    for backend in ["threads", "epoll"] {
+        let server = TestServer::start(&["--backend", backend]);
+        let mut follower = TcpStream::connect(server.addr).unwrap();
+        follower
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        follower
+            .write_all(b"GET /events HTTP/1.1\r\nHost: test\r\n\r\n")
+            .unwrap();
+        let head = read_until(&mut follower, "\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("\r\nContent-Type: text/event-stream\r\n"));
+
+        let mut chat = websocket(&server, "/chat", &text_frame("first"));
+        read_until(&mut follower, "data: guest-0: first\nid: 2\n\n");
+        chat.get_mut().write_all(&text_frame("second")).unwrap();
+        read_until(&mut follower, "data: guest-0: second\nid: 3\n\n");
+
+        // A client reconnecting after event 2 gets what it missed.
+        let mut resumed = TcpStream::connect(server.addr).unwrap();
+        resumed
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        resumed
+            .write_all(b"GET /events HTTP/1.1\r\nHost: test\r\nLast-Event-ID: 2\r\n\r\n")
+            .unwrap();
+        let replay = read_until(&mut resumed, "data: guest-0: second\n");
+        assert!(!replay.contains("first"), "{}", replay);
+    }
+}